@@ -3,9 +3,9 @@ use std::{
 };
 
 use crate::{
-    cpu::tokens::{
-        AluOp, BlockOp, Condition, DataValue, IntMode, Reg, RegPair, ShiftOp, Token, TokenType
-    }, mkword
+    cpu::{CpuModel, tokens::{
+        AluOp, BarrelOp, BlockOp, Condition, DataValue, IntMode, Reg, RegPair, ShiftOp, Token, TokenType
+    }}, mkword
 };
 
 use super::Instruction;
@@ -20,9 +20,9 @@ pub struct TokenDecodeResult {
 
 /// Create coroutine which accepts bytes, yields decoded
 /// tokens and returns cpu instruction when entire instruction sequence is decoded
-pub fn instruction_decoder() -> impl Coroutine<u8, Yield=TokenDecodeResult, Return=Instruction> {
+pub fn instruction_decoder(model: CpuModel) -> impl Coroutine<u8, Yield=TokenDecodeResult, Return=Instruction> {
 
-    let mut tdecoder = token_decoder();
+    let mut tdecoder = token_decoder(model);
     let mut displacement: Option<i8> = None;
     let mut data: Option<DataValue> = None;
     let mut opcode: Option<Token> = None;
@@ -46,8 +46,10 @@ pub fn instruction_decoder() -> impl Coroutine<u8, Yield=TokenDecodeResult, Retu
             }
 
             if complete {
+                let opcode = opcode.expect("Expecting opcode to be defined");
                 return Instruction {
-                    opcode: opcode.expect("Expecting opcode to be defined"),
+                    // Z180 traps on undocumented Z80 instructions
+                    opcode: if model == CpuModel::Z180 && is_undocumented(&opcode) { Token::TRAP } else { opcode },
                     displacement,
                     data,
                 };
@@ -67,11 +69,43 @@ fn get_z(byte: u8) -> u8 {  byte & 0b00000111       }
 fn get_p(byte: u8) -> u8 { (byte & 0b00110000) >> 4 }
 fn get_q(byte: u8) -> u8 { (byte & 0b00001000) >> 3 }
 
+/// Check if ED-prefixed opcode is one of Z80N extended instructions
+fn is_z80n_opcode(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x23 | 0x24 | 0x27..=0x2c | 0x30..=0x36 | 0x8a | 0x90..=0x95 | 0x98 | 0xa4 | 0xa5 | 0xac | 0xb4 | 0xb7 | 0xbc
+    )
+}
+
+/// Check if ED-prefixed opcode is documented Z80 instruction
+fn is_documented_ed_opcode(byte: u8) -> bool {
+    match (get_x(byte), get_y(byte), get_z(byte)) {
+        (1, 6, 0 | 1) => false,
+        (1, _, 0..=3) => true,
+        (1, 0, 4) | (1, 0 | 1, 5) | (1, 0 | 2 | 3, 6) => true,
+        (1, y, 7) => y <= 5,
+        (2, y, z) => y >= 4 && z <= 3,
+        _ => false
+    }
+}
+
+/// Check if decoded (non ED-prefixed) opcode is undocumented Z80 instruction
+fn is_undocumented(opcode: &Token) -> bool {
+    let idx_half = |reg: &Reg| matches!(reg, Reg::IXH | Reg::IXL | Reg::IYH | Reg::IYL);
+    match opcode {
+        Token::SHOP(ShiftOp::SLL, ..) | Token::SHOP(_, _, Some(_)) |
+        Token::SET(_, _, Some(_)) | Token::RES(_, _, Some(_)) => true,
+        Token::LD_RG_RG(dst, src) => idx_half(dst) || idx_half(src),
+        Token::LD_RG_N(reg) | Token::INC_RG(reg) | Token::DEC_RG(reg) | Token::ALU(_, Some(reg)) => idx_half(reg),
+        _ => false
+    }
+}
+
 /// Create coroutine which accepts bytes, yields decoded
 /// tokens and returns when entire instruction sequence is decoded
-fn token_decoder() -> impl Coroutine<u8, Yield=TokenDecodeResult, Return=TokenDecodeResult> {
+fn token_decoder(model: CpuModel) -> impl Coroutine<u8, Yield=TokenDecodeResult, Return=TokenDecodeResult> {
 
-    #[coroutine] |mut byte: u8| {
+    #[coroutine] move |mut byte: u8| {
 
         let mut prefix: Option<u16> = None;
 
@@ -121,6 +155,108 @@ fn token_decoder() -> impl Coroutine<u8, Yield=TokenDecodeResult, Return=TokenDe
 
         match prefix {
 
+            Some(0xed) if model == CpuModel::Z80N && is_z80n_opcode(byte) => match byte {
+                0x23 => TokenDecodeResult { token: Token::SWAPNIB, upnext: TokenType::Opcode },
+                0x24 => TokenDecodeResult { token: Token::MIRROR_A, upnext: TokenType::Opcode },
+                0x27 => {
+                    let data_byte = yield TokenDecodeResult { token: Token::TEST_N, upnext: TokenType::Data };
+                    TokenDecodeResult { token: Token::Data(DataValue::Byte(data_byte)), upnext: TokenType::Opcode }
+                },
+                0x28..=0x2c => TokenDecodeResult { token: Token::BSOP(BarrelOp::from(byte)), upnext: TokenType::Opcode },
+                0x30 => TokenDecodeResult { token: Token::MUL_DE, upnext: TokenType::Opcode },
+                0x31..=0x33 => TokenDecodeResult {
+                    token: Token::ADD_RP_A([RegPair::HL, RegPair::DE, RegPair::BC][(byte - 0x31) as usize]),
+                    upnext: TokenType::Opcode
+                },
+                0x34..=0x36 => {
+                    let low_data_byte = yield TokenDecodeResult {
+                        token: Token::ADD_RP_NN([RegPair::HL, RegPair::DE, RegPair::BC][(byte - 0x34) as usize]),
+                        upnext: TokenType::Data
+                    };
+                    let high_data_byte = yield TokenDecodeResult {
+                        token: Token::Data(DataValue::Byte(low_data_byte)),
+                        upnext: TokenType::Data
+                    };
+                    TokenDecodeResult {
+                        token: Token::Data(DataValue::Word(mkword!(high_data_byte, low_data_byte))),
+                        upnext: TokenType::Opcode
+                    }
+                },
+                0x8a => {
+                    // Unlike other instructions, immediate word is stored big-endian
+                    let high_data_byte = yield TokenDecodeResult { token: Token::PUSH_NN, upnext: TokenType::Data };
+                    let low_data_byte = yield TokenDecodeResult {
+                        token: Token::Data(DataValue::Byte(high_data_byte)),
+                        upnext: TokenType::Data
+                    };
+                    TokenDecodeResult {
+                        token: Token::Data(DataValue::Word(mkword!(high_data_byte, low_data_byte))),
+                        upnext: TokenType::Opcode
+                    }
+                },
+                0x90 => TokenDecodeResult { token: Token::OUTINB, upnext: TokenType::Opcode },
+                0x91 => {
+                    let register = yield TokenDecodeResult { token: Token::NEXTREG_N_N, upnext: TokenType::Data };
+                    let value = yield TokenDecodeResult {
+                        token: Token::Data(DataValue::Byte(register)),
+                        upnext: TokenType::Data
+                    };
+                    TokenDecodeResult {
+                        token: Token::Data(DataValue::Word(mkword!(register, value))),
+                        upnext: TokenType::Opcode
+                    }
+                },
+                0x92 => {
+                    let register = yield TokenDecodeResult { token: Token::NEXTREG_N_A, upnext: TokenType::Data };
+                    TokenDecodeResult { token: Token::Data(DataValue::Byte(register)), upnext: TokenType::Opcode }
+                },
+                0x93 => TokenDecodeResult { token: Token::PIXELDN, upnext: TokenType::Opcode },
+                0x94 => TokenDecodeResult { token: Token::PIXELAD, upnext: TokenType::Opcode },
+                0x95 => TokenDecodeResult { token: Token::SETAE, upnext: TokenType::Opcode },
+                0x98 => TokenDecodeResult { token: Token::JP_AtC, upnext: TokenType::Opcode },
+                0xa4 => TokenDecodeResult { token: Token::BLOP(BlockOp::LDIX), upnext: TokenType::Opcode },
+                0xa5 => TokenDecodeResult { token: Token::LDWS, upnext: TokenType::Opcode },
+                0xac => TokenDecodeResult { token: Token::BLOP(BlockOp::LDDX), upnext: TokenType::Opcode },
+                0xb4 => TokenDecodeResult { token: Token::BLOP(BlockOp::LDIRX), upnext: TokenType::Opcode },
+                0xb7 => TokenDecodeResult { token: Token::BLOP(BlockOp::LDPIRX), upnext: TokenType::Opcode },
+                0xbc => TokenDecodeResult { token: Token::BLOP(BlockOp::LDDRX), upnext: TokenType::Opcode },
+                _ => unreachable!()
+            },
+
+            Some(0xed) if model == CpuModel::Z180 && !is_documented_ed_opcode(byte) => {
+                match (get_x(byte), get_y(byte), get_z(byte)) {
+                    (0, y, z @ (0 | 1)) if y != 6 || z == 0 => {
+                        let port_byte = yield TokenDecodeResult {
+                            token: match (y, z) {
+                                (6, _) => Token::IN0_N,
+                                (_, 0) => Token::IN0_RG_N(Reg::from(y)),
+                                _ => Token::OUT0_N_RG(Reg::from(y)),
+                            },
+                            upnext: TokenType::Data
+                        };
+                        TokenDecodeResult { token: Token::Data(DataValue::Byte(port_byte)), upnext: TokenType::Opcode }
+                    },
+                    (0, y, 4) => TokenDecodeResult { token: Token::TST(Some(Reg::from(y))), upnext: TokenType::Opcode },
+                    (1, y @ (4 | 6), 4) => {
+                        let data_byte = yield TokenDecodeResult {
+                            token: if y == 4 { Token::TST(None) } else { Token::TSTIO_N },
+                            upnext: TokenType::Data
+                        };
+                        TokenDecodeResult { token: Token::Data(DataValue::Byte(data_byte)), upnext: TokenType::Opcode }
+                    },
+                    (1, y, 4) if y & 1 == 1 => TokenDecodeResult {
+                        token: Token::MLT(RegPair::from(get_p(byte)).prefer_sp()),
+                        upnext: TokenType::Opcode
+                    },
+                    (1, 6, 6) => TokenDecodeResult { token: Token::SLP, upnext: TokenType::Opcode },
+                    (2, y @ 0..=3, 3) => TokenDecodeResult {
+                        token: Token::BLOP([BlockOp::OTIM, BlockOp::OTDM, BlockOp::OTIMR, BlockOp::OTDMR][y as usize]),
+                        upnext: TokenType::Opcode
+                    },
+                    (_, _, _) => TokenDecodeResult { token: Token::TRAP, upnext: TokenType::Opcode }
+                }
+            },
+
             Some(0xed) => match (get_x(byte), get_y(byte), get_z(byte)) {
                 (1, 6, 0) => TokenDecodeResult { token: Token::IN_AtBC, upnext: TokenType::Opcode },
                 (1, y, 0) => TokenDecodeResult { token: Token::IN_RG_AtBC(Reg::from(y)), upnext: TokenType::Opcode },
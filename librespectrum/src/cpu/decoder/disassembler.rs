@@ -2,6 +2,8 @@ use std::{
     ops::{Coroutine, CoroutineState}, pin::Pin
 };
 
+use crate::cpu::CpuModel;
+
 use super::{Instruction, instruction_decoder};
 
 pub struct DisassembledLine {
@@ -12,12 +14,13 @@ pub struct DisassembledLine {
 
 pub fn disassembler(
     base_address: u16,
-    line_bytes: usize
+    line_bytes: usize,
+    model: CpuModel
 ) -> impl Coroutine<u8, Yield=Option<DisassembledLine>, Return=!> {
 
     let mut address = base_address;
     let mut bytes = Vec::with_capacity(line_bytes);
-    let mut decoder = instruction_decoder();
+    let mut decoder = instruction_decoder(model);
 
     #[coroutine] move |mut byte: u8| {
 
@@ -29,7 +32,7 @@ pub fn disassembler(
                 byte = yield Some(DisassembledLine { address, bytes, instruction: Some(instruction) });
                 address = address.wrapping_add(bytes_len);
                 bytes = Vec::with_capacity(line_bytes);
-                decoder = instruction_decoder();
+                decoder = instruction_decoder(model);
             } else if bytes.len() >= line_bytes {
                 byte = yield Some(DisassembledLine { address, bytes, instruction: None });
                 address = address.wrapping_add(bytes_len);
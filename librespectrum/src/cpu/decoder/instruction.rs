@@ -1,8 +1,8 @@
 use std::fmt;
 
-use crate::cpu::tokens::{
-    AluOp, BlockOp, Condition, DataValue, IntMode, Reg, RegPair, ShiftOp, Token
-};
+use crate::{cpu::tokens::{
+    AluOp, BarrelOp, BlockOp, Condition, DataValue, IntMode, Reg, RegPair, ShiftOp, Token
+}, spword};
 
 /// Z80 CPU instruction
 pub struct Instruction {
@@ -196,8 +196,55 @@ impl Instruction {
                 BlockOp::CPDR => "CPDR",
                 BlockOp::INDR => "INDR",
                 BlockOp::OTDR => "OTDR",
+                BlockOp::LDIX => "LDIX",
+                BlockOp::LDIRX => "LDIRX",
+                BlockOp::LDDX => "LDDX",
+                BlockOp::LDDRX => "LDDRX",
+                BlockOp::LDPIRX => "LDPIRX",
+                BlockOp::OTIM => "OTIM",
+                BlockOp::OTDM => "OTDM",
+                BlockOp::OTIMR => "OTIMR",
+                BlockOp::OTDMR => "OTDMR",
             }),
 
+            // Z80N extended instructions
+            Token::SWAPNIB => String::from("SWAPNIB"),
+            Token::MIRROR_A => String::from("MIRROR A"),
+            Token::TEST_N => format!("TEST {}", self.format_data()),
+            Token::BSOP(op) => format!("{} DE,B", match op {
+                BarrelOp::BSLA => "BSLA",
+                BarrelOp::BSRA => "BSRA",
+                BarrelOp::BSRL => "BSRL",
+                BarrelOp::BSRF => "BSRF",
+                BarrelOp::BRLC => "BRLC",
+            }),
+            Token::MUL_DE => String::from("MUL D,E"),
+            Token::ADD_RP_A(rpair) => format!("ADD {},A", self.format_regpair(rpair)),
+            Token::ADD_RP_NN(rpair) => format!("ADD {},{}", self.format_regpair(rpair), self.format_data()),
+            Token::PUSH_NN => format!("PUSH {}", self.format_data()),
+            Token::OUTINB => String::from("OUTINB"),
+            Token::NEXTREG_N_N => {
+                let (register, value) = spword!(self.expect_word_data());
+                format!("NEXTREG {},{}", self.format_byte(register), self.format_byte(value))
+            },
+            Token::NEXTREG_N_A => format!("NEXTREG {},A", self.format_data()),
+            Token::PIXELDN => String::from("PIXELDN"),
+            Token::PIXELAD => String::from("PIXELAD"),
+            Token::SETAE => String::from("SETAE"),
+            Token::JP_AtC => String::from("JP (C)"),
+            Token::LDWS => String::from("LDWS"),
+
+            // Z180 extended instructions
+            Token::IN0_RG_N(reg) => format!("IN0 {},({})", self.format_reg(reg), self.format_data()),
+            Token::IN0_N => format!("IN0 F,({})", self.format_data()),
+            Token::OUT0_N_RG(reg) => format!("OUT0 ({}),{}", self.format_data(), self.format_reg(reg)),
+            Token::TST(Some(reg)) => format!("TST {}", self.format_reg(reg)),
+            Token::TST(None) => format!("TST {}", self.format_data()),
+            Token::TSTIO_N => format!("TSTIO {}", self.format_data()),
+            Token::MLT(rpair) => format!("MLT {}", self.format_regpair(rpair)),
+            Token::SLP => String::from("SLP"),
+            Token::TRAP => String::from("TRAP"),
+
             other => unreachable!("{:?}", other)
 
         }
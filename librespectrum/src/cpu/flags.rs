@@ -50,7 +50,8 @@ impl Flags {
         value ^= value >> 4;
         value ^= value >> 2;
         value ^= value >> 1;
-        self.set(Flags::P, value & 1 != 0);
+        // Set for even number of one bits
+        self.set(Flags::P, value & 1 == 0);
         self
     }
 
@@ -3,4 +3,10 @@ pub mod decoder;
 mod flags;
 pub use flags::*;
 
+mod model;
+pub use model::*;
+
+mod timing;
+pub use timing::*;
+
 pub mod tokens;
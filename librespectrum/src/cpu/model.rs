@@ -0,0 +1,34 @@
+use std::str::FromStr;
+
+/// CPU model which defines supported instruction set
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CpuModel {
+    /// Zilog Z80 including undocumented instructions
+    #[default]
+    Z80,
+    /// ZX Spectrum Next Z80N with extended ED-prefixed instructions
+    Z80N,
+    /// Zilog Z180 / Hitachi HD64180 with extended ED-prefixed instructions,
+    /// undefined and undocumented Z80 opcodes trap
+    Z180,
+}
+
+impl FromStr for CpuModel {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "z80" => Ok(CpuModel::Z80),
+            "z80n" => Ok(CpuModel::Z80N),
+            "z180" | "hd64180" => Ok(CpuModel::Z180),
+            _ => Err(format!("Unknown CPU model: {}", name))
+        }
+    }
+}
+
+/// Z80N port selecting Next register to be accessed by NEXTREG
+pub const NEXTREG_SELECT_PORT: u16 = 0x243b;
+
+/// Z80N port to write value of Next register selected by NEXTREG
+pub const NEXTREG_ACCESS_PORT: u16 = 0x253b;
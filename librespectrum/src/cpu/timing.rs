@@ -0,0 +1,205 @@
+use crate::cpu::tokens::{BlockOp, Condition, Reg, ShiftOp, Token};
+
+/// Opcode fetch (M1) duration in t-states, including prefix fetches
+pub const OPCODE_FETCH_TSTATES: u64 = 4;
+
+/// Displacement or immediate data read duration in t-states
+pub const OPERAND_READ_TSTATES: u64 = 3;
+
+/// Duration of NMI response in t-states
+pub const NMI_TSTATES: u64 = 11;
+
+/// Duration of IM0 interrupt response in t-states, not counting
+/// execution of the instruction supplied by interrupting device
+pub const IM0_TSTATES: u64 = 2;
+
+/// Duration of IM1 interrupt response in t-states
+pub const IM1_TSTATES: u64 = 13;
+
+/// Duration of IM2 interrupt response in t-states
+pub const IM2_TSTATES: u64 = 19;
+
+/// Instruction execution timing in t-states, not counting
+/// opcode fetches and displacement / immediate data reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// T-states which are always spent
+    pub base: u64,
+    /// Additional t-states when condition is met (jump is taken,
+    /// block instruction repeats, etc)
+    pub taken: u64,
+}
+
+impl Timing {
+    const fn new(base: u64, taken: u64) -> Self {
+        Self { base, taken }
+    }
+}
+
+/// Get execution timing of the instruction opcode
+pub fn timing(opcode: &Token) -> Timing {
+
+    let at_mem = |reg: &Reg| matches!(reg, Reg::AtHL);
+    let at_idx = |reg: &Reg| matches!(reg, Reg::AtIX | Reg::AtIY);
+
+    match opcode {
+
+        // 8-bit Load
+        Token::LD_RG_RG(dst, src) if at_idx(dst) || at_idx(src) => Timing::new(8, 0),
+        Token::LD_RG_RG(dst, src) if at_mem(dst) || at_mem(src) => Timing::new(3, 0),
+        Token::LD_RG_RG(Reg::I | Reg::R, _) | Token::LD_RG_RG(_, Reg::I | Reg::R) => Timing::new(1, 0),
+        Token::LD_RG_RG(..) => Timing::new(0, 0),
+        Token::LD_RG_N(reg) if at_idx(reg) => Timing::new(5, 0),
+        Token::LD_RG_N(reg) if at_mem(reg) => Timing::new(3, 0),
+        Token::LD_RG_N(..) => Timing::new(0, 0),
+        Token::LD_A_AtRP(..) | Token::LD_AtRP_A(..) | Token::LD_A_MM | Token::LD_MM_A => Timing::new(3, 0),
+
+        // 16-bit Load
+        Token::LD_RP_NN(..) => Timing::new(0, 0),
+        Token::LD_RP_MM(..) | Token::LD_MM_RP(..) => Timing::new(6, 0),
+        Token::LD_SP_RP(..) => Timing::new(2, 0),
+        Token::POP(..) => Timing::new(6, 0),
+        Token::PUSH(..) => Timing::new(7, 0),
+
+        // Exchange
+        Token::EX_DE_HL | Token::EX_AF | Token::EXX => Timing::new(0, 0),
+        Token::EX_AtSP_RP(..) => Timing::new(15, 0),
+
+        // 8-bit arithmetic and logic
+        Token::ALU(_, Some(reg)) if at_idx(reg) => Timing::new(8, 0),
+        Token::ALU(_, Some(reg)) if at_mem(reg) => Timing::new(3, 0),
+        Token::ALU(..) => Timing::new(0, 0),
+        Token::INC_RG(reg) | Token::DEC_RG(reg) if at_idx(reg) => Timing::new(12, 0),
+        Token::INC_RG(reg) | Token::DEC_RG(reg) if at_mem(reg) => Timing::new(7, 0),
+        Token::INC_RG(..) | Token::DEC_RG(..) => Timing::new(0, 0),
+
+        // General-Purpose Arithmetic and CPU Control
+        Token::DAA | Token::CPL | Token::NEG | Token::CCF | Token::SCF |
+        Token::NOP | Token::HALT | Token::DI | Token::EI | Token::IM(..) => Timing::new(0, 0),
+
+        // 16-Bit Arithmetic
+        Token::ADD_RP_RP(..) | Token::ADC_HL_RP(..) | Token::SBC_HL_RP(..) => Timing::new(7, 0),
+        Token::INC_RP(..) | Token::DEC_RP(..) => Timing::new(2, 0),
+
+        // Rotate and Shift
+        Token::SHOP(ShiftOp::RLD | ShiftOp::RRD, ..) => Timing::new(10, 0),
+        Token::SHOP(_, reg, _) if at_idx(reg) => Timing::new(8, 0),
+        Token::SHOP(_, reg, _) if at_mem(reg) => Timing::new(7, 0),
+        Token::SHOP(..) => Timing::new(0, 0),
+
+        // Bit Set, Reset and Test
+        Token::BIT(_, reg) if at_idx(reg) => Timing::new(5, 0),
+        Token::BIT(_, reg) if at_mem(reg) => Timing::new(4, 0),
+        Token::BIT(..) => Timing::new(0, 0),
+        Token::SET(_, reg, _) | Token::RES(_, reg, _) if at_idx(reg) => Timing::new(8, 0),
+        Token::SET(_, reg, _) | Token::RES(_, reg, _) if at_mem(reg) => Timing::new(7, 0),
+        Token::SET(..) | Token::RES(..) => Timing::new(0, 0),
+
+        // Jump, Call and Return
+        Token::JP(..) | Token::JP_RP(..) => Timing::new(0, 0),
+        Token::JR(..) => Timing::new(0, 5),
+        Token::DJNZ => Timing::new(1, 5),
+        Token::CALL(..) => Timing::new(0, 7),
+        Token::RET(Condition::None) => Timing::new(6, 0),
+        Token::RET(..) => Timing::new(1, 6),
+        Token::RETI | Token::RETN => Timing::new(6, 0),
+        Token::RST(..) => Timing::new(7, 0),
+
+        // IO
+        Token::IN_A_N | Token::OUT_N_A | Token::IN_RG_AtBC(..) |
+        Token::OUT_AtBC_RG(..) | Token::IN_AtBC | Token::OUT_AtBC_0 => Timing::new(4, 0),
+
+        // Block transfer, search and IO
+        Token::BLOP(BlockOp::LDI | BlockOp::LDD | BlockOp::LDIR | BlockOp::LDDR |
+            BlockOp::CPI | BlockOp::CPD | BlockOp::CPIR | BlockOp::CPDR |
+            BlockOp::INI | BlockOp::IND | BlockOp::INIR | BlockOp::INDR |
+            BlockOp::OUTI | BlockOp::OUTD | BlockOp::OTIR | BlockOp::OTDR |
+            BlockOp::LDIX | BlockOp::LDIRX | BlockOp::LDDX | BlockOp::LDDRX | BlockOp::LDPIRX |
+            BlockOp::OTIM | BlockOp::OTDM | BlockOp::OTIMR | BlockOp::OTDMR) => Timing::new(8, 5),
+
+        // Z80N extended instructions
+        Token::SWAPNIB | Token::MIRROR_A | Token::TEST_N | Token::BSOP(..) | Token::MUL_DE |
+        Token::ADD_RP_A(..) | Token::PIXELDN | Token::PIXELAD | Token::SETAE => Timing::new(0, 0),
+        Token::ADD_RP_NN(..) => Timing::new(2, 0),
+        Token::PUSH_NN => Timing::new(9, 0),
+        Token::OUTINB => Timing::new(8, 0),
+        // Next register is written with two IO cycles, which is 2 t-states longer
+        // than on the real hardware
+        Token::NEXTREG_N_N | Token::NEXTREG_N_A => Timing::new(8, 0),
+        Token::JP_AtC => Timing::new(5, 0),
+        Token::LDWS => Timing::new(6, 0),
+
+        // Z180 extended instructions. Bus cycles follow Z80 timing rather than Z180 one.
+        Token::IN0_RG_N(..) | Token::IN0_N | Token::OUT0_N_RG(..) | Token::TSTIO_N => Timing::new(4, 0),
+        Token::TST(Some(reg)) if at_mem(reg) => Timing::new(3, 0),
+        Token::TST(..) | Token::SLP => Timing::new(0, 0),
+        Token::MLT(..) => Timing::new(9, 0),
+        Token::TRAP => Timing::new(7, 0),
+
+        Token::Prefix(..) | Token::Displacement(..) | Token::Data(..) => unreachable!()
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{ops::{Coroutine, CoroutineState}, pin::Pin};
+
+    use super::*;
+    use crate::cpu::{CpuModel, decoder::instruction_decoder, tokens::TokenType};
+
+    /// Decode instruction and get its total duration in t-states
+    fn tstates(bytes: &[u8], taken: bool) -> u64 {
+        let mut decoder = instruction_decoder(CpuModel::Z80);
+        let mut fetch = 0;
+        let mut upnext = TokenType::Opcode;
+        for &byte in bytes {
+            fetch += match upnext {
+                TokenType::Opcode => OPCODE_FETCH_TSTATES,
+                TokenType::Displacement | TokenType::Data => OPERAND_READ_TSTATES,
+            };
+            match Pin::new(&mut decoder).resume(byte) {
+                CoroutineState::Yielded(result) => upnext = result.upnext,
+                CoroutineState::Complete(instruction) => {
+                    let timing = timing(&instruction.opcode);
+                    return fetch + timing.base + if taken { timing.taken } else { 0 };
+                }
+            }
+        }
+        panic!("Incomplete instruction");
+    }
+
+    #[test]
+    fn matches_documented_instruction_timings() {
+        assert_eq!(tstates(&[0x00], false), 4); // NOP
+        assert_eq!(tstates(&[0x7e], false), 7); // LD A,(HL)
+        assert_eq!(tstates(&[0xdd, 0x7e, 0x05], false), 19); // LD A,(IX+5)
+        assert_eq!(tstates(&[0xdd, 0x36, 0x05, 0x00], false), 19); // LD (IX+5),0
+        assert_eq!(tstates(&[0xed, 0x57], false), 9); // LD A,I
+        assert_eq!(tstates(&[0x2a, 0x00, 0x80], false), 16); // LD HL,(8000h)
+        assert_eq!(tstates(&[0xed, 0x4b, 0x00, 0x80], false), 20); // LD BC,(8000h)
+        assert_eq!(tstates(&[0xc5], false), 11); // PUSH BC
+        assert_eq!(tstates(&[0xfd, 0xe3], false), 23); // EX (SP),IY
+        assert_eq!(tstates(&[0x34], false), 11); // INC (HL)
+        assert_eq!(tstates(&[0xdd, 0x34, 0x00], false), 23); // INC (IX+0)
+        assert_eq!(tstates(&[0x09], false), 11); // ADD HL,BC
+        assert_eq!(tstates(&[0xed, 0x4a], false), 15); // ADC HL,BC
+        assert_eq!(tstates(&[0xcb, 0x06], false), 15); // RLC (HL)
+        assert_eq!(tstates(&[0xdd, 0xcb, 0x01, 0x06], false), 23); // RLC (IX+1)
+        assert_eq!(tstates(&[0xcb, 0x46], false), 12); // BIT 0,(HL)
+        assert_eq!(tstates(&[0xfd, 0xcb, 0x01, 0x46], false), 20); // BIT 0,(IY+1)
+        assert_eq!(tstates(&[0xed, 0x6f], false), 18); // RLD
+        assert_eq!((tstates(&[0x20, 0x00], false), tstates(&[0x20, 0x00], true)), (7, 12)); // JR NZ
+        assert_eq!((tstates(&[0x10, 0x00], false), tstates(&[0x10, 0x00], true)), (8, 13)); // DJNZ
+        assert_eq!((tstates(&[0xc4, 0x00, 0x00], false), tstates(&[0xc4, 0x00, 0x00], true)), (10, 17)); // CALL NZ
+        assert_eq!((tstates(&[0xc0], false), tstates(&[0xc0], true)), (5, 11)); // RET NZ
+        assert_eq!(tstates(&[0xc9], false), 10); // RET
+        assert_eq!(tstates(&[0xed, 0x4d], false), 14); // RETI
+        assert_eq!(tstates(&[0xdb, 0xfe], false), 11); // IN A,(FEh)
+        assert_eq!(tstates(&[0xed, 0x78], false), 12); // IN A,(C)
+        assert_eq!((tstates(&[0xed, 0xb0], false), tstates(&[0xed, 0xb0], true)), (16, 21)); // LDIR
+    }
+
+}
@@ -86,6 +86,34 @@ pub enum Token {
     // Block transfer, search and IO
     BLOP(BlockOp),
 
+    // Z80N extended instructions
+    SWAPNIB,
+    MIRROR_A,
+    TEST_N,
+    BSOP(BarrelOp),
+    MUL_DE,
+    ADD_RP_A(RegPair),
+    ADD_RP_NN(RegPair),
+    PUSH_NN, // big-endian immediate data
+    OUTINB,
+    NEXTREG_N_N, // register in high and value in low byte of immediate data
+    NEXTREG_N_A,
+    PIXELDN,
+    PIXELAD,
+    SETAE,
+    JP_AtC,
+    LDWS,
+
+    // Z180 extended instructions
+    IN0_RG_N(Reg),
+    IN0_N, // sets flags only
+    OUT0_N_RG(Reg),
+    TST(Option<Reg>),
+    TSTIO_N,
+    MLT(RegPair),
+    SLP,
+    TRAP, // undefined opcode
+
 }
 
 /// CPU M-cycle type
@@ -234,7 +262,8 @@ impl From<u8> for ShiftOp {
 }
 
 /// Interrupt mode
-#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IntMode {
     #[default] IM0 = 0, IM01, IM1, IM2, // DO NOT reorder
 }
@@ -262,6 +291,8 @@ pub enum BlockOp { // DO NOT reorder
     LDD,     CPD,  IND,  OUTD,
     LDIR,    CPIR, INIR, OTIR,
     LDDR,    CPDR, INDR, OTDR,
+    LDIX, LDIRX, LDDX, LDDRX, LDPIRX, // Z80N
+    OTIM, OTDM, OTIMR, OTDMR, // Z180
 }
 
 impl From<u8> for BlockOp {
@@ -270,6 +301,25 @@ impl From<u8> for BlockOp {
     }
 }
 
+/// Z80N barrel shift operation on DE by B bits
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BarrelOp {
+    BSLA = 0, BSRA, BSRL, BSRF, BRLC, // DO NOT reorder
+}
+
+impl From<u8> for BarrelOp {
+    fn from(code: u8) -> Self {
+        match code & 0b111 {
+            0 => BarrelOp::BSLA,
+            1 => BarrelOp::BSRA,
+            2 => BarrelOp::BSRL,
+            3 => BarrelOp::BSRF,
+            4 => BarrelOp::BRLC,
+            other => unreachable!("{}", other)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
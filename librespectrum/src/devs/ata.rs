@@ -0,0 +1,354 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use super::{BLOCK_SIZE, BlockImage};
+
+/// Task file registers
+const DATA: u8 = 0;
+const ERROR: u8 = 1;
+const COUNT: u8 = 2;
+const SECTOR: u8 = 3;
+const CYLINDER_LOW: u8 = 4;
+const CYLINDER_HIGH: u8 = 5;
+const DEVICE_HEAD: u8 = 6;
+const COMMAND: u8 = 7;
+
+/// Status register bits
+const STATUS_BSY: u8 = 0x80;
+const STATUS_DRDY: u8 = 0x40;
+const STATUS_DSC: u8 = 0x10;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_ERR: u8 = 0x01;
+
+/// Error register bits: command aborted, sector not found
+const ERROR_ABRT: u8 = 0x04;
+const ERROR_IDNF: u8 = 0x10;
+
+/// Device/head register bits: LBA addressing, slave device selected
+const DEVICE_LBA: u8 = 0x40;
+const DEVICE_SLAVE: u8 = 0x10;
+
+/// Default translation geometry
+const HEADS: u8 = 16;
+const SECTORS: u8 = 63;
+const MAX_CYLINDERS: u64 = 16383;
+
+/// Model name reported by IDENTIFY DEVICE
+const MODEL: &[u8] = b"RESPECTRUM DISK IMAGE";
+
+/// Data transfer in progress
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    None,
+    /// Buffer is read by host, sectors are read from the image
+    Read,
+    /// Buffer is written by host, sectors are written to the image
+    Write,
+    /// Buffer holds identification data
+    Identify,
+}
+
+/// ATA hard disk backed by disk image, connected as master device. Both CHS
+/// and LBA addressing are supported, data is transferred by PIO byte after byte.
+pub struct AtaDrive {
+    image: RefCell<Option<Rc<dyn BlockImage>>>,
+    registers: [Cell<u8>; 8],
+    status: Cell<u8>,
+    error: Cell<u8>,
+    heads: Cell<u8>,
+    sectors: Cell<u8>,
+    transfer: Cell<Transfer>,
+    /// Sectors left to transfer by current command
+    remaining: Cell<usize>,
+    buffer: RefCell<Vec<u8>>,
+    position: Cell<usize>,
+}
+
+impl Default for AtaDrive {
+    fn default() -> Self {
+        Self {
+            image: RefCell::new(None),
+            registers: Default::default(),
+            status: Cell::new(0),
+            error: Cell::new(0),
+            heads: Cell::new(HEADS),
+            sectors: Cell::new(SECTORS),
+            transfer: Cell::new(Transfer::None),
+            remaining: Cell::new(0),
+            buffer: RefCell::new(vec![]),
+            position: Cell::new(0),
+        }
+    }
+}
+
+impl AtaDrive {
+
+    /// Attach disk image
+    pub fn insert(&self, image: &Rc<dyn BlockImage>) {
+        self.image.replace(Some(Rc::clone(image)));
+        self.reset();
+    }
+
+    /// Detach disk image
+    pub fn eject(&self) {
+        self.image.replace(None);
+        self.reset();
+    }
+
+    /// Disk image is attached
+    pub fn is_present(&self) -> bool {
+        self.image.borrow().is_some()
+    }
+
+    /// Return drive to the state after power on
+    pub fn reset(&self) {
+        for (index, register) in self.registers.iter().enumerate() {
+            // Signature of ATA device: count and sector are 1, cylinder is 0
+            register.set(if index == COUNT as usize || index == SECTOR as usize { 1 } else { 0 });
+        }
+        self.status.set(if self.is_present() { STATUS_DRDY | STATUS_DSC } else { 0 });
+        self.error.set(0x01);
+        self.heads.set(HEADS);
+        self.sectors.set(SECTORS);
+        self.transfer.set(Transfer::None);
+        self.buffer.borrow_mut().clear();
+    }
+
+    /// Read task file register (0-7)
+    pub fn read(&self, reg: u8) -> u8 {
+        if !self.selected() {
+            return 0;
+        }
+        match reg & 0x07 {
+            DATA => self.read_data(),
+            ERROR => self.error.get(),
+            COMMAND => self.status.get(),
+            reg => self.registers[reg as usize].get(),
+        }
+    }
+
+    /// Write task file register (0-7)
+    pub fn write(&self, reg: u8, byte: u8) {
+        match reg & 0x07 {
+            DATA => if self.selected() { self.write_data(byte) },
+            COMMAND => if self.selected() && self.status.get() & STATUS_BSY == 0 { self.execute(byte) },
+            reg => self.registers[reg as usize].set(byte),
+        }
+    }
+
+    /// Master device with attached image is selected
+    fn selected(&self) -> bool {
+        self.is_present() && self.registers[DEVICE_HEAD as usize].get() & DEVICE_SLAVE == 0
+    }
+
+    fn register(&self, reg: u8) -> u64 {
+        self.registers[reg as usize].get() as u64
+    }
+
+    fn blocks(&self) -> u64 {
+        self.image.borrow().as_ref().map_or(0, |image| image.blocks())
+    }
+
+    fn cylinders(&self) -> u64 {
+        (self.blocks() / (self.heads.get() as u64 * self.sectors.get() as u64)).min(MAX_CYLINDERS)
+    }
+
+    /// Block addressed by task file registers
+    fn address(&self) -> Option<u64> {
+        let head = self.register(DEVICE_HEAD) & 0x0f;
+        let cylinder = self.register(CYLINDER_HIGH) << 8 | self.register(CYLINDER_LOW);
+        let sector = self.register(SECTOR);
+        let block = if self.register(DEVICE_HEAD) as u8 & DEVICE_LBA != 0 {
+            head << 24 | cylinder << 8 | sector
+        } else {
+            if sector == 0 || sector > self.sectors.get() as u64 || head >= self.heads.get() as u64 {
+                return None;
+            }
+            (cylinder * self.heads.get() as u64 + head) * self.sectors.get() as u64 + sector - 1
+        };
+        (block < self.blocks()).then_some(block)
+    }
+
+    /// Store block address to task file registers
+    fn set_address(&self, block: u64) {
+        let (head, cylinder, sector) = if self.register(DEVICE_HEAD) as u8 & DEVICE_LBA != 0 {
+            (block >> 24, block >> 8, block)
+        } else {
+            let track = block / self.sectors.get() as u64;
+            (track % self.heads.get() as u64, track / self.heads.get() as u64, block % self.sectors.get() as u64 + 1)
+        };
+        let device = self.registers[DEVICE_HEAD as usize].get();
+        self.registers[DEVICE_HEAD as usize].set(device & 0xf0 | head as u8 & 0x0f);
+        self.registers[CYLINDER_HIGH as usize].set((cylinder >> 8) as u8);
+        self.registers[CYLINDER_LOW as usize].set(cylinder as u8);
+        self.registers[SECTOR as usize].set(sector as u8);
+    }
+
+    fn complete(&self) {
+        self.transfer.set(Transfer::None);
+        self.status.set(STATUS_DRDY | STATUS_DSC);
+    }
+
+    fn abort(&self, error: u8) {
+        self.transfer.set(Transfer::None);
+        self.error.set(error);
+        self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+    }
+
+    fn execute(&self, command: u8) {
+
+        self.error.set(0);
+        let count = match self.register(COUNT) { 0 => 256, count => count as usize };
+        match command {
+            // READ SECTORS, with and without retries
+            0x20 | 0x21 => {
+                self.remaining.set(count);
+                self.load_sector();
+            },
+            // WRITE SECTORS, with and without retries
+            0x30 | 0x31 => {
+                if self.address().is_none() {
+                    return self.abort(ERROR_IDNF);
+                }
+                self.remaining.set(count);
+                self.buffer.borrow_mut().clear();
+                self.transfer.set(Transfer::Write);
+                self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            },
+            // IDENTIFY DEVICE
+            0xec => {
+                self.buffer.replace(self.identify());
+                self.position.set(0);
+                self.transfer.set(Transfer::Identify);
+                self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            },
+            // INITIALIZE DEVICE PARAMETERS sets translation geometry
+            0x91 => {
+                let (heads, sectors) = ((self.register(DEVICE_HEAD) & 0x0f) as u8 + 1, self.register(COUNT) as u8);
+                if sectors == 0 {
+                    return self.abort(ERROR_ABRT);
+                }
+                self.heads.set(heads);
+                self.sectors.set(sectors);
+                self.complete();
+            },
+            // RECALIBRATE, SEEK, power management, FLUSH CACHE and SET FEATURES have nothing to do
+            0x10..=0x1f | 0x70..=0x7f | 0xe0..=0xe3 | 0xe7 | 0xef => self.complete(),
+            // CHECK POWER MODE reports active device
+            0xe5 => {
+                self.registers[COUNT as usize].set(0xff);
+                self.complete();
+            },
+            _ => self.abort(ERROR_ABRT),
+        }
+
+    }
+
+    /// Read sector at current address into the buffer
+    fn load_sector(&self) {
+        let Some(block) = self.address() else { return self.abort(ERROR_IDNF) };
+        let mut data = [0; BLOCK_SIZE];
+        let read = self.image.borrow().as_ref().is_some_and(|image| image.read_block(block, &mut data).is_ok());
+        if !read {
+            return self.abort(ERROR_IDNF);
+        }
+        self.buffer.replace(data.to_vec());
+        self.position.set(0);
+        self.transfer.set(Transfer::Read);
+        self.status.set(STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+    }
+
+    fn read_data(&self) -> u8 {
+        let transfer = self.transfer.get();
+        if !matches!(transfer, Transfer::Read | Transfer::Identify) {
+            return 0xff;
+        }
+        let position = self.position.get();
+        let byte = self.buffer.borrow()[position];
+        self.position.set(position + 1);
+        if position + 1 < BLOCK_SIZE {
+            return byte;
+        }
+        if transfer == Transfer::Identify {
+            self.complete();
+            return byte;
+        }
+        self.registers[COUNT as usize].set(self.registers[COUNT as usize].get().wrapping_sub(1));
+        let remaining = self.remaining.get() - 1;
+        self.remaining.set(remaining);
+        if remaining > 0 {
+            self.set_address(self.address().map_or(0, |block| block + 1));
+            self.load_sector();
+        } else {
+            self.complete();
+        }
+        byte
+    }
+
+    fn write_data(&self, byte: u8) {
+        if self.transfer.get() != Transfer::Write {
+            return;
+        }
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.push(byte);
+        if buffer.len() < BLOCK_SIZE {
+            return;
+        }
+        let data: [u8; BLOCK_SIZE] = buffer[..].try_into().unwrap();
+        buffer.clear();
+        drop(buffer);
+
+        let Some(block) = self.address() else { return self.abort(ERROR_IDNF) };
+        let written = self.image.borrow().as_ref().is_some_and(|image| image.write_block(block, &data).is_ok());
+        if !written {
+            return self.abort(ERROR_IDNF);
+        }
+        self.registers[COUNT as usize].set(self.registers[COUNT as usize].get().wrapping_sub(1));
+        let remaining = self.remaining.get() - 1;
+        self.remaining.set(remaining);
+        if remaining > 0 {
+            self.set_address(block + 1);
+        } else {
+            self.complete();
+        }
+    }
+
+    /// IDENTIFY DEVICE data: 256 little-endian words
+    fn identify(&self) -> Vec<u8> {
+
+        let mut words = [0u16; 256];
+        let (cylinders, heads, sectors) = (self.cylinders() as u16, self.heads.get() as u16, self.sectors.get() as u16);
+        let lba_sectors = self.blocks().min(0x0fff_ffff) as u32;
+        let chs_sectors = cylinders as u32 * heads as u32 * sectors as u32;
+
+        // Fixed device with default and current geometry and LBA support
+        words[0] = 0x0040;
+        words[1] = cylinders;
+        words[3] = HEADS as u16;
+        words[6] = SECTORS as u16;
+        words[49] = 0x0200;
+        words[53] = 0x0001;
+        words[54] = cylinders;
+        words[55] = heads;
+        words[56] = sectors;
+        words[57] = chs_sectors as u16;
+        words[58] = (chs_sectors >> 16) as u16;
+        words[60] = lba_sectors as u16;
+        words[61] = (lba_sectors >> 16) as u16;
+
+        // Strings have the first character in the high byte of each word
+        let mut set_string = |first: usize, length: usize, text: &[u8]| {
+            let mut padded = text.to_vec();
+            padded.resize(length * 2, b' ');
+            for (index, pair) in padded.chunks_exact(2).enumerate() {
+                words[first + index] = u16::from_be_bytes([pair[0], pair[1]]);
+            }
+        };
+        set_string(10, 10, b"0000000001");
+        set_string(23, 4, b"1.0");
+        set_string(27, 20, MODEL);
+
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+
+    }
+
+}
@@ -0,0 +1,153 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, FloppyDrive, IoDevice, Snapshot, Wd1793, mem::MemoryOverlay, run_io_device},
+    formats::{FormatError, Reader},
+};
+
+/// TR-DOS ROM size
+const ROM_SIZE: usize = 0x4000;
+
+/// ROM is paged in by opcode fetch from this 256-byte page
+const ENTRY_PAGE: u16 = 0x3d00;
+
+/// System register port: drive, side and controller reset on write, INTRQ and DRQ on read
+const SYSTEM_PORT: u8 = 0xff;
+
+/// System register bits
+const SYSTEM_DRIVE: u8 = 0x03;
+const SYSTEM_RESET: u8 = 0x04;
+const SYSTEM_SIDE: u8 = 0x10;
+
+/// Beta 128 disk interface with WD1793 controller and four drives. TR-DOS ROM
+/// is paged in over BASIC ROM by opcode fetch from 3D00h-3DFFh and paged out by
+/// opcode fetch from RAM. Controller registers are mapped to ports 1Fh, 3Fh, 5Fh
+/// and 7Fh, and system register to port FFh, only while TR-DOS ROM is paged in.
+pub struct Beta128 {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    rom: Vec<u8>,
+    active: Cell<bool>,
+    fdc: Wd1793,
+}
+
+impl Beta128 {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, rom: &[u8]) -> Self {
+        let mut rom = rom.to_vec();
+        rom.resize(ROM_SIZE, 0xff);
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            rom,
+            active: Cell::new(false),
+            fdc: Wd1793::new(clock, 4),
+        }
+    }
+
+    /// TR-DOS ROM is paged in
+    pub fn is_active(&self) -> bool {
+        self.active.get()
+    }
+
+    /// Drive A-D (0-3)
+    pub fn drive(&self, index: usize) -> &FloppyDrive {
+        self.fdc.drive(index)
+    }
+
+    /// Disk controller
+    pub fn fdc(&self) -> &Wd1793 {
+        &self.fdc
+    }
+
+    /// Controller register selected by port, if interface responds to it
+    fn select(&self, addr: u16) -> Option<u8> {
+        let port = addr as u8;
+        (self.active.get() && port & 0x9f == 0x1f).then_some((port >> 5) & 0x03)
+    }
+
+}
+
+impl MemoryOverlay for Beta128 {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.active.get() && (addr as usize) < ROM_SIZE
+    }
+
+    fn writable(&self, _addr: u16) -> bool {
+        false
+    }
+
+    fn write(&self, _addr: u16, _byte: u8) {}
+
+    fn read(&self, addr: u16) -> u8 {
+        self.rom[addr as usize]
+    }
+
+}
+
+/// TR-DOS ROM paging. Controller and disks aren't captured.
+impl Snapshot for Beta128 {
+
+    fn save_snapshot(&self) -> Vec<u8> {
+        vec![self.active.get() as u8]
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        self.active.set(Reader::new(data).byte()? != 0);
+        Ok(())
+    }
+
+}
+
+impl Identifiable for Beta128 {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for Beta128 {
+
+    /// Opcode fetch pages ROM in or out before memory is read
+    fn clock_tick(&self) {
+        self.fdc.update();
+        let ctrl = self.bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+        if ctrl.contains(Ctrl::MREQ | Ctrl::RD) && self.bus.m1.probe().unwrap_or(false) {
+            let addr = self.bus.addr.expect();
+            if addr & 0xff00 == ENTRY_PAGE {
+                self.active.set(true);
+            } else if addr as usize >= ROM_SIZE {
+                self.active.set(false);
+            }
+        }
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        if self.active.get() && addr as u8 == SYSTEM_PORT {
+            return Some(0x3f | ((self.fdc.intrq() as u8) << 7) | ((self.fdc.drq() as u8) << 6));
+        }
+        self.select(addr).map(|reg| self.fdc.read(reg))
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        if self.active.get() && addr as u8 == SYSTEM_PORT {
+            // Side 0 is selected by set bit
+            self.fdc.select((byte & SYSTEM_DRIVE) as usize, (byte & SYSTEM_SIDE == 0) as u8);
+            if byte & SYSTEM_RESET == 0 {
+                self.fdc.reset();
+            }
+        } else if let Some(reg) = self.select(addr) {
+            self.fdc.write(reg, byte);
+        }
+    }
+
+}
+
+impl Device for Beta128 {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
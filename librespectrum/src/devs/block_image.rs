@@ -0,0 +1,109 @@
+use std::{cell::RefCell, fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path};
+
+/// Size of a block read or written at once
+pub const BLOCK_SIZE: usize = 512;
+
+/// Storage of 512-byte blocks backing SD cards and hard disks
+pub trait BlockImage {
+
+    /// Number of whole blocks in the image
+    fn blocks(&self) -> u64;
+
+    /// Read block with given index
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> io::Result<()>;
+
+    /// Write block with given index
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> io::Result<()>;
+
+}
+
+fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "block is out of image")
+}
+
+/// Raw disk image in a local file, written in place
+pub struct FileImage {
+    file: RefCell<File>,
+    blocks: u64,
+}
+
+impl FileImage {
+
+    /// Open existing image for reading and writing
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let blocks = file.metadata()?.len() / BLOCK_SIZE as u64;
+        Ok(Self { file: RefCell::new(file), blocks })
+    }
+
+}
+
+impl BlockImage for FileImage {
+
+    fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        if index >= self.blocks { return Err(out_of_range()); }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(index * BLOCK_SIZE as u64))?;
+        file.read_exact(buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        if index >= self.blocks { return Err(out_of_range()); }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(index * BLOCK_SIZE as u64))?;
+        file.write_all(data)
+    }
+
+}
+
+/// Disk image kept in memory
+#[derive(Default)]
+pub struct MemoryImage {
+    data: RefCell<Vec<u8>>,
+}
+
+impl MemoryImage {
+
+    /// Zero-filled image with given number of blocks
+    pub fn new(blocks: u64) -> Self {
+        Self::from(vec![0; blocks as usize * BLOCK_SIZE])
+    }
+
+    /// Copy of the image contents
+    pub fn data(&self) -> Vec<u8> {
+        self.data.borrow().clone()
+    }
+
+}
+
+impl From<Vec<u8>> for MemoryImage {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data: RefCell::new(data) }
+    }
+}
+
+impl BlockImage for MemoryImage {
+
+    fn blocks(&self) -> u64 {
+        (self.data.borrow().len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        if index >= self.blocks() { return Err(out_of_range()); }
+        let offset = index as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.data.borrow()[offset..offset + BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        if index >= self.blocks() { return Err(out_of_range()); }
+        let offset = index as usize * BLOCK_SIZE;
+        self.data.borrow_mut()[offset..offset + BLOCK_SIZE].copy_from_slice(data);
+        Ok(())
+    }
+
+}
@@ -1,17 +1,17 @@
 use std::{
-    cell::Cell, ops::{Coroutine, CoroutineState, Deref}, pin::Pin, rc::Rc
+    cell::{Cell, RefCell}, fmt, ops::{Coroutine, CoroutineState, Deref}, pin::Pin, rc::Rc
 };
 
 use crate::{
     core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask, Task, U16Cell}, cpu::{
-        Flags, decoder::instruction_decoder, tokens::{AluOp, BlockOp, Condition, IntMode, Reg, RegPair, ShiftOp, Token, TokenType}
+        CpuModel, Flags, NEXTREG_ACCESS_PORT, NEXTREG_SELECT_PORT, decoder::instruction_decoder, tokens::{AluOp, BarrelOp, BlockOp, Condition, IntMode, Reg, RegPair, ShiftOp, Token, TokenType}
     }, devs::BreakpointManager, mkword, spword, yield_break_if, yield_from, yield_wait
 };
 
 use super::Device;
 
 /// Z80 CPU registers and state
-#[derive(Default)]
+#[derive(Default, Debug)]
 pub struct CpuState {
     pub af: U16Cell,
     pub bc: U16Cell,
@@ -31,6 +31,678 @@ pub struct CpuState {
     pub im: Cell<IntMode>,
     pub int: Cell<bool>,
     pub nmi: Cell<bool>,
+    /// Last executed instruction was EI, so maskable interrupt can't be accepted yet
+    pub after_ei: Cell<bool>,
+    /// Last executed instruction was LD A,I or LD A,R (NMOS Z80 resets P/V flag
+    /// if interrupt is accepted right after it)
+    pub after_ld_air: Cell<bool>,
+    /// CPU is halted and executes NOPs until interrupt
+    pub halted: Cell<bool>,
+}
+
+impl CpuState {
+
+    /// Get reference to register value
+    pub fn rg(&self, reg: Reg) -> &Cell<u8> {
+        match reg {
+            Reg::B   => &self.bc.bytes().hi,
+            Reg::C   => &self.bc.bytes().lo,
+            Reg::D   => &self.de.bytes().hi,
+            Reg::E   => &self.de.bytes().lo,
+            Reg::H   => &self.hl.bytes().hi,
+            Reg::L   => &self.hl.bytes().lo,
+            Reg::A   => &self.af.bytes().hi,
+            Reg::F   => &self.af.bytes().lo,
+            Reg::I   => &self.ir.bytes().hi,
+            Reg::R   => &self.ir.bytes().lo,
+            Reg::IXH => &self.ix.bytes().hi,
+            Reg::IXL => &self.ix.bytes().lo,
+            Reg::IYH => &self.iy.bytes().hi,
+            Reg::IYL => &self.iy.bytes().lo,
+            _ => unreachable!()
+        }
+    }
+
+    /// Get reference to regpair value
+    pub fn rp(&self, rpair: RegPair) -> &Cell<u16> {
+        match rpair {
+            RegPair::BC => &self.bc.value(),
+            RegPair::DE => &self.de.value(),
+            RegPair::HL => &self.hl.value(),
+            RegPair::AF => &self.af.value(),
+            RegPair::SP => &self.sp.value(),
+            RegPair::PC => &self.pc.value(),
+            RegPair::IR => &self.ir.value(),
+            RegPair::IX => &self.ix.value(),
+            RegPair::IY => &self.iy.value(),
+            _ => unreachable!()
+        }
+    }
+
+    /// Get CPU flags
+    pub fn get_flags(&self) -> Flags {
+        Flags::from(self.rg(Reg::F).get())
+    }
+
+    /// Set CPU flags
+    pub fn set_flags(&self, flags: Flags) {
+        self.rg(Reg::F).set(flags.bits())
+    }
+
+    /// Swap primary and alternative accumulator (AF)
+    pub fn swap_acc(&self) {
+        self.af.value().swap(&self.alt_af.value());
+    }
+
+    /// Swap primary and alternative BC,DE and HL
+    pub fn swap_regfile(&self) {
+        self.bc.value().swap(&self.alt_bc.value());
+        self.de.value().swap(&self.alt_de.value());
+        self.hl.value().swap(&self.alt_hl.value());
+    }
+
+    /// Swap HL and DE
+    pub fn swap_hlde(&self) {
+        self.hl.value().swap(&self.de.value());
+    }
+
+    /// Calculate absolute address for IX+d or IY+d offsets
+    pub fn idx_addr(&self, reg: Reg, displacement: i8) -> u16 {
+        let rpair = match reg {
+            Reg::AtIX => RegPair::IX,
+            Reg::AtIY => RegPair::IY,
+            _ => unreachable!()
+        };
+        let addr = self.rp(rpair).get() as i32 + displacement as i32;
+        return addr as u16;
+    }
+
+    /// Increment R (lower 7 bits) as every M1 cycle does
+    pub fn inc_refresh(&self) {
+        let r = self.rg(Reg::R).get();
+        self.rg(Reg::R).set(((r + 1) & 0x7f) | (r & 0x80));
+    }
+
+    // Token execution semantics shared by bus-accurate and fast CPU cores.
+    // Operations take already fetched operands and update registers and flags.
+
+    /// 8-bit arithmetic or logic operation with accumulator
+    pub fn alu(&self, op: AluOp, rhs: u8) {
+        let lhs = self.rg(Reg::A).get();
+        let mut flags = self.get_flags() & Flags::C;
+        let result = match op {
+            AluOp::ADD | AluOp::ADC => {
+                let (result, carry) = lhs.carrying_add(rhs, op == AluOp::ADC && flags.contains(Flags::C));
+                flags.set(Flags::C, carry);
+                flags.set(Flags::P, (!(lhs ^ rhs) & (lhs ^ result)) & 0x80 != 0);
+                flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x10 != 0);
+                result
+            },
+            AluOp::CP | AluOp::SUB | AluOp::SBC => {
+                flags.insert(Flags::N);
+                let (result, carry) = lhs.borrowing_sub(rhs, op == AluOp::SBC && flags.contains(Flags::C));
+                flags.set(Flags::C, carry);
+                flags.set(Flags::P, ((lhs ^ rhs) & (lhs ^ result)) & 0x80 != 0);
+                flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x10 != 0);
+                result
+            },
+            AluOp::AND | AluOp::XOR | AluOp::OR => {
+                flags.remove(Flags::C);
+                flags.set(Flags::H, op == AluOp::AND);
+                let result = match op {
+                    AluOp::AND => lhs & rhs,
+                    AluOp::XOR => lhs ^ rhs,
+                    AluOp::OR => lhs | rhs,
+                    _ => unreachable!()
+                };
+                flags.set_parity_flag(result);
+                result
+            },
+        };
+        flags |= Flags::from(result) & Flags::XY;
+        flags.set_zs_flags_u8(result);
+        self.set_flags(flags);
+        if op != AluOp::CP {
+            self.rg(Reg::A).set(result);
+        }
+    }
+
+    /// 8-bit increment or decrement, returns the result
+    pub fn inc_dec(&self, increment: bool, value: u8) -> u8 {
+        let mut flags = self.get_flags() & Flags::C;
+        let result = if increment {
+            flags.set(Flags::P, (value as i8).overflowing_add(1 as i8).1);
+            flags.set(Flags::H, (value << 4).overflowing_add(1 << 4).1);
+            value.wrapping_add(1)
+        } else {
+            flags.insert(Flags::N);
+            flags.set(Flags::P, (value as i8).overflowing_sub(1 as i8).1);
+            flags.set(Flags::H, (value << 4).overflowing_sub(1 << 4).1);
+            value.wrapping_sub(1)
+        };
+        flags |= Flags::from(result) & Flags::XY;
+        flags.set_zs_flags_u8(result);
+        self.set_flags(flags);
+        result
+    }
+
+    /// Decimal adjust accumulator
+    pub fn daa(&self) {
+        let value = self.rg(Reg::A).get();
+        let mut flags = self.get_flags();
+        let mut correction: u8 = 0;
+        if value & 0x0f > 0x09 || flags.contains(Flags::H) { correction |= 0x06; }
+        if value > 0x99 || flags.contains(Flags::C) { correction |= 0x60; }
+        let result = if flags.contains(Flags::N) {
+            value.wrapping_sub(correction)
+        } else {
+            value.wrapping_add(correction)
+        };
+        flags.set(Flags::C, correction & 0x60 != 0);
+        flags.set(Flags::H, if flags.contains(Flags::N) {
+            (value << 4).overflowing_sub(correction << 4).1
+        } else {
+            (value << 4).overflowing_add(correction << 4).1
+        });
+        flags |= Flags::from(result) & Flags::XY;
+        flags.set_zs_flags_u8(result);
+        flags.set_parity_flag(result);
+        self.set_flags(flags);
+        self.rg(Reg::A).set(result);
+    }
+
+    /// Complement accumulator
+    pub fn cpl(&self) {
+        let result = !self.rg(Reg::A).get();
+        self.rg(Reg::A).set(result);
+        self.set_flags(
+            (self.get_flags() & !Flags::XY) |
+            (Flags::from(result) & Flags::XY) |
+            Flags::H | Flags::N
+        );
+    }
+
+    /// Set carry flag
+    pub fn scf(&self) {
+        self.set_flags((self.get_flags() & !(Flags::H | Flags::N)) | Flags::C);
+    }
+
+    /// Complement carry flag, previous carry goes to half-carry
+    pub fn ccf(&self) {
+        let flags = self.get_flags();
+        let mut result = (flags & !(Flags::H | Flags::N)) ^ Flags::C;
+        result.set(Flags::H, flags.contains(Flags::C));
+        self.set_flags(result);
+    }
+
+    /// Negate accumulator
+    pub fn neg(&self) {
+        let value = self.rg(Reg::A).get();
+        let (result, carry) = (0 as u8).overflowing_sub(value);
+        let mut flags = Flags::N | (Flags::from(result) & Flags::XY);
+        flags.set(Flags::C, carry);
+        flags.set(Flags::P, (0 as i8).overflowing_sub(value as i8).1);
+        flags.set(Flags::H, (0 as u8).overflowing_sub(value << 4).1);
+        flags.set_zs_flags_u8(result);
+        self.set_flags(flags);
+        self.rg(Reg::A).set(result);
+    }
+
+    /// Load accumulator from I or R, P/V flag gets IFF2
+    pub fn ld_a_ir(&self, src: Reg) {
+        let value = self.rg(src).get();
+        self.rg(Reg::A).set(value);
+        let mut flags = (self.get_flags() & Flags::C) | (Flags::from(value) & Flags::XY);
+        flags.set_zs_flags_u8(value);
+        flags.set(Flags::P, self.iff2.get());
+        self.set_flags(flags);
+        self.after_ld_air.set(true);
+    }
+
+    /// 16-bit addition without carry
+    pub fn add_rp(&self, dst: RegPair, src: RegPair) {
+        let lhs = self.rp(dst).get();
+        let rhs = self.rp(src).get();
+        let (result, carry) = lhs.overflowing_add(rhs);
+        let mut flags = self.get_flags() & !Flags::N;
+        flags.set(Flags::C, carry);
+        flags.set(Flags::H, (lhs << 4).overflowing_add(rhs << 4).1);
+        self.rp(dst).set(result);
+        self.set_flags(flags);
+    }
+
+    /// 16-bit addition to HL with carry
+    pub fn adc_hl(&self, rpair: RegPair) {
+        let lhs = self.rp(RegPair::HL).get();
+        let rhs = self.rp(rpair).get();
+        let mut flags = self.get_flags();
+        let (result, carry) = lhs.carrying_add(rhs, flags.contains(Flags::C));
+        flags = Flags::NONE;
+        flags.set_zs_flags_u16(result);
+        flags.set(Flags::C, carry);
+        flags.set(Flags::P, (!(lhs ^ rhs) & (lhs ^ result)) & 0x8000 != 0);
+        flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x1000 != 0);
+        self.rp(RegPair::HL).set(result);
+        self.set_flags(flags);
+    }
+
+    /// 16-bit subtraction from HL with carry
+    pub fn sbc_hl(&self, rpair: RegPair) {
+        let lhs = self.rp(RegPair::HL).get();
+        let rhs = self.rp(rpair).get();
+        let mut flags = self.get_flags();
+        let (result, carry) = lhs.borrowing_sub(rhs, flags.contains(Flags::C));
+        flags = Flags::N;
+        flags.set_zs_flags_u16(result);
+        flags.set(Flags::C, carry);
+        flags.set(Flags::P, ((lhs ^ rhs) & (lhs ^ result)) & 0x8000 != 0);
+        flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x1000 != 0);
+        self.rp(RegPair::HL).set(result);
+        self.set_flags(flags);
+    }
+
+    /// Rotate or shift operation, returns the result.
+    /// RLD and RRD also update accumulator.
+    pub fn shift(&self, op: ShiftOp, val: u8) -> u8 {
+
+        let mut flags = self.get_flags() & !(Flags::H | Flags::N);
+
+        let result = match op {
+            ShiftOp::RLC => {
+                let val = val.rotate_left(1);
+                flags.set(Flags::C, val & 0x1 != 0);
+                val
+            },
+            ShiftOp::RRC => {
+                let val = val.rotate_right(1);
+                flags.set(Flags::C, val & 0x80 != 0);
+                val
+            },
+            ShiftOp::RL => {
+                let mut val = val.rotate_left(1);
+                let carry = val & 0x1 != 0; val &= !0x1;
+                if flags.contains(Flags::C) { val |= 0x1; }
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::RR => {
+                let mut val = val.rotate_right(1);
+                let carry = val & 0x80 != 0; val &= !0x80;
+                if flags.contains(Flags::C) { val |= 0x80; }
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::SLA => {
+                let mut val = val.rotate_left(1);
+                let carry = val & 0x1 != 0; val &= !0x1;
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::SRA => {
+                let mut val = val.rotate_right(1);
+                let carry = val & 0x80 != 0; val &= !0x80;
+                val |= (val & 0x40) << 1;
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::SLL => {
+                let mut val = val.rotate_left(1);
+                let carry = val & 0x1 != 0; val |= 0x1;
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::SRL => {
+                let mut val = val.rotate_right(1);
+                let carry = val & 0x80 != 0; val &= !0x80;
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::RLCA => {
+                let val = val.rotate_left(1);
+                flags.set(Flags::C, val & 0x1 != 0);
+                val
+            },
+            ShiftOp::RRCA => {
+                let val = val.rotate_right(1);
+                flags.set(Flags::C, val & 0x80 != 0);
+                val
+            },
+            ShiftOp::RLA => {
+                let mut val = val.rotate_left(1);
+                let carry = val & 0x1 != 0; val &= !0x1;
+                if flags.contains(Flags::C) { val |= 0x1; }
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::RRA => {
+                let mut val = val.rotate_right(1);
+                let carry = val & 0x80 != 0; val &= !0x80;
+                if flags.contains(Flags::C) { val |= 0x80; }
+                flags.set(Flags::C, carry);
+                val
+            },
+            ShiftOp::RLD => {
+                let acc = self.rg(Reg::A).get();
+                self.rg(Reg::A).set((acc & 0xf0) | (val >> 4));
+                (val << 4) | (acc & 0xf)
+            },
+            ShiftOp::RRD => {
+                let acc = self.rg(Reg::A).get();
+                self.rg(Reg::A).set((acc & 0xf0) | val & 0xf);
+                (val >> 4) | (acc << 4)
+            },
+        };
+
+        match op {
+            ShiftOp::RLCA | ShiftOp::RRCA | ShiftOp::RLA | ShiftOp::RRA => (),
+            ShiftOp::RLD | ShiftOp::RRD => { // Z,S,P reflect accumulator
+                let acc = self.rg(Reg::A).get();
+                flags.set_zs_flags_u8(acc);
+                flags.set_parity_flag(acc);
+            },
+            _ => { // Set Z,S,P flags for all ops except above
+                flags.set_zs_flags_u8(result);
+                flags.set_parity_flag(result);
+            }
+        }
+
+        self.set_flags(flags);
+        result
+
+    }
+
+    /// Test bit of the value
+    pub fn bit(&self, bit: u8, val: u8) {
+        let mut flags = (self.get_flags() | Flags::H) & !Flags::N;
+        let zero = (val >> bit) & 0x1 == 0;
+        flags.set(Flags::Z, zero);
+        flags.set(Flags::P, zero);
+        self.set_flags(flags);
+    }
+
+    /// Update registers after LDI/LDD/LDIR/LDDR transferred the value,
+    /// returns true if instruction has to be repeated
+    pub fn block_ld(&self, op: BlockOp, val: u8) -> bool {
+        let ctr = self.rp(RegPair::BC).get().wrapping_sub(1);
+        let increment = matches!(op, BlockOp::LDI | BlockOp::LDIR);
+        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+        self.rp(RegPair::DE).update(|de| if increment { de.wrapping_add(1) } else { de.wrapping_sub(1) });
+        self.rp(RegPair::BC).set(ctr);
+        let n = self.rg(Reg::A).get().wrapping_add(val);
+        let mut flags = self.get_flags() & (Flags::S | Flags::Z | Flags::C);
+        flags.set(Flags::P, ctr != 0);
+        flags.set(Flags::Y, n & (1 << 1) != 0);
+        flags.set(Flags::X, n & (1 << 3) != 0);
+        self.set_flags(flags);
+        matches!(op, BlockOp::LDIR | BlockOp::LDDR) && flags.contains(Flags::P)
+    }
+
+    /// Update registers after CPI/CPD/CPIR/CPDR read the value,
+    /// returns true if instruction has to be repeated
+    pub fn block_cp(&self, op: BlockOp, rhs: u8) -> bool {
+        let ctr = self.rp(RegPair::BC).get().wrapping_sub(1);
+        let lhs = self.rg(Reg::A).get();
+        let increment = matches!(op, BlockOp::CPI | BlockOp::CPIR);
+        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+        self.rp(RegPair::BC).set(ctr);
+        let mut n = lhs.wrapping_sub(rhs);
+        let mut flags = (self.get_flags() & Flags::C) | Flags::N;
+        flags.set_zs_flags_u8(n);
+        flags.set(Flags::H, (lhs << 4).overflowing_sub(rhs << 4).1);
+        if flags.contains(Flags::H) { n = n.wrapping_sub(1); }
+        flags.set(Flags::P, ctr != 0);
+        flags.set(Flags::Y, n & (1 << 1) != 0);
+        flags.set(Flags::X, n & (1 << 3) != 0);
+        self.set_flags(flags);
+        matches!(op, BlockOp::CPIR | BlockOp::CPDR) && flags.contains(Flags::P) && !flags.contains(Flags::Z)
+    }
+
+    /// Update registers after INI/IND/INIR/INDR or OUTI/OUTD/OTIR/OTDR
+    /// transferred the value, returns true if instruction has to be repeated
+    pub fn block_io(&self, op: BlockOp) -> bool {
+        let ctr = self.rg(Reg::B).get().wrapping_sub(1);
+        let increment = matches!(op, BlockOp::INI | BlockOp::INIR | BlockOp::OUTI | BlockOp::OTIR);
+        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+        self.rg(Reg::B).set(ctr);
+        let mut flags = (self.get_flags() & Flags::C) | Flags::N;
+        flags.set_zs_flags_u8(ctr);
+        self.set_flags(flags);
+        matches!(op, BlockOp::INIR | BlockOp::INDR | BlockOp::OTIR | BlockOp::OTDR) && !flags.contains(Flags::Z)
+    }
+
+    // Z80N extended instructions. None of them affects flags unless noted otherwise.
+
+    /// Swap accumulator nibbles
+    pub fn swapnib(&self) {
+        self.rg(Reg::A).update(|a| a.rotate_left(4));
+    }
+
+    /// Reverse accumulator bits
+    pub fn mirror_a(&self) {
+        self.rg(Reg::A).update(|a| a.reverse_bits());
+    }
+
+    /// Set flags as AND of two values does, but leave accumulator intact
+    pub fn test(&self, lhs: u8, rhs: u8) {
+        let acc = self.rg(Reg::A).get();
+        self.rg(Reg::A).set(lhs);
+        self.alu(AluOp::AND, rhs);
+        self.rg(Reg::A).set(acc);
+    }
+
+    /// Barrel shift or rotate DE by B bits
+    pub fn barrel_shift(&self, op: BarrelOp) {
+        let de = self.rp(RegPair::DE).get();
+        let shift = self.rg(Reg::B).get() as u32 & 0x1f;
+        let result = match op {
+            BarrelOp::BSLA => de.checked_shl(shift).unwrap_or(0),
+            BarrelOp::BSRA => (de as i16).checked_shr(shift).unwrap_or(if de & 0x8000 != 0 { -1 } else { 0 }) as u16,
+            BarrelOp::BSRL => de.checked_shr(shift).unwrap_or(0),
+            BarrelOp::BSRF => !(!de).checked_shr(shift).unwrap_or(0),
+            BarrelOp::BRLC => de.rotate_left(shift & 0xf),
+        };
+        self.rp(RegPair::DE).set(result);
+    }
+
+    /// Multiply D by E and store the result to DE
+    pub fn mul_de(&self) {
+        let result = self.rg(Reg::D).get() as u16 * self.rg(Reg::E).get() as u16;
+        self.rp(RegPair::DE).set(result);
+    }
+
+    /// Move HL screen address one pixel line down
+    pub fn pixel_dn(&self) {
+        let (h, l) = (self.rg(Reg::H).get(), self.rg(Reg::L).get());
+        let (h, l) = if h & 0x07 != 0x07 {
+            (h + 1, l)
+        } else if l & 0xe0 != 0xe0 {
+            (h & 0xf8, l + 0x20)
+        } else {
+            ((h & 0xf8).wrapping_add(0x08), l & 0x1f)
+        };
+        self.rp(RegPair::HL).set(mkword!(h, l));
+    }
+
+    /// Calculate HL screen address of the pixel at DE (D is Y, E is X)
+    pub fn pixel_ad(&self) {
+        let (y, x) = (self.rg(Reg::D).get() as u16, self.rg(Reg::E).get() as u16);
+        let addr = 0x4000 | ((y & 0xc0) << 5) | ((y & 0x07) << 8) | ((y & 0x38) << 2) | (x >> 3);
+        self.rp(RegPair::HL).set(addr);
+    }
+
+    /// Set accumulator to pixel mask of the column E
+    pub fn setae(&self) {
+        self.rg(Reg::A).set(0x80 >> (self.rg(Reg::E).get() & 0x07));
+    }
+
+    /// Source address of LDIX/LDDX/LDIRX/LDDRX/LDPIRX. LDPIRX takes bytes from
+    /// 8-byte aligned pattern at HL indexed by low 3 bits of E.
+    pub fn block_ldx_source(&self, op: BlockOp) -> u16 {
+        let hl = self.rp(RegPair::HL).get();
+        if op == BlockOp::LDPIRX {
+            (hl & 0xfff8) | (self.rg(Reg::E).get() & 0x07) as u16
+        } else {
+            hl
+        }
+    }
+
+    /// Update registers after LDIX/LDDX/LDIRX/LDDRX/LDPIRX transferred the value
+    /// (value equal to accumulator is skipped), returns true if instruction has to be repeated
+    pub fn block_ldx(&self, op: BlockOp) -> bool {
+        let ctr = self.rp(RegPair::BC).get().wrapping_sub(1);
+        match op {
+            BlockOp::LDIX | BlockOp::LDIRX => self.rp(RegPair::HL).update(|hl| hl.wrapping_add(1)),
+            BlockOp::LDDX | BlockOp::LDDRX => self.rp(RegPair::HL).update(|hl| hl.wrapping_sub(1)),
+            _ => {}
+        }
+        self.rp(RegPair::DE).update(|de| de.wrapping_add(1));
+        self.rp(RegPair::BC).set(ctr);
+        matches!(op, BlockOp::LDIRX | BlockOp::LDDRX | BlockOp::LDPIRX) && ctr != 0
+    }
+
+    /// Update registers after LDWS transferred the value. Flags are set as by INC D.
+    pub fn ldws(&self) {
+        self.rg(Reg::L).update(|l| l.wrapping_add(1));
+        let d = self.inc_dec(true, self.rg(Reg::D).get());
+        self.rg(Reg::D).set(d);
+    }
+
+    /// Jump target of JP (C): address within the current 16K bank
+    /// at 64-byte boundary selected by value read from port
+    pub fn jp_c_target(&self, pc: u16, val: u8) -> u16 {
+        (pc & 0xc000) | ((val as u16) << 6)
+    }
+
+    // Z180 extended instructions
+
+    /// Set flags after IN0 read the value
+    pub fn in0_flags(&self, val: u8) {
+        let mut flags = (self.get_flags() & Flags::C) | (Flags::from(val) & Flags::XY);
+        flags.set_zs_flags_u8(val);
+        flags.set_parity_flag(val);
+        self.set_flags(flags);
+    }
+
+    /// Multiply high and low bytes of the register pair and store the result to it
+    pub fn mlt(&self, rpair: RegPair) {
+        let (hi, lo) = spword!(self.rp(rpair).get());
+        self.rp(rpair).set(hi as u16 * lo as u16);
+    }
+
+    /// Update registers after OTIM/OTDM/OTIMR/OTDMR transferred the value,
+    /// returns true if instruction has to be repeated
+    pub fn block_otm(&self, op: BlockOp, val: u8) -> bool {
+        let increment = matches!(op, BlockOp::OTIM | BlockOp::OTIMR);
+        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+        self.rg(Reg::C).update(|c| if increment { c.wrapping_add(1) } else { c.wrapping_sub(1) });
+        let ctr = self.inc_dec(false, self.rg(Reg::B).get());
+        self.rg(Reg::B).set(ctr);
+        let mut flags = self.get_flags();
+        flags.set(Flags::N, val & 0x80 != 0);
+        self.set_flags(flags);
+        matches!(op, BlockOp::OTIMR | BlockOp::OTDMR) && ctr != 0
+    }
+
+}
+
+/// Plain value snapshot of Z80 CPU registers and state
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuRegisters {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub alt_af: u16,
+    pub alt_bc: u16,
+    pub alt_de: u16,
+    pub alt_hl: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub ir: u16,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: IntMode,
+    pub int: bool,
+    pub nmi: bool,
+    pub after_ei: bool,
+    pub after_ld_air: bool,
+    pub halted: bool,
+}
+
+/// Single register which differs between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterDiff {
+    pub name: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for RegisterDiff {
+
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}: {} -> {}", self.name, self.old, self.new)
+    }
+
+}
+
+impl CpuRegisters {
+
+    /// List registers which differ in the other snapshot
+    pub fn diff(&self, other: &Self) -> Vec<RegisterDiff> {
+
+        let mut diffs = vec![];
+
+        macro_rules! compare {
+            ($($field:ident: $name:literal),* ; $($flag:ident: $flag_name:literal),*) => {
+                $(
+                    if self.$field != other.$field {
+                        diffs.push(RegisterDiff {
+                            name: $name,
+                            old: format!("{:04X}h", self.$field),
+                            new: format!("{:04X}h", other.$field),
+                        });
+                    }
+                )*
+                $(
+                    if self.$flag != other.$flag {
+                        diffs.push(RegisterDiff {
+                            name: $flag_name,
+                            old: self.$flag.to_string(),
+                            new: other.$flag.to_string(),
+                        });
+                    }
+                )*
+            };
+        }
+
+        compare!(
+            af: "AF", bc: "BC", de: "DE", hl: "HL",
+            alt_af: "AF'", alt_bc: "BC'", alt_de: "DE'", alt_hl: "HL'",
+            ix: "IX", iy: "IY", sp: "SP", pc: "PC", ir: "IR";
+            iff1: "IFF1", iff2: "IFF2", im: "IM", int: "INT", nmi: "NMI",
+            after_ei: "after EI", after_ld_air: "after LD A,I/R", halted: "HALT"
+        );
+
+        diffs
+
+    }
+
+}
+
+/// Hook which handles execution at given address instead of the CPU,
+/// like ROM routine replaced with native code. Trap takes no time.
+pub trait CpuTrap {
+
+    /// Address of the instruction to intercept
+    fn address(&self) -> u16;
+
+    /// Handle the trap updating CPU state (including PC). Returns false
+    /// if the trap doesn't apply, so the instruction is executed normally.
+    fn handle(&self, cpu: &CpuState) -> bool;
+
 }
 
 /// Z80 CPU
@@ -40,6 +712,12 @@ pub struct Cpu {
     bus: Rc<CpuBus>,
     clock: Rc<Clock>,
     breakpoint_manager: Rc<BreakpointManager>,
+    model: Cell<CpuModel>,
+    /// Registers were replaced, so running task has to reload its cached PC
+    state_loaded: Cell<bool>,
+    /// NMI line state at the last probe, NMI is requested by its rising edge
+    nmi_line: Cell<bool>,
+    traps: RefCell<Vec<Rc<dyn CpuTrap>>>,
     state: CpuState,
 }
 
@@ -64,73 +742,120 @@ impl Device for Cpu {
 
             self.bus.m1.drive(self, false);
             self.bus.busak.drive(self, false);
-            self.bus.halt.drive(self, false);
+            self.bus.halt.drive(self, self.halted.get());
 
             let mut pc = self.rp(RegPair::PC).get();
 
             // Instruction loop
             'fetch: loop {
 
-                self.rp(RegPair::PC).set(pc);
+                if !self.state_loaded.get() {
+                    self.rp(RegPair::PC).set(pc);
+                }
+
+                yield_break_if!(self.breakpoint_manager.hits_before_opcode_read(self.rp(RegPair::PC).get()));
 
-                yield_break_if!(self.breakpoint_manager.hits_before_opcode_read(pc));
+                // Registers could be loaded while the task was suspended
+                if self.state_loaded.replace(false) {
+                    pc = self.rp(RegPair::PC).get();
+                    self.bus.halt.drive(self, self.halted.get());
+                }
+
+                if self.run_trap(pc) {
+                    pc = self.rp(RegPair::PC).get();
+                    continue 'fetch;
+                }
+
+                // Interrupts are processed on instruction boundary only, thus prefixed instruction
+                // is never interrupted between its prefix and opcode. Maskable interrupt is also
+                // not accepted right after EI, so chain of EIs blocks it until the chain ends.
+                let after_ei = self.after_ei.replace(false);
+                let after_ld_air = self.after_ld_air.replace(false);
+                let accept_nmi = self.nmi.get();
+                let accept_int = !accept_nmi && self.int.get() && self.iff1.get() && !after_ei;
+
+                if accept_nmi || accept_int {
+                    // Leave HALT state, PC already points to the instruction after HALT
+                    self.halted.set(false);
+                    self.bus.halt.drive(self, false);
+                }
+                if accept_int && after_ld_air {
+                    // NMOS Z80 quirk: IFF2 is already cleared when LD A,I / LD A,R copies it to P/V.
+                    // NMI leaves IFF2 intact, so P/V stays as copied.
+                    self.set_flags(self.get_flags() & !Flags::P);
+                }
+
+                // In IM0 interrupting device supplies an instruction to execute. Its first byte
+                // comes from interrupt response and the rest from further acknowledge cycles.
+                let mut injected: Option<u8> = None;
+                let mut acknowledge = false;
+
+                if accept_nmi {
+                    // Handle non maskable interrupt: push PC, jump to 0x0066
+                    self.nmi.set(false);
+                    self.iff1.set(false);
+                    yield_from!(self.stack_push(pc));
+                    pc = 0x0066;
+                    continue 'fetch;
+                } else if accept_int {
+                    // Handle maskable interrupt
+                    self.int.set(false);
+                    self.iff1.set(false);
+                    self.iff2.set(false);
+                    let vec_byte = yield_from!(self.interrupt_response(pc));
+                    match self.im.get() {
+                        // IM0: external device supplies instruction; it is responsible for
+                        // pushing PC (e.g. via an injected RST or CALL instruction)
+                        IntMode::IM0 | IntMode::IM01 => {
+                            injected = Some(vec_byte);
+                            acknowledge = true;
+                        },
+                        IntMode::IM1 => {
+                            // Push PC and jump to fixed address 0x0038
+                            yield_from!(self.stack_push(pc));
+                            pc = 0x0038;
+                            continue 'fetch;
+                        },
+                        IntMode::IM2 => {
+                            // Push PC, read 16-bit vector from table at (I:vec_byte)
+                            yield_from!(self.stack_push(pc));
+                            let vec_addr = mkword!(self.rg(Reg::I).get(), vec_byte);
+                            let lo = yield_from!(self.memory_read(vec_addr));
+                            let hi = yield_from!(self.memory_read(vec_addr.wrapping_add(1)));
+                            pc = mkword!(hi, lo);
+                            continue 'fetch;
+                        },
+                    }
+                } else if self.halted.get() {
+                    // Halted CPU keeps doing M1 cycles (with memory refresh) at the instruction
+                    // following HALT, but ignores fetched opcode and doesn't advance PC
+                    yield_from!(self.opcode_read(pc));
+                    continue 'fetch;
+                }
 
-                let mut decoder = instruction_decoder();
+                let mut decoder = instruction_decoder(self.model.get());
                 let mut upnext = TokenType::Opcode;
 
                 // Instruction decode loop
                 let instruction = loop {
 
-                    // Read the next byte using appropriate M-cycle
-                    let byte: u8 = match upnext {
-                        TokenType::Opcode => {
-                            // Process possible interrupts
-                            if self.nmi.get() || self.int.get() {
-                                self.bus.halt.drive(self, false);
-                            }
-                            if self.nmi.get() {
-                                // Handle non maskable interrupt: push PC, jump to 0x0066
-                                self.nmi.set(false);
-                                self.iff1.set(false);
-                                yield_from!(self.stack_push(pc));
-                                pc = 0x0066;
-                                continue 'fetch;
-                            } else if self.int.get() && self.iff1.get() {
-                                // Handle maskable interrupt
-                                self.int.set(false);
-                                self.iff1.set(false);
-                                self.iff2.set(false);
-                                let vec_byte = yield_from!(self.interrupt_response(pc));
-                                match self.im.get() {
-                                    // IM0: external device supplies opcode; it is responsible for
-                                    // pushing PC (e.g. via an injected RST instruction)
-                                    IntMode::IM0 | IntMode::IM01 => vec_byte,
-                                    IntMode::IM1 => {
-                                        // Push PC and jump to fixed address 0x0038
-                                        yield_from!(self.stack_push(pc));
-                                        pc = 0x0038;
-                                        continue 'fetch;
-                                    },
-                                    IntMode::IM2 => {
-                                        // Push PC, read 16-bit vector from table at (I:vec_byte)
-                                        yield_from!(self.stack_push(pc));
-                                        let vec_addr = mkword!(self.rg(Reg::I).get(), vec_byte);
-                                        let lo = yield_from!(self.memory_read(vec_addr));
-                                        let hi = yield_from!(self.memory_read(vec_addr.wrapping_add(1)));
-                                        pc = mkword!(hi, lo);
-                                        continue 'fetch;
-                                    },
-                                }
-                            } else {
-                                // Normal instruction fetch
-                                yield_from!(self.opcode_read(pc))
-                            }
-                        },
-                        TokenType::Displacement | TokenType::Data => yield_from!(self.memory_read(pc))
+                    // Read the next byte using appropriate M-cycle. Injected bytes don't advance PC.
+                    let byte: u8 = if let Some(byte) = injected.take() {
+                        byte
+                    } else if acknowledge {
+                        match upnext {
+                            TokenType::Opcode => yield_from!(self.interrupt_response(pc)),
+                            TokenType::Displacement | TokenType::Data => yield_from!(self.interrupt_data_read(pc))
+                        }
+                    } else {
+                        let byte = match upnext {
+                            TokenType::Opcode => yield_from!(self.opcode_read(pc)),
+                            TokenType::Displacement | TokenType::Data => yield_from!(self.memory_read(pc))
+                        };
+                        pc = pc.wrapping_add(1);
+                        byte
                     };
 
-                    pc = pc.wrapping_add(1);
-
                     match Pin::new(&mut decoder).resume(byte) {
                         CoroutineState::Yielded(result) => upnext = result.upnext,
                         CoroutineState::Complete(instruction) => break instruction
@@ -143,12 +868,12 @@ impl Device for Cpu {
                     // 8-bit Load
 
                     Token::LD_RG_RG(dst @ (Reg::AtIX | Reg::AtIY), src) => {
-                        yield_wait!(self.clock.rising(2)); // complement M3 to 5 t-cycles for index calculation
+                        yield_wait!(self.clock.rising(5)); // index calculation delay
                         let addr = self.idx_addr(dst, instruction.displacement.unwrap());
                         yield_from!(self.memory_write(addr, self.rg(src).get()));
                     },
                     Token::LD_RG_RG(dst, src @ (Reg::AtIX | Reg::AtIY)) => {
-                        yield_wait!(self.clock.rising(2)); // complement M3 to 5 t-cycles for index calculation
+                        yield_wait!(self.clock.rising(5)); // index calculation delay
                         let addr = self.idx_addr(src, instruction.displacement.unwrap());
                         self.rg(dst).set(yield_from!(self.memory_read(addr)));
                     },
@@ -166,12 +891,7 @@ impl Device for Cpu {
                     },
                     Token::LD_RG_RG(Reg::A, src @ (Reg::I | Reg::R)) => {
                         yield_wait!(self.clock.rising(1)); // complement M1 to 5 t-cycles
-                        let value = self.rg(src).get();
-                        self.rg(Reg::A).set(value);
-                        let mut flags = (self.get_flags() & Flags::C) | (Flags::from(value) & Flags::XY);
-                        flags.set_zs_flags_u8(value);
-                        flags.set(Flags::P, self.iff2.get());
-                        self.set_flags(flags);
+                        self.ld_a_ir(src);
                     },
                     Token::LD_RG_RG(dst, src) => {
                         self.rg(dst).set(self.rg(src).get());
@@ -250,21 +970,10 @@ impl Device for Cpu {
                     Token::BLOP(op @ (BlockOp::LDI | BlockOp::LDD | BlockOp::LDIR | BlockOp::LDDR))  => {
                         let src = self.rp(RegPair::HL).get();
                         let dst = self.rp(RegPair::DE).get();
-                        let ctr = self.rp(RegPair::BC).get().wrapping_sub(1);
                         let val = yield_from!(self.memory_read(src));
                         yield_from!(self.memory_write(dst, val));
                         yield_wait!(self.clock.rising(2)); // complement MW to 5 t-cycles
-                        let increment = matches!(op, BlockOp::LDI | BlockOp::LDIR);
-                        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
-                        self.rp(RegPair::DE).update(|de| if increment { de.wrapping_add(1) } else { de.wrapping_sub(1) });
-                        self.rp(RegPair::BC).set(ctr);
-                        let n = self.rg(Reg::A).get().wrapping_add(val);
-                        let mut flags = self.get_flags() & Flags::C;
-                        flags.set(Flags::P, ctr != 0);
-                        flags.set(Flags::Y, n & (1 << 1) != 0);
-                        flags.set(Flags::X, n & (1 << 3) != 0);
-                        self.set_flags(flags);
-                        if matches!(op, BlockOp::LDIR | BlockOp::LDDR) && flags.contains(Flags::P) { // repeat
+                        if self.block_ld(op, val) { // repeat
                             yield_wait!(self.clock.rising(5));
                             pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
                         }
@@ -272,23 +981,9 @@ impl Device for Cpu {
 
                     Token::BLOP(op @ (BlockOp::CPI | BlockOp::CPD | BlockOp::CPIR | BlockOp::CPDR))  => {
                         let src = self.rp(RegPair::HL).get();
-                        let ctr = self.rp(RegPair::BC).get().wrapping_sub(1);
-                        let lhs = self.rg(Reg::A).get();
                         let rhs = yield_from!(self.memory_read(src));
                         yield_wait!(self.clock.rising(5));
-                        let increment = matches!(op, BlockOp::CPI | BlockOp::CPIR);
-                        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
-                        self.rp(RegPair::BC).set(ctr);
-                        let mut n = lhs.wrapping_sub(rhs);
-                        let mut flags = (self.get_flags() & Flags::C) | Flags::N;
-                        flags.set_zs_flags_u8(n);
-                        flags.set(Flags::H, (lhs << 4).overflowing_sub(rhs << 4).1);
-                        if flags.contains(Flags::H) { n = n.wrapping_sub(1); }
-                        flags.set(Flags::P, ctr != 0);
-                        flags.set(Flags::Y, n & (1 << 1) != 0);
-                        flags.set(Flags::X, n & (1 << 3) != 0);
-                        self.set_flags(flags);
-                        if matches!(op, BlockOp::CPIR | BlockOp::CPDR) && flags.contains(Flags::P) { // repeat
+                        if self.block_cp(op, rhs) { // repeat
                             yield_wait!(self.clock.rising(5));
                             pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
                         }
@@ -297,7 +992,6 @@ impl Device for Cpu {
                     // 8-bit arithmetic and logic
 
                     Token::ALU(op, maybe_reg) => {
-                        let lhs = self.rg(Reg::A).get();
                         let rhs = if let Some(reg) = maybe_reg {
                             if matches!(reg, Reg::AtIX | Reg::AtIY) {
                                 yield_wait!(self.clock.rising(5)); // index calculation delay
@@ -306,40 +1000,7 @@ impl Device for Cpu {
                         } else {
                             instruction.expect_byte_data()
                         };
-                        let mut flags = self.get_flags() & Flags::C;
-                        let result = match op {
-                            AluOp::ADD | AluOp::ADC => {
-                                let (result, carry) = lhs.carrying_add(rhs, op == AluOp::ADC && flags.contains(Flags::C));
-                                flags.set(Flags::C, carry);
-                                flags.set(Flags::P, (!(lhs ^ rhs) & (lhs ^ result)) & 0x80 != 0);
-                                flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x10 != 0);
-                                result
-                            },
-                            AluOp::CP | AluOp::SUB | AluOp::SBC => {
-                                flags.insert(Flags::N);
-                                let (result, carry) = lhs.borrowing_sub(rhs, op == AluOp::SBC && flags.contains(Flags::C));
-                                flags.set(Flags::C, carry);
-                                flags.set(Flags::P, ((lhs ^ rhs) & (lhs ^ result)) & 0x80 != 0);
-                                flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x10 != 0);
-                                result
-                            },
-                            AluOp::AND | AluOp::XOR | AluOp::OR => {
-                                let result = match op {
-                                    AluOp::AND => lhs & rhs,
-                                    AluOp::XOR => lhs ^ rhs,
-                                    AluOp::OR => lhs | rhs,
-                                    _ => unreachable!()
-                                };
-                                flags.set_parity_flag(result);
-                                result
-                            },
-                        };
-                        flags |= Flags::from(result) & Flags::XY;
-                        flags.set_zs_flags_u8(result);
-                        self.set_flags(flags);
-                        if op != AluOp::CP {
-                            self.rg(Reg::A).set(result);
-                        }
+                        self.alu(op, rhs);
                     },
 
                     Token::INC_RG(reg) | Token::DEC_RG(reg) => {
@@ -349,78 +1010,21 @@ impl Device for Cpu {
                             _ => {}
                         }
                         let value = yield_from!(self.read_register(reg, instruction.displacement));
-                        let mut flags = self.get_flags() & Flags::C;
-                        let result = if let Token::INC_RG(..) = instruction.opcode {
-                            flags.set(Flags::P, (value as i8).overflowing_add(1 as i8).1);
-                            flags.set(Flags::H, (value << 4).overflowing_add(1 << 4).1);
-                            value.wrapping_add(1)
-                        } else {
-                            flags.insert(Flags::N);
-                            flags.set(Flags::P, (value as i8).overflowing_sub(1 as i8).1);
-                            flags.set(Flags::H, (value << 4).overflowing_sub(1 << 4).1);
-                            value.wrapping_sub(1)
-                        };
-                        flags |= Flags::from(result) & Flags::XY;
-                        flags.set_zs_flags_u8(result);
-                        self.set_flags(flags);
+                        let result = self.inc_dec(matches!(instruction.opcode, Token::INC_RG(..)), value);
                         yield_from!(self.write_register(reg, result, instruction.displacement));
                     },
 
                     // General-Purpose Arithmetic and CPU Control
 
-                    Token::DAA => {
-                        let value = self.rg(Reg::A).get();
-                        let mut flags = self.get_flags();
-                        let mut correction: u8 = 0;
-                        if value & 0x0f > 0x09 || flags.contains(Flags::H) { correction |= 0x06; }
-                        if value & 0xf0 > 0x90 || flags.contains(Flags::C) { correction |= 0x60; }
-                        let (result, carry) = if flags.contains(Flags::N) {
-                            value.overflowing_sub(correction)
-                        } else {
-                            value.overflowing_add(correction)
-                        };
-                        flags.set(Flags::C, carry);
-                        flags.set(Flags::H, if flags.contains(Flags::N) {
-                            (value << 4).overflowing_sub(correction << 4).1
-                        } else {
-                            (value << 4).overflowing_add(correction << 4).1
-                        });
-                        flags |= Flags::from(result) & Flags::XY;
-                        flags.set_zs_flags_u8(result);
-                        flags.set_parity_flag(result);
-                        self.set_flags(flags);
-                        self.rg(Reg::A).set(result);
-                    },
-                    Token::CPL => {
-                        let result = !self.rg(Reg::A).get();
-                        self.rg(Reg::A).set(result);
-                        self.set_flags(
-                            (self.get_flags() & !Flags::XY) |
-                            (Flags::from(result) & Flags::XY) |
-                            Flags::H | Flags::N
-                        );
-                    },
-                    Token::NEG => {
-                        let value = self.rg(Reg::A).get();
-                        let (result, carry) = (0 as u8).overflowing_sub(value);
-                        let mut flags = Flags::N | (Flags::from(result) & Flags::XY);
-                        flags.set(Flags::C, carry);
-                        flags.set(Flags::P, (0 as i8).overflowing_sub(value as i8).1);
-                        flags.set(Flags::H, (0 as u8).overflowing_sub(value << 4).1);
-                        flags.set_zs_flags_u8(result);
-                        self.set_flags(flags);
-                        self.rg(Reg::A).set(result);
-                    },
-                    Token::CCF => {
-                        self.set_flags(self.get_flags() ^ Flags::C);
-                    },
-                    Token::SCF => {
-                        self.set_flags(self.get_flags() | Flags::C);
-                    },
+                    Token::DAA => self.daa(),
+                    Token::CPL => self.cpl(),
+                    Token::NEG => self.neg(),
+                    Token::CCF => self.ccf(),
+                    Token::SCF => self.scf(),
                     Token::NOP => {},
                     Token::HALT => {
+                        self.halted.set(true);
                         self.bus.halt.drive(self, true);
-                        pc = pc.wrapping_sub(1); // rewind PC 1 byte back
                     },
                     Token::DI => {
                         self.iff1.set(false);
@@ -429,6 +1033,7 @@ impl Device for Cpu {
                     Token::EI => {
                         self.iff1.set(true);
                         self.iff2.set(true);
+                        self.after_ei.set(true);
                     },
                     Token::IM(mode) => {
                         self.im.set(mode);
@@ -438,42 +1043,15 @@ impl Device for Cpu {
 
                     Token::ADD_RP_RP(dst, src) => {
                         yield_wait!(self.clock.rising(7)); // Last 2 M-cycles = 4+3 t-cycles
-                        let lhs = self.rp(dst).get();
-                        let rhs = self.rp(src).get();
-                        let (result, carry) = lhs.overflowing_add(rhs);
-                        let mut flags = self.get_flags() & !Flags::N;
-                        flags.set(Flags::C, carry);
-                        flags.set(Flags::H, (lhs << 4).overflowing_add(rhs << 4).1);
-                        self.rp(dst).set(result);
-                        self.set_flags(flags);
+                        self.add_rp(dst, src);
                     },
                     Token::ADC_HL_RP(rpair) => {
                         yield_wait!(self.clock.rising(7)); // Last 2 M-cycles = 4+3 t-cycles
-                        let lhs = self.rp(RegPair::HL).get();
-                        let rhs = self.rp(rpair).get();
-                        let mut flags = self.get_flags();
-                        let (result, carry) = lhs.carrying_add(rhs, flags.contains(Flags::C));
-                        flags = Flags::NONE;
-                        flags.set_zs_flags_u16(result);
-                        flags.set(Flags::C, carry);
-                        flags.set(Flags::P, (!(lhs ^ rhs) & (lhs ^ result)) & 0x8000 != 0);
-                        flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x1000 != 0);
-                        self.rp(RegPair::HL).set(result);
-                        self.set_flags(flags);
+                        self.adc_hl(rpair);
                     },
                     Token::SBC_HL_RP(rpair) => {
                         yield_wait!(self.clock.rising(7)); // Last 2 M-cycles = 4+3 t-cycles
-                        let lhs = self.rp(RegPair::HL).get();
-                        let rhs = self.rp(rpair).get();
-                        let mut flags = self.get_flags();
-                        let (result, carry) = lhs.borrowing_sub(rhs, flags.contains(Flags::C));
-                        flags = Flags::NONE;
-                        flags.set_zs_flags_u16(result);
-                        flags.set(Flags::C, carry);
-                        flags.set(Flags::P, ((lhs ^ rhs) & (lhs ^ result)) & 0x8000 != 0);
-                        flags.set(Flags::H, (lhs ^ rhs ^ result) & 0x1000 != 0);
-                        self.rp(RegPair::HL).set(result);
-                        self.set_flags(flags);
+                        self.sbc_hl(rpair);
                     },
                     Token::INC_RP(rpair) => {
                         yield_wait!(self.clock.rising(2)); // complement M-cycle to 6 t-cycles
@@ -495,103 +1073,10 @@ impl Device for Cpu {
                             yield_wait!(self.clock.rising(1)); // complement MR to 4 t-cycles
                         }
 
-                        let mut flags = self.get_flags() & !(Flags::H | Flags::N);
-
-                        let result = match op {
-                            ShiftOp::RLC => {
-                                let val = val.rotate_left(1);
-                                flags.set(Flags::C, val & 0x1 != 0);
-                                val
-                            },
-                            ShiftOp::RRC => {
-                                let val = val.rotate_right(1);
-                                flags.set(Flags::C, val & 0x80 != 0);
-                                val
-                            },
-                            ShiftOp::RL => {
-                                let mut val = val.rotate_left(1);
-                                let carry = val & 0x1 != 0; val &= !0x1;
-                                if flags.contains(Flags::C) { val |= 0x1; }
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::RR => {
-                                let mut val = val.rotate_right(1);
-                                let carry = val & 0x80 != 0; val &= !0x80;
-                                if flags.contains(Flags::C) { val |= 0x80; }
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::SLA => {
-                                let mut val = val.rotate_left(1);
-                                let carry = val & 0x1 != 0; val &= !0x1;
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::SRA => {
-                                let mut val = val.rotate_right(1);
-                                let carry = val & 0x80 != 0; val &= !0x80;
-                                val |= (val | 0x40) << 1;
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::SLL => {
-                                let mut val = val.rotate_left(1);
-                                let carry = val & 0x1 != 0; val |= 0x1;
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::SRL => {
-                                let mut val = val.rotate_right(1);
-                                let carry = val & 0x80 != 0; val &= !0x80;
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::RLCA => {
-                                let val = val.rotate_left(1);
-                                flags.set(Flags::C, val & 0x1 != 0);
-                                val
-                            },
-                            ShiftOp::RRCA => {
-                                let val = val.rotate_right(1);
-                                flags.set(Flags::C, val & 0x80 != 0);
-                                val
-                            },
-                            ShiftOp::RLA => {
-                                let mut val = val.rotate_left(1);
-                                let carry = val & 0x1 != 0; val &= !0x1;
-                                if flags.contains(Flags::C) { val |= 0x1; }
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::RRA => {
-                                let mut val = val.rotate_right(1);
-                                let carry = val & 0x80 != 0; val &= !0x80;
-                                if flags.contains(Flags::C) { val |= 0x80; }
-                                flags.set(Flags::C, carry);
-                                val
-                            },
-                            ShiftOp::RLD => {
-                                yield_wait!(self.clock.rising(3)); // M4
-                                let acc = self.rg(Reg::A).get();
-                                self.rg(Reg::A).set((acc & 0xf0) | (val >> 4));
-                                (val << 4) | (acc & 0xf)
-                            },
-                            ShiftOp::RRD => {
-                                yield_wait!(self.clock.rising(3)); // M4
-                                let acc = self.rg(Reg::A).get();
-                                self.rg(Reg::A).set((acc & 0xf0) | val & 0xf);
-                                (val >> 4) | (acc << 4)
-                            },
-                        };
-
-                        match op {
-                            ShiftOp::RLCA | ShiftOp::RRCA | ShiftOp::RLA | ShiftOp::RRA => (),
-                            _ => { // Set Z,S,P flags for all ops except above
-                                flags.set_zs_flags_u8(result);
-                                flags.set_parity_flag(result);
-                            }
+                        if matches!(op, ShiftOp::RLD | ShiftOp::RRD) {
+                            yield_wait!(self.clock.rising(3)); // M4
                         }
+                        let result = self.shift(op, val);
 
                         yield_from!(self.write_register(reg, result, instruction.displacement));
 
@@ -600,8 +1085,6 @@ impl Device for Cpu {
                         if let Some(dst) = maybe_dst {
                             self.rg(dst).set(result);
                         }
-
-                        self.set_flags(flags);
                     },
 
                     // Bit Set, Reset and Test
@@ -614,11 +1097,7 @@ impl Device for Cpu {
                         if matches!(reg, Reg::AtHL | Reg::AtIX | Reg::AtIY) {
                             yield_wait!(self.clock.rising(1)); // complement MR to 4 t-cycles
                         }
-                        let mut flags = self.get_flags() | Flags::H & !Flags::N;
-                        let zero = (val >> bit) & 0x1 == 0;
-                        flags.set(Flags::Z, zero);
-                        flags.set(Flags::P, zero);
-                        self.set_flags(flags);
+                        self.bit(bit, val);
                     },
                     Token::SET(bit, reg, maybe_dst) | Token::RES(bit, reg, maybe_dst) => {
                         if matches!(reg, Reg::AtIX | Reg::AtIY) {
@@ -662,6 +1141,7 @@ impl Device for Cpu {
                         }
                     },
                     Token::DJNZ => {
+                        yield_wait!(self.clock.rising(1)); // complement M1 to 5 t-cycles
                         self.rg(Reg::B).update(|b| b.wrapping_sub(1));
                         if self.rg(Reg::B).get() != 0 {
                             yield_wait!(self.clock.rising(5)); // M3 = 5 T-cycles
@@ -727,18 +1207,10 @@ impl Device for Cpu {
                     Token::BLOP(op @ (BlockOp::INI | BlockOp::IND | BlockOp::INIR | BlockOp::INDR))  => {
                         let src = self.rp(RegPair::BC).get();
                         let dst = self.rp(RegPair::HL).get();
-                        let ctr = self.rg(Reg::B).get().wrapping_sub(1);
                         yield_wait!(self.clock.rising(1)); // complement M2 to 5 t-cycles
                         let val = yield_from!(self.io_read(src));
                         yield_from!(self.memory_write(dst, val));
-                        yield_wait!(self.clock.rising(1)); // complement M4 to 4 t-cycles
-                        let increment = matches!(op, BlockOp::INI | BlockOp::INIR);
-                        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
-                        self.rg(Reg::B).set(ctr);
-                        let mut flags = (self.get_flags() & Flags::C) | Flags::N;
-                        flags.set_zs_flags_u8(ctr);
-                        self.set_flags(flags);
-                        if matches!(op, BlockOp::INIR | BlockOp::INDR) && !flags.contains(Flags::Z) { // repeat
+                        if self.block_io(op) { // repeat
                             yield_wait!(self.clock.rising(5));
                             pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
                         }
@@ -746,22 +1218,135 @@ impl Device for Cpu {
                     Token::BLOP(op @ (BlockOp::OUTI | BlockOp::OUTD | BlockOp::OTIR | BlockOp::OTDR)) => {
                         let src = self.rp(RegPair::HL).get();
                         let dst = self.rp(RegPair::BC).get();
-                        let ctr = self.rg(Reg::B).get().wrapping_sub(1);
                         yield_wait!(self.clock.rising(1)); // complement M2 to 5 t-cycles
                         let val = yield_from!(self.memory_read(src));
                         yield_from!(self.io_write(dst, val));
-                        yield_wait!(self.clock.rising(1)); // complement M4 to 4 t-cycles
-                        let increment = matches!(op, BlockOp::OUTI | BlockOp::OTIR);
-                        self.rp(RegPair::HL).update(|hl| if increment { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
-                        self.rg(Reg::B).set(ctr);
-                        let mut flags = (self.get_flags() & Flags::C) | Flags::N;
-                        flags.set_zs_flags_u8(ctr);
-                        self.set_flags(flags);
-                        if matches!(op, BlockOp::OTIR | BlockOp::OTDR) && !flags.contains(Flags::Z) { // repeat
+                        if self.block_io(op) { // repeat
+                            yield_wait!(self.clock.rising(5));
+                            pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
+                        }
+                    },
+
+                    // Z80N extended instructions
+
+                    Token::SWAPNIB => self.swapnib(),
+                    Token::MIRROR_A => self.mirror_a(),
+                    Token::TEST_N => self.test(self.rg(Reg::A).get(), instruction.expect_byte_data()),
+                    Token::BSOP(op) => self.barrel_shift(op),
+                    Token::MUL_DE => self.mul_de(),
+                    Token::ADD_RP_A(rpair) => {
+                        let acc = self.rg(Reg::A).get();
+                        self.rp(rpair).update(|rp| rp.wrapping_add(acc as u16));
+                    },
+                    Token::ADD_RP_NN(rpair) => {
+                        yield_wait!(self.clock.rising(2));
+                        self.rp(rpair).update(|rp| rp.wrapping_add(instruction.expect_word_data()));
+                    },
+                    Token::PUSH_NN => {
+                        yield_wait!(self.clock.rising(3));
+                        yield_from!(self.stack_push(instruction.expect_word_data()));
+                    },
+                    Token::OUTINB => {
+                        let src = self.rp(RegPair::HL).get();
+                        let dst = self.rp(RegPair::BC).get();
+                        yield_wait!(self.clock.rising(1)); // complement M2 to 5 t-cycles
+                        let val = yield_from!(self.memory_read(src));
+                        yield_from!(self.io_write(dst, val));
+                        self.rp(RegPair::HL).update(|hl| hl.wrapping_add(1));
+                    },
+                    Token::NEXTREG_N_N => {
+                        let (register, value) = spword!(instruction.expect_word_data());
+                        yield_from!(self.io_write(NEXTREG_SELECT_PORT, register));
+                        yield_from!(self.io_write(NEXTREG_ACCESS_PORT, value));
+                    },
+                    Token::NEXTREG_N_A => {
+                        yield_from!(self.io_write(NEXTREG_SELECT_PORT, instruction.expect_byte_data()));
+                        yield_from!(self.io_write(NEXTREG_ACCESS_PORT, self.rg(Reg::A).get()));
+                    },
+                    Token::PIXELDN => self.pixel_dn(),
+                    Token::PIXELAD => self.pixel_ad(),
+                    Token::SETAE => self.setae(),
+                    Token::JP_AtC => {
+                        let addr = self.rp(RegPair::BC).get();
+                        let val = yield_from!(self.io_read(addr));
+                        yield_wait!(self.clock.rising(1));
+                        pc = self.jp_c_target(pc, val);
+                    },
+                    Token::BLOP(op @ (BlockOp::LDIX | BlockOp::LDDX | BlockOp::LDIRX | BlockOp::LDDRX | BlockOp::LDPIRX)) => {
+                        let src = self.block_ldx_source(op);
+                        let dst = self.rp(RegPair::DE).get();
+                        let val = yield_from!(self.memory_read(src));
+                        if val != self.rg(Reg::A).get() {
+                            yield_from!(self.memory_write(dst, val));
+                        } else {
+                            yield_wait!(self.clock.falling(3)); // write is skipped, but takes the same time
+                        }
+                        yield_wait!(self.clock.rising(2)); // complement MW to 5 t-cycles
+                        if self.block_ldx(op) { // repeat
+                            yield_wait!(self.clock.rising(5));
+                            pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
+                        }
+                    },
+                    Token::LDWS => {
+                        let src = self.rp(RegPair::HL).get();
+                        let dst = self.rp(RegPair::DE).get();
+                        let val = yield_from!(self.memory_read(src));
+                        yield_from!(self.memory_write(dst, val));
+                        self.ldws();
+                    },
+
+                    // Z180 extended instructions
+
+                    Token::IN0_RG_N(reg) => {
+                        let val = yield_from!(self.io_read(instruction.expect_byte_data() as u16));
+                        self.rg(reg).set(val);
+                        self.in0_flags(val);
+                    },
+                    Token::IN0_N => {
+                        let val = yield_from!(self.io_read(instruction.expect_byte_data() as u16));
+                        self.in0_flags(val);
+                    },
+                    Token::OUT0_N_RG(reg) => {
+                        yield_from!(self.io_write(instruction.expect_byte_data() as u16, self.rg(reg).get()));
+                    },
+                    Token::TST(maybe_reg) => {
+                        let rhs = if let Some(reg) = maybe_reg {
+                            yield_from!(self.read_register(reg, None))
+                        } else {
+                            instruction.expect_byte_data()
+                        };
+                        self.test(self.rg(Reg::A).get(), rhs);
+                    },
+                    Token::TSTIO_N => {
+                        let val = yield_from!(self.io_read(self.rg(Reg::C).get() as u16));
+                        self.test(val, instruction.expect_byte_data());
+                    },
+                    Token::MLT(rpair) => {
+                        yield_wait!(self.clock.rising(9));
+                        self.mlt(rpair);
+                    },
+                    Token::SLP => {
+                        // Sleep mode is modelled as HALT
+                        self.halted.set(true);
+                        self.bus.halt.drive(self, true);
+                    },
+                    Token::BLOP(op @ (BlockOp::OTIM | BlockOp::OTDM | BlockOp::OTIMR | BlockOp::OTDMR)) => {
+                        let src = self.rp(RegPair::HL).get();
+                        let dst = self.rg(Reg::C).get() as u16;
+                        yield_wait!(self.clock.rising(1)); // complement M2 to 5 t-cycles
+                        let val = yield_from!(self.memory_read(src));
+                        yield_from!(self.io_write(dst, val));
+                        if self.block_otm(op, val) { // repeat
                             yield_wait!(self.clock.rising(5));
                             pc = pc.wrapping_sub(2); // rewind PC 2 bytes back
                         }
                     },
+                    Token::TRAP => {
+                        // Undefined opcode restarts from 0000h, pushing address of the instruction
+                        yield_wait!(self.clock.rising(1)); // complement M1 to 5 t-cycles
+                        yield_from!(self.stack_push(self.rp(RegPair::PC).get()));
+                        pc = 0x0000;
+                    },
 
                     // Non-opcode is not expected
 
@@ -790,89 +1375,105 @@ impl Cpu {
         }
     }
 
-    /// Get reference to register value
-    pub fn rg(&self, reg: Reg) -> &Cell<u8> {
-        match reg {
-            Reg::B   => &self.bc.bytes().hi,
-            Reg::C   => &self.bc.bytes().lo,
-            Reg::D   => &self.de.bytes().hi,
-            Reg::E   => &self.de.bytes().lo,
-            Reg::H   => &self.hl.bytes().hi,
-            Reg::L   => &self.hl.bytes().lo,
-            Reg::A   => &self.af.bytes().hi,
-            Reg::F   => &self.af.bytes().lo,
-            Reg::I   => &self.ir.bytes().hi,
-            Reg::R   => &self.ir.bytes().lo,
-            Reg::IXH => &self.ix.bytes().hi,
-            Reg::IXL => &self.ix.bytes().lo,
-            Reg::IYH => &self.iy.bytes().hi,
-            Reg::IYL => &self.iy.bytes().lo,
-            _ => unreachable!()
-        }
+    /// Get CPU model
+    pub fn model(&self) -> CpuModel {
+        self.model.get()
     }
 
-    /// Get reference to regpair value
-    pub fn rp(&self, rpair: RegPair) -> &Cell<u16> {
-        match rpair {
-            RegPair::BC => &self.bc.value(),
-            RegPair::DE => &self.de.value(),
-            RegPair::HL => &self.hl.value(),
-            RegPair::AF => &self.af.value(),
-            RegPair::SP => &self.sp.value(),
-            RegPair::PC => &self.pc.value(),
-            RegPair::IR => &self.ir.value(),
-            RegPair::IX => &self.ix.value(),
-            RegPair::IY => &self.iy.value(),
-            _ => unreachable!()
-        }
+    /// Set CPU model. Takes effect starting from the next instruction.
+    pub fn set_model(&self, model: CpuModel) {
+        self.model.set(model);
     }
 
-    /// Get CPU flags
-    pub fn get_flags(&self) -> Flags {
-        Flags::from(self.rg(Reg::F).get())
+    /// Register trap intercepting execution at its address
+    pub fn add_trap(&self, trap: &Rc<dyn CpuTrap>) {
+        self.traps.borrow_mut().push(Rc::clone(trap));
     }
 
-    /// Set CPU flags
-    pub fn set_flags(&self, flags: Flags) {
-        self.rg(Reg::F).set(flags.bits())
+    /// Unregister previously added trap
+    pub fn remove_trap(&self, trap: &Rc<dyn CpuTrap>) {
+        self.traps.borrow_mut().retain(|other| !Rc::ptr_eq(other, trap));
     }
 
-    /// Swap primary and alternative accumulator (AF)
-    fn swap_acc(&self) {
-        self.af.value().swap(&self.alt_af.value());
+    /// Check if the trap is registered
+    pub fn has_trap(&self, trap: &Rc<dyn CpuTrap>) -> bool {
+        self.traps.borrow().iter().any(|other| Rc::ptr_eq(other, trap))
     }
 
-    /// Swap primary and alternative BC,DE and HL
-    fn swap_regfile(&self) {
-        self.bc.value().swap(&self.alt_bc.value());
-        self.de.value().swap(&self.alt_de.value());
-        self.hl.value().swap(&self.alt_hl.value());
+    /// Run trap registered at given address. Returns true if it was handled.
+    pub fn run_trap(&self, addr: u16) -> bool {
+        let traps: Vec<_> = self.traps.borrow().iter()
+            .filter(|trap| trap.address() == addr)
+            .cloned()
+            .collect();
+        traps.iter().any(|trap| trap.handle(&self.state))
     }
 
-    /// Swap HL and DE
-    fn swap_hlde(&self) {
-        self.hl.value().swap(&self.de.value());
+    /// Capture registers and state as a plain value
+    pub fn save_state(&self) -> CpuRegisters {
+        let word = |cell: &U16Cell| cell.value().get();
+        CpuRegisters {
+            af: word(&self.af),
+            bc: word(&self.bc),
+            de: word(&self.de),
+            hl: word(&self.hl),
+            alt_af: word(&self.alt_af),
+            alt_bc: word(&self.alt_bc),
+            alt_de: word(&self.alt_de),
+            alt_hl: word(&self.alt_hl),
+            ix: word(&self.ix),
+            iy: word(&self.iy),
+            sp: word(&self.sp),
+            pc: word(&self.pc),
+            ir: word(&self.ir),
+            iff1: self.iff1.get(),
+            iff2: self.iff2.get(),
+            im: self.im.get(),
+            int: self.int.get(),
+            nmi: self.nmi.get(),
+            after_ei: self.after_ei.get(),
+            after_ld_air: self.after_ld_air.get(),
+            halted: self.halted.get(),
+        }
     }
 
-    /// Calculate absolute address for IX+d or IY+d offsets
-    fn idx_addr(&self, reg: Reg, displacement: i8) -> u16 {
-        let rpair = match reg {
-            Reg::AtIX => RegPair::IX,
-            Reg::AtIY => RegPair::IY,
-            _ => unreachable!()
-        };
-        let addr = self.rp(rpair).get() as i32 + displacement as i32;
-        return addr as u16;
+    /// Restore registers and state. Running CPU task picks up the new PC
+    /// on the next instruction boundary, so it should be called between instructions.
+    pub fn load_state(&self, regs: &CpuRegisters) {
+        let set = |cell: &U16Cell, value: u16| cell.value().set(value);
+        set(&self.af, regs.af);
+        set(&self.bc, regs.bc);
+        set(&self.de, regs.de);
+        set(&self.hl, regs.hl);
+        set(&self.alt_af, regs.alt_af);
+        set(&self.alt_bc, regs.alt_bc);
+        set(&self.alt_de, regs.alt_de);
+        set(&self.alt_hl, regs.alt_hl);
+        set(&self.ix, regs.ix);
+        set(&self.iy, regs.iy);
+        set(&self.sp, regs.sp);
+        set(&self.pc, regs.pc);
+        set(&self.ir, regs.ir);
+        self.iff1.set(regs.iff1);
+        self.iff2.set(regs.iff2);
+        self.im.set(regs.im);
+        self.int.set(regs.int);
+        self.nmi.set(regs.nmi);
+        self.after_ei.set(regs.after_ei);
+        self.after_ld_air.set(regs.after_ld_air);
+        self.halted.set(regs.halted);
+        self.state_loaded.set(true);
     }
 
-    /// Probe INT & NMI bus lines and sets corresponding CPU flags
+    /// Probe INT & NMI bus lines and sets corresponding CPU flags.
+    /// NMI is edge-triggered and latched, while INT is level-triggered and reflects the last sampled state.
     fn probe_interrupts(&self) {
-        if self.bus.nmi.probe().unwrap_or(false) {
+        let nmi = self.bus.nmi.probe().unwrap_or(false);
+        if nmi && !self.nmi_line.get() {
             self.nmi.set(true);
         }
-        if self.bus.int.probe().unwrap_or(false) {
-            self.int.set(true);
-        }
+        self.nmi_line.set(nmi);
+        self.int.set(self.bus.int.probe().unwrap_or(false));
     }
 
     /// Probe WAIT signal and wait while it's set
@@ -916,10 +1517,8 @@ impl Cpu {
             yield_wait!(self.clock.falling(1)); // TW2 falling
             yield_from!(self.process_wait());
             yield_wait!(self.clock.rising(1)); // T3 rising
-            let byte = self.bus.data.expect();
-            // Increment R (lower 7 bits)
-            let r = self.rg(Reg::R).get();
-            self.rg(Reg::R).set(((r + 1) & 0x7f) | (r & 0x80));
+            let byte = self.bus.data.probe().unwrap_or(0xff); // floating bus reads as FFh
+            self.inc_refresh();
             self.bus.addr.drive(self, self.rp(RegPair::IR).get());
             self.bus.ctrl.drive(self, Ctrl::RFSH); // clears IORQ
             self.bus.m1.drive(self, false);
@@ -935,6 +1534,32 @@ impl Cpu {
         }
     }
 
+    /// Interrupt acknowledge cycle which reads operand (displacement or immediate data)
+    /// of the instruction supplied by interrupting device in IM0. Takes 3 t-cycles.
+    /// Similar to memory read, but with M1 & IORQ instead of MREQ & RD.
+    fn interrupt_data_read<'a>(&'a self, addr: u16) -> impl Task<u8> + 'a {
+        #[coroutine] move || {
+            yield_wait!(self.clock.rising(1)); // T1 rising
+            self.bus.data.release(self);
+            self.bus.addr.drive(self, addr);
+            self.bus.ctrl.drive(self, Ctrl::NONE);
+            self.bus.m1.drive(self, true);
+            yield_wait!(self.clock.falling(1)); // T1 falling
+            self.bus.ctrl.drive(self, Ctrl::IORQ);
+            yield_wait!(self.clock.falling(1)); // T2 falling
+            yield_from!(self.process_wait());
+            yield_wait!(self.clock.rising(1)); // T3 rising
+            let busrq = self.bus.busrq.probe().unwrap_or(false);
+            self.probe_interrupts();
+            yield_wait!(self.clock.falling(1)); // T3 falling
+            let byte = self.bus.data.probe().unwrap_or(0xff); // floating bus reads as FFh
+            self.bus.ctrl.drive(self, Ctrl::NONE);
+            self.bus.m1.drive(self, false);
+            if busrq { yield_from!(self.process_busrq()); }
+            return byte;
+        }
+    }
+
     /// Instruction opcode fetch m-cycle
     /// (usually referred to as M1). Takes 4 t-cycles.
     fn opcode_read<'a>(&'a self, addr: u16) -> impl Task<u8> + 'a {
@@ -950,9 +1575,7 @@ impl Cpu {
             yield_from!(self.process_wait());
             yield_wait!(self.clock.rising(1)); // T3 rising
             let byte = self.bus.data.expect();
-            // Increment R (lower 7 bits)
-            let r = self.rg(Reg::R).get();
-            self.rg(Reg::R).set(((r + 1) & 0x7f) | (r & 0x80));
+            self.inc_refresh();
             self.bus.addr.drive(self, self.rp(RegPair::IR).get());
             self.bus.ctrl.drive(self, Ctrl::RFSH); // clears MREQ & RD
             self.bus.m1.drive(self, false);
@@ -1012,7 +1635,7 @@ impl Cpu {
         }
     }
 
-    /// IO read m-cycle. Takes 3 t-cycles.
+    /// IO read m-cycle. Takes 4 t-cycles including the wait state.
     fn io_read<'a>(&'a self, addr: u16) -> impl Task<u8> + 'a {
         #[coroutine] move || {
             yield_wait!(self.clock.rising(1)); // T1 rising
@@ -1027,14 +1650,14 @@ impl Cpu {
             let busrq = self.bus.busrq.probe().unwrap_or(false);
             self.probe_interrupts();
             yield_wait!(self.clock.falling(1)); // T3 falling
-            let byte = self.bus.data.expect();
+            let byte = self.bus.data.probe().unwrap_or(0xff); // floating bus if no device responds
             self.bus.ctrl.drive(self, Ctrl::NONE);
             if busrq { yield_from!(self.process_busrq()); }
             return byte;
         }
     }
 
-    /// IO write m-cycle. Takes 3 t-cycles.
+    /// IO write m-cycle. Takes 4 t-cycles including the wait state.
     fn io_write<'a>(&'a self, addr: u16, val: u8) -> impl Task<()> + 'a {
         #[coroutine] move || {
             yield_wait!(self.clock.rising(1)); // T1 rising
@@ -0,0 +1,362 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, InterruptSource, IoDevice, Snapshot, run_io_device},
+    formats::{FormatError, Reader},
+};
+
+bitflags! {
+    /// CTC channel control word
+    #[derive(Default)]
+    pub struct CtcControl : u8 {
+        /// Control word marker (otherwise it's an interrupt vector)
+        const CONTROL = 1 << 0;
+        /// Software reset
+        const RESET = 1 << 1;
+        /// Time constant follows
+        const TIME_CONSTANT = 1 << 2;
+        /// Timer is started by CLK/TRG edge (otherwise automatically on time constant load)
+        const TRIGGER = 1 << 3;
+        /// CLK/TRG rising edge (not used, edges are delivered as pulses)
+        const RISING_EDGE = 1 << 4;
+        /// Prescaler is 256 (otherwise 16)
+        const PRESCALER_256 = 1 << 5;
+        /// Counter mode (otherwise timer mode)
+        const COUNTER = 1 << 6;
+        /// Interrupt enable
+        const INTERRUPT = 1 << 7;
+    }
+}
+
+/// Size of channel state in snapshot
+const CHANNEL_SNAPSHOT_SIZE: usize = 10;
+
+/// CTC channel state
+#[derive(Default)]
+struct CtcChannel {
+    control: Cell<CtcControl>,
+    time_constant: Cell<u8>,
+    counter: Cell<u8>,
+    prescaler: Cell<u16>,
+    running: Cell<bool>,
+    expect_time_constant: Cell<bool>,
+    wait_trigger: Cell<bool>,
+    int_pending: Cell<bool>,
+    int_in_service: Cell<bool>,
+}
+
+/// Z80 CTC (counter/timer circuit) with four channels.
+/// Channel registers are mapped to IO ports `base_port..base_port+3` (low address byte).
+/// Interrupts are delivered through `DaisyChain`.
+pub struct Ctc {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    base_port: u8,
+    vector: Cell<u8>,
+    channels: [CtcChannel; 4],
+}
+
+impl Ctc {
+
+    /// Create new CTC instance
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, base_port: u8) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            base_port: base_port & !0x03,
+            vector: Cell::new(0),
+            channels: Default::default(),
+        }
+    }
+
+    /// Write channel register (control word, time constant or interrupt vector)
+    pub fn write(&self, channel: usize, byte: u8) {
+
+        let ch = &self.channels[channel];
+
+        if ch.expect_time_constant.get() {
+            ch.expect_time_constant.set(false);
+            ch.time_constant.set(byte);
+            if !ch.running.get() {
+                // Channel is (re)started, otherwise time constant takes effect on next reload
+                ch.counter.set(byte);
+                ch.prescaler.set(0);
+                let control = ch.control.get();
+                let wait_trigger = !control.contains(CtcControl::COUNTER) && control.contains(CtcControl::TRIGGER);
+                ch.wait_trigger.set(wait_trigger);
+                ch.running.set(!wait_trigger);
+            }
+        } else if byte & CtcControl::CONTROL.bits() != 0 {
+            let control = CtcControl::from_bits_truncate(byte);
+            ch.control.set(control);
+            ch.expect_time_constant.set(control.contains(CtcControl::TIME_CONSTANT));
+            if !control.contains(CtcControl::INTERRUPT) {
+                ch.int_pending.set(false);
+            }
+            if control.contains(CtcControl::RESET) {
+                ch.running.set(false);
+                ch.wait_trigger.set(false);
+                ch.int_pending.set(false);
+            }
+        } else if channel == 0 {
+            // Interrupt vector, channel number is substituted to bits 1-2
+            self.vector.set(byte & 0xf8);
+        }
+
+    }
+
+    /// Read channel down-counter
+    pub fn read(&self, channel: usize) -> u8 {
+        self.channels[channel].counter.get()
+    }
+
+    /// Pulse CLK/TRG input of the channel
+    pub fn trigger(&self, channel: usize) {
+        let ch = &self.channels[channel];
+        if ch.wait_trigger.get() {
+            ch.wait_trigger.set(false);
+            ch.running.set(true);
+        } else if ch.running.get() && ch.control.get().contains(CtcControl::COUNTER) {
+            self.count_down(channel);
+        }
+    }
+
+    /// Advance timers by one system clock t-cycle
+    fn tick(&self) {
+        for (channel, ch) in self.channels.iter().enumerate() {
+            let control = ch.control.get();
+            if ch.running.get() && !control.contains(CtcControl::COUNTER) {
+                let prescaler = if control.contains(CtcControl::PRESCALER_256) { 256 } else { 16 };
+                let ticks = ch.prescaler.get() + 1;
+                if ticks == prescaler {
+                    ch.prescaler.set(0);
+                    self.count_down(channel);
+                } else {
+                    ch.prescaler.set(ticks);
+                }
+            }
+        }
+    }
+
+    /// Decrement channel down-counter, reload it and request interrupt on zero count
+    fn count_down(&self, channel: usize) {
+        let ch = &self.channels[channel];
+        let counter = ch.counter.get().wrapping_sub(1);
+        if counter == 0 {
+            ch.counter.set(ch.time_constant.get()); // 0 stands for 256
+            if ch.control.get().contains(CtcControl::INTERRUPT) {
+                ch.int_pending.set(true);
+            }
+        } else {
+            ch.counter.set(counter);
+        }
+    }
+
+    /// Highest priority channel requesting interrupt. Channel with interrupt
+    /// under service blocks itself and all channels with lower priority.
+    fn requesting_channel(&self) -> Option<usize> {
+        for (channel, ch) in self.channels.iter().enumerate() {
+            if ch.int_in_service.get() {
+                break;
+            }
+            if ch.int_pending.get() {
+                return Some(channel);
+            }
+        }
+        None
+    }
+
+    /// Map IO address to channel if CTC is selected
+    fn select(&self, addr: u16) -> Option<usize> {
+        let port = addr as u8;
+        if port & !0x03 == self.base_port { Some((port & 0x03) as usize) } else { None }
+    }
+
+}
+
+impl Snapshot for Ctc {
+
+    /// Interrupt vector followed by channel registers and states
+    fn save_snapshot(&self) -> Vec<u8> {
+        let mut data = vec![self.vector.get()];
+        for ch in &self.channels {
+            data.extend([ch.control.get().bits(), ch.time_constant.get(), ch.counter.get()]);
+            data.extend(ch.prescaler.get().to_le_bytes());
+            data.extend([ch.running.get(), ch.expect_time_constant.get(), ch.wait_trigger.get(), ch.int_pending.get(), ch.int_in_service.get()].map(u8::from));
+        }
+        data
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        if data.len() != 1 + self.channels.len() * CHANNEL_SNAPSHOT_SIZE {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        let mut rd = Reader::new(data);
+        self.vector.set(rd.byte()?);
+        for ch in &self.channels {
+            ch.control.set(CtcControl::from_bits_truncate(rd.byte()?));
+            ch.time_constant.set(rd.byte()?);
+            ch.counter.set(rd.byte()?);
+            ch.prescaler.set(rd.word()?);
+            for state in [&ch.running, &ch.expect_time_constant, &ch.wait_trigger, &ch.int_pending, &ch.int_in_service] {
+                state.set(rd.byte()? != 0);
+            }
+        }
+        Ok(())
+    }
+
+}
+
+impl Identifiable for Ctc {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl InterruptSource for Ctc {
+
+    fn int_pending(&self) -> bool {
+        self.requesting_channel().is_some()
+    }
+
+    fn int_in_service(&self) -> bool {
+        self.channels.iter().any(|ch| ch.int_in_service.get())
+    }
+
+    fn int_acknowledge(&self) -> u8 {
+        let channel = self.requesting_channel().expect("Expecting CTC channel to request an interrupt");
+        let ch = &self.channels[channel];
+        ch.int_pending.set(false);
+        ch.int_in_service.set(true);
+        self.vector.get() | (channel << 1) as u8
+    }
+
+    fn int_return(&self) {
+        if let Some(ch) = self.channels.iter().find(|ch| ch.int_in_service.get()) {
+            ch.int_in_service.set(false);
+        }
+    }
+
+}
+
+impl IoDevice for Ctc {
+
+    fn clock_tick(&self) {
+        self.tick();
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        self.select(addr).map(|channel| self.read(channel))
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        if let Some(channel) = self.select(addr) {
+            self.write(channel, byte);
+        }
+    }
+
+}
+
+impl Device for Ctc {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn mkctc() -> Ctc {
+        Ctc::new(0, &Default::default(), &Default::default(), 0x80)
+    }
+
+    #[test]
+    fn timer_counts_down_every_prescaler_period() {
+        let ctc = mkctc();
+        ctc.write(1, 0x07); // timer, prescaler 16, time constant follows, reset
+        ctc.write(1, 10);
+        assert_eq!(ctc.read(1), 10);
+        for _ in 0..16 { ctc.tick(); }
+        assert_eq!(ctc.read(1), 9);
+        for _ in 0..(16 * 9) { ctc.tick(); }
+        assert_eq!(ctc.read(1), 10); // reloaded
+    }
+
+    #[test]
+    fn timer_with_prescaler_256_and_zero_time_constant_counts_from_256() {
+        let ctc = mkctc();
+        ctc.write(0, 0x27); // timer, prescaler 256, time constant follows, reset
+        ctc.write(0, 0);
+        for _ in 0..256 { ctc.tick(); }
+        assert_eq!(ctc.read(0), 255);
+    }
+
+    #[test]
+    fn timer_started_by_trigger_waits_for_clk_trg_pulse() {
+        let ctc = mkctc();
+        ctc.write(2, 0x0f); // timer, prescaler 16, trigger start, time constant follows, reset
+        ctc.write(2, 5);
+        for _ in 0..32 { ctc.tick(); }
+        assert_eq!(ctc.read(2), 5);
+        ctc.trigger(2);
+        for _ in 0..32 { ctc.tick(); }
+        assert_eq!(ctc.read(2), 3);
+    }
+
+    #[test]
+    fn counter_requests_interrupt_on_zero_count() {
+        let ctc = mkctc();
+        ctc.write(0, 0x40); // vector
+        ctc.write(3, 0xc7); // interrupt, counter, time constant follows, reset
+        ctc.write(3, 3);
+        ctc.trigger(3);
+        ctc.trigger(3);
+        assert!(!ctc.int_pending());
+        ctc.trigger(3);
+        assert!(ctc.int_pending());
+        assert_eq!(ctc.read(3), 3);
+        assert_eq!(ctc.int_acknowledge(), 0x46);
+        assert!(ctc.int_in_service() && !ctc.int_pending());
+        ctc.int_return();
+        assert!(!ctc.int_in_service());
+    }
+
+    #[test]
+    fn new_time_constant_takes_effect_on_next_reload() {
+        let ctc = mkctc();
+        ctc.write(0, 0xc5); // interrupt, counter, time constant follows
+        ctc.write(0, 2);
+        ctc.write(0, 0xc5);
+        ctc.write(0, 4);
+        ctc.trigger(0);
+        assert_eq!(ctc.read(0), 1);
+        ctc.trigger(0);
+        assert_eq!(ctc.read(0), 4);
+    }
+
+    #[test]
+    fn channel_in_service_blocks_lower_priority_channels_only() {
+        let ctc = mkctc();
+        for channel in 0..3 {
+            ctc.write(channel, 0xc7); // interrupt, counter, time constant follows, reset
+            ctc.write(channel, 1);
+        }
+        ctc.trigger(1);
+        assert_eq!(ctc.int_acknowledge(), 0x02);
+        ctc.trigger(2);
+        assert!(!ctc.int_pending());
+        ctc.trigger(0);
+        assert!(ctc.int_pending());
+        assert_eq!(ctc.int_acknowledge(), 0x00);
+        ctc.int_return(); // channel 0
+        assert!(!ctc.int_pending());
+        ctc.int_return(); // channel 1
+        assert_eq!(ctc.int_acknowledge(), 0x04);
+    }
+
+}
@@ -0,0 +1,141 @@
+use std::rc::Rc;
+
+use crate::{
+    core::{CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask},
+    devs::Device, yield_wait
+};
+
+/// Z80 family peripheral which is able to request interrupt and supply
+/// IM2 vector during interrupt acknowledge cycle (e.g. PIO, CTC, SIO)
+pub trait InterruptSource: Identifiable {
+
+    /// Check if device requests an interrupt. Device with multiple interrupt sources
+    /// (e.g. CTC channels) should only report requests which have higher
+    /// internal priority than the interrupt it has under service (if any).
+    fn int_pending(&self) -> bool;
+
+    /// Check if device has interrupt under service (acknowledged, but RETI isn't executed yet)
+    fn int_in_service(&self) -> bool;
+
+    /// Acknowledge pending interrupt and return vector byte to put on the data bus
+    fn int_acknowledge(&self) -> u8;
+
+    /// RETI is executed for the interrupt under service
+    fn int_return(&self);
+
+}
+
+/// Interrupt daisy chain which connects IEI/IEO pins of Z80 family peripherals.
+/// Devices are ordered by priority (first is the highest). Device is enabled (its IEI is high)
+/// only when none of higher priority devices has interrupt under service.
+/// Chain drives INT line, supplies vector of the highest priority pending device during
+/// interrupt acknowledge and decodes RETI (ED 4D) fetched by CPU to end the interrupt service.
+/// Lines are driven on behalf of the requesting device, so bus logger shows it as an owner.
+pub struct DaisyChain {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    devices: Vec<Rc<dyn InterruptSource>>,
+}
+
+impl DaisyChain {
+
+    /// Create new daisy chain with devices ordered by priority
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, devices: Vec<Rc<dyn InterruptSource>>) -> Self {
+        Self { id, bus: Rc::clone(bus), devices }
+    }
+
+    /// Highest priority device which is pending and has its IEI high
+    fn requesting(&self) -> Option<&Rc<dyn InterruptSource>> {
+        for device in &self.devices {
+            if device.int_pending() {
+                return Some(device);
+            }
+            if device.int_in_service() {
+                break; // IEO is low
+            }
+        }
+        None
+    }
+
+    /// Highest priority device with interrupt under service (the only one with IEI high)
+    fn in_service(&self) -> Option<&Rc<dyn InterruptSource>> {
+        self.devices.iter().find(|device| device.int_in_service())
+    }
+
+}
+
+impl Identifiable for DaisyChain {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl Device for DaisyChain {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+
+        Box::new(#[coroutine] move || {
+
+            // Interrupt acknowledge cycle is in progress
+            let mut acknowledging = false;
+
+            // Device driving INT line and device driving data bus with vector
+            let mut int_owner: Option<Identifier> = None;
+            let mut data_owner: Option<Identifier> = None;
+
+            // Opcode fetched during current M1 cycle and during the previous one
+            let mut opcode: Option<u8> = None;
+            let mut prev_opcode: Option<u8> = None;
+
+            loop {
+
+                let ctrl = self.bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+                let m1 = self.bus.m1.probe().unwrap_or(false);
+
+                if m1 && ctrl.contains(Ctrl::IORQ) {
+                    // Interrupt acknowledge: highest priority requesting device puts vector on the bus
+                    if !acknowledging {
+                        acknowledging = true;
+                        if let Some(device) = self.requesting() {
+                            self.bus.data.drive(&device.id(), device.int_acknowledge());
+                            data_owner = Some(device.id());
+                        }
+                    }
+                } else {
+                    acknowledging = false;
+                    if let Some(owner) = data_owner.take() {
+                        self.bus.data.release(&owner);
+                    }
+                    if m1 && ctrl.contains(Ctrl::MREQ | Ctrl::RD) {
+                        // Snoop opcode fetch
+                        if let Some(byte) = self.bus.data.probe() {
+                            opcode = Some(byte);
+                        }
+                    } else if let Some(byte) = opcode.take() {
+                        // Opcode fetch is complete, look for RETI sequence
+                        if prev_opcode == Some(0xed) && byte == 0x4d
+                            && let Some(device) = self.in_service() {
+                            device.int_return();
+                        }
+                        prev_opcode = Some(byte);
+                    }
+                }
+
+                let requesting = self.requesting().map(|device| device.id());
+                if int_owner != requesting {
+                    if let Some(owner) = int_owner {
+                        self.bus.int.release(&owner);
+                    }
+                    if let Some(owner) = requesting {
+                        self.bus.int.drive(&owner, true);
+                    }
+                    int_owner = requesting;
+                }
+
+                yield_wait!(1); // Advance to the next T-cycle edge (rising or falling)
+
+            }
+
+        })
+
+    }
+
+}
@@ -2,13 +2,26 @@ use std::{cell::{Cell, RefCell}, collections::HashMap, rc::Rc};
 
 use crate::{
     core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
-    devs::{BreakpointManager, BusLogger, Cpu, mem::Static48k},
+    devs::{Beta128, BreakpointManager, BusLogger, Cpu, Ctc, DaisyChain, DivIde, DivMmc, Interface1, InterruptSource, Multiface, MultifaceModel, Plus3Disk, PrinterModel, TapeDeck, TapePlayer, TapeRecorder, ZxPrinter, mem::{MemoryOverlay, Static48k}},
+    formats::FormatError,
 };
 
 pub trait Device: Identifiable {
     fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a>;
 }
 
+/// Device with state which is captured in machine snapshot beside CPU registers
+/// and memory contents, such as paging latches and inserted media
+pub trait Snapshot {
+
+    /// Serialize the state
+    fn save_snapshot(&self) -> Vec<u8>;
+
+    /// Restore the state serialized by `save_snapshot`
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError>;
+
+}
+
 /// Device manager for creating and managing devices in the system
 pub struct DeviceManager {
     bus: Rc<CpuBus>,
@@ -16,6 +29,8 @@ pub struct DeviceManager {
     breakpoint_manager: Rc<BreakpointManager>,
     next_id: Cell<Identifier>,
     device_names: RefCell<HashMap<Identifier, &'static str>>,
+    /// Devices decoding IO ports, which only see IO cycles of bus-accurate CPU
+    port_devices: RefCell<Vec<Identifier>>,
 }
 
 impl DeviceManager {
@@ -28,6 +43,7 @@ impl DeviceManager {
             breakpoint_manager: Rc::clone(breakpoint_manager),
             next_id: Cell::new(0),
             device_names: RefCell::new(HashMap::new()),
+            port_devices: RefCell::new(Vec::new()),
         }
     }
 
@@ -42,6 +58,16 @@ impl DeviceManager {
         self.device_names.borrow_mut().insert(id, name);
     }
 
+    /// Register a device which decodes IO ports
+    fn register_port_device(&self, id: Identifier) {
+        self.port_devices.borrow_mut().push(id);
+    }
+
+    /// IDs of created devices which decode IO ports
+    pub fn port_devices(&self) -> Vec<Identifier> {
+        self.port_devices.borrow().clone()
+    }
+
     /// Get the readable name for a device by its ID or device reference
     pub fn get_name<T: Identifiable>(&self, identifiable: T) -> Option<&'static str> {
         self.device_names.borrow().get(&identifiable.id()).copied()
@@ -61,6 +87,98 @@ impl DeviceManager {
         memory
     }
 
+    /// Create a new Z80 CTC instance with channels mapped to IO ports `base_port..base_port+3`
+    pub fn create_ctc(&self, base_port: u8) -> Rc<Ctc> {
+        let ctc = Rc::new(Ctc::new(self.generate_id(), &self.bus, &self.clock, base_port));
+        self.register_name(ctc.id(), "Z80 CTC");
+        self.register_port_device(ctc.id());
+        ctc
+    }
+
+    /// Create a new interrupt daisy chain with devices ordered by priority
+    pub fn create_daisy_chain(&self, devices: Vec<Rc<dyn InterruptSource>>) -> Rc<DaisyChain> {
+        let chain = Rc::new(DaisyChain::new(self.generate_id(), &self.bus, devices));
+        self.register_name(chain.id(), "Interrupt Daisy Chain");
+        chain
+    }
+
+    /// Create a new tape player reading blocks from given deck
+    pub fn create_tape_player(&self, deck: &Rc<TapeDeck>) -> Rc<TapePlayer> {
+        let player = Rc::new(TapePlayer::new(self.generate_id(), &self.bus, &self.clock, deck));
+        self.register_name(player.id(), "Tape Player");
+        self.register_port_device(player.id());
+        player
+    }
+
+    /// Create a new tape recorder
+    pub fn create_tape_recorder(&self) -> Rc<TapeRecorder> {
+        let recorder = Rc::new(TapeRecorder::new(self.generate_id(), &self.bus, &self.clock));
+        self.register_name(recorder.id(), "Tape Recorder");
+        self.register_port_device(recorder.id());
+        recorder
+    }
+
+    /// Create a new Beta 128 disk interface with given TR-DOS ROM paged over the memory
+    pub fn create_beta128(&self, memory: &Rc<Static48k>, rom: &[u8]) -> Rc<Beta128> {
+        let beta128 = Rc::new(Beta128::new(self.generate_id(), &self.bus, &self.clock, rom));
+        self.register_name(beta128.id(), "Beta 128 Disk Interface");
+        self.register_port_device(beta128.id());
+        memory.add_overlay(&(Rc::clone(&beta128) as Rc<dyn MemoryOverlay>));
+        beta128
+    }
+
+    /// Create a new DivMMC interface with given EEPROM contents, paged over the memory
+    pub fn create_divmmc(&self, memory: &Rc<Static48k>, rom: &[u8]) -> Rc<DivMmc> {
+        let divmmc = Rc::new(DivMmc::new(self.generate_id(), &self.bus, &self.clock, rom));
+        self.register_name(divmmc.id(), "DivMMC");
+        self.register_port_device(divmmc.id());
+        memory.add_overlay(&(Rc::clone(&divmmc) as Rc<dyn MemoryOverlay>));
+        divmmc
+    }
+
+    /// Create a new DivIDE interface with given EEPROM contents, paged over the memory
+    pub fn create_divide(&self, memory: &Rc<Static48k>, rom: &[u8]) -> Rc<DivIde> {
+        let divide = Rc::new(DivIde::new(self.generate_id(), &self.bus, &self.clock, rom));
+        self.register_name(divide.id(), "DivIDE");
+        self.register_port_device(divide.id());
+        memory.add_overlay(&(Rc::clone(&divide) as Rc<dyn MemoryOverlay>));
+        divide
+    }
+
+    /// Create a new Interface 1 with given shadow ROM contents, paged over the memory
+    pub fn create_interface1(&self, memory: &Rc<Static48k>, rom: &[u8]) -> Rc<Interface1> {
+        let interface1 = Rc::new(Interface1::new(self.generate_id(), &self.bus, &self.clock, rom));
+        self.register_name(interface1.id(), "Interface 1");
+        self.register_port_device(interface1.id());
+        memory.add_overlay(&(Rc::clone(&interface1) as Rc<dyn MemoryOverlay>));
+        interface1
+    }
+
+    /// Create a new Multiface of given model with given ROM contents, paged over the memory
+    pub fn create_multiface(&self, memory: &Rc<Static48k>, model: MultifaceModel, rom: &[u8]) -> Rc<Multiface> {
+        let multiface = Rc::new(Multiface::new(self.generate_id(), &self.bus, &self.clock, model, rom));
+        self.register_name(multiface.id(), "Multiface");
+        self.register_port_device(multiface.id());
+        memory.add_overlay(&(Rc::clone(&multiface) as Rc<dyn MemoryOverlay>));
+        multiface
+    }
+
+    /// Create a new +3 disk drive unit with uPD765A controller
+    pub fn create_plus3_disk(&self) -> Rc<Plus3Disk> {
+        let disk = Rc::new(Plus3Disk::new(self.generate_id(), &self.bus, &self.clock));
+        self.register_name(disk.id(), "+3 Disk Drive");
+        self.register_port_device(disk.id());
+        disk
+    }
+
+    /// Create a new ZX Printer or compatible printer of given model
+    pub fn create_zx_printer(&self, model: PrinterModel) -> Rc<ZxPrinter> {
+        let printer = Rc::new(ZxPrinter::new(self.generate_id(), &self.bus, &self.clock, model));
+        self.register_name(printer.id(), "ZX Printer");
+        self.register_port_device(printer.id());
+        printer
+    }
+
     /// Create a new bus logger instance
     pub fn create_bus_logger(&self) -> Rc<BusLogger> {
         let logger = Rc::new(BusLogger::new(self.generate_id(), &self.bus, &self.clock));
@@ -0,0 +1,206 @@
+use std::cell::{Cell, RefCell};
+
+/// Highest cylinder the drive head can step to
+const MAX_CYLINDER: u8 = 83;
+
+/// Disk revolution at 300 RPM in t-states
+pub(crate) const REVOLUTION: u64 = 700_000;
+
+/// Byte time at 250 kbit/s MFM
+pub(crate) const BYTE: u64 = 112;
+
+/// Bytes of the track between index pulses
+pub(crate) const TRACK_BYTES: usize = 6250;
+
+/// Bytes from the index pulse to the first sector ID (gap 4a, index mark and gap 1)
+pub(crate) const FIRST_ID: usize = 146;
+
+/// Bytes of ID field with leading sync and trailing CRC
+pub(crate) const ID_FIELD: u64 = 22;
+
+/// Bytes from the end of ID field to the first data byte (gap 2, sync and data mark)
+pub(crate) const ID_TO_DATA: u64 = 38;
+
+/// Time from the index pulse until the ID of the sector with given index passes
+/// the head, with sectors spread evenly around the track
+pub(crate) fn id_position(index: usize, count: usize) -> u64 {
+    (FIRST_ID + index * ((TRACK_BYTES - FIRST_ID) / count.max(1))) as u64 * BYTE
+}
+
+/// Floppy disk sector with its ID field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskSector {
+    pub cylinder: u8,
+    pub head: u8,
+    pub sector: u8,
+    /// Sector length is `128 << size_code` bytes
+    pub size_code: u8,
+    /// Data address mark is deleted data mark
+    pub deleted: bool,
+    /// Controller status registers 1 and 2 reported on reading the sector, such
+    /// as data CRC error kept by copy protected images
+    pub st1: u8,
+    pub st2: u8,
+    pub data: Vec<u8>,
+    /// Other versions of weak sector data, returned in turn by successive reads
+    pub copies: Vec<Vec<u8>>,
+}
+
+impl DiskSector {
+
+    /// Sector with given ID and data, size code is derived from data length
+    pub fn new(cylinder: u8, head: u8, sector: u8, data: Vec<u8>) -> Self {
+        let size_code = (data.len().max(128) / 128).ilog2() as u8;
+        Self { cylinder, head, sector, size_code, deleted: false, st1: 0, st2: 0, data, copies: vec![] }
+    }
+
+    /// Data returned by given read of the sector
+    pub fn read(&self, count: usize) -> &[u8] {
+        match count % (self.copies.len() + 1) {
+            0 => &self.data,
+            index => &self.copies[index - 1],
+        }
+    }
+
+}
+
+/// Floppy disk contents. Sectors of each track are listed in the order they pass the head.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Disk {
+    pub sides: u8,
+    /// Tracks ordered by cylinder, then by side
+    pub tracks: Vec<Vec<DiskSector>>,
+    pub write_protected: bool,
+}
+
+impl Disk {
+
+    /// Unformatted disk with given geometry
+    pub fn new(cylinders: u8, sides: u8) -> Self {
+        Self { sides, tracks: vec![vec![]; cylinders as usize * sides as usize], write_protected: false }
+    }
+
+    /// Number of cylinders
+    pub fn cylinders(&self) -> u8 {
+        (self.tracks.len() / self.sides.max(1) as usize) as u8
+    }
+
+    /// Sectors of the track, None if it doesn't exist
+    pub fn track(&self, cylinder: u8, side: u8) -> Option<&Vec<DiskSector>> {
+        if side >= self.sides { return None; }
+        self.tracks.get(cylinder as usize * self.sides as usize + side as usize)
+    }
+
+    /// Mutable sectors of the track, None if it doesn't exist
+    pub fn track_mut(&mut self, cylinder: u8, side: u8) -> Option<&mut Vec<DiskSector>> {
+        if side >= self.sides { return None; }
+        self.tracks.get_mut(cylinder as usize * self.sides as usize + side as usize)
+    }
+
+}
+
+/// Floppy drive with head position and the disk inserted
+#[derive(Default)]
+pub struct FloppyDrive {
+    disk: RefCell<Option<Disk>>,
+    cylinder: Cell<u8>,
+    /// Disk was written since it was inserted
+    modified: Cell<bool>,
+    /// Sectors read so far, choosing the version of weak sector data
+    reads: Cell<usize>,
+}
+
+impl FloppyDrive {
+
+    /// Insert disk replacing the previous one
+    pub fn insert(&self, disk: Disk) {
+        self.disk.replace(Some(disk));
+        self.modified.set(false);
+    }
+
+    /// Remove the disk
+    pub fn eject(&self) -> Option<Disk> {
+        self.modified.set(false);
+        self.disk.take()
+    }
+
+    /// Copy of the disk
+    pub fn disk(&self) -> Option<Disk> {
+        self.disk.borrow().clone()
+    }
+
+    /// Disk is inserted
+    pub fn is_ready(&self) -> bool {
+        self.disk.borrow().is_some()
+    }
+
+    /// Disk is inserted and write protected
+    pub fn is_write_protected(&self) -> bool {
+        self.disk.borrow().as_ref().is_some_and(|disk| disk.write_protected)
+    }
+
+    /// Disk was written since it was inserted
+    pub fn is_modified(&self) -> bool {
+        self.modified.get()
+    }
+
+    /// Cylinder under the head
+    pub fn cylinder(&self) -> u8 {
+        self.cylinder.get()
+    }
+
+    /// Move the head one cylinder towards the spindle or out to cylinder 0
+    pub fn step(&self, inward: bool) {
+        let cylinder = self.cylinder.get();
+        self.cylinder.set(if inward { (cylinder + 1).min(MAX_CYLINDER) } else { cylinder.saturating_sub(1) });
+    }
+
+    /// Copy of the sectors under the head, empty if the track doesn't exist
+    pub fn track(&self, side: u8) -> Vec<DiskSector> {
+        self.disk.borrow().as_ref()
+            .and_then(|disk| disk.track(self.cylinder.get(), side).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Data of the sector with given index in the track under the head
+    pub fn read_sector(&self, side: u8, index: usize) -> Option<Vec<u8>> {
+        let count = self.reads.get();
+        self.reads.set(count.wrapping_add(1));
+        self.disk.borrow().as_ref()
+            .and_then(|disk| disk.track(self.cylinder.get(), side))
+            .and_then(|track| track.get(index))
+            .map(|sector| sector.read(count).to_vec())
+    }
+
+    /// Replace data of the sector with given index in the track under the head.
+    /// Written sector is no longer weak and has no errors.
+    pub fn write_sector(&self, side: u8, index: usize, data: &[u8], deleted: bool) {
+        let cylinder = self.cylinder.get();
+        if let Some(sector) = self.disk.borrow_mut().as_mut()
+            .and_then(|disk| disk.track_mut(cylinder, side))
+            .and_then(|track| track.get_mut(index)) {
+            sector.data = data.to_vec();
+            sector.deleted = deleted;
+            sector.st1 = 0;
+            sector.st2 = 0;
+            sector.copies.clear();
+            self.modified.set(true);
+        }
+    }
+
+    /// Replace sectors of the track under the head, as formatting does
+    pub fn write_track(&self, side: u8, sectors: Vec<DiskSector>) {
+        let cylinder = self.cylinder.get();
+        if let Some(disk) = self.disk.borrow_mut().as_mut() {
+            // Formatting past the last track extends the disk
+            while disk.cylinders() <= cylinder && side < disk.sides {
+                disk.tracks.extend(vec![vec![]; disk.sides as usize]);
+            }
+            if let Some(track) = disk.track_mut(cylinder, side) {
+                *track = sectors;
+                self.modified.set(true);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,187 @@
+use std::cell::{Cell, RefCell};
+
+use crate::{
+    core::{CpuBus, Ctrl},
+    devs::{Snapshot, mem::MemoryOverlay},
+    formats::{FormatError, Reader},
+};
+
+/// Size of EEPROM and of each RAM bank
+const PAGE_SIZE: usize = 0x2000;
+
+/// Opcode fetch from these addresses maps memory in after the fetch
+const ENTRY_POINTS: [u16; 6] = [0x0000, 0x0008, 0x0038, 0x0066, 0x04c6, 0x0562];
+
+/// Opcode fetch from this page maps memory in instantly
+const INSTANT_PAGE: u16 = 0x3d00;
+
+/// Opcode fetch from this range maps memory out after the fetch
+const OFF_AREA: std::ops::RangeInclusive<u16> = 0x1ff8..=0x1fff;
+
+/// Control register bits: bank number, MAPRAM and CONMEM
+const CONTROL_MAPRAM: u8 = 0x40;
+const CONTROL_CONMEM: u8 = 0x80;
+
+/// Bank mapped read only at 0000h-1FFFh in MAPRAM mode
+const MAPRAM_BANK: usize = 3;
+
+/// Paged memory of DivIDE and DivMMC interfaces: 8K EEPROM and RAM banks mapped
+/// to 0000h-3FFFh by control port E3h or by automapping on opcode fetch.
+pub struct DivMemory {
+    rom: Vec<u8>,
+    ram: RefCell<Vec<u8>>,
+    control: Cell<u8>,
+    /// MAPRAM bit can't be reset after it's set
+    mapram: Cell<bool>,
+    automap: Cell<bool>,
+    /// Automap state applied when the current opcode fetch ends
+    pending: Cell<Option<bool>>,
+    /// Opcode fetch is in progress on the bus
+    fetching: Cell<bool>,
+}
+
+impl DivMemory {
+
+    pub fn new(rom: &[u8], banks: usize) -> Self {
+        let mut rom = rom.to_vec();
+        rom.resize(PAGE_SIZE, 0xff);
+        Self {
+            rom,
+            ram: RefCell::new(vec![0; banks.max(MAPRAM_BANK + 1).next_power_of_two() * PAGE_SIZE]),
+            control: Cell::new(0),
+            mapram: Cell::new(false),
+            automap: Cell::new(false),
+            pending: Cell::new(None),
+            fetching: Cell::new(false),
+        }
+    }
+
+    /// Memory is paged in, by CONMEM bit or automapping
+    pub fn is_paged(&self) -> bool {
+        self.control.get() & CONTROL_CONMEM != 0 || self.automap.get()
+    }
+
+    /// Memory is mapped in by automapping
+    pub fn is_automapped(&self) -> bool {
+        self.automap.get()
+    }
+
+    /// Last value written to control port
+    pub fn control(&self) -> u8 {
+        self.control.get()
+    }
+
+    /// Write control port E3h
+    pub fn write_control(&self, byte: u8) {
+        self.control.set(byte);
+        if byte & CONTROL_MAPRAM != 0 {
+            self.mapram.set(true);
+        }
+    }
+
+    /// Opcode fetch from given address started
+    pub fn fetch(&self, addr: u16) {
+        if addr & 0xff00 == INSTANT_PAGE {
+            self.automap.set(true);
+        } else if ENTRY_POINTS.contains(&addr) {
+            self.pending.set(Some(true));
+        } else if OFF_AREA.contains(&addr) {
+            self.pending.set(Some(false));
+        }
+    }
+
+    /// Opcode fetch ended, delayed automapping takes effect
+    pub fn fetch_end(&self) {
+        if let Some(automap) = self.pending.take() {
+            self.automap.set(automap);
+        }
+    }
+
+    /// Follow opcode fetches on the bus, called on every rising clock edge
+    pub fn track_fetch(&self, bus: &CpuBus) {
+        let ctrl = bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+        if ctrl.contains(Ctrl::MREQ | Ctrl::RD) && bus.m1.probe().unwrap_or(false) {
+            if !self.fetching.replace(true) {
+                self.fetch(bus.addr.expect());
+            }
+        } else if self.fetching.replace(false) {
+            self.fetch_end();
+        }
+    }
+
+    /// Copy of RAM contents, bank after bank
+    pub fn ram(&self) -> Vec<u8> {
+        self.ram.borrow().clone()
+    }
+
+    fn bank(&self) -> usize {
+        self.control.get() as usize & (self.ram.borrow().len() / PAGE_SIZE - 1)
+    }
+
+    /// Bank mapped at given address, or None for EEPROM
+    fn page(&self, addr: u16) -> Option<usize> {
+        if addr as usize >= PAGE_SIZE {
+            Some(self.bank())
+        } else if self.control.get() & CONTROL_CONMEM == 0 && self.mapram.get() {
+            Some(MAPRAM_BANK)
+        } else {
+            None
+        }
+    }
+
+}
+
+impl Snapshot for DivMemory {
+
+    /// Control register, MAPRAM and automap state followed by RAM contents
+    fn save_snapshot(&self) -> Vec<u8> {
+        let mut data = vec![self.control.get(), self.mapram.get() as u8, self.automap.get() as u8];
+        data.extend(self.ram.borrow().iter());
+        data
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        let mut rd = Reader::new(data);
+        let (control, mapram, automap) = (rd.byte()?, rd.byte()? != 0, rd.byte()? != 0);
+        let ram = rd.rest();
+        if ram.len() != self.ram.borrow().len() {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        self.control.set(control);
+        self.mapram.set(mapram);
+        self.automap.set(automap);
+        self.pending.set(None);
+        self.fetching.set(false);
+        self.ram.borrow_mut().copy_from_slice(ram);
+        Ok(())
+    }
+
+}
+
+impl MemoryOverlay for DivMemory {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.is_paged() && (addr as usize) < 2 * PAGE_SIZE
+    }
+
+    fn writable(&self, addr: u16) -> bool {
+        // Bank 3 is write protected in MAPRAM mode unless CONMEM is set
+        let protected = self.mapram.get() && self.control.get() & CONTROL_CONMEM == 0;
+        addr as usize >= PAGE_SIZE && !(protected && self.bank() == MAPRAM_BANK)
+    }
+
+    fn write(&self, addr: u16, byte: u8) {
+        if let Some(bank) = self.page(addr) {
+            self.ram.borrow_mut()[bank * PAGE_SIZE + (addr as usize & (PAGE_SIZE - 1))] = byte;
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let offset = addr as usize & (PAGE_SIZE - 1);
+        match self.page(addr) {
+            Some(bank) => self.ram.borrow()[bank * PAGE_SIZE + offset],
+            None => self.rom[offset],
+        }
+    }
+
+}
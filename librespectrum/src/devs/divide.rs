@@ -0,0 +1,122 @@
+use std::rc::Rc;
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{AtaDrive, Device, DivMemory, IoDevice, Snapshot, mem::MemoryOverlay, run_io_device},
+    formats::FormatError,
+};
+
+/// Number of 8K RAM banks
+const BANKS: usize = 4;
+
+/// Control port
+const CONTROL_PORT: u8 = 0xe3;
+
+/// Task file registers are mapped to ports A3h-BFh, every fourth port
+const ATA_MASK: u8 = 0xe3;
+const ATA_PORTS: u8 = 0xa3;
+
+/// DivIDE interface with 8K EEPROM, 32K RAM and ATA hard disk. Memory is paged
+/// by control port E3h and by automapping, the same way as on DivMMC.
+pub struct DivIde {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    memory: DivMemory,
+    drive: AtaDrive,
+}
+
+impl DivIde {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, rom: &[u8]) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            memory: DivMemory::new(rom, BANKS),
+            drive: AtaDrive::default(),
+        }
+    }
+
+    /// Paged EEPROM and RAM
+    pub fn memory(&self) -> &DivMemory {
+        &self.memory
+    }
+
+    /// Hard disk
+    pub fn drive(&self) -> &AtaDrive {
+        &self.drive
+    }
+
+    /// Task file register selected by port, if any
+    fn register(addr: u16) -> Option<u8> {
+        let port = addr as u8;
+        (port & ATA_MASK == ATA_PORTS).then_some((port >> 2) & 0x07)
+    }
+
+}
+
+impl MemoryOverlay for DivIde {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.memory.covers(addr)
+    }
+
+    fn writable(&self, addr: u16) -> bool {
+        self.memory.writable(addr)
+    }
+
+    fn write(&self, addr: u16, byte: u8) {
+        self.memory.write(addr, byte)
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.memory.read(addr)
+    }
+
+}
+
+/// Paging state and RAM, ATA drive state isn't captured
+impl Snapshot for DivIde {
+
+    fn save_snapshot(&self) -> Vec<u8> {
+        self.memory.save_snapshot()
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        self.memory.load_snapshot(data)
+    }
+
+}
+
+impl Identifiable for DivIde {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for DivIde {
+
+    fn clock_tick(&self) {
+        self.memory.track_fetch(&self.bus);
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        Self::register(addr).map(|reg| self.drive.read(reg))
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        if addr as u8 == CONTROL_PORT {
+            self.memory.write_control(byte);
+        } else if let Some(reg) = Self::register(addr) {
+            self.drive.write(reg, byte);
+        }
+    }
+
+}
+
+impl Device for DivIde {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
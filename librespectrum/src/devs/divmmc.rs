@@ -0,0 +1,120 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, DivMemory, IoDevice, SdCard, Snapshot, mem::MemoryOverlay, run_io_device},
+    formats::FormatError,
+};
+
+/// Number of 8K RAM banks
+const BANKS: usize = 16;
+
+/// Control port, card select port and SPI data port
+const CONTROL_PORT: u8 = 0xe3;
+const SELECT_PORT: u8 = 0xe7;
+const SPI_PORT: u8 = 0xeb;
+
+/// DivMMC interface with 8K EEPROM, 128K RAM and SD card slot. Memory is paged
+/// by control port E3h and by automapping, SD card is accessed over SPI by port
+/// EBh and selected by bit 0 of port E7h (active low).
+pub struct DivMmc {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    memory: DivMemory,
+    card: SdCard,
+    /// Byte received by the last SPI transfer
+    received: Cell<u8>,
+}
+
+impl DivMmc {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, rom: &[u8]) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            memory: DivMemory::new(rom, BANKS),
+            card: SdCard::default(),
+            received: Cell::new(0xff),
+        }
+    }
+
+    /// Paged EEPROM and RAM
+    pub fn memory(&self) -> &DivMemory {
+        &self.memory
+    }
+
+    /// SD card in the slot
+    pub fn card(&self) -> &SdCard {
+        &self.card
+    }
+
+}
+
+impl MemoryOverlay for DivMmc {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.memory.covers(addr)
+    }
+
+    fn writable(&self, addr: u16) -> bool {
+        self.memory.writable(addr)
+    }
+
+    fn write(&self, addr: u16, byte: u8) {
+        self.memory.write(addr, byte)
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.memory.read(addr)
+    }
+
+}
+
+/// Paging state and RAM, card state isn't captured
+impl Snapshot for DivMmc {
+
+    fn save_snapshot(&self) -> Vec<u8> {
+        self.memory.save_snapshot()
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        self.memory.load_snapshot(data)
+    }
+
+}
+
+impl Identifiable for DivMmc {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for DivMmc {
+
+    fn clock_tick(&self) {
+        self.memory.track_fetch(&self.bus);
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        // Reading the data port starts a transfer clocking the card out
+        (addr as u8 == SPI_PORT).then(|| self.received.replace(self.card.transfer(0xff)))
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        match addr as u8 {
+            CONTROL_PORT => self.memory.write_control(byte),
+            SELECT_PORT => self.card.select(byte & 0x01 == 0),
+            SPI_PORT => self.received.set(self.card.transfer(byte)),
+            _ => (),
+        }
+    }
+
+}
+
+impl Device for DivMmc {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
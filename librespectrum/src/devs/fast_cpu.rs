@@ -0,0 +1,538 @@
+use std::{
+    cell::Cell, ops::{Coroutine, CoroutineState, Deref}, pin::Pin, rc::Rc
+};
+
+use crate::{
+    core::{Clock, Identifier},
+    cpu::{
+        Flags, IM0_TSTATES, IM1_TSTATES, IM2_TSTATES, NMI_TSTATES, OPCODE_FETCH_TSTATES, OPERAND_READ_TSTATES,
+        decoder::{Instruction, instruction_decoder}, timing,
+        tokens::{BlockOp, Condition, IntMode, Reg, RegPair, Token, TokenType}
+    },
+    devs::{BreakpointManager, Cpu, CpuState, mem::Memory},
+    mkword, spword
+};
+
+/// Fast instruction-stepped Z80 CPU core. Executes whole instructions at once,
+/// reads and writes memory directly instead of running bus cycles and counts
+/// t-states from the timing table. Shares registers with the bus-accurate `Cpu`,
+/// so cores are interchangeable on instruction boundary.
+///
+/// There are no bus devices to talk to: IN reads floating bus value (FFh), OUT is ignored,
+/// interrupts are requested by setting `int` / `nmi` CPU state flags and only
+/// "before opcode read" breakpoints are checked.
+pub struct FastCpu {
+    cpu: Rc<Cpu>,
+    memory: Rc<dyn Memory>,
+    clock: Rc<Clock>,
+    breakpoint_manager: Rc<BreakpointManager>,
+    /// Breakpoint was hit before the current instruction, so it's not checked again on resume
+    resumed: Cell<bool>,
+}
+
+impl Deref for FastCpu {
+    type Target = CpuState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cpu
+    }
+}
+
+impl FastCpu {
+
+    /// Create new fast core operating on given CPU registers
+    pub fn new(cpu: &Rc<Cpu>, memory: &Rc<dyn Memory>, clock: &Rc<Clock>, breakpoint_manager: &Rc<BreakpointManager>) -> Self {
+        Self {
+            cpu: Rc::clone(cpu),
+            memory: Rc::clone(memory),
+            clock: Rc::clone(clock),
+            breakpoint_manager: Rc::clone(breakpoint_manager),
+            resumed: Cell::new(false),
+        }
+    }
+
+    /// Run for given half t-cycles or until breakpoint is hit.
+    /// Execution stops on the first instruction boundary after target htcycles.
+    pub fn run(&self, htcycles: u64) -> Option<Identifier> {
+        let target_htcycles = self.clock.get() + htcycles;
+        while self.clock.get() < target_htcycles {
+            if !self.resumed.replace(false)
+                && let Some(id) = self.breakpoint_manager.hits_before_opcode_read(self.pc.value().get()) {
+                self.resumed.set(true);
+                return Some(id);
+            }
+            self.step();
+        }
+        None
+    }
+
+    /// Execute single instruction (or accept interrupt), advance the clock
+    /// and return number of t-states spent
+    pub fn step(&self) -> u64 {
+        let tstates = self.execute();
+        self.clock.set(self.clock.get() + (tstates << 1));
+        tstates
+    }
+
+    fn execute(&self) -> u64 {
+
+        if self.cpu.run_trap(self.rp(RegPair::PC).get()) {
+            return 0;
+        }
+
+        let mut pc = self.rp(RegPair::PC).get();
+
+        let after_ei = self.after_ei.replace(false);
+        let after_ld_air = self.after_ld_air.replace(false);
+        let accept_nmi = self.nmi.get();
+        let accept_int = !accept_nmi && self.int.get() && self.iff1.get() && !after_ei;
+
+        if accept_nmi || accept_int {
+            self.halted.set(false);
+        }
+        if accept_int && after_ld_air {
+            self.set_flags(self.get_flags() & !Flags::P);
+        }
+
+        if accept_nmi {
+            self.nmi.set(false);
+            self.iff1.set(false);
+            self.stack_push(pc);
+            self.rp(RegPair::PC).set(0x0066);
+            return NMI_TSTATES;
+        } else if accept_int {
+            self.int.set(false);
+            self.iff1.set(false);
+            self.iff2.set(false);
+            self.inc_refresh();
+            // Nothing drives the data bus during interrupt response, so it reads as FFh
+            // which is RST 38h for IM0 and the last vector table entry for IM2
+            let tstates = match self.im.get() {
+                IntMode::IM0 | IntMode::IM01 => {
+                    self.stack_push(pc);
+                    pc = 0x0038;
+                    IM0_TSTATES + OPCODE_FETCH_TSTATES + timing(&Token::RST(0x38)).base
+                },
+                IntMode::IM1 => {
+                    self.stack_push(pc);
+                    pc = 0x0038;
+                    IM1_TSTATES
+                },
+                IntMode::IM2 => {
+                    self.stack_push(pc);
+                    let vec_addr = mkword!(self.rg(Reg::I).get(), 0xff);
+                    pc = self.read_word(vec_addr);
+                    IM2_TSTATES
+                },
+            };
+            self.rp(RegPair::PC).set(pc);
+            return tstates;
+        } else if self.halted.get() {
+            self.inc_refresh();
+            return OPCODE_FETCH_TSTATES;
+        }
+
+        let mut decoder = instruction_decoder(self.cpu.model());
+        let mut upnext = TokenType::Opcode;
+        let mut tstates = 0;
+
+        let instruction = loop {
+            let byte = self.memory.read(pc);
+            pc = pc.wrapping_add(1);
+            tstates += match upnext {
+                TokenType::Opcode => {
+                    self.inc_refresh();
+                    OPCODE_FETCH_TSTATES
+                },
+                TokenType::Displacement | TokenType::Data => OPERAND_READ_TSTATES
+            };
+            match Pin::new(&mut decoder).resume(byte) {
+                CoroutineState::Yielded(result) => upnext = result.upnext,
+                CoroutineState::Complete(instruction) => break instruction
+            }
+        };
+
+        let timing = timing(&instruction.opcode);
+        let taken = self.process(&instruction, &mut pc);
+        self.rp(RegPair::PC).set(pc);
+
+        tstates + timing.base + if taken { timing.taken } else { 0 }
+
+    }
+
+    /// Process decoded instruction, returns true if condition is met
+    /// (jump is taken or block instruction repeats)
+    fn process(&self, instruction: &Instruction, pc: &mut u16) -> bool {
+
+        match instruction.opcode {
+
+            // 8-bit Load
+
+            Token::LD_RG_RG(dst @ (Reg::I | Reg::R), Reg::A) => {
+                self.rg(dst).set(self.rg(Reg::A).get());
+            },
+            Token::LD_RG_RG(Reg::A, src @ (Reg::I | Reg::R)) => {
+                self.ld_a_ir(src);
+            },
+            Token::LD_RG_RG(dst, src) => {
+                let value = self.read_register(src, instruction.displacement);
+                self.write_register(dst, value, instruction.displacement);
+            },
+            Token::LD_RG_N(reg) => {
+                self.write_register(reg, instruction.expect_byte_data(), instruction.displacement);
+            },
+            Token::LD_A_AtRP(rpair) => {
+                self.rg(Reg::A).set(self.memory.read(self.rp(rpair).get()));
+            },
+            Token::LD_AtRP_A(rpair) => {
+                self.memory.write(self.rp(rpair).get(), self.rg(Reg::A).get());
+            },
+            Token::LD_A_MM => {
+                self.rg(Reg::A).set(self.memory.read(instruction.expect_word_data()));
+            },
+            Token::LD_MM_A => {
+                self.memory.write(instruction.expect_word_data(), self.rg(Reg::A).get());
+            },
+
+            // 16-bit Load
+
+            Token::LD_RP_NN(rpair) => {
+                self.rp(rpair).set(instruction.expect_word_data());
+            },
+            Token::LD_RP_MM(rpair) => {
+                let addr = instruction.expect_word_data();
+                let lo = self.memory.read(addr);
+                let hi = self.memory.read(addr + 1);
+                self.rp(rpair).set(mkword!(hi, lo));
+            },
+            Token::LD_MM_RP(rpair) => {
+                let addr = instruction.expect_word_data();
+                let (hi, lo) = spword!(self.rp(rpair).get());
+                self.memory.write(addr, lo);
+                self.memory.write(addr + 1, hi);
+            },
+            Token::LD_SP_RP(rpair) => {
+                self.rp(RegPair::SP).set(self.rp(rpair).get());
+            },
+            Token::POP(rpair) => {
+                self.rp(rpair).set(self.stack_pop());
+            },
+            Token::PUSH(rpair) => {
+                self.stack_push(self.rp(rpair).get());
+            },
+
+            // Exchange
+
+            Token::EX_DE_HL => self.swap_hlde(),
+            Token::EX_AF => self.swap_acc(),
+            Token::EXX => self.swap_regfile(),
+            Token::EX_AtSP_RP(rpair) => {
+                let addr = self.rp(RegPair::SP).get();
+                let rd_lo = self.memory.read(addr);
+                let rd_hi = self.memory.read(addr + 1);
+                let (wr_hi, wr_lo) = spword!(self.rp(rpair).get());
+                self.rp(rpair).set(mkword!(rd_hi, rd_lo));
+                self.memory.write(addr + 1, wr_hi);
+                self.memory.write(addr, wr_lo);
+            },
+
+            // Block transfer, search group
+
+            Token::BLOP(op @ (BlockOp::LDI | BlockOp::LDD | BlockOp::LDIR | BlockOp::LDDR)) => {
+                let val = self.memory.read(self.rp(RegPair::HL).get());
+                self.memory.write(self.rp(RegPair::DE).get(), val);
+                return self.block_repeat(self.block_ld(op, val), pc);
+            },
+            Token::BLOP(op @ (BlockOp::CPI | BlockOp::CPD | BlockOp::CPIR | BlockOp::CPDR)) => {
+                let rhs = self.memory.read(self.rp(RegPair::HL).get());
+                return self.block_repeat(self.block_cp(op, rhs), pc);
+            },
+
+            // 8-bit arithmetic and logic
+
+            Token::ALU(op, maybe_reg) => {
+                let rhs = if let Some(reg) = maybe_reg {
+                    self.read_register(reg, instruction.displacement)
+                } else {
+                    instruction.expect_byte_data()
+                };
+                self.alu(op, rhs);
+            },
+            Token::INC_RG(reg) | Token::DEC_RG(reg) => {
+                let value = self.read_register(reg, instruction.displacement);
+                let result = self.inc_dec(matches!(instruction.opcode, Token::INC_RG(..)), value);
+                self.write_register(reg, result, instruction.displacement);
+            },
+
+            // General-Purpose Arithmetic and CPU Control
+
+            Token::DAA => self.daa(),
+            Token::CPL => self.cpl(),
+            Token::NEG => self.neg(),
+            Token::CCF => self.ccf(),
+            Token::SCF => self.scf(),
+            Token::NOP => {},
+            Token::HALT => {
+                self.halted.set(true);
+            },
+            Token::DI => {
+                self.iff1.set(false);
+                self.iff2.set(false);
+            },
+            Token::EI => {
+                self.iff1.set(true);
+                self.iff2.set(true);
+                self.after_ei.set(true);
+            },
+            Token::IM(mode) => {
+                self.im.set(mode);
+            },
+
+            // 16-Bit Arithmetic
+
+            Token::ADD_RP_RP(dst, src) => self.add_rp(dst, src),
+            Token::ADC_HL_RP(rpair) => self.adc_hl(rpair),
+            Token::SBC_HL_RP(rpair) => self.sbc_hl(rpair),
+            Token::INC_RP(rpair) => {
+                self.rp(rpair).update(|rp| rp.wrapping_add(1));
+            },
+            Token::DEC_RP(rpair) => {
+                self.rp(rpair).update(|rp| rp.wrapping_sub(1));
+            },
+
+            // Rotate and Shift
+
+            Token::SHOP(op, reg, maybe_dst) => {
+                let val = self.read_register(reg, instruction.displacement);
+                let result = self.shift(op, val);
+                self.write_register(reg, result, instruction.displacement);
+                if let Some(dst) = maybe_dst {
+                    self.rg(dst).set(result);
+                }
+            },
+
+            // Bit Set, Reset and Test
+
+            Token::BIT(bit, reg) => {
+                let val = self.read_register(reg, instruction.displacement);
+                self.bit(bit, val);
+            },
+            Token::SET(bit, reg, maybe_dst) | Token::RES(bit, reg, maybe_dst) => {
+                let val = self.read_register(reg, instruction.displacement);
+                let result = if let Token::SET(..) = instruction.opcode {
+                    val | (0x1 << bit)
+                } else {
+                    val & !(0x1 << bit)
+                };
+                self.write_register(reg, result, instruction.displacement);
+                if let Some(dst) = maybe_dst {
+                    self.rg(dst).set(result);
+                }
+            },
+
+            // Jump, Call and Return
+
+            Token::JP(cond) => {
+                if self.get_flags().satisfy(cond) {
+                    *pc = instruction.expect_word_data();
+                }
+            },
+            Token::JP_RP(rpair) => {
+                *pc = self.rp(rpair).get();
+            },
+            Token::JR(cond) => {
+                if self.get_flags().satisfy(cond) {
+                    *pc = pc.wrapping_add_signed(instruction.displacement.unwrap() as i16);
+                    return true;
+                }
+            },
+            Token::DJNZ => {
+                self.rg(Reg::B).update(|b| b.wrapping_sub(1));
+                if self.rg(Reg::B).get() != 0 {
+                    *pc = pc.wrapping_add_signed(instruction.displacement.unwrap() as i16);
+                    return true;
+                }
+            },
+            Token::CALL(cond) => {
+                if self.get_flags().satisfy(cond) {
+                    self.stack_push(*pc);
+                    *pc = instruction.expect_word_data();
+                    return true;
+                }
+            },
+            Token::RET(Condition::None) => {
+                *pc = self.stack_pop();
+            },
+            Token::RET(cond) => {
+                if self.get_flags().satisfy(cond) {
+                    *pc = self.stack_pop();
+                    return true;
+                }
+            },
+            Token::RETN | Token::RETI => {
+                self.iff1.set(self.iff2.get());
+                *pc = self.stack_pop();
+            },
+            Token::RST(addr) => {
+                self.stack_push(*pc);
+                *pc = addr as u16;
+            },
+
+            // IO group
+
+            Token::IN_A_N => {
+                self.rg(Reg::A).set(self.io_read());
+            },
+            Token::OUT_N_A | Token::OUT_AtBC_RG(..) | Token::OUT_AtBC_0 => {},
+            Token::IN_RG_AtBC(reg) => {
+                self.rg(reg).set(self.io_read());
+            },
+            Token::IN_AtBC => {
+                self.io_read();
+            },
+            Token::BLOP(op @ (BlockOp::INI | BlockOp::IND | BlockOp::INIR | BlockOp::INDR)) => {
+                self.memory.write(self.rp(RegPair::HL).get(), self.io_read());
+                return self.block_repeat(self.block_io(op), pc);
+            },
+            Token::BLOP(op @ (BlockOp::OUTI | BlockOp::OUTD | BlockOp::OTIR | BlockOp::OTDR)) => {
+                self.memory.read(self.rp(RegPair::HL).get());
+                return self.block_repeat(self.block_io(op), pc);
+            },
+
+            // Z80N extended instructions
+
+            Token::SWAPNIB => self.swapnib(),
+            Token::MIRROR_A => self.mirror_a(),
+            Token::TEST_N => self.test(self.rg(Reg::A).get(), instruction.expect_byte_data()),
+            Token::BSOP(op) => self.barrel_shift(op),
+            Token::MUL_DE => self.mul_de(),
+            Token::ADD_RP_A(rpair) => {
+                let acc = self.rg(Reg::A).get();
+                self.rp(rpair).update(|rp| rp.wrapping_add(acc as u16));
+            },
+            Token::ADD_RP_NN(rpair) => {
+                self.rp(rpair).update(|rp| rp.wrapping_add(instruction.expect_word_data()));
+            },
+            Token::PUSH_NN => {
+                self.stack_push(instruction.expect_word_data());
+            },
+            Token::OUTINB => {
+                self.memory.read(self.rp(RegPair::HL).get());
+                self.rp(RegPair::HL).update(|hl| hl.wrapping_add(1));
+            },
+            Token::NEXTREG_N_N | Token::NEXTREG_N_A => {},
+            Token::PIXELDN => self.pixel_dn(),
+            Token::PIXELAD => self.pixel_ad(),
+            Token::SETAE => self.setae(),
+            Token::JP_AtC => {
+                *pc = self.jp_c_target(*pc, self.io_read());
+            },
+            Token::BLOP(op @ (BlockOp::LDIX | BlockOp::LDDX | BlockOp::LDIRX | BlockOp::LDDRX | BlockOp::LDPIRX)) => {
+                let val = self.memory.read(self.block_ldx_source(op));
+                if val != self.rg(Reg::A).get() {
+                    self.memory.write(self.rp(RegPair::DE).get(), val);
+                }
+                return self.block_repeat(self.block_ldx(op), pc);
+            },
+            Token::LDWS => {
+                let val = self.memory.read(self.rp(RegPair::HL).get());
+                self.memory.write(self.rp(RegPair::DE).get(), val);
+                self.ldws();
+            },
+
+            // Z180 extended instructions
+
+            Token::IN0_RG_N(reg) => {
+                let val = self.io_read();
+                self.rg(reg).set(val);
+                self.in0_flags(val);
+            },
+            Token::IN0_N => {
+                let val = self.io_read();
+                self.in0_flags(val);
+            },
+            Token::OUT0_N_RG(..) => {},
+            Token::TST(maybe_reg) => {
+                let rhs = if let Some(reg) = maybe_reg {
+                    self.read_register(reg, None)
+                } else {
+                    instruction.expect_byte_data()
+                };
+                self.test(self.rg(Reg::A).get(), rhs);
+            },
+            Token::TSTIO_N => {
+                self.test(self.io_read(), instruction.expect_byte_data());
+            },
+            Token::MLT(rpair) => self.mlt(rpair),
+            Token::SLP => {
+                self.halted.set(true);
+            },
+            Token::BLOP(op @ (BlockOp::OTIM | BlockOp::OTDM | BlockOp::OTIMR | BlockOp::OTDMR)) => {
+                let val = self.memory.read(self.rp(RegPair::HL).get());
+                return self.block_repeat(self.block_otm(op, val), pc);
+            },
+            Token::TRAP => {
+                self.stack_push(self.rp(RegPair::PC).get());
+                *pc = 0x0000;
+            },
+
+            // Non-opcode is not expected
+
+            Token::Prefix(..) | Token::Displacement(..) | Token::Data(..) => unreachable!()
+
+        }
+
+        false
+
+    }
+
+    /// Rewind PC 2 bytes back if block instruction repeats
+    fn block_repeat(&self, repeat: bool, pc: &mut u16) -> bool {
+        if repeat {
+            *pc = pc.wrapping_sub(2);
+        }
+        repeat
+    }
+
+    /// Read from IO port. There are no devices, so floating bus value is returned.
+    fn io_read(&self) -> u8 {
+        0xff
+    }
+
+    fn read_register(&self, reg: Reg, displacement: Option<i8>) -> u8 {
+        match reg {
+            Reg::AtHL => self.memory.read(self.rp(RegPair::HL).get()),
+            Reg::AtIX | Reg::AtIY => self.memory.read(self.idx_addr(reg, displacement.unwrap())),
+            reg => self.rg(reg).get(),
+        }
+    }
+
+    fn write_register(&self, reg: Reg, value: u8, displacement: Option<i8>) {
+        match reg {
+            Reg::AtHL => self.memory.write(self.rp(RegPair::HL).get(), value),
+            Reg::AtIX | Reg::AtIY => self.memory.write(self.idx_addr(reg, displacement.unwrap()), value),
+            reg => self.rg(reg).set(value),
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        let lo = self.memory.read(addr);
+        let hi = self.memory.read(addr.wrapping_add(1));
+        mkword!(hi, lo)
+    }
+
+    fn stack_pop(&self) -> u16 {
+        let addr = self.rp(RegPair::SP).get();
+        self.rp(RegPair::SP).set(addr.wrapping_add(2));
+        self.read_word(addr)
+    }
+
+    fn stack_push(&self, value: u16) {
+        let addr = self.rp(RegPair::SP).get();
+        let (hi, lo) = spword!(value);
+        self.memory.write(addr.wrapping_sub(1), hi);
+        self.memory.write(addr.wrapping_sub(2), lo);
+        self.rp(RegPair::SP).set(addr.wrapping_sub(2));
+    }
+
+}
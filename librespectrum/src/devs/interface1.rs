@@ -0,0 +1,307 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, IoDevice, Microdrive, SerialPort, Snapshot, mem::MemoryOverlay, run_io_device},
+    formats::{FormatError, Reader},
+};
+
+/// Shadow ROM size
+const ROM_SIZE: usize = 0x2000;
+
+/// Opcode fetch from these addresses pages shadow ROM in before the opcode is read
+const PAGE_IN: [u16; 2] = [0x0008, 0x1708];
+
+/// Opcode fetch from this address pages shadow ROM out after the opcode is read
+const PAGE_OUT: u16 = 0x0700;
+
+/// Microdrive data port, control/status port and RS-232/network port
+const DATA_PORT: u8 = 0xe7;
+const CONTROL_PORT: u8 = 0xef;
+const SERIAL_PORT: u8 = 0xf7;
+
+/// Control port bits written
+const CONTROL_COMMS_DATA: u8 = 0x01;
+const CONTROL_COMMS_CLK: u8 = 0x02;
+const CONTROL_READ: u8 = 0x04;
+const CONTROL_ERASE_OFF: u8 = 0x08;
+const CONTROL_CTS_OFF: u8 = 0x10;
+
+/// Status port bits read
+const STATUS_WRITE_ENABLED: u8 = 0x01;
+const STATUS_SYNC_OFF: u8 = 0x02;
+const STATUS_GAP_OFF: u8 = 0x04;
+const STATUS_DTR: u8 = 0x08;
+
+/// Serial port bit read with received data
+const SERIAL_RXD: u8 = 0x80;
+
+/// Number of microdrives
+const DRIVES: usize = 8;
+
+/// CPU clock frequency, which serial bit timing is derived from
+const CLOCK_HZ: u64 = 3_500_000;
+
+/// Default RS-232 speed
+const BAUD: u32 = 9600;
+
+/// Byte being sent over RS-232: start time, number of data bits sampled and their values
+type Transmission = (u64, u8, u8);
+
+/// Byte being received over RS-232: start time and data
+type Reception = (u64, u8);
+
+/// Interface 1 with shadow ROM, eight microdrives and RS-232 port.
+///
+/// Shadow ROM is paged in by opcode fetch from 0008h or 1708h and paged out by
+/// opcode fetch from 0700h. Microdrive motors are switched by a shift register
+/// clocked by COMMS CLK bit of port EFh, with inverted COMMS DATA bit shifted
+/// into drive 1. RS-232 data is sent by bit 0 of port F7h and received by bit 7
+/// of it, both inverted: 1 is space (start bit), 0 is mark (idle line and stop
+/// bits). Bytes have 8 data bits and are received only while CTS is active.
+/// Network isn't emulated.
+pub struct Interface1 {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    rom: Vec<u8>,
+    paged: Cell<bool>,
+    /// Opcode fetch is in progress and shadow ROM is paged out when it ends
+    fetching: Cell<bool>,
+    page_out: Cell<bool>,
+    drives: [Microdrive; DRIVES],
+    control: Cell<u8>,
+    serial: RefCell<Option<Rc<dyn SerialPort>>>,
+    baud: Cell<u32>,
+    /// Last value written to TXDATA bit
+    txdata: Cell<bool>,
+    transmission: Cell<Option<Transmission>>,
+    reception: Cell<Option<Reception>>,
+}
+
+impl Interface1 {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, rom: &[u8]) -> Self {
+        let mut rom = rom.to_vec();
+        rom.resize(ROM_SIZE, 0xff);
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            rom,
+            paged: Cell::new(false),
+            fetching: Cell::new(false),
+            page_out: Cell::new(false),
+            drives: Default::default(),
+            control: Cell::new(CONTROL_READ | CONTROL_ERASE_OFF | CONTROL_CTS_OFF),
+            serial: RefCell::new(None),
+            baud: Cell::new(BAUD),
+            txdata: Cell::new(false),
+            transmission: Cell::new(None),
+            reception: Cell::new(None),
+        }
+    }
+
+    /// Shadow ROM is paged in
+    pub fn is_paged(&self) -> bool {
+        self.paged.get()
+    }
+
+    /// Microdrive 1-8 (0-7)
+    pub fn drive(&self, index: usize) -> &Microdrive {
+        &self.drives[index]
+    }
+
+    /// Connect RS-232 port to the other end of the line
+    pub fn connect(&self, serial: &Rc<dyn SerialPort>) {
+        self.serial.replace(Some(Rc::clone(serial)));
+    }
+
+    pub fn disconnect(&self) {
+        self.serial.replace(None);
+    }
+
+    /// Set RS-232 speed, which has to match the speed set by BASIC FORMAT "b"
+    pub fn set_baud(&self, baud: u32) {
+        self.baud.set(baud.max(1));
+    }
+
+    /// Duration of a serial bit in t-states
+    fn bit_time(&self) -> u64 {
+        CLOCK_HZ / self.baud.get() as u64
+    }
+
+    fn running_drive(&self) -> Option<&Microdrive> {
+        self.drives.iter().find(|drive| drive.is_running())
+    }
+
+    fn cts(&self) -> bool {
+        self.control.get() & CONTROL_CTS_OFF == 0
+    }
+
+    /// Microdrive is in write mode with erase head on
+    fn writing(&self) -> bool {
+        self.control.get() & (CONTROL_READ | CONTROL_ERASE_OFF) == 0
+    }
+
+    fn read_status(&self) -> u8 {
+        let mut status = 0xe0 | STATUS_SYNC_OFF | STATUS_GAP_OFF;
+        if let Some(drive) = self.running_drive() {
+            let drive_status = drive.status();
+            if drive_status.sync { status &= !STATUS_SYNC_OFF; }
+            if drive_status.gap { status &= !STATUS_GAP_OFF; }
+            if !drive_status.write_protected { status |= STATUS_WRITE_ENABLED; }
+        }
+        if self.serial.borrow().is_some() {
+            status |= STATUS_DTR;
+        }
+        status
+    }
+
+    fn write_control(&self, byte: u8) {
+        let previous = self.control.replace(byte);
+        // Falling edge of COMMS CLK shifts motor states from drive to drive
+        if previous & CONTROL_COMMS_CLK != 0 && byte & CONTROL_COMMS_CLK == 0 {
+            for index in (1..DRIVES).rev() {
+                self.drives[index].set_motor(self.drives[index - 1].motor());
+            }
+            self.drives[0].set_motor(byte & CONTROL_COMMS_DATA == 0);
+        }
+        if self.writing() && previous & (CONTROL_READ | CONTROL_ERASE_OFF) != 0 {
+            self.drives.iter().for_each(Microdrive::start_write);
+        }
+    }
+
+    /// Received line is at space level
+    fn rxd_space(&self) -> bool {
+        let Some((start, byte)) = self.reception.get() else { return false };
+        match (self.clock.get() >> 1).saturating_sub(start) / self.bit_time() {
+            0 => true,
+            bit @ 1..=8 => byte >> (bit - 1) & 0x01 == 0,
+            _ => false,
+        }
+    }
+
+}
+
+impl MemoryOverlay for Interface1 {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.paged.get() && (addr as usize) < ROM_SIZE
+    }
+
+    fn writable(&self, _addr: u16) -> bool {
+        false
+    }
+
+    fn write(&self, _addr: u16, _byte: u8) {}
+
+    fn read(&self, addr: u16) -> u8 {
+        self.rom[addr as usize]
+    }
+
+}
+
+/// Paging, control port and microdrive motors. Cartridges and serial line aren't captured.
+impl Snapshot for Interface1 {
+
+    fn save_snapshot(&self) -> Vec<u8> {
+        let motors = self.drives.iter().enumerate().fold(0u8, |motors, (index, drive)| motors | (drive.motor() as u8) << index);
+        vec![self.paged.get() as u8, self.control.get(), motors, self.txdata.get() as u8]
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        let mut rd = Reader::new(data);
+        let (paged, control, motors, txdata) = (rd.byte()? != 0, rd.byte()?, rd.byte()?, rd.byte()? != 0);
+        self.paged.set(paged);
+        self.control.set(control);
+        self.drives.iter().enumerate().for_each(|(index, drive)| drive.set_motor(motors & 1 << index != 0));
+        self.txdata.set(txdata);
+        self.fetching.set(false);
+        self.transmission.set(None);
+        self.reception.set(None);
+        Ok(())
+    }
+
+}
+
+impl Identifiable for Interface1 {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for Interface1 {
+
+    fn clock_tick(&self) {
+
+        let ctrl = self.bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+        if ctrl.contains(Ctrl::MREQ | Ctrl::RD) && self.bus.m1.probe().unwrap_or(false) {
+            if !self.fetching.replace(true) {
+                let addr = self.bus.addr.expect();
+                if PAGE_IN.contains(&addr) {
+                    self.paged.set(true);
+                }
+                self.page_out.set(addr == PAGE_OUT);
+            }
+        } else if self.fetching.replace(false) && self.page_out.get() {
+            self.paged.set(false);
+        }
+
+        let now = self.clock.get() >> 1;
+        let bit_time = self.bit_time();
+
+        // Sent bits are sampled in the middle, the byte is complete in the middle of the stop bit
+        match self.transmission.get() {
+            None => if self.txdata.get() {
+                self.transmission.set(Some((now, 0, 0)));
+            },
+            Some((start, count, byte)) => if now >= start + (count as u64 + 1) * bit_time + bit_time / 2 {
+                if count < 8 {
+                    self.transmission.set(Some((start, count + 1, byte | (!self.txdata.get() as u8) << count)));
+                } else {
+                    if let Some(serial) = &*self.serial.borrow() {
+                        serial.send(byte);
+                    }
+                    self.transmission.set(None);
+                }
+            },
+        }
+
+        // Next byte is taken from the line only while CTS is active
+        if let Some((start, _)) = self.reception.get() && now - start >= 10 * bit_time {
+            self.reception.set(None);
+        }
+        if self.reception.get().is_none() && self.cts() && let Some(serial) = &*self.serial.borrow() && let Some(byte) = serial.receive() {
+            self.reception.set(Some((now, byte)));
+        }
+
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        match addr as u8 {
+            DATA_PORT => Some(self.running_drive().map_or(0xff, Microdrive::read)),
+            CONTROL_PORT => Some(self.read_status()),
+            SERIAL_PORT => Some(if self.rxd_space() { 0x7f | SERIAL_RXD } else { 0x7f }),
+            _ => None,
+        }
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        match addr as u8 {
+            DATA_PORT => if self.writing() && let Some(drive) = self.running_drive() {
+                drive.write(byte);
+            },
+            CONTROL_PORT => self.write_control(byte),
+            SERIAL_PORT => self.txdata.set(byte & 0x01 != 0),
+            _ => (),
+        }
+    }
+
+}
+
+impl Device for Interface1 {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
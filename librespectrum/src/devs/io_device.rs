@@ -0,0 +1,70 @@
+use crate::{
+    core::{Clock, CpuBus, Ctrl, Identifiable, NoReturnTask},
+    yield_wait,
+};
+
+/// Device decoding IO ports, which is run by `run_io_device`
+pub trait IoDevice: Identifiable {
+
+    /// Work done on every rising clock edge before IO cycle is handled,
+    /// like advancing timers or paging memory on opcode fetch
+    fn clock_tick(&self) {}
+
+    /// Byte driven on the data bus when given port is read, None for ports
+    /// the device doesn't decode. Called once per IO read cycle.
+    fn read_port(&self, addr: u16) -> Option<u8>;
+
+    /// Handle write to given port, called once per IO write cycle
+    fn write_port(&self, addr: u16, byte: u8);
+
+}
+
+/// Task running IO device on every rising clock edge. Each IO read or write
+/// cycle is passed to the device once, data bus is driven until the read ends.
+/// Interrupt acknowledge cycles (IORQ with M1) aren't IO cycles.
+pub fn run_io_device<'a, D: IoDevice>(device: &'a D, bus: &'a CpuBus, clock: &'a Clock) -> Box<dyn NoReturnTask + 'a> {
+
+    Box::new(#[coroutine] move || {
+
+        // IO read or write is already processed during current cycle
+        let mut accessed = false;
+        // Data bus is driven by the device
+        let mut reading = false;
+
+        loop {
+
+            device.clock_tick();
+
+            let ctrl = bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+            let m1 = bus.m1.probe().unwrap_or(false);
+
+            if ctrl.contains(Ctrl::IORQ) && ctrl.intersects(Ctrl::RD | Ctrl::WR) && !m1 {
+                if !accessed {
+                    let addr = bus.addr.expect();
+                    if ctrl.contains(Ctrl::RD) {
+                        if let Some(byte) = device.read_port(addr) {
+                            bus.data.drive(device, byte);
+                            reading = true;
+                        }
+                    } else {
+                        device.write_port(addr, bus.data.expect());
+                    }
+                    accessed = true;
+                }
+            } else {
+                // Data bus may be driven by other devices, like daisy chain
+                // during interrupt acknowledge, so release only own drive
+                if reading {
+                    bus.data.release(device);
+                    reading = false;
+                }
+                accessed = false;
+            }
+
+            yield_wait!(clock.rising(1));
+
+        }
+
+    })
+
+}
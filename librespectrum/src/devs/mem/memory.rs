@@ -12,3 +12,21 @@ pub trait Memory: Device {
     fn read(&self, addr: u16) -> u8;
 
 }
+
+/// Memory paged over part of the address space by a peripheral, like interface ROM.
+/// Overlays take precedence over the memory they're added to.
+pub trait MemoryOverlay {
+
+    /// Check if the overlay is paged in at given address
+    fn covers(&self, addr: u16) -> bool;
+
+    /// Check if given covered address is writable
+    fn writable(&self, addr: u16) -> bool;
+
+    /// Write byte to covered address
+    fn write(&self, addr: u16, byte: u8);
+
+    /// Read byte from covered address
+    fn read(&self, addr: u16) -> u8;
+
+}
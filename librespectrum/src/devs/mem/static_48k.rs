@@ -1,4 +1,4 @@
-use std::{cell::Cell, rc::Rc};
+use std::{cell::{Cell, RefCell}, rc::Rc};
 
 use crate::{
     core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask},
@@ -6,7 +6,7 @@ use crate::{
     yield_break_if, yield_wait
 };
 
-use super::Memory;
+use super::{Memory, MemoryOverlay};
 
 /// Static 48k memory
 #[derive(Default)]
@@ -15,6 +15,7 @@ pub struct Static48k {
     bus: Rc<CpuBus>,
     clock: Rc<Clock>,
     memory: Vec<Cell<u8>>,
+    overlays: RefCell<Vec<Rc<dyn MemoryOverlay>>>,
     breakpoint_manager: Rc<BreakpointManager>,
 }
 
@@ -27,6 +28,7 @@ impl Static48k {
             bus: Rc::clone(bus),
             clock: Rc::clone(clock),
             memory: vec![Default::default(); usize::pow(2, 16)],
+            overlays: RefCell::new(vec![]),
             breakpoint_manager: Rc::clone(breakpoint_manager),
         }
     }
@@ -38,22 +40,53 @@ impl Static48k {
         }
     }
 
+    /// Copy whole memory contents including ROM, without overlays
+    pub fn dump(&self) -> Vec<u8> {
+        self.memory.iter().map(Cell::get).collect()
+    }
+
+    /// Register overlay paged over the memory
+    pub fn add_overlay(&self, overlay: &Rc<dyn MemoryOverlay>) {
+        self.overlays.borrow_mut().push(Rc::clone(overlay));
+    }
+
+    /// Unregister previously added overlay
+    pub fn remove_overlay(&self, overlay: &Rc<dyn MemoryOverlay>) {
+        self.overlays.borrow_mut().retain(|other| !Rc::ptr_eq(other, overlay));
+    }
+
+    /// First overlay paged in at given address
+    fn overlay(&self, addr: u16) -> Option<Rc<dyn MemoryOverlay>> {
+        self.overlays.borrow().iter().find(|overlay| overlay.covers(addr)).cloned()
+    }
+
 }
 
 impl Memory for Static48k {
 
     fn writable(&self, addr: u16) -> bool {
-        addr & 0xc000 != 0 // First 16KB are not writable (ROM)
+        match self.overlay(addr) {
+            Some(overlay) => overlay.writable(addr),
+            None => addr & 0xc000 != 0, // First 16KB are not writable (ROM)
+        }
     }
 
     fn write(&self, addr: u16, byte: u8) {
-        if self.writable(addr) {
-            self.memory[addr as usize].set(byte);
+        match self.overlay(addr) {
+            Some(overlay) => if overlay.writable(addr) {
+                overlay.write(addr, byte);
+            },
+            None => if addr & 0xc000 != 0 {
+                self.memory[addr as usize].set(byte);
+            },
         }
     }
 
     fn read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize].get()
+        match self.overlay(addr) {
+            Some(overlay) => overlay.read(addr),
+            None => self.memory[addr as usize].get(),
+        }
     }
 
 }
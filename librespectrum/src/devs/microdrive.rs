@@ -0,0 +1,160 @@
+use std::cell::{Cell, RefCell};
+
+use crate::formats::mdr::{DATA_SIZE, HEADER_SIZE, Mdr, SECTOR_SIZE};
+
+/// Status reads during which gap before a block and its preamble pass the head
+const GAP_READS: u8 = 16;
+const SYNC_READS: u8 = 16;
+
+/// Preamble written before each block: 10 zero bytes and 2 FFh bytes
+const PREAMBLE: usize = 12;
+
+/// Microdrive status seen by Interface 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MicrodriveStatus {
+    /// Gap between blocks is passing the head
+    pub gap: bool,
+    /// Preamble of the next block was detected, its bytes follow
+    pub sync: bool,
+    pub write_protected: bool,
+}
+
+/// Microdrive with endless tape loop cartridge. Tape moves as the blocks are
+/// transferred: each block is preceded by a gap and a preamble, which pass the
+/// head while the status is polled, and the block is skipped if the status is
+/// polled past its preamble.
+#[derive(Default)]
+pub struct Microdrive {
+    cartridge: RefCell<Option<Mdr>>,
+    motor: Cell<bool>,
+    /// Position of the head in cartridge data
+    position: Cell<usize>,
+    /// Bytes transferred in the current block, not counting the preamble
+    transferred: Cell<usize>,
+    /// Preamble bytes written before the current block
+    preamble: Cell<usize>,
+    gap: Cell<u8>,
+    sync: Cell<u8>,
+}
+
+impl Microdrive {
+
+    /// Insert cartridge, which is positioned at the start of the first sector
+    pub fn insert(&self, cartridge: Mdr) {
+        self.cartridge.replace(Some(cartridge));
+        self.position.set(0);
+        self.start_block();
+    }
+
+    /// Remove cartridge
+    pub fn eject(&self) -> Option<Mdr> {
+        self.cartridge.take()
+    }
+
+    /// Copy of inserted cartridge
+    pub fn cartridge(&self) -> Option<Mdr> {
+        self.cartridge.borrow().clone()
+    }
+
+    pub fn set_motor(&self, on: bool) {
+        self.motor.set(on);
+    }
+
+    pub fn motor(&self) -> bool {
+        self.motor.get()
+    }
+
+    /// Motor is on with a cartridge inserted
+    pub fn is_running(&self) -> bool {
+        self.motor.get() && self.cartridge.borrow().as_ref().is_some_and(|cartridge| !cartridge.data.is_empty())
+    }
+
+    /// Poll the status. Gap signal is followed by sync signal, after which the
+    /// block passes the head unless it's read.
+    pub fn status(&self) -> MicrodriveStatus {
+        let write_protected = self.cartridge.borrow().as_ref().is_some_and(|cartridge| cartridge.write_protected);
+        if !self.is_running() {
+            return MicrodriveStatus { gap: false, sync: false, write_protected };
+        }
+        // Tape has moved on since the transfer stopped in the middle of a block
+        if self.transferred.get() > 0 {
+            self.skip_block();
+        }
+        let (gap, sync) = if self.gap.get() > 0 {
+            self.gap.update(|reads| reads - 1);
+            (true, false)
+        } else if self.sync.get() > 0 {
+            self.sync.update(|reads| reads - 1);
+            (false, true)
+        } else {
+            self.skip_block();
+            (false, false)
+        };
+        MicrodriveStatus { gap, sync, write_protected }
+    }
+
+    /// Read byte of the block under the head
+    pub fn read(&self) -> u8 {
+        if !self.is_running() {
+            return 0xff;
+        }
+        let byte = self.cartridge.borrow().as_ref().map_or(0xff, |cartridge| cartridge.data[self.position.get()]);
+        self.advance();
+        byte
+    }
+
+    /// Write byte of the block under the head. Block is preceded by the preamble.
+    pub fn write(&self, byte: u8) {
+        if !self.is_running() {
+            return;
+        }
+        if self.preamble.get() < PREAMBLE {
+            self.preamble.update(|count| count + 1);
+            return;
+        }
+        if let Some(cartridge) = self.cartridge.borrow_mut().as_mut() && !cartridge.write_protected {
+            cartridge.data[self.position.get()] = byte;
+        }
+        self.advance();
+    }
+
+    /// Write mode was switched on, so the preamble is expected
+    pub fn start_write(&self) {
+        self.preamble.set(0);
+    }
+
+    fn len(&self) -> usize {
+        self.cartridge.borrow().as_ref().map_or(0, |cartridge| cartridge.data.len())
+    }
+
+    /// Length of the block the head is in
+    fn block_size(&self) -> usize {
+        let start = self.position.get() - self.transferred.get();
+        if start.is_multiple_of(SECTOR_SIZE) { HEADER_SIZE } else { DATA_SIZE }
+    }
+
+    fn advance(&self) {
+        self.position.update(|position| position + 1);
+        self.transferred.update(|count| count + 1);
+        if self.transferred.get() >= self.block_size() {
+            self.position.update(|position| position % self.len());
+            self.start_block();
+        }
+    }
+
+    /// Move the head to the start of the next block
+    fn skip_block(&self) {
+        let start = self.position.get() - self.transferred.get();
+        let next = start + self.block_size();
+        self.position.set(next % self.len());
+        self.start_block();
+    }
+
+    fn start_block(&self) {
+        self.transferred.set(0);
+        self.preamble.set(0);
+        self.gap.set(GAP_READS);
+        self.sync.set(SYNC_READS);
+    }
+
+}
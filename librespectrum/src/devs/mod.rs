@@ -1,5 +1,14 @@
 pub mod mem;
 
+mod ata;
+pub use ata::*;
+
+mod beta128;
+pub use beta128::*;
+
+mod block_image;
+pub use block_image::*;
+
 mod breakpoints;
 pub use breakpoints::*;
 
@@ -9,5 +18,62 @@ pub use bus_logger::*;
 mod cpu;
 pub use cpu::*;
 
+mod ctc;
+pub use ctc::*;
+
+mod daisy_chain;
+pub use daisy_chain::*;
+
+mod disk;
+pub use disk::*;
+
+mod div_memory;
+pub use div_memory::*;
+
+mod divide;
+pub use divide::*;
+
+mod divmmc;
+pub use divmmc::*;
+
+mod fast_cpu;
+pub use fast_cpu::*;
+
+mod interface1;
+pub use interface1::*;
+
+mod io_device;
+pub use io_device::*;
+
+mod microdrive;
+pub use microdrive::*;
+
+mod multiface;
+pub use multiface::*;
+
+mod plus3_disk;
+pub use plus3_disk::*;
+
+mod sd_card;
+pub use sd_card::*;
+
+mod serial;
+pub use serial::*;
+
+mod tape;
+pub use tape::*;
+
+mod tape_recorder;
+pub use tape_recorder::*;
+
+mod upd765;
+pub use upd765::*;
+
+mod wd1793;
+pub use wd1793::*;
+
+mod zx_printer;
+pub use zx_printer::*;
+
 mod device;
 pub use device::*;
@@ -0,0 +1,201 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, IoDevice, Snapshot, mem::MemoryOverlay, run_io_device},
+    formats::{FormatError, Reader},
+};
+
+/// Size of ROM and of RAM
+const PAGE_SIZE: usize = 0x2000;
+
+/// NMI handler address, opcode fetch from it pages memory in after the button was pressed
+const NMI_ADDR: u16 = 0x0066;
+
+/// Multiface model, which defines its paging ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultifaceModel {
+    /// Multiface One: IN 9Fh pages in, IN 1Fh pages out
+    One,
+    /// Multiface 128: IN BFh pages in and returns screen bit of port 7FFDh, IN 3Fh pages out
+    M128,
+    /// Multiface 3: IN 3Fh pages in, IN BFh pages out. While paged in, ports
+    /// 7F3Fh and 1F3Fh return the last values written to 7FFDh and 1FFDh.
+    Three,
+}
+
+/// Multiface NMI cartridge with 8K ROM and 8K RAM paged in at 0000h-3FFFh.
+/// Red button asserts NMI, and opcode fetch of the NMI handler pages memory in.
+pub struct Multiface {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    model: MultifaceModel,
+    rom: Vec<u8>,
+    ram: RefCell<Vec<u8>>,
+    paged: Cell<bool>,
+    /// Red button was pressed and NMI is asserted until the handler is fetched
+    pressed: Cell<bool>,
+    /// NMI line is driven
+    asserting: Cell<bool>,
+    /// Last values written to 128K and +3 memory paging ports
+    port_7ffd: Cell<u8>,
+    port_1ffd: Cell<u8>,
+}
+
+impl Multiface {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, model: MultifaceModel, rom: &[u8]) -> Self {
+        let mut rom = rom.to_vec();
+        rom.resize(PAGE_SIZE, 0xff);
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            model,
+            rom,
+            ram: RefCell::new(vec![0; PAGE_SIZE]),
+            paged: Cell::new(false),
+            pressed: Cell::new(false),
+            asserting: Cell::new(false),
+            port_7ffd: Cell::new(0),
+            port_1ffd: Cell::new(0),
+        }
+    }
+
+    pub fn model(&self) -> MultifaceModel {
+        self.model
+    }
+
+    /// Press the red button
+    pub fn press(&self) {
+        self.pressed.set(true);
+    }
+
+    /// ROM and RAM are paged in
+    pub fn is_paged(&self) -> bool {
+        self.paged.get()
+    }
+
+    /// Copy of RAM contents
+    pub fn ram(&self) -> Vec<u8> {
+        self.ram.borrow().clone()
+    }
+
+}
+
+impl MemoryOverlay for Multiface {
+
+    fn covers(&self, addr: u16) -> bool {
+        self.paged.get() && (addr as usize) < 2 * PAGE_SIZE
+    }
+
+    fn writable(&self, addr: u16) -> bool {
+        addr as usize >= PAGE_SIZE
+    }
+
+    fn write(&self, addr: u16, byte: u8) {
+        self.ram.borrow_mut()[addr as usize & (PAGE_SIZE - 1)] = byte;
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let offset = addr as usize & (PAGE_SIZE - 1);
+        if (addr as usize) < PAGE_SIZE { self.rom[offset] } else { self.ram.borrow()[offset] }
+    }
+
+}
+
+impl Snapshot for Multiface {
+
+    /// Paging, button and latched ports followed by RAM contents
+    fn save_snapshot(&self) -> Vec<u8> {
+        let mut data = vec![self.paged.get() as u8, self.pressed.get() as u8, self.port_7ffd.get(), self.port_1ffd.get()];
+        data.extend(self.ram.borrow().iter());
+        data
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        let mut rd = Reader::new(data);
+        let (paged, pressed, port_7ffd, port_1ffd) = (rd.byte()? != 0, rd.byte()? != 0, rd.byte()?, rd.byte()?);
+        let ram = rd.rest();
+        if ram.len() != PAGE_SIZE {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        self.paged.set(paged);
+        self.pressed.set(pressed);
+        self.port_7ffd.set(port_7ffd);
+        self.port_1ffd.set(port_1ffd);
+        self.ram.borrow_mut().copy_from_slice(ram);
+        Ok(())
+    }
+
+}
+
+impl Identifiable for Multiface {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for Multiface {
+
+    /// NMI is held until the handler is fetched, which pages memory in
+    fn clock_tick(&self) {
+        if self.pressed.get() {
+            let ctrl = self.bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+            let m1 = self.bus.m1.probe().unwrap_or(false);
+            if ctrl.contains(Ctrl::MREQ | Ctrl::RD) && m1 && self.bus.addr.expect() == NMI_ADDR {
+                self.pressed.set(false);
+                self.paged.set(true);
+            } else if !self.asserting.replace(true) {
+                self.bus.nmi.drive(self, true);
+            }
+        }
+        if !self.pressed.get() && self.asserting.replace(false) {
+            self.bus.nmi.release(self);
+        }
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        match (self.model, addr as u8) {
+            (MultifaceModel::One, 0x9f) => self.paged.set(true),
+            (MultifaceModel::One, 0x1f) | (MultifaceModel::M128, 0x3f) | (MultifaceModel::Three, 0xbf) => self.paged.set(false),
+            (MultifaceModel::M128, 0xbf) => {
+                self.paged.set(true);
+                return Some(0x7f | (self.port_7ffd.get() & 0x08) << 4);
+            },
+            (MultifaceModel::Three, 0x3f) => {
+                // Paging registers are readable if memory was already paged in
+                let paged = self.paged.replace(true);
+                return match addr >> 8 {
+                    0x7f if paged => Some(self.port_7ffd.get()),
+                    0x1f if paged => Some(self.port_1ffd.get()),
+                    _ => None,
+                };
+            },
+            _ => (),
+        }
+        None
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        match self.model {
+            MultifaceModel::One => (),
+            MultifaceModel::M128 => if addr & 0x8002 == 0 {
+                self.port_7ffd.set(byte);
+            },
+            MultifaceModel::Three => match addr & 0xf002 {
+                0x1000 => self.port_1ffd.set(byte),
+                0x4000..=0x7000 => self.port_7ffd.set(byte),
+                _ => (),
+            },
+        }
+    }
+
+}
+
+impl Device for Multiface {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
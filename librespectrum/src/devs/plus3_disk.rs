@@ -0,0 +1,87 @@
+use std::rc::Rc;
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, FloppyDrive, IoDevice, Upd765, run_io_device},
+};
+
+/// Port address lines decoded by +3: A15-A12 and A1
+const PORT_MASK: u16 = 0xf002;
+
+/// Memory control port 1FFDh, of which only the motor bit belongs to disk drives
+const CONTROL_PORT: u16 = 0x1000;
+
+/// Controller main status register port 2FFDh and data register port 3FFDh
+const STATUS_PORT: u16 = 0x2000;
+const DATA_PORT: u16 = 0x3000;
+
+/// Disk motor bit of port 1FFDh
+const MOTOR: u8 = 0x08;
+
+/// +3 disk drives A and B with uPD765A controller. Paging and printer strobe
+/// bits of port 1FFDh are left to the memory and the printer.
+pub struct Plus3Disk {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    fdc: Upd765,
+}
+
+impl Plus3Disk {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            fdc: Upd765::new(clock, 2),
+        }
+    }
+
+    /// Drive A or B (0-1)
+    pub fn drive(&self, index: usize) -> &FloppyDrive {
+        self.fdc.drive(index)
+    }
+
+    /// Disk controller
+    pub fn fdc(&self) -> &Upd765 {
+        &self.fdc
+    }
+
+}
+
+impl Identifiable for Plus3Disk {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for Plus3Disk {
+
+    fn clock_tick(&self) {
+        self.fdc.update();
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        match addr & PORT_MASK {
+            STATUS_PORT => Some(self.fdc.status()),
+            DATA_PORT => Some(self.fdc.read_data()),
+            _ => None,
+        }
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        match addr & PORT_MASK {
+            CONTROL_PORT => self.fdc.set_motor(byte & MOTOR != 0),
+            DATA_PORT => self.fdc.write_data(byte),
+            _ => (),
+        }
+    }
+
+}
+
+impl Device for Plus3Disk {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
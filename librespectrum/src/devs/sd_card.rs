@@ -0,0 +1,248 @@
+use std::{cell::{Cell, RefCell}, collections::VecDeque, rc::Rc};
+
+use super::{BLOCK_SIZE, BlockImage};
+
+/// R1 response bits
+const IDLE: u8 = 0x01;
+const ILLEGAL_COMMAND: u8 = 0x04;
+const ADDRESS_ERROR: u8 = 0x20;
+
+/// Tokens starting data blocks and ending multiple block write
+const START_BLOCK: u8 = 0xfe;
+const START_MULTIPLE_WRITE: u8 = 0xfc;
+const STOP_TRANSMISSION: u8 = 0xfd;
+
+/// Data response tokens: data accepted, or rejected by write error
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+/// Operation conditions: powered up, high capacity card, 2.7-3.6 V
+const OCR: [u8; 4] = [0xc0, 0xff, 0x80, 0x00];
+
+/// Card identification: manufacturer, OEM, product name, revision, serial number and date
+const CID: [u8; 16] = [0x00, b'R', b'S', b'S', b'D', b'I', b'M', b'G', 0x10, 0x00, 0x00, 0x00, 0x01, 0x01, 0x8a, 0x01];
+
+/// Data block being received by write command
+struct WriteState {
+    block: u64,
+    multiple: bool,
+    /// Data token was received and data bytes with CRC follow
+    receiving: bool,
+    buffer: Vec<u8>,
+}
+
+/// SD card in SPI mode backed by disk image. High capacity card protocol is
+/// used, with block addressing and CRC checking off.
+#[derive(Default)]
+pub struct SdCard {
+    image: RefCell<Option<Rc<dyn BlockImage>>>,
+    selected: Cell<bool>,
+    idle: Cell<bool>,
+    /// Next command is application specific
+    app_command: Cell<bool>,
+    /// Command bytes received so far
+    command: RefCell<Vec<u8>>,
+    /// Bytes sent in the following transfers
+    response: RefCell<VecDeque<u8>>,
+    /// Next block sent by multiple block read
+    next_block: Cell<Option<u64>>,
+    write: RefCell<Option<WriteState>>,
+}
+
+impl SdCard {
+
+    /// Insert card with given image
+    pub fn insert(&self, image: &Rc<dyn BlockImage>) {
+        self.image.replace(Some(Rc::clone(image)));
+        self.idle.set(true);
+    }
+
+    /// Remove the card
+    pub fn eject(&self) {
+        self.image.replace(None);
+    }
+
+    /// Card is inserted
+    pub fn is_present(&self) -> bool {
+        self.image.borrow().is_some()
+    }
+
+    /// Set chip select. Command in progress is abandoned when card is deselected.
+    pub fn select(&self, selected: bool) {
+        if !selected {
+            self.command.borrow_mut().clear();
+            self.response.borrow_mut().clear();
+            self.next_block.set(None);
+        }
+        self.selected.set(selected);
+    }
+
+    /// Exchange byte over SPI: receive given byte and send the next response byte
+    pub fn transfer(&self, byte: u8) -> u8 {
+
+        if !self.selected.get() || !self.is_present() {
+            return 0xff;
+        }
+
+        let output = self.next_output();
+        if self.write.borrow().is_some() {
+            self.receive(byte);
+        } else {
+            let mut command = self.command.borrow_mut();
+            // Command starts with 01 bits, other bytes are clocked out by the host
+            if !command.is_empty() || byte & 0xc0 == 0x40 {
+                command.push(byte);
+            }
+            if command.len() == 6 {
+                let command = std::mem::take(&mut *command);
+                self.execute(command[0] & 0x3f, u32::from_be_bytes(command[1..5].try_into().unwrap()));
+            }
+        }
+        output
+
+    }
+
+    fn next_output(&self) -> u8 {
+        let mut response = self.response.borrow_mut();
+        if response.is_empty() && let Some(block) = self.next_block.get() {
+            drop(response);
+            self.send_block(block);
+            self.next_block.set(Some(block + 1));
+            response = self.response.borrow_mut();
+        }
+        response.pop_front().unwrap_or(0xff)
+    }
+
+    fn respond(&self, bytes: &[u8]) {
+        let mut response = self.response.borrow_mut();
+        // Response follows a byte of command response time
+        response.clear();
+        response.push_back(0xff);
+        response.extend(bytes);
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        flags | if self.idle.get() { IDLE } else { 0 }
+    }
+
+    fn execute(&self, command: u8, arg: u32) {
+
+        let app_command = self.app_command.replace(false);
+        match (app_command, command) {
+            // GO_IDLE_STATE
+            (_, 0) => {
+                self.idle.set(true);
+                self.respond(&[IDLE]);
+            },
+            // SEND_OP_COND and SD_SEND_OP_COND finish initialization
+            (false, 1) | (true, 41) => {
+                self.idle.set(false);
+                self.respond(&[0x00]);
+            },
+            // SEND_IF_COND echoes voltage and check pattern
+            (false, 8) => self.respond(&[self.r1(0), 0x00, 0x00, (arg >> 8) as u8 & 0x0f, arg as u8]),
+            // SEND_CSD and SEND_CID
+            (false, 9) => self.respond(&[[self.r1(0), 0xff, START_BLOCK].as_slice(), &self.csd(), &[0xff, 0xff]].concat()),
+            (false, 10) => self.respond(&[[self.r1(0), 0xff, START_BLOCK].as_slice(), &CID, &[0xff, 0xff]].concat()),
+            // STOP_TRANSMISSION ends multiple block read
+            (false, 12) => {
+                self.next_block.set(None);
+                self.respond(&[0xff, self.r1(0)]);
+            },
+            // SET_BLOCKLEN, CRC_ON_OFF: blocks are always 512 bytes, CRC isn't checked
+            (false, 16 | 59) => self.respond(&[self.r1(0)]),
+            // READ_SINGLE_BLOCK and READ_MULTIPLE_BLOCK
+            (false, 17 | 18) => {
+                if !self.in_range(arg) {
+                    return self.respond(&[self.r1(ADDRESS_ERROR)]);
+                }
+                self.respond(&[self.r1(0)]);
+                self.send_block(arg as u64);
+                self.next_block.set((command == 18).then_some(arg as u64 + 1));
+            },
+            // WRITE_BLOCK and WRITE_MULTIPLE_BLOCK
+            (false, 24 | 25) => {
+                if !self.in_range(arg) {
+                    return self.respond(&[self.r1(ADDRESS_ERROR)]);
+                }
+                self.respond(&[self.r1(0)]);
+                self.write.replace(Some(WriteState { block: arg as u64, multiple: command == 25, receiving: false, buffer: vec![] }));
+            },
+            // APP_CMD
+            (false, 55) => {
+                self.app_command.set(true);
+                self.respond(&[self.r1(0)]);
+            },
+            // READ_OCR
+            (false, 58) => self.respond(&[[self.r1(0)].as_slice(), &OCR].concat()),
+            _ => self.respond(&[self.r1(ILLEGAL_COMMAND)]),
+        }
+
+    }
+
+    fn in_range(&self, block: u32) -> bool {
+        self.image.borrow().as_ref().is_some_and(|image| (block as u64) < image.blocks())
+    }
+
+    /// Queue data token, block contents and CRC
+    fn send_block(&self, block: u64) {
+        let mut data = [0xff; BLOCK_SIZE];
+        let image = self.image.borrow();
+        let read = image.as_ref().map(|image| image.read_block(block, &mut data));
+        let mut response = self.response.borrow_mut();
+        match read {
+            Some(Ok(())) => {
+                response.push_back(0xff);
+                response.push_back(START_BLOCK);
+                response.extend(data);
+                response.extend([0xff, 0xff]);
+            },
+            // Data error token reports failed read
+            _ => {
+                self.next_block.set(None);
+                response.push_back(0x08);
+            },
+        }
+    }
+
+    /// Handle byte of write command data phase
+    fn receive(&self, byte: u8) {
+        let mut guard = self.write.borrow_mut();
+        let Some(write) = guard.as_mut() else { return };
+        if !write.receiving {
+            match byte {
+                START_BLOCK | START_MULTIPLE_WRITE => write.receiving = true,
+                STOP_TRANSMISSION if write.multiple => *guard = None,
+                _ => (),
+            }
+            return;
+        }
+        write.buffer.push(byte);
+        if write.buffer.len() < BLOCK_SIZE + 2 {
+            return;
+        }
+        let data: [u8; BLOCK_SIZE] = write.buffer[..BLOCK_SIZE].try_into().unwrap();
+        let written = self.image.borrow().as_ref().is_some_and(|image| image.write_block(write.block, &data).is_ok());
+        // Data response is followed by a busy byte
+        self.response.borrow_mut().extend([if written { DATA_ACCEPTED } else { DATA_WRITE_ERROR }, 0x00]);
+        if write.multiple && written {
+            write.block += 1;
+            write.receiving = false;
+            write.buffer.clear();
+        } else {
+            *guard = None;
+        }
+    }
+
+    /// Card specific data, version 2.0 with the capacity of the image
+    fn csd(&self) -> [u8; 16] {
+        let blocks = self.image.borrow().as_ref().map_or(0, |image| image.blocks());
+        let size = (blocks / 1024).saturating_sub(1) as u32;
+        [
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00,
+            (size >> 16) as u8 & 0x3f, (size >> 8) as u8, size as u8,
+            0x7f, 0x80, 0x0a, 0x40, 0x00, 0x01,
+        ]
+    }
+
+}
@@ -0,0 +1,88 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Other end of a serial line, exchanging whole bytes
+pub trait SerialPort {
+
+    /// Byte was sent over the line
+    fn send(&self, byte: u8);
+
+    /// Next byte to be received from the line, if any
+    fn receive(&self) -> Option<u8>;
+
+}
+
+/// Serial line connected back to itself: bytes sent are received in the same order
+#[derive(Default)]
+pub struct SerialLoopback {
+    buffer: RefCell<VecDeque<u8>>,
+}
+
+impl SerialPort for SerialLoopback {
+
+    fn send(&self, byte: u8) {
+        self.buffer.borrow_mut().push_back(byte);
+    }
+
+    fn receive(&self) -> Option<u8> {
+        self.buffer.borrow_mut().pop_front()
+    }
+
+}
+
+/// Serial line connected to host files or pipes. Input is read by a background
+/// thread, so a pipe without data doesn't stall the emulation.
+pub struct HostSerial {
+    input: Receiver<u8>,
+    output: RefCell<Box<dyn Write>>,
+}
+
+impl HostSerial {
+
+    pub fn new(input: impl Read + Send + 'static, output: impl Write + 'static) -> Self {
+        Self::spawn(move || Ok(input), Box::new(output))
+    }
+
+    /// Read from input file and append to output file, which are opened as
+    /// they are, so named pipes can be used
+    pub fn open(input: impl AsRef<Path>, output: impl AsRef<Path>) -> io::Result<Self> {
+        let output = OpenOptions::new().create(true).append(true).open(output)?;
+        let input: PathBuf = input.as_ref().into();
+        Ok(Self::spawn(move || File::open(input), Box::new(output)))
+    }
+
+    fn spawn<R: Read>(open: impl FnOnce() -> io::Result<R> + Send + 'static, output: Box<dyn Write>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let Ok(input) = open() else { return };
+            for byte in BufReader::new(input).bytes().map_while(Result::ok) {
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { input: receiver, output: RefCell::new(output) }
+    }
+
+}
+
+impl SerialPort for HostSerial {
+
+    fn send(&self, byte: u8) {
+        let mut output = self.output.borrow_mut();
+        // Host side errors aren't visible to the emulated machine
+        let _ = output.write_all(&[byte]).and_then(|_| output.flush());
+    }
+
+    fn receive(&self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+}
@@ -0,0 +1,573 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    cpu::{Flags, tokens::{AluOp, Reg, RegPair}},
+    devs::{CpuState, CpuTrap, Device, IoDevice, Snapshot, mem::Memory, run_io_device},
+    formats::{FormatError, Reader, tap::TapBlock},
+};
+
+/// ROM LD-BYTES routine address
+pub const LD_BYTES: u16 = 0x0556;
+
+/// ROM SA/LD-RET routine address, where LD-BYTES exits
+const SA_LD_RET: u16 = 0x053f;
+
+/// First instructions of LD-BYTES in 48K ROM: INC D; EX AF,AF'; DEC D; DI
+const LD_BYTES_CODE: [u8; 4] = [0x14, 0x08, 0x15, 0xf3];
+
+/// T-states per millisecond at 3.5MHz
+pub const TSTATES_PER_MS: u32 = 3500;
+
+/// Standard ROM loader timings in t-states
+pub const PILOT_PULSE: u32 = 2168;
+pub const HEADER_PILOT_PULSES: u32 = 8063;
+pub const DATA_PILOT_PULSES: u32 = 3223;
+pub const SYNC1_PULSE: u32 = 667;
+pub const SYNC2_PULSE: u32 = 735;
+pub const ZERO_PULSE: u32 = 855;
+pub const ONE_PULSE: u32 = 1710;
+
+/// Pause after standard speed block in milliseconds
+pub const STANDARD_PAUSE_MS: u32 = 1000;
+
+/// Player stops automatically when port FEh isn't read for that long
+const AUTO_STOP_TSTATES: u64 = 3_500_000;
+
+/// Part of the tape signal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapeSegment {
+    /// Pulses of equal length in t-states
+    Tone { pulse: u32, count: u32 },
+    /// Pulses of given lengths in t-states
+    Pulses(Vec<u32>),
+    /// Bytes with each bit encoded as two equal pulses, most significant bit first.
+    /// Only `last_bits` most significant bits of the last byte are used.
+    Data { zero: u32, one: u32, data: Vec<u8>, last_bits: u8 },
+    /// Silence for given t-states with the signal low
+    Pause(u32),
+    /// Arbitrary pulses
+    Raw(Vec<TapePulse>),
+    /// Stop the tape
+    Stop,
+}
+
+/// Signal change: either the level is flipped, kept or set, then held for given t-states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapePulse {
+    Edge(u32),
+    Hold(u32),
+    Level(bool, u32),
+    /// Stop the tape
+    Stop,
+}
+
+impl TapeSegment {
+
+    /// Signal of the block loadable by ROM LD-BYTES routine followed by pause in milliseconds
+    pub fn standard(block: &TapBlock, pause_ms: u32) -> Vec<TapeSegment> {
+        let header = block.flag().is_some_and(|flag| flag < 0x80);
+        vec![
+            TapeSegment::Tone { pulse: PILOT_PULSE, count: if header { HEADER_PILOT_PULSES } else { DATA_PILOT_PULSES } },
+            TapeSegment::Pulses(vec![SYNC1_PULSE, SYNC2_PULSE]),
+            TapeSegment::Data { zero: ZERO_PULSE, one: ONE_PULSE, data: block.data.clone(), last_bits: 8 },
+            TapeSegment::Pause(pause_ms * TSTATES_PER_MS),
+        ]
+    }
+
+    /// Pulse with given index, or None past the end of the segment
+    pub fn pulse(&self, index: usize) -> Option<TapePulse> {
+        match self {
+            TapeSegment::Tone { pulse, count } =>
+                (index < *count as usize).then_some(TapePulse::Edge(*pulse)),
+            TapeSegment::Pulses(pulses) =>
+                pulses.get(index).map(|&pulse| TapePulse::Edge(pulse)),
+            TapeSegment::Data { zero, one, data, last_bits } => {
+                let bits = (data.len() * 8).saturating_sub(8 - *last_bits as usize);
+                let bit = index / 2;
+                (bit < bits).then(|| {
+                    let set = data[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                    TapePulse::Edge(if set { *one } else { *zero })
+                })
+            },
+            TapeSegment::Pause(tstates) =>
+                (index == 0 && *tstates > 0).then_some(TapePulse::Level(false, *tstates)),
+            TapeSegment::Raw(pulses) => pulses.get(index).copied(),
+            TapeSegment::Stop => (index == 0).then_some(TapePulse::Stop),
+        }
+    }
+
+}
+
+/// Tape block as presented by tape browser and played by tape player
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeBlock {
+    /// One-line description
+    pub description: String,
+    /// Block loadable by ROM LD-BYTES routine, used by fast load trap
+    pub data: Option<TapBlock>,
+    /// Signal, empty for blocks carrying only information
+    pub segments: Vec<TapeSegment>,
+}
+
+impl TapeBlock {
+
+    /// Block without signal
+    pub fn info(description: String) -> Self {
+        Self { description, data: None, segments: vec![] }
+    }
+
+    /// Standard speed block followed by pause in milliseconds
+    pub fn standard(block: TapBlock, pause_ms: u32) -> Self {
+        Self {
+            description: block.describe(),
+            segments: TapeSegment::standard(&block, pause_ms),
+            data: Some(block),
+        }
+    }
+
+    /// All pulses of the block signal
+    pub fn pulses(&self) -> Vec<TapePulse> {
+        self.segments.iter()
+            .flat_map(|segment| (0..).map_while(|index| segment.pulse(index)))
+            .collect()
+    }
+
+}
+
+impl From<TapBlock> for TapeBlock {
+    fn from(block: TapBlock) -> Self {
+        TapeBlock::standard(block, STANDARD_PAUSE_MS)
+    }
+}
+
+/// Tape deck holding inserted tape and the position of the next block
+#[derive(Default)]
+pub struct TapeDeck {
+    blocks: RefCell<Vec<TapeBlock>>,
+    position: Cell<usize>,
+}
+
+impl TapeDeck {
+
+    /// Insert tape and rewind it
+    pub fn insert(&self, blocks: Vec<TapeBlock>) {
+        self.blocks.replace(blocks);
+        self.position.set(0);
+    }
+
+    /// Remove tape
+    pub fn eject(&self) {
+        self.insert(vec![]);
+    }
+
+    /// Copy of the tape blocks
+    pub fn blocks(&self) -> Vec<TapeBlock> {
+        self.blocks.borrow().clone()
+    }
+
+    /// Index of the next block to be read
+    pub fn position(&self) -> usize {
+        self.position.get()
+    }
+
+    /// Move to the block with given index
+    pub fn seek(&self, position: usize) {
+        self.position.set(position.min(self.blocks.borrow().len()));
+    }
+
+    /// Next block without advancing the tape
+    pub fn peek_block(&self) -> Option<TapeBlock> {
+        self.blocks.borrow().get(self.position.get()).cloned()
+    }
+
+    /// Take the next block advancing the tape
+    pub fn next_block(&self) -> Option<TapeBlock> {
+        let block = self.peek_block();
+        if block.is_some() {
+            self.position.set(self.position.get() + 1);
+        }
+        block
+    }
+
+}
+
+impl Snapshot for TapeDeck {
+
+    /// Position followed by the blocks
+    fn save_snapshot(&self) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend((self.position.get() as u32).to_le_bytes());
+        let blocks = self.blocks.borrow();
+        data.extend((blocks.len() as u32).to_le_bytes());
+        for block in blocks.iter() {
+            write_bytes(&mut data, block.description.as_bytes());
+            match &block.data {
+                Some(tap) => { data.push(1); write_bytes(&mut data, &tap.data); },
+                None => data.push(0),
+            }
+            data.extend((block.segments.len() as u32).to_le_bytes());
+            block.segments.iter().for_each(|segment| write_segment(&mut data, segment));
+        }
+        data
+    }
+
+    fn load_snapshot(&self, data: &[u8]) -> Result<(), FormatError> {
+        let mut rd = Reader::new(data);
+        let position = rd.dword()? as usize;
+        let mut blocks = vec![];
+        for _ in 0..rd.dword()? {
+            let description = String::from_utf8(read_bytes(&mut rd)?.to_vec())
+                .map_err(|_| FormatError::Invalid("tape block description isn't UTF-8"))?;
+            let data = match rd.byte()? {
+                0 => None,
+                _ => Some(TapBlock { data: read_bytes(&mut rd)?.to_vec() }),
+            };
+            let segments = (0..rd.dword()?).map(|_| read_segment(&mut rd)).collect::<Result<_, _>>()?;
+            blocks.push(TapeBlock { description, data, segments });
+        }
+        if position > blocks.len() {
+            return Err(FormatError::Invalid("tape position is past the end"));
+        }
+        self.blocks.replace(blocks);
+        self.position.set(position);
+        Ok(())
+    }
+
+}
+
+/// Length-prefixed bytes
+fn write_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend((bytes.len() as u32).to_le_bytes());
+    data.extend(bytes);
+}
+
+fn read_bytes<'a>(rd: &mut Reader<'a>) -> Result<&'a [u8], FormatError> {
+    let len = rd.dword()? as usize;
+    rd.bytes(len)
+}
+
+fn write_durations(data: &mut Vec<u8>, durations: &[u32]) {
+    data.extend((durations.len() as u32).to_le_bytes());
+    durations.iter().for_each(|duration| data.extend(duration.to_le_bytes()));
+}
+
+fn read_durations(rd: &mut Reader) -> Result<Vec<u32>, FormatError> {
+    (0..rd.dword()?).map(|_| rd.dword()).collect()
+}
+
+/// Segment tag followed by its fields
+fn write_segment(data: &mut Vec<u8>, segment: &TapeSegment) {
+    match segment {
+        TapeSegment::Tone { pulse, count } => {
+            data.push(0);
+            data.extend(pulse.to_le_bytes());
+            data.extend(count.to_le_bytes());
+        },
+        TapeSegment::Pulses(pulses) => {
+            data.push(1);
+            write_durations(data, pulses);
+        },
+        TapeSegment::Data { zero, one, data: bytes, last_bits } => {
+            data.push(2);
+            data.extend(zero.to_le_bytes());
+            data.extend(one.to_le_bytes());
+            data.push(*last_bits);
+            write_bytes(data, bytes);
+        },
+        TapeSegment::Pause(tstates) => {
+            data.push(3);
+            data.extend(tstates.to_le_bytes());
+        },
+        TapeSegment::Raw(pulses) => {
+            data.push(4);
+            data.extend((pulses.len() as u32).to_le_bytes());
+            for pulse in pulses {
+                let (tag, tstates) = match *pulse {
+                    TapePulse::Edge(tstates) => (0, tstates),
+                    TapePulse::Hold(tstates) => (1, tstates),
+                    TapePulse::Level(level, tstates) => (2 + level as u8, tstates),
+                    TapePulse::Stop => (4, 0),
+                };
+                data.push(tag);
+                data.extend(tstates.to_le_bytes());
+            }
+        },
+        TapeSegment::Stop => data.push(5),
+    }
+}
+
+fn read_segment(rd: &mut Reader) -> Result<TapeSegment, FormatError> {
+    Ok(match rd.byte()? {
+        0 => TapeSegment::Tone { pulse: rd.dword()?, count: rd.dword()? },
+        1 => TapeSegment::Pulses(read_durations(rd)?),
+        2 => TapeSegment::Data { zero: rd.dword()?, one: rd.dword()?, last_bits: rd.byte()?, data: read_bytes(rd)?.to_vec() },
+        3 => TapeSegment::Pause(rd.dword()?),
+        4 => TapeSegment::Raw((0..rd.dword()?).map(|_| {
+            let tag = rd.byte()?;
+            let tstates = rd.dword()?;
+            Ok(match tag {
+                0 => TapePulse::Edge(tstates),
+                1 => TapePulse::Hold(tstates),
+                2 | 3 => TapePulse::Level(tag == 3, tstates),
+                4 => TapePulse::Stop,
+                _ => return Err(FormatError::Invalid("unknown tape pulse")),
+            })
+        }).collect::<Result<_, _>>()?),
+        5 => TapeSegment::Stop,
+        _ => return Err(FormatError::Invalid("unknown tape segment")),
+    })
+}
+
+/// Fast load trap replacing ROM LD-BYTES routine. Takes flag in A, load (carry set)
+/// or verify (carry reset) in F, destination in IX and length in DE, loads the next
+/// tape block directly to memory and returns with carry set on success.
+/// The trap doesn't fire unless 48K ROM is seen at LD-BYTES, so interface
+/// ROMs paged over it run their own code.
+pub struct LdBytesTrap {
+    deck: Rc<TapeDeck>,
+    memory: Rc<dyn Memory>,
+}
+
+impl LdBytesTrap {
+
+    pub fn new(deck: &Rc<TapeDeck>, memory: &Rc<dyn Memory>) -> Self {
+        Self { deck: Rc::clone(deck), memory: Rc::clone(memory) }
+    }
+
+    /// Load block, returns false on flag, length, verify or checksum error
+    fn load(&self, cpu: &CpuState, block: &TapBlock) -> bool {
+
+        let data = &block.data;
+        if data.first() != Some(&cpu.rg(Reg::A).get()) {
+            return false;
+        }
+
+        let verify = !cpu.get_flags().contains(Flags::C);
+        let mut parity = data[0];
+        let mut offset = 1;
+
+        // The last byte of the block is checksum, so it's not loaded
+        while cpu.rp(RegPair::DE).get() != 0 {
+            if offset + 1 >= data.len() {
+                return false;
+            }
+            let byte = data[offset];
+            let addr = cpu.rp(RegPair::IX).get();
+            if verify {
+                if self.memory.read(addr) != byte {
+                    return false;
+                }
+            } else {
+                self.memory.write(addr, byte);
+            }
+            parity ^= byte;
+            cpu.rg(Reg::L).set(byte);
+            cpu.rp(RegPair::IX).set(addr.wrapping_add(1));
+            cpu.rp(RegPair::DE).set(cpu.rp(RegPair::DE).get().wrapping_sub(1));
+            offset += 1;
+        }
+
+        // Checksum is correct when parity of all bytes is zero, like in ROM
+        // which finishes with LD A,H; CP 1 setting carry when H is zero
+        cpu.rg(Reg::H).set(parity ^ data[offset]);
+        cpu.rg(Reg::A).set(cpu.rg(Reg::H).get());
+        cpu.alu(AluOp::CP, 1);
+        cpu.get_flags().contains(Flags::C)
+
+    }
+
+}
+
+impl CpuTrap for LdBytesTrap {
+
+    fn address(&self) -> u16 { LD_BYTES }
+
+    fn handle(&self, cpu: &CpuState) -> bool {
+
+        if !(LD_BYTES..).zip(LD_BYTES_CODE).all(|(addr, byte)| self.memory.read(addr) == byte) {
+            return false;
+        }
+
+        // Blocks without signal are skipped, while blocks with custom timings
+        // are left to ROM which waits for the tape signal, as it does at the end of the tape
+        let block = loop {
+            match self.deck.peek_block() {
+                Some(TapeBlock { data: Some(data), .. }) => break data,
+                Some(TapeBlock { segments, .. }) if segments.is_empty() => self.deck.seek(self.deck.position() + 1),
+                _ => return false,
+            }
+        };
+        self.deck.seek(self.deck.position() + 1);
+
+        if !self.load(cpu, &block) {
+            cpu.set_flags(cpu.get_flags() & !Flags::C);
+        }
+
+        // Leave through SA/LD-RET like ROM does, restoring border, checking BREAK
+        // and enabling interrupts before returning to the caller with carry preserved
+        cpu.rp(RegPair::PC).set(SA_LD_RET);
+        true
+
+    }
+
+}
+
+/// Tape player presenting tape signal on EAR input, which is read as bit 6
+/// of port FEh (any even port). Blocks are taken from the tape deck as they
+/// start playing. Until ULA is emulated the player answers port FEh reads itself.
+pub struct TapePlayer {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    deck: Rc<TapeDeck>,
+    playing: Cell<bool>,
+    /// Stop when the machine doesn't read EAR input for a while
+    pub auto_stop: Cell<bool>,
+    level: Cell<bool>,
+    segments: RefCell<Vec<TapeSegment>>,
+    segment: Cell<usize>,
+    pulse: Cell<usize>,
+    /// Time of the next signal change in half t-cycles
+    next_edge: Cell<u64>,
+    /// Time of the last port FEh read in half t-cycles
+    last_read: Cell<u64>,
+}
+
+impl TapePlayer {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, deck: &Rc<TapeDeck>) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            deck: Rc::clone(deck),
+            playing: Cell::new(false),
+            auto_stop: Cell::new(true),
+            level: Cell::new(false),
+            segments: RefCell::new(vec![]),
+            segment: Cell::new(0),
+            pulse: Cell::new(0),
+            next_edge: Cell::new(0),
+            last_read: Cell::new(0),
+        }
+    }
+
+    /// Start playing from the current position
+    pub fn play(&self) {
+        if !self.playing.replace(true) {
+            self.next_edge.set(self.clock.get());
+            self.last_read.set(self.clock.get());
+        }
+    }
+
+    /// Stop playing keeping the position within the block
+    pub fn stop(&self) {
+        self.playing.set(false);
+    }
+
+    /// Stop and rewind to the start of the tape
+    pub fn rewind(&self) {
+        self.stop();
+        self.deck.seek(0);
+        self.segments.borrow_mut().clear();
+        self.segment.set(0);
+        self.pulse.set(0);
+        self.level.set(false);
+    }
+
+    /// Tape is playing
+    pub fn is_playing(&self) -> bool {
+        self.playing.get()
+    }
+
+    /// Current EAR signal level
+    pub fn ear(&self) -> bool {
+        self.level.get()
+    }
+
+    /// Apply the next pulse, taking the next block from the deck when
+    /// the current one is over. Stops at the end of the tape.
+    fn advance(&self) {
+        loop {
+            let pulse = self.segments.borrow().get(self.segment.get())
+                .map(|segment| segment.pulse(self.pulse.get()));
+            match pulse {
+                Some(Some(pulse)) => {
+                    self.pulse.set(self.pulse.get() + 1);
+                    let tstates = match pulse {
+                        TapePulse::Edge(tstates) => {
+                            self.level.set(!self.level.get());
+                            tstates
+                        },
+                        TapePulse::Hold(tstates) => tstates,
+                        TapePulse::Level(level, tstates) => {
+                            self.level.set(level);
+                            tstates
+                        },
+                        TapePulse::Stop => {
+                            self.stop();
+                            return;
+                        },
+                    };
+                    self.next_edge.set(self.next_edge.get() + ((tstates as u64) << 1));
+                    return;
+                },
+                Some(None) => {
+                    self.segment.set(self.segment.get() + 1);
+                    self.pulse.set(0);
+                },
+                None => match self.deck.next_block() {
+                    Some(block) => {
+                        self.segments.replace(block.segments);
+                        self.segment.set(0);
+                        self.pulse.set(0);
+                    },
+                    None => {
+                        self.stop();
+                        self.level.set(false);
+                        return;
+                    },
+                },
+            }
+        }
+    }
+
+}
+
+impl Identifiable for TapePlayer {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for TapePlayer {
+
+    fn clock_tick(&self) {
+        let now = self.clock.get();
+        while self.playing.get() && now >= self.next_edge.get() {
+            self.advance();
+        }
+        if self.playing.get() && self.auto_stop.get() && now - self.last_read.get() >= AUTO_STOP_TSTATES << 1 {
+            self.stop();
+        }
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        if addr & 0x01 != 0 {
+            return None;
+        }
+        self.last_read.set(self.clock.get());
+        // No keys pressed, bits 5 and 7 are always set
+        Some(0xbf | ((self.level.get() as u8) << 6))
+    }
+
+    fn write_port(&self, _addr: u16, _byte: u8) {}
+
+}
+
+impl Device for TapePlayer {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
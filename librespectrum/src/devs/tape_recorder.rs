@@ -0,0 +1,127 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, IoDevice, run_io_device},
+    formats::{tap::Tap, tzx::{Tzx, TzxBlock}, wav::Wav},
+};
+
+/// MIC output bit of port FEh
+const MIC_BIT: u8 = 0x08;
+
+/// Tape recorder capturing MIC output, which is bit 3 of port FEh (any even port) writes.
+/// Edges are timestamped in t-states while recording, so the signal can be decoded
+/// back into tape blocks or saved as audio.
+pub struct TapeRecorder {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    recording: Cell<bool>,
+    /// Last MIC level written
+    mic: Cell<bool>,
+    /// MIC level when recording started
+    initial_level: Cell<bool>,
+    /// Recording start and end in t-states
+    start: Cell<u64>,
+    end: Cell<u64>,
+    /// MIC edge times in t-states
+    edges: RefCell<Vec<u64>>,
+}
+
+impl TapeRecorder {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            recording: Cell::new(false),
+            mic: Cell::new(false),
+            initial_level: Cell::new(false),
+            start: Cell::new(0),
+            end: Cell::new(0),
+            edges: RefCell::new(vec![]),
+        }
+    }
+
+    /// Start new recording discarding the previous one
+    pub fn record(&self) {
+        self.edges.borrow_mut().clear();
+        self.initial_level.set(self.mic.get());
+        self.start.set(self.clock.get() >> 1);
+        self.recording.set(true);
+    }
+
+    /// Stop recording
+    pub fn stop(&self) {
+        if self.recording.replace(false) {
+            self.end.set(self.clock.get() >> 1);
+        }
+    }
+
+    /// Recording is in progress
+    pub fn is_recording(&self) -> bool {
+        self.recording.get()
+    }
+
+    /// Initial level and pulse lengths in t-states, from the start of recording
+    /// to its end or the current time
+    pub fn pulses(&self) -> (bool, Vec<u32>) {
+        let end = if self.recording.get() { self.clock.get() >> 1 } else { self.end.get() };
+        let edges = self.edges.borrow();
+        let times: Vec<u64> = [self.start.get()].into_iter().chain(edges.iter().copied()).chain([end]).collect();
+        let pulses = times.windows(2).map(|pair| (pair[1] - pair[0]).min(u32::MAX as u64) as u32).collect();
+        (self.initial_level.get(), pulses)
+    }
+
+    /// Recording as TZX, with direct recording blocks where standard blocks aren't recognized
+    pub fn to_tzx(&self) -> Tzx {
+        let (level, pulses) = self.pulses();
+        Tzx::from_pulses(level, &pulses)
+    }
+
+    /// Standard speed blocks of the recording
+    pub fn to_tap(&self) -> Tap {
+        let blocks = self.to_tzx().blocks.into_iter().filter_map(|block| match block {
+            TzxBlock::Standard { data, .. } => Some(data),
+            _ => None,
+        }).collect();
+        Tap { blocks }
+    }
+
+    /// Recording as 8-bit mono audio
+    pub fn to_wav(&self, sample_rate: u32) -> Wav {
+        let (level, pulses) = self.pulses();
+        Wav::from_pulses(sample_rate, level, &pulses)
+    }
+
+}
+
+impl Identifiable for TapeRecorder {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for TapeRecorder {
+
+    fn read_port(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        if addr & 0x01 == 0 {
+            let mic = byte & MIC_BIT != 0;
+            if self.mic.replace(mic) != mic && self.recording.get() {
+                self.edges.borrow_mut().push(self.clock.get() >> 1);
+            }
+        }
+    }
+
+}
+
+impl Device for TapeRecorder {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
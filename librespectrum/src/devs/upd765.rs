@@ -0,0 +1,647 @@
+use std::{cell::{Cell, RefCell}, collections::VecDeque, rc::Rc};
+
+use crate::core::Clock;
+
+use super::{DiskSector, FloppyDrive, disk::{BYTE, ID_FIELD, ID_TO_DATA, REVOLUTION, id_position}};
+
+/// Main status register bits
+const REQUEST: u8 = 0x80;
+const DATA_OUTPUT: u8 = 0x40;
+const EXECUTION: u8 = 0x20;
+const FDC_BUSY: u8 = 0x10;
+
+/// Status register 0 bits
+const INVALID: u8 = 0x80;
+const ABNORMAL: u8 = 0x40;
+const SEEK_END: u8 = 0x20;
+const EQUIPMENT_CHECK: u8 = 0x10;
+const NOT_READY: u8 = 0x08;
+
+/// Status register 1 bits
+const END_OF_CYLINDER: u8 = 0x80;
+const OVERRUN: u8 = 0x10;
+const NO_DATA: u8 = 0x04;
+const NOT_WRITABLE: u8 = 0x02;
+const MISSING_ADDRESS: u8 = 0x01;
+
+/// Status register 2 bits
+const CONTROL_MARK: u8 = 0x40;
+const WRONG_CYLINDER: u8 = 0x10;
+const BAD_CYLINDER: u8 = 0x02;
+
+/// Status register 3 bits
+const WRITE_PROTECTED: u8 = 0x40;
+const READY: u8 = 0x20;
+const TRACK_0: u8 = 0x10;
+const TWO_SIDE: u8 = 0x08;
+
+/// Command codes in bits 0-4 of the command byte
+const READ_TRACK: u8 = 0x02;
+const SPECIFY: u8 = 0x03;
+const SENSE_DRIVE_STATUS: u8 = 0x04;
+const WRITE_DATA: u8 = 0x05;
+const READ_DATA: u8 = 0x06;
+const RECALIBRATE: u8 = 0x07;
+const SENSE_INTERRUPT_STATUS: u8 = 0x08;
+const WRITE_DELETED_DATA: u8 = 0x09;
+const READ_ID: u8 = 0x0a;
+const READ_DELETED_DATA: u8 = 0x0c;
+const FORMAT_TRACK: u8 = 0x0d;
+const SEEK: u8 = 0x0f;
+
+/// Command flags: multi-track and skip deleted data
+const MULTI_TRACK: u8 = 0x80;
+const SKIP: u8 = 0x20;
+
+/// Index holes passed while looking for the sector before giving up
+const SEARCH_REVOLUTIONS: u64 = 2;
+
+/// Steps made by recalibrate before reporting that track 0 wasn't found
+const RECALIBRATE_STEPS: u8 = 77;
+
+/// Stepping rate of 2 ms units at 4 MHz clock
+const STEP_UNIT: u64 = 7_000;
+
+/// Largest size code of a sector that is read in full
+const MAX_SIZE_CODE: u8 = 7;
+
+/// Command execution phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Command and parameter bytes are accepted
+    Command,
+    /// Waiting for the sector ID or the index hole
+    Search,
+    /// Bytes are transferred to CPU
+    Read,
+    /// Bytes are transferred from CPU
+    Write,
+    /// Result bytes are read by CPU
+    Result,
+}
+
+/// Drive with the head position known to controller and its seek in progress
+#[derive(Default)]
+struct Unit {
+    drive: FloppyDrive,
+    /// Present cylinder number
+    cylinder: Cell<u8>,
+    /// Cylinder the head is moving to
+    target: Cell<Option<u8>>,
+    /// Steps left for recalibrate, which stops at track 0
+    recalibrate: Cell<Option<u8>>,
+    next_step: Cell<u64>,
+    /// Status register 0 of completed seek waiting for sense interrupt status
+    interrupt: Cell<Option<u8>>,
+}
+
+/// NEC uPD765A floppy disk controller in non-DMA mode, as used by +3. Commands
+/// run on the clock in t-states: heads step at the specified rate, sectors
+/// pass the heads as the disk rotates, and data bytes are requested every
+/// 32 µs. Overrun ends the command when CPU doesn't keep up with data requests.
+/// Terminal count isn't connected, so read and write commands end with end of
+/// cylinder error after the last sector.
+pub struct Upd765 {
+    clock: Rc<Clock>,
+    units: Vec<Unit>,
+    motor: Cell<bool>,
+    step_rate: Cell<u64>,
+    phase: Cell<Phase>,
+    /// Command and parameter bytes received so far
+    received: RefCell<Vec<u8>>,
+    /// Command being executed with its parameters
+    command: RefCell<Vec<u8>>,
+    result: RefCell<VecDeque<u8>>,
+    /// Time of the next phase event in t-states
+    next_event: Cell<u64>,
+    /// Sector ID of the command: cylinder, head, sector and size code
+    id: Cell<[u8; 4]>,
+    st0: Cell<u8>,
+    st1: Cell<u8>,
+    st2: Cell<u8>,
+    /// Index of the sector found by search in the track under the head
+    found: Cell<Option<usize>>,
+    /// Data byte is waiting for CPU in read phase, or requested from CPU in write phase
+    request: Cell<bool>,
+    data: Cell<u8>,
+    buffer: RefCell<Vec<u8>>,
+    /// Bytes transferred and bytes to transfer in data phase
+    position: Cell<usize>,
+    length: Cell<usize>,
+    /// Sectors read by read track command
+    sectors: Cell<u8>,
+}
+
+impl Upd765 {
+
+    /// Create controller with given number of drives
+    pub fn new(clock: &Rc<Clock>, drives: usize) -> Self {
+        Self {
+            clock: Rc::clone(clock),
+            units: (0..drives).map(|_| Unit::default()).collect(),
+            motor: Cell::new(false),
+            step_rate: Cell::new(6 * STEP_UNIT),
+            phase: Cell::new(Phase::Command),
+            received: RefCell::new(vec![]),
+            command: RefCell::new(vec![]),
+            result: RefCell::new(VecDeque::new()),
+            next_event: Cell::new(0),
+            id: Cell::new([0; 4]),
+            st0: Cell::new(0),
+            st1: Cell::new(0),
+            st2: Cell::new(0),
+            found: Cell::new(None),
+            request: Cell::new(false),
+            data: Cell::new(0),
+            buffer: RefCell::new(vec![]),
+            position: Cell::new(0),
+            length: Cell::new(0),
+            sectors: Cell::new(0),
+        }
+    }
+
+    /// Drive with given index
+    pub fn drive(&self, index: usize) -> &FloppyDrive {
+        &self.units[index].drive
+    }
+
+    /// Switch motors of all drives on or off. Drives are not ready with motor off.
+    pub fn set_motor(&self, on: bool) {
+        self.motor.set(on);
+    }
+
+    /// Drive motors are on
+    pub fn motor(&self) -> bool {
+        self.motor.get()
+    }
+
+    /// Command is in progress
+    pub fn is_busy(&self) -> bool {
+        self.phase.get() != Phase::Command || !self.received.borrow().is_empty()
+    }
+
+    /// Read main status register
+    pub fn status(&self) -> u8 {
+        let seeking = self.units.iter().enumerate()
+            .filter(|(_, unit)| unit.target.get().is_some())
+            .fold(0, |bits, (index, _)| bits | (1 << index));
+        let busy = if self.is_busy() { FDC_BUSY } else { 0 };
+        seeking | busy | match self.phase.get() {
+            Phase::Command => REQUEST,
+            Phase::Search => EXECUTION,
+            Phase::Read => EXECUTION | DATA_OUTPUT | if self.request.get() { REQUEST } else { 0 },
+            Phase::Write => EXECUTION | if self.request.get() { REQUEST } else { 0 },
+            Phase::Result => REQUEST | DATA_OUTPUT,
+        }
+    }
+
+    /// Read data register: data byte in read phase or result byte
+    pub fn read_data(&self) -> u8 {
+        match self.phase.get() {
+            Phase::Read => self.request.set(false),
+            Phase::Result => {
+                let mut result = self.result.borrow_mut();
+                if let Some(byte) = result.pop_front() {
+                    self.data.set(byte);
+                }
+                if result.is_empty() {
+                    self.phase.set(Phase::Command);
+                }
+            },
+            _ => (),
+        }
+        self.data.get()
+    }
+
+    /// Write data register: command or parameter byte, or data byte in write phase
+    pub fn write_data(&self, byte: u8) {
+        match self.phase.get() {
+            Phase::Command => {
+                let mut received = self.received.borrow_mut();
+                received.push(byte);
+                if received.len() >= command_length(received[0]) {
+                    let command = std::mem::take(&mut *received);
+                    drop(received);
+                    self.start(command);
+                }
+            },
+            Phase::Write if self.request.get() => {
+                self.data.set(byte);
+                self.request.set(false);
+            },
+            _ => (),
+        }
+    }
+
+    /// Advance seeks and command in progress to the current time
+    pub fn update(&self) {
+        let now = self.now();
+        for (index, unit) in self.units.iter().enumerate() {
+            while unit.target.get().is_some() && now >= unit.next_step.get() {
+                self.step(index);
+            }
+        }
+        while matches!(self.phase.get(), Phase::Search | Phase::Read | Phase::Write) && now >= self.next_event.get() {
+            self.event();
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.get() >> 1
+    }
+
+    fn opcode(&self) -> u8 {
+        self.command.borrow().first().map_or(0, |&command| command & 0x1f)
+    }
+
+    /// Unit and head selected by the command
+    fn selected(&self) -> (usize, u8) {
+        let select = self.command.borrow().get(1).copied().unwrap_or_default();
+        ((select & 0x03) as usize % self.units.len(), (select >> 2) & 1)
+    }
+
+    fn unit(&self) -> &Unit {
+        &self.units[self.selected().0]
+    }
+
+    fn is_ready(&self, unit: &Unit) -> bool {
+        self.motor.get() && unit.drive.is_ready()
+    }
+
+    /// Execute command with all its parameters
+    fn start(&self, command: Vec<u8>) {
+
+        self.command.replace(command.clone());
+        let opcode = self.opcode();
+        let (index, head) = self.selected();
+        let unit = &self.units[index];
+        let select = (head << 2) | index as u8;
+        self.st0.set(select);
+        self.st1.set(0);
+        self.st2.set(0);
+
+        match opcode {
+
+            SPECIFY => {
+                self.step_rate.set((16 - (command[1] >> 4) as u64) * STEP_UNIT);
+                self.phase.set(Phase::Command);
+            },
+
+            SENSE_DRIVE_STATUS => {
+                let mut st3 = select;
+                if unit.drive.is_write_protected() { st3 |= WRITE_PROTECTED; }
+                if self.is_ready(unit) { st3 |= READY; }
+                if unit.drive.cylinder() == 0 { st3 |= TRACK_0; }
+                if unit.drive.disk().is_some_and(|disk| disk.sides > 1) { st3 |= TWO_SIDE; }
+                self.finish(&[st3]);
+            },
+
+            SENSE_INTERRUPT_STATUS => {
+                match self.units.iter().find(|unit| unit.interrupt.get().is_some()) {
+                    Some(unit) => self.finish(&[unit.interrupt.take().unwrap_or_default(), unit.cylinder.get()]),
+                    None => self.finish(&[INVALID]),
+                }
+            },
+
+            SEEK | RECALIBRATE => {
+                unit.interrupt.set(None);
+                if opcode == SEEK {
+                    unit.target.set(Some(command[2]));
+                    unit.recalibrate.set(None);
+                } else {
+                    unit.target.set(Some(0));
+                    unit.recalibrate.set(Some(RECALIBRATE_STEPS));
+                }
+                unit.next_step.set(self.now());
+                self.phase.set(Phase::Command);
+            },
+
+            READ_DATA | READ_DELETED_DATA | WRITE_DATA | WRITE_DELETED_DATA | READ_TRACK => {
+                self.id.set(command[2..6].try_into().unwrap());
+                self.sectors.set(0);
+                if !self.is_ready(unit) {
+                    self.st0.update(|st0| st0 | ABNORMAL | NOT_READY);
+                    return self.finish_sector();
+                }
+                if matches!(opcode, WRITE_DATA | WRITE_DELETED_DATA) && unit.drive.is_write_protected() {
+                    self.st0.update(|st0| st0 | ABNORMAL);
+                    self.st1.set(NOT_WRITABLE);
+                    return self.finish_sector();
+                }
+                let now = self.now();
+                // Read track starts at the index hole
+                self.search(if opcode == READ_TRACK { now + REVOLUTION - now % REVOLUTION } else { now });
+            },
+
+            READ_ID => {
+                if !self.is_ready(unit) {
+                    self.st0.update(|st0| st0 | ABNORMAL | NOT_READY);
+                    return self.finish_sector();
+                }
+                self.search(self.now());
+            },
+
+            FORMAT_TRACK => {
+                self.id.set([unit.cylinder.get(), head, 1, command[2]]);
+                if !self.is_ready(unit) {
+                    self.st0.update(|st0| st0 | ABNORMAL | NOT_READY);
+                    return self.finish_sector();
+                }
+                if unit.drive.is_write_protected() {
+                    self.st0.update(|st0| st0 | ABNORMAL);
+                    self.st1.set(NOT_WRITABLE);
+                    return self.finish_sector();
+                }
+                // Sector IDs are requested from the index hole on
+                let now = self.now();
+                self.request.set(true);
+                self.length.set(4 * command[3] as usize);
+                self.transfer(Phase::Write, vec![], now + REVOLUTION - now % REVOLUTION);
+            },
+
+            _ => self.finish(&[INVALID]),
+
+        }
+
+    }
+
+    /// Step the head of the seeking drive
+    fn step(&self, index: usize) {
+        let unit = &self.units[index];
+        let Some(target) = unit.target.get() else { return };
+        let cylinder = unit.cylinder.get();
+        let done = match unit.recalibrate.get() {
+            Some(_) if unit.drive.cylinder() == 0 => {
+                unit.cylinder.set(0);
+                Some(SEEK_END)
+            },
+            Some(0) => Some(SEEK_END | ABNORMAL | EQUIPMENT_CHECK),
+            Some(steps) => {
+                unit.recalibrate.set(Some(steps - 1));
+                unit.drive.step(false);
+                None
+            },
+            None if cylinder == target => Some(SEEK_END),
+            None => {
+                unit.cylinder.set(if target > cylinder { cylinder + 1 } else { cylinder - 1 });
+                unit.drive.step(target > cylinder);
+                None
+            },
+        };
+        match done {
+            Some(st0) => {
+                let not_ready = if self.is_ready(unit) { 0 } else { ABNORMAL | NOT_READY };
+                unit.target.set(None);
+                unit.recalibrate.set(None);
+                unit.interrupt.set(Some(st0 | not_ready | index as u8));
+            },
+            None => unit.next_step.set(unit.next_step.get() + self.step_rate.get()),
+        }
+    }
+
+    /// Look for the sector ID matching the command starting at given time.
+    /// Search phase ends when the ID has passed the head, or after the index
+    /// hole has passed twice if it isn't found.
+    fn search(&self, start: u64) {
+        let opcode = self.opcode();
+        let id = self.id.get();
+        let track = self.unit().drive.track(self.selected().1);
+        let found = match opcode {
+            // Read track takes sectors one after another from the index hole
+            READ_TRACK => {
+                let index = self.sectors.get() as usize;
+                (index < track.len()).then(|| ((id_position(index, track.len()) + REVOLUTION - start % REVOLUTION) % REVOLUTION, index))
+            },
+            _ => track.iter().enumerate()
+                .filter(|(_, sector)| opcode == READ_ID || sector_id(sector) == id)
+                .map(|(index, _)| ((id_position(index, track.len()) + REVOLUTION - start % REVOLUTION) % REVOLUTION, index))
+                .min(),
+        };
+        self.found.set(found.map(|(_, index)| index));
+        match found {
+            Some((position, _)) => self.schedule(Phase::Search, start + position + ID_FIELD * BYTE),
+            None => {
+                if track.is_empty() {
+                    self.st1.set(MISSING_ADDRESS);
+                } else {
+                    self.st1.set(NO_DATA);
+                    if let Some(other) = track.iter().find(|sector| sector.sector == id[2] && sector.cylinder != id[0]) {
+                        self.st2.set(if other.cylinder == 0xff { BAD_CYLINDER } else { WRONG_CYLINDER });
+                    }
+                }
+                self.st0.update(|st0| st0 | ABNORMAL);
+                self.schedule(Phase::Search, start + SEARCH_REVOLUTIONS * REVOLUTION);
+            },
+        }
+    }
+
+    fn schedule(&self, phase: Phase, time: u64) {
+        self.phase.set(phase);
+        self.next_event.set(time);
+    }
+
+    /// Start data transfer phase with given buffer at given time
+    fn transfer(&self, phase: Phase, buffer: Vec<u8>, time: u64) {
+        self.buffer.replace(buffer);
+        self.position.set(0);
+        self.schedule(phase, time);
+    }
+
+    /// Handle scheduled event of the current phase
+    fn event(&self) {
+
+        let now = self.next_event.get();
+        let opcode = self.opcode();
+        let (index, head) = self.selected();
+        let drive = &self.units[index].drive;
+
+        match self.phase.get() {
+
+            Phase::Command | Phase::Result => (),
+
+            Phase::Search => {
+                let track = drive.track(head);
+                let Some(found) = self.found.get() else { return self.finish_sector() };
+                let sector = &track[found];
+                match opcode {
+                    READ_ID => {
+                        self.id.set(sector_id(sector));
+                        self.finish_sector();
+                    },
+                    WRITE_DATA | WRITE_DELETED_DATA => {
+                        self.request.set(true);
+                        self.length.set(self.data_length());
+                        self.transfer(Phase::Write, vec![], now + ID_TO_DATA * BYTE);
+                    },
+                    _ => {
+                        if opcode == READ_TRACK && sector_id(sector) != self.id.get() {
+                            self.st1.update(|st1| st1 | NO_DATA);
+                        }
+                        // Sector with the other data mark is skipped or ends the command
+                        if opcode != READ_TRACK && sector.deleted != (opcode == READ_DELETED_DATA) {
+                            self.st2.update(|st2| st2 | CONTROL_MARK);
+                            if self.command.borrow()[0] & SKIP != 0 {
+                                return self.next_sector(now);
+                            }
+                        }
+                        let mut data = drive.read_sector(head, found).unwrap_or_default();
+                        data.resize(self.data_length(), 0x4e);
+                        self.request.set(false);
+                        self.transfer(Phase::Read, data, now + ID_TO_DATA * BYTE);
+                    },
+                }
+            },
+
+            Phase::Read => {
+                if self.request.get() {
+                    return self.overrun();
+                }
+                let buffer = self.buffer.borrow();
+                let position = self.position.get();
+                if position < buffer.len() {
+                    self.data.set(buffer[position]);
+                    self.request.set(true);
+                    self.position.set(position + 1);
+                    self.next_event.set(now + BYTE);
+                } else {
+                    drop(buffer);
+                    // Errors recorded for the sector are reported after its data
+                    let sector = self.found.get().and_then(|found| drive.track(head).get(found).cloned());
+                    if let Some(sector) = sector.filter(|sector| sector.st1 != 0 || sector.st2 != 0) {
+                        self.st0.update(|st0| st0 | ABNORMAL);
+                        self.st1.update(|st1| st1 | sector.st1);
+                        self.st2.update(|st2| st2 | sector.st2);
+                        return self.finish_sector();
+                    }
+                    let skip = self.command.borrow()[0] & SKIP != 0;
+                    if self.st2.get() & CONTROL_MARK != 0 && !skip && opcode != READ_TRACK {
+                        return self.finish_sector();
+                    }
+                    self.next_sector(now);
+                }
+            },
+
+            Phase::Write => {
+                if self.request.get() {
+                    return self.overrun();
+                }
+                let mut buffer = self.buffer.borrow_mut();
+                buffer.push(self.data.get());
+                if buffer.len() < self.length.get() {
+                    self.request.set(true);
+                    // Format requests each sector ID as the sector passes the head
+                    self.next_event.set(match opcode {
+                        FORMAT_TRACK if buffer.len().is_multiple_of(4) => {
+                            let count = self.length.get() / 4;
+                            now - now % REVOLUTION + id_position(buffer.len() / 4, count)
+                        },
+                        _ => now + BYTE,
+                    });
+                    return;
+                }
+                let buffer = std::mem::take(&mut *buffer);
+                match opcode {
+                    FORMAT_TRACK => {
+                        let (size_code, filler) = {
+                            let command = self.command.borrow();
+                            (command[2], command[5])
+                        };
+                        let sectors: Vec<_> = buffer.chunks_exact(4).map(|id| {
+                            let mut sector = DiskSector::new(id[0], id[1], id[2], vec![filler; 128 << size_code.min(MAX_SIZE_CODE)]);
+                            sector.size_code = id[3];
+                            sector
+                        }).collect();
+                        if let Some(last) = sectors.last() {
+                            self.id.set(sector_id(last));
+                        }
+                        drive.write_track(head, sectors);
+                        self.finish_sector();
+                    },
+                    _ => {
+                        if let Some(found) = self.found.get() {
+                            drive.write_sector(head, found, &buffer, opcode == WRITE_DELETED_DATA);
+                        }
+                        self.next_sector(now);
+                    },
+                }
+            },
+
+        }
+
+    }
+
+    /// Bytes transferred for each sector: sector size, or data length if size code is 0
+    fn data_length(&self) -> usize {
+        let [.., size_code] = self.id.get();
+        match size_code {
+            0 => self.command.borrow()[8] as usize,
+            _ => 128 << size_code.min(MAX_SIZE_CODE),
+        }
+    }
+
+    /// End data transfer as CPU has missed the byte
+    fn overrun(&self) {
+        self.request.set(false);
+        self.st0.update(|st0| st0 | ABNORMAL);
+        self.st1.update(|st1| st1 | OVERRUN);
+        self.finish_sector();
+    }
+
+    /// Continue with the next sector after CRC passes, or end the command after
+    /// the last sector of the track or the cylinder
+    fn next_sector(&self, now: u64) {
+        let [cylinder, head, sector, size_code] = self.id.get();
+        let eot = self.command.borrow()[6];
+        self.sectors.update(|sectors| sectors + 1);
+        let last = match self.opcode() {
+            READ_TRACK => self.sectors.get() >= eot,
+            _ => sector == eot,
+        };
+        if !last {
+            self.id.set([cylinder, head, sector.wrapping_add(1), size_code]);
+            return self.search(now + 2 * BYTE);
+        }
+        let multi_track = self.command.borrow()[0] & MULTI_TRACK != 0;
+        if multi_track && self.selected().1 == 0 && self.opcode() != READ_TRACK {
+            // Multi-track command continues on side 1
+            self.command.borrow_mut()[1] |= 0x04;
+            self.st0.update(|st0| st0 | 0x04);
+            self.id.set([cylinder, head ^ 1, 1, size_code]);
+            return self.search(now + 2 * BYTE);
+        }
+        self.id.set([cylinder.wrapping_add(1), head, 1, size_code]);
+        self.st0.update(|st0| st0 | ABNORMAL);
+        self.st1.update(|st1| st1 | END_OF_CYLINDER);
+        self.finish_sector();
+    }
+
+    /// Enter result phase of read, write and format commands
+    fn finish_sector(&self) {
+        let [cylinder, head, sector, size_code] = self.id.get();
+        self.finish(&[self.st0.get(), self.st1.get(), self.st2.get(), cylinder, head, sector, size_code]);
+    }
+
+    /// Enter result phase with given bytes, or command phase if there are none
+    fn finish(&self, result: &[u8]) {
+        self.request.set(false);
+        self.result.replace(result.iter().copied().collect());
+        self.phase.set(if result.is_empty() { Phase::Command } else { Phase::Result });
+    }
+
+}
+
+/// Command and parameter bytes of the command
+fn command_length(command: u8) -> usize {
+    match command & 0x1f {
+        SPECIFY | SEEK => 3,
+        SENSE_DRIVE_STATUS | RECALIBRATE | READ_ID => 2,
+        READ_TRACK | WRITE_DATA | READ_DATA | WRITE_DELETED_DATA | READ_DELETED_DATA => 9,
+        FORMAT_TRACK => 6,
+        _ => 1,
+    }
+}
+
+/// Sector ID: cylinder, head, sector and size code
+fn sector_id(sector: &DiskSector) -> [u8; 4] {
+    [sector.cylinder, sector.head, sector.sector, sector.size_code]
+}
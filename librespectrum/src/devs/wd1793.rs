@@ -0,0 +1,588 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::core::Clock;
+
+use super::{DiskSector, FloppyDrive, disk::{BYTE, ID_FIELD, ID_TO_DATA, REVOLUTION, TRACK_BYTES, id_position}};
+
+/// Index pulse length (4 ms)
+const INDEX_PULSE: u64 = 14_000;
+
+/// Bytes of ID field sync and address mark
+const ID_MARK: u64 = 16;
+
+/// Head load and settle delay (15 ms)
+const SETTLE: u64 = 52_500;
+
+/// Stepping rates selected by command bits 0-1 (6, 12, 20 and 30 ms)
+const STEP_RATES: [u64; 4] = [21_000, 42_000, 70_000, 105_000];
+
+/// Revolutions to look for the sector ID before giving up
+const SEARCH_REVOLUTIONS: u64 = 5;
+
+/// Status register bits, some have different meaning after type I commands
+const NOT_READY: u8 = 0x80;
+const WRITE_PROTECT: u8 = 0x40;
+const HEAD_LOADED: u8 = 0x20;
+const RECORD_TYPE: u8 = 0x20;
+const SEEK_ERROR: u8 = 0x10;
+const RECORD_NOT_FOUND: u8 = 0x10;
+const CRC_ERROR: u8 = 0x08;
+const TRACK_0: u8 = 0x04;
+const LOST_DATA: u8 = 0x04;
+const INDEX: u8 = 0x02;
+const DATA_REQUEST: u8 = 0x02;
+const BUSY: u8 = 0x01;
+
+/// Command flags
+const FLAG_DELETED: u8 = 0x01;
+const FLAG_COMPARE_SIDE: u8 = 0x02;
+const FLAG_VERIFY: u8 = 0x04;
+const FLAG_DELAY: u8 = 0x04;
+const FLAG_HEAD_LOAD: u8 = 0x08;
+const FLAG_SIDE: u8 = 0x08;
+const FLAG_UPDATE: u8 = 0x10;
+const FLAG_MULTIPLE: u8 = 0x10;
+
+/// CRC-CCITT of ID and data fields
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+/// ID field bytes following the address mark, with CRC
+fn id_field(sector: &DiskSector) -> [u8; 6] {
+    let crc = crc16(&[0xa1, 0xa1, 0xa1, 0xfe, sector.cylinder, sector.head, sector.sector, sector.size_code]);
+    let [hi, lo] = crc.to_be_bytes();
+    [sector.cylinder, sector.head, sector.sector, sector.size_code, hi, lo]
+}
+
+/// Track contents as read by READ TRACK command, in IBM System 34 layout
+fn raw_track(sectors: &[DiskSector]) -> Vec<u8> {
+    let mut raw = [vec![0x4e; 80], vec![0x00; 12], vec![0xc2; 3], vec![0xfc], vec![0x4e; 50]].concat();
+    for sector in sectors {
+        raw.extend([0x00; 12]);
+        raw.extend([0xa1, 0xa1, 0xa1, 0xfe]);
+        raw.extend(id_field(sector));
+        raw.extend([0x4e; 22]);
+        raw.extend([0x00; 12]);
+        let mark = if sector.deleted { 0xf8 } else { 0xfb };
+        let field = [&[0xa1, 0xa1, 0xa1, mark], sector.data.as_slice()].concat();
+        raw.extend(&field);
+        raw.extend(crc16(&field).to_be_bytes());
+        raw.extend([0x4e; 54]);
+    }
+    raw.resize(raw.len().max(TRACK_BYTES), 0x4e);
+    raw
+}
+
+/// Sectors formatted by bytes written with WRITE TRACK command, where F5h
+/// writes sync byte A1h followed by the address mark
+fn parse_track(raw: &[u8]) -> Vec<DiskSector> {
+    let mut sectors = vec![];
+    let mut id: Option<&[u8]> = None;
+    let mut index = 0;
+    while index < raw.len() {
+        if raw[index] != 0xf5 {
+            index += 1;
+            continue;
+        }
+        while raw.get(index) == Some(&0xf5) {
+            index += 1;
+        }
+        match raw.get(index).copied() {
+            Some(0xfe) => if let Some(field) = raw.get(index + 1..index + 5) {
+                id = Some(field);
+                index += 5;
+            },
+            Some(mark @ (0xfb | 0xf8)) => if let Some(&[cylinder, head, sector, size_code]) = id.take() {
+                let size = 128 << (size_code & 0x03);
+                if let Some(data) = raw.get(index + 1..index + 1 + size) {
+                    let mut sector = DiskSector::new(cylinder, head, sector, data.to_vec());
+                    sector.size_code = size_code;
+                    sector.deleted = mark == 0xf8;
+                    sectors.push(sector);
+                    index += size;
+                }
+            },
+            _ => (),
+        }
+    }
+    sectors
+}
+
+/// Command execution phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Type I command is stepping the head
+    Step,
+    /// Type I command is verifying the track number
+    Verify,
+    /// Waiting for the sector ID or the index pulse
+    Search,
+    /// Bytes are transferred to CPU
+    Read,
+    /// Bytes are transferred from CPU
+    Write,
+}
+
+/// WD1793 floppy disk controller. Commands run on the clock in t-states:
+/// head steps at the selected rate, sectors pass the head as the disk rotates,
+/// and data bytes are requested every 32 µs. Lost data is flagged when CPU
+/// doesn't keep up with data requests.
+pub struct Wd1793 {
+    clock: Rc<Clock>,
+    drives: Vec<FloppyDrive>,
+    drive: Cell<usize>,
+    side: Cell<u8>,
+    command: Cell<u8>,
+    status: Cell<u8>,
+    track: Cell<u8>,
+    sector: Cell<u8>,
+    data: Cell<u8>,
+    /// Status shows type I command bits
+    type1: Cell<bool>,
+    head_loaded: Cell<bool>,
+    inward: Cell<bool>,
+    drq: Cell<bool>,
+    intrq: Cell<bool>,
+    phase: Cell<Phase>,
+    /// Time of the next phase event in t-states
+    next_event: Cell<u64>,
+    /// Steps left to do by type I command
+    steps: Cell<u8>,
+    /// Index of the sector found by search in the track under the head
+    found: Cell<Option<usize>>,
+    buffer: RefCell<Vec<u8>>,
+    /// Bytes transferred and bytes to transfer in write phase
+    position: Cell<usize>,
+    length: Cell<usize>,
+}
+
+impl Wd1793 {
+
+    /// Create controller with given number of drives
+    pub fn new(clock: &Rc<Clock>, drives: usize) -> Self {
+        Self {
+            clock: Rc::clone(clock),
+            drives: (0..drives).map(|_| FloppyDrive::default()).collect(),
+            drive: Cell::new(0),
+            side: Cell::new(0),
+            command: Cell::new(0),
+            status: Cell::new(0),
+            track: Cell::new(0),
+            sector: Cell::new(1),
+            data: Cell::new(0),
+            type1: Cell::new(true),
+            head_loaded: Cell::new(false),
+            inward: Cell::new(true),
+            drq: Cell::new(false),
+            intrq: Cell::new(false),
+            phase: Cell::new(Phase::Idle),
+            next_event: Cell::new(0),
+            steps: Cell::new(0),
+            found: Cell::new(None),
+            buffer: RefCell::new(vec![]),
+            position: Cell::new(0),
+            length: Cell::new(0),
+        }
+    }
+
+    /// Drive with given index
+    pub fn drive(&self, index: usize) -> &FloppyDrive {
+        &self.drives[index]
+    }
+
+    /// Select drive and side of the disk
+    pub fn select(&self, drive: usize, side: u8) {
+        self.drive.set(drive.min(self.drives.len() - 1));
+        self.side.set(side);
+    }
+
+    /// Master reset aborting the command in progress
+    pub fn reset(&self) {
+        self.phase.set(Phase::Idle);
+        self.command.set(0);
+        self.status.set(0);
+        self.sector.set(1);
+        self.type1.set(true);
+        self.drq.set(false);
+        self.intrq.set(false);
+    }
+
+    /// Data request output
+    pub fn drq(&self) -> bool {
+        self.drq.get()
+    }
+
+    /// Interrupt request output
+    pub fn intrq(&self) -> bool {
+        self.intrq.get()
+    }
+
+    /// Command is in progress
+    pub fn is_busy(&self) -> bool {
+        self.phase.get() != Phase::Idle
+    }
+
+    /// Read register: status, track, sector or data (0-3)
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg & 0x03 {
+            0 => {
+                self.intrq.set(false);
+                self.status()
+            },
+            1 => self.track.get(),
+            2 => self.sector.get(),
+            _ => {
+                self.drq.set(false);
+                self.data.get()
+            },
+        }
+    }
+
+    /// Write register: command, track, sector or data (0-3)
+    pub fn write(&self, reg: u8, byte: u8) {
+        match reg & 0x03 {
+            0 => self.command(byte),
+            1 => self.track.set(byte),
+            2 => self.sector.set(byte),
+            _ => {
+                self.drq.set(false);
+                self.data.set(byte);
+            },
+        }
+    }
+
+    /// Advance command in progress to the current time
+    pub fn update(&self) {
+        let now = self.clock.get() >> 1;
+        while self.phase.get() != Phase::Idle && now >= self.next_event.get() {
+            self.event();
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.get() >> 1
+    }
+
+    fn current_drive(&self) -> &FloppyDrive {
+        &self.drives[self.drive.get()]
+    }
+
+    fn status(&self) -> u8 {
+        let drive = self.current_drive();
+        let busy = if self.is_busy() { BUSY } else { 0 };
+        let not_ready = if drive.is_ready() { 0 } else { NOT_READY };
+        if self.type1.get() {
+            let mut status = self.status.get() & (SEEK_ERROR | CRC_ERROR) | not_ready | busy;
+            if drive.is_write_protected() { status |= WRITE_PROTECT; }
+            if self.head_loaded.get() { status |= HEAD_LOADED; }
+            if drive.cylinder() == 0 { status |= TRACK_0; }
+            if drive.is_ready() && self.now() % REVOLUTION < INDEX_PULSE { status |= INDEX; }
+            status
+        } else {
+            let drq = if self.drq.get() { DATA_REQUEST } else { 0 };
+            self.status.get() & !(NOT_READY | DATA_REQUEST | BUSY) | not_ready | drq | busy
+        }
+    }
+
+    /// Start command
+    fn command(&self, command: u8) {
+
+        if command & 0xf0 == 0xd0 {
+            return self.force_interrupt(command);
+        }
+        if self.is_busy() {
+            return;
+        }
+
+        let now = self.now();
+        self.command.set(command);
+        self.status.set(0);
+        self.intrq.set(false);
+        self.drq.set(false);
+
+        if command & 0x80 == 0 {
+            // Type I: restore, seek, step, step in and step out
+            self.type1.set(true);
+            self.head_loaded.set(command & FLAG_HEAD_LOAD != 0);
+            match command >> 5 {
+                0 => self.steps.set(if command & 0x10 == 0 { 255 } else { 0 }),
+                1 => self.steps.set(1),
+                2 => { self.inward.set(true); self.steps.set(1); },
+                _ => { self.inward.set(false); self.steps.set(1); },
+            }
+            self.schedule(Phase::Step, now);
+            return;
+        }
+
+        // Type II and III: read and write sector, read address, read and write track
+        self.type1.set(false);
+        let drive = self.current_drive();
+        let writing = matches!(command >> 4, 0xa | 0xb | 0xf);
+        if !drive.is_ready() {
+            self.status.set(NOT_READY);
+            return self.finish();
+        }
+        if writing && drive.is_write_protected() {
+            self.status.set(WRITE_PROTECT);
+            return self.finish();
+        }
+        self.head_loaded.set(true);
+        let start = now + if command & FLAG_DELAY != 0 { SETTLE } else { 0 };
+        if command >> 5 == 7 {
+            // Track commands start at the index pulse, write track requests the first byte right away
+            self.drq.set(command & 0x10 != 0);
+            self.schedule(Phase::Search, start + (REVOLUTION - start % REVOLUTION) % REVOLUTION);
+        } else {
+            self.search(start);
+        }
+
+    }
+
+    /// Terminate command in progress. Interrupt is requested if any condition is given.
+    fn force_interrupt(&self, command: u8) {
+        if !self.is_busy() {
+            self.type1.set(true);
+            self.status.set(0);
+        }
+        self.phase.set(Phase::Idle);
+        self.drq.set(false);
+        self.intrq.set(command & 0x0f != 0);
+    }
+
+    fn schedule(&self, phase: Phase, time: u64) {
+        self.phase.set(phase);
+        self.next_event.set(time);
+    }
+
+    /// Complete command requesting interrupt
+    fn finish(&self) {
+        self.phase.set(Phase::Idle);
+        self.drq.set(false);
+        self.intrq.set(true);
+    }
+
+    /// Look for the sector ID matching the command starting at given time.
+    /// Search phase ends when the ID has passed the head, or after several
+    /// revolutions if it isn't found.
+    fn search(&self, start: u64) {
+        let command = self.command.get();
+        let matches = |sector: &DiskSector| match command >> 4 {
+            // Read address takes any ID, type I verify checks the track only
+            0xc => true,
+            0x0..=0x7 => sector.cylinder == self.track.get(),
+            _ => sector.cylinder == self.track.get() && sector.sector == self.sector.get()
+                && (command & FLAG_COMPARE_SIDE == 0 || (sector.head & 1 != 0) == (command & FLAG_SIDE != 0)),
+        };
+        let track = self.current_drive().track(self.side.get());
+        let found = track.iter().enumerate()
+            .filter(|(_, sector)| matches(sector))
+            .map(|(index, _)| {
+                let position = (id_position(index, track.len()) + REVOLUTION - start % REVOLUTION) % REVOLUTION;
+                (position, index)
+            })
+            .min();
+        self.found.set(found.map(|(_, index)| index));
+        let phase = if command & 0x80 == 0 { Phase::Verify } else { Phase::Search };
+        // Read address transfers ID field bytes as they pass the head
+        let passed = if command >> 4 == 0xc { ID_MARK } else { ID_FIELD };
+        match found {
+            Some((position, _)) => self.schedule(phase, start + position + passed * BYTE),
+            None => self.schedule(phase, start + SEARCH_REVOLUTIONS * REVOLUTION),
+        }
+    }
+
+    /// Handle scheduled event of the current phase
+    fn event(&self) {
+
+        let now = self.next_event.get();
+        let command = self.command.get();
+        let side = self.side.get();
+        let drive = self.current_drive();
+
+        match self.phase.get() {
+
+            Phase::Idle => (),
+
+            Phase::Step => {
+                let done = match command >> 4 {
+                    // Restore steps out until track 0 is reached
+                    0 => {
+                        if drive.cylinder() == 0 {
+                            self.track.set(0);
+                            true
+                        } else if self.steps.get() == 0 {
+                            self.status.set(SEEK_ERROR);
+                            return self.finish();
+                        } else {
+                            self.steps.update(|steps| steps - 1);
+                            drive.step(false);
+                            false
+                        }
+                    },
+                    // Seek steps until track register matches data register
+                    1 => {
+                        let (track, target) = (self.track.get(), self.data.get());
+                        if track != target {
+                            self.inward.set(target > track);
+                            self.track.set(if target > track { track + 1 } else { track - 1 });
+                            drive.step(target > track);
+                        }
+                        track == target
+                    },
+                    _ => {
+                        if self.steps.get() > 0 {
+                            self.steps.set(0);
+                            if command & FLAG_UPDATE != 0 {
+                                let track = self.track.get();
+                                self.track.set(if self.inward.get() { track.wrapping_add(1) } else { track.wrapping_sub(1) });
+                            }
+                            drive.step(self.inward.get());
+                            false
+                        } else {
+                            true
+                        }
+                    },
+                };
+                if !done {
+                    self.next_event.set(now + STEP_RATES[(command & 0x03) as usize]);
+                } else if command & FLAG_VERIFY != 0 {
+                    self.head_loaded.set(true);
+                    self.search(now + SETTLE);
+                } else {
+                    self.finish();
+                }
+            },
+
+            Phase::Verify => {
+                if self.found.get().is_none() {
+                    self.status.set(SEEK_ERROR);
+                }
+                self.finish();
+            },
+
+            Phase::Search => {
+                let track = drive.track(side);
+                let sector = self.found.get().map(|index| &track[index]);
+                match command >> 4 {
+                    0xe => self.transfer(Phase::Read, raw_track(&track), now + BYTE),
+                    0xf => {
+                        self.length.set(TRACK_BYTES);
+                        self.transfer(Phase::Write, vec![], now);
+                    },
+                    _ => match sector {
+                        None => {
+                            self.status.set(RECORD_NOT_FOUND);
+                            self.finish();
+                        },
+                        Some(sector) => match command >> 4 {
+                            0xc => {
+                                self.sector.set(sector.cylinder);
+                                self.transfer(Phase::Read, id_field(sector).to_vec(), now);
+                            },
+                            0x8 | 0x9 => {
+                                if sector.deleted {
+                                    self.status.set(RECORD_TYPE);
+                                }
+                                let data = drive.read_sector(side, self.found.get().unwrap_or_default()).unwrap_or_default();
+                                self.transfer(Phase::Read, data, now + ID_TO_DATA * BYTE);
+                            },
+                            _ => {
+                                self.drq.set(true);
+                                self.length.set(sector.data.len());
+                                self.transfer(Phase::Write, vec![], now + ID_TO_DATA * BYTE);
+                            },
+                        },
+                    },
+                }
+            },
+
+            Phase::Read => {
+                let buffer = self.buffer.borrow();
+                let position = self.position.get();
+                if position < buffer.len() {
+                    if self.drq.get() {
+                        self.status.update(|status| status | LOST_DATA);
+                    }
+                    self.data.set(buffer[position]);
+                    self.drq.set(true);
+                    self.position.set(position + 1);
+                    self.next_event.set(now + BYTE);
+                } else {
+                    drop(buffer);
+                    self.next_sector(now);
+                }
+            },
+
+            Phase::Write => {
+                let byte = if self.drq.get() {
+                    self.status.update(|status| status | LOST_DATA);
+                    0
+                } else {
+                    self.data.get()
+                };
+                let mut buffer = self.buffer.borrow_mut();
+                buffer.push(byte);
+                if buffer.len() < self.length.get() {
+                    self.drq.set(true);
+                    self.next_event.set(now + BYTE);
+                } else {
+                    match (command >> 4, self.found.get()) {
+                        (0xf, _) => drive.write_track(side, parse_track(&buffer)),
+                        (_, Some(index)) => drive.write_sector(side, index, &buffer, command & FLAG_DELETED != 0),
+                        _ => (),
+                    }
+                    drop(buffer);
+                    self.drq.set(false);
+                    self.next_sector(now);
+                }
+            },
+
+        }
+
+    }
+
+    /// Start data transfer phase with given buffer at given time
+    fn transfer(&self, phase: Phase, buffer: Vec<u8>, time: u64) {
+        self.buffer.replace(buffer);
+        self.position.set(0);
+        self.schedule(phase, time);
+    }
+
+    /// Continue multiple sector command with the next sector after CRC passes, or complete the command
+    fn next_sector(&self, now: u64) {
+        let command = self.command.get();
+        if matches!(command >> 5, 4 | 5) && command & FLAG_MULTIPLE != 0 {
+            self.sector.set(self.sector.get().wrapping_add(1));
+            self.search(now + 2 * BYTE);
+        } else {
+            self.finish();
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn formatted_track_reads_back() {
+        let sectors: Vec<_> = (1..=16).map(|sector| DiskSector::new(5, 0, sector, vec![sector; 256])).collect();
+        // Written F5h bytes are read back as A1h sync bytes
+        let written: Vec<u8> = raw_track(&sectors).iter().map(|&byte| if byte == 0xa1 { 0xf5 } else { byte }).collect();
+        assert_eq!(parse_track(&written), sectors);
+        assert!(raw_track(&sectors).len() == TRACK_BYTES);
+    }
+
+    #[test]
+    fn id_field_crc() {
+        // Well-known CRC of A1 A1 A1 FE 00 00 01 01 ID field
+        assert_eq!(id_field(&DiskSector::new(0, 0, 1, vec![0; 256]))[4..], [0xfa, 0x0c]);
+    }
+
+}
@@ -0,0 +1,189 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifiable, Identifier, NoReturnTask},
+    devs::{Device, IoDevice, run_io_device},
+    formats::bitmap::Bitmap,
+};
+
+/// Printer port. The printer decodes A2 only, but the port is decoded fully so
+/// it doesn't clash with other interfaces.
+const PORT: u8 = 0xfb;
+
+/// Port bits written
+const SLOW: u8 = 0x02;
+const MOTOR_OFF: u8 = 0x04;
+const STYLUS_ON: u8 = 0x80;
+
+/// Port bits read. Bit 6 is 0 when the printer is present.
+const ENCODER: u8 = 0x01;
+const LINE_START: u8 = 0x80;
+
+/// Dots across the paper
+pub const PAPER_WIDTH: usize = 256;
+
+/// Encoder steps per stylus pass: dots on the paper, then the way back to its left edge
+const PASS_STEPS: usize = 384;
+
+/// Steps before the left edge during which line start is signalled
+const LEAD_IN_STEPS: usize = 32;
+
+/// Printer model, which defines the stylus speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrinterModel {
+    /// ZX Printer: spark printer with aluminium paper, slowed down for the last lines of a printout
+    ZxPrinter,
+    /// Alphacom 32: thermal printer driven the same way, which prints faster and has no slow speed
+    Alphacom32,
+}
+
+impl PrinterModel {
+
+    /// Duration of an encoder step in t-states
+    fn step_time(&self, slow: bool) -> u64 {
+        match self {
+            PrinterModel::ZxPrinter if slow => 2000,
+            PrinterModel::ZxPrinter => 1000,
+            PrinterModel::Alphacom32 => 600,
+        }
+    }
+
+}
+
+/// ZX Printer on port FBh. Stylus moves across the paper while the motor is on,
+/// and the paper advances by a dot row on each pass. Encoder disk signal (bit 0)
+/// is latched as the stylus reaches the next dot and cleared by writing to the
+/// port, bit 7 is set just before the stylus reaches the left edge. Dot is
+/// printed when the stylus is powered (bit 7 written) while it's over the dot.
+/// Printed paper strip grows by a bitmap row on each pass.
+pub struct ZxPrinter {
+    id: Identifier,
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    model: PrinterModel,
+    /// Last value written to the port
+    control: Cell<u8>,
+    /// Stylus position in encoder steps from the left edge of the paper
+    position: Cell<usize>,
+    encoder: Cell<bool>,
+    /// Time of the next encoder step while the motor is on
+    next_step: Cell<Option<u64>>,
+    paper: RefCell<Bitmap>,
+}
+
+impl ZxPrinter {
+
+    pub fn new(id: Identifier, bus: &Rc<CpuBus>, clock: &Rc<Clock>, model: PrinterModel) -> Self {
+        Self {
+            id,
+            bus: Rc::clone(bus),
+            clock: Rc::clone(clock),
+            model,
+            control: Cell::new(MOTOR_OFF),
+            position: Cell::new(PAPER_WIDTH),
+            encoder: Cell::new(false),
+            next_step: Cell::new(None),
+            paper: RefCell::new(Bitmap::new(PAPER_WIDTH, 0)),
+        }
+    }
+
+    pub fn model(&self) -> PrinterModel {
+        self.model
+    }
+
+    pub fn motor(&self) -> bool {
+        self.control.get() & MOTOR_OFF == 0
+    }
+
+    /// Copy of the printed paper strip
+    pub fn paper(&self) -> Bitmap {
+        self.paper.borrow().clone()
+    }
+
+    /// Take the printed paper strip, leaving the printer with blank paper
+    pub fn tear_off(&self) -> Bitmap {
+        self.paper.replace(Bitmap::new(PAPER_WIDTH, 0))
+    }
+
+    fn stylus(&self) -> bool {
+        self.control.get() & STYLUS_ON != 0
+    }
+
+    /// Blacken the dot under the stylus if it's powered and over the paper
+    fn burn(&self) {
+        let mut paper = self.paper.borrow_mut();
+        let position = self.position.get();
+        if self.stylus() && position < PAPER_WIDTH && paper.height > 0 {
+            let y = paper.height - 1;
+            paper.set_pixel(position, y, true);
+        }
+    }
+
+    /// Move the stylus to the next dot. New dot row starts at the left edge.
+    fn step(&self) {
+        self.burn();
+        let position = (self.position.get() + 1) % PASS_STEPS;
+        self.position.set(position);
+        if position == 0 {
+            let mut paper = self.paper.borrow_mut();
+            let stride = paper.stride();
+            paper.data.extend(vec![0; stride]);
+            paper.height += 1;
+        }
+        self.encoder.set(position < PAPER_WIDTH);
+    }
+
+}
+
+impl Identifiable for ZxPrinter {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl IoDevice for ZxPrinter {
+
+    fn clock_tick(&self) {
+        if self.motor() {
+            let now = self.clock.get() >> 1;
+            let step_time = self.model.step_time(self.control.get() & SLOW != 0);
+            let mut time = self.next_step.get().unwrap_or(now + step_time);
+            if now >= time {
+                self.step();
+                time += step_time;
+            }
+            self.next_step.set(Some(time));
+        } else {
+            self.next_step.set(None);
+        }
+    }
+
+    fn read_port(&self, addr: u16) -> Option<u8> {
+        if addr as u8 != PORT {
+            return None;
+        }
+        let mut byte = 0x3e;
+        if self.encoder.get() {
+            byte |= ENCODER;
+        }
+        if self.position.get() >= PASS_STEPS - LEAD_IN_STEPS {
+            byte |= LINE_START;
+        }
+        Some(byte)
+    }
+
+    fn write_port(&self, addr: u16, byte: u8) {
+        if addr as u8 == PORT {
+            self.control.set(byte);
+            self.encoder.set(false);
+            self.burn();
+        }
+    }
+
+}
+
+impl Device for ZxPrinter {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+        run_io_device(self, &self.bus, &self.clock)
+    }
+
+}
@@ -0,0 +1,115 @@
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use super::FormatError;
+
+/// PNG file signature
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Monochrome image with rows packed 8 pixels per byte, leftmost pixel in the
+/// most significant bit and 1 for black, as in binary PBM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Bitmap {
+
+    /// White bitmap of given size
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, data: vec![0; width.div_ceil(8) * height] }
+    }
+
+    /// Bytes per row
+    pub fn stride(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.stride()..(y + 1) * self.stride()]
+    }
+
+    /// Pixel is black
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.row(y)[x / 8] & 0x80 >> (x % 8) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, black: bool) {
+        let offset = y * self.stride() + x / 8;
+        if black {
+            self.data[offset] |= 0x80 >> (x % 8);
+        } else {
+            self.data[offset] &= !(0x80 >> (x % 8));
+        }
+    }
+
+    /// Binary PBM (P4) image
+    pub fn write_pbm(&self) -> Vec<u8> {
+        let mut data = format!("P4\n{} {}\n", self.width, self.height).into_bytes();
+        data.extend(&self.data);
+        data
+    }
+
+    /// PNG image with 1-bit grayscale pixels. PNG can't have zero size, so the
+    /// bitmap must not be empty.
+    pub fn write_png(&self) -> Result<Vec<u8>, FormatError> {
+
+        if self.width == 0 || self.height == 0 {
+            return Err(FormatError::Invalid("empty bitmap"));
+        }
+
+        // Grayscale 0 is black, each row is preceded by filter type 0 (none)
+        let mut rows = Vec::with_capacity((self.stride() + 1) * self.height);
+        for y in 0..self.height {
+            rows.push(0);
+            rows.extend(self.row(y).iter().map(|byte| !byte));
+        }
+
+        let mut header = vec![];
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // Bit depth, grayscale color type, deflate compression, adaptive filtering, no interlace
+        header.extend([1, 0, 0, 0, 0]);
+
+        let mut data = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut data, b"IHDR", &header);
+        write_chunk(&mut data, b"IDAT", &compress_to_vec_zlib(&rows, 9));
+        write_chunk(&mut data, b"IEND", &[]);
+        Ok(data)
+
+    }
+
+}
+
+/// Append PNG chunk with its length and CRC
+fn write_chunk(data: &mut Vec<u8>, kind: &[u8; 4], contents: &[u8]) {
+    data.extend((contents.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend(kind);
+    data.extend(contents);
+    data.extend(crc32(&data[start..]).to_be_bytes());
+}
+
+/// CRC-32 as used by PNG and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+
+}
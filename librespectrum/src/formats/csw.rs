@@ -0,0 +1,142 @@
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+use crate::devs::{TapeBlock, TapePulse, TapeSegment};
+
+use super::{FormatError, Reader};
+
+/// File signature
+const MAGIC: &[u8; 23] = b"Compressed Square Wave\x1a";
+
+/// CPU clock the t-states refer to
+const CLOCK_HZ: u64 = 3_500_000;
+
+/// Initial polarity flag
+const FLAG_HIGH: u8 = 0x01;
+
+/// Compression of pulse lengths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CswCompression {
+    /// Run-length encoded pulse lengths
+    Rle,
+    /// Run-length encoded pulse lengths compressed with zlib
+    ZRle,
+}
+
+impl CswCompression {
+
+    fn decode(value: u8) -> Result<Self, FormatError> {
+        match value {
+            1 => Ok(CswCompression::Rle),
+            2 => Ok(CswCompression::ZRle),
+            _ => Err(FormatError::Invalid("unknown CSW compression")),
+        }
+    }
+
+}
+
+/// Decode pulse lengths in samples. Each length is stored in a byte,
+/// longer pulses are stored as zero byte followed by 32-bit length.
+pub fn decode_rle(data: &[u8], compression: CswCompression) -> Result<Vec<u32>, FormatError> {
+    let inflated;
+    let data = match compression {
+        CswCompression::Rle => data,
+        CswCompression::ZRle => {
+            inflated = decompress_to_vec_zlib(data).map_err(|_| FormatError::Invalid("corrupted Z-RLE data"))?;
+            &inflated
+        },
+    };
+    let mut rd = Reader::new(data);
+    let mut pulses = vec![];
+    while !rd.is_empty() {
+        pulses.push(match rd.byte()? {
+            0 => rd.dword()?,
+            length => length as u32,
+        });
+    }
+    Ok(pulses)
+}
+
+/// Convert pulse lengths in samples at given rate to t-states without accumulating rounding errors
+pub fn samples_to_tstates(pulses: &[u32], sample_rate: u32) -> Vec<u32> {
+    let mut samples = 0u64;
+    let mut tstates = 0u64;
+    pulses.iter().map(|&pulse| {
+        samples += pulse as u64;
+        let end = samples * CLOCK_HZ / sample_rate.max(1) as u64;
+        let length = end - tstates;
+        tstates = end;
+        length as u32
+    }).collect()
+}
+
+/// CSW (compressed square wave) recording, versions 1.01 and 2.0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Csw {
+    pub sample_rate: u32,
+    /// Signal level of the first pulse
+    pub initial_level: bool,
+    /// Pulse lengths in samples
+    pub pulses: Vec<u32>,
+}
+
+impl Csw {
+
+    /// Parse recording
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        if data.len() < 0x20 || &data[..MAGIC.len()] != MAGIC {
+            return Err(FormatError::Invalid("CSW signature is missing"));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let dword = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        let (sample_rate, compression, flags, offset) = match data[0x17] {
+            1 => (word(0x19) as u32, CswCompression::decode(data[0x1b])?, data[0x1c], 0x20),
+            2 => {
+                if data.len() < 0x34 {
+                    return Err(FormatError::InvalidSize(data.len()));
+                }
+                (dword(0x19), CswCompression::decode(data[0x21])?, data[0x22], 0x34 + data[0x23] as usize)
+            },
+            _ => return Err(FormatError::Invalid("unsupported CSW version")),
+        };
+
+        let contents = data.get(offset..).ok_or(FormatError::InvalidSize(data.len()))?;
+        Ok(Self { sample_rate, initial_level: flags & FLAG_HIGH != 0, pulses: decode_rle(contents, compression)? })
+
+    }
+
+    /// Pulse lengths in t-states
+    pub fn tstates(&self) -> Vec<u32> {
+        samples_to_tstates(&self.pulses, self.sample_rate)
+    }
+
+    /// Whole recording as a single block
+    pub fn tape_blocks(&self) -> Vec<TapeBlock> {
+        let pulses = self.tstates().into_iter().enumerate()
+            .map(|(index, pulse)| if index == 0 { TapePulse::Level(self.initial_level, pulse) } else { TapePulse::Edge(pulse) })
+            .collect();
+        vec![TapeBlock {
+            description: format!("CSW recording: {} pulses", self.pulses.len()),
+            data: None,
+            segments: vec![TapeSegment::Raw(pulses)],
+        }]
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn samples_convert_without_drift() {
+        // 44100 Hz sample is 79.36 t-states
+        let tstates = samples_to_tstates(&[1; 441], 44100);
+        assert_eq!(tstates.iter().sum::<u32>(), 35000);
+        assert!(tstates.iter().all(|&pulse| pulse == 79 || pulse == 80));
+    }
+
+}
@@ -0,0 +1,211 @@
+use crate::devs::{Disk, DiskSector};
+
+use super::FormatError;
+
+/// Signatures of standard and extended images, checked up to the emulator name
+const STANDARD_SIGNATURE: &[u8] = b"MV - CPC";
+const EXTENDED_SIGNATURE: &[u8] = b"EXTENDED CPC DSK File";
+
+/// Disk info block headers written
+const STANDARD_HEADER: &[u8; 34] = b"MV - CPCEMU Disk-File\r\nDisk-Info\r\n";
+const EXTENDED_HEADER: &[u8; 34] = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n";
+
+/// Track info block header
+const TRACK_HEADER: &[u8; 12] = b"Track-Info\r\n";
+
+/// Name of the creator written to disk info block
+const CREATOR: &[u8; 14] = b"respectrum\0\0\0\0";
+
+/// Size of disk and track info blocks, extended track sizes are its multiples
+const BLOCK: usize = 256;
+
+/// Sector info list of the track info block
+const SECTOR_INFO: usize = 0x18;
+const MAX_SECTORS: usize = (BLOCK - SECTOR_INFO) / 8;
+
+/// Gap 3 length and filler byte written to track info block
+const GAP_3: u8 = 0x4e;
+const FILLER: u8 = 0xe5;
+
+/// ST2 bit set for sectors with deleted data address mark
+const CONTROL_MARK: u8 = 0x40;
+
+/// Largest size code of a sector that is read in full
+const MAX_SIZE_CODE: u8 = 8;
+
+/// CPCEMU disk image, in standard or extended variant. Extended images keep
+/// sectors of any size, controller status of each sector and several copies
+/// of weak sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dsk {
+    pub extended: bool,
+    pub disk: Disk,
+}
+
+impl Dsk {
+
+    /// Parse disk image. Unformatted tracks of extended images are left empty.
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        let extended = if data.starts_with(EXTENDED_SIGNATURE) {
+            true
+        } else if data.starts_with(STANDARD_SIGNATURE) {
+            false
+        } else {
+            return Err(FormatError::Invalid("DSK signature is missing"));
+        };
+        let info = data.get(..BLOCK).ok_or(FormatError::InvalidSize(data.len()))?;
+        let (cylinders, sides) = (info[0x30], info[0x31]);
+        if !(1..=2).contains(&sides) {
+            return Err(FormatError::Invalid("DSK image has unsupported number of sides"));
+        }
+
+        let mut disk = Disk::new(cylinders, sides);
+        let mut offset = BLOCK;
+        for (index, track) in disk.tracks.iter_mut().enumerate() {
+            let size = if extended {
+                *info.get(0x34 + index).ok_or(FormatError::Invalid("too many tracks in DSK image"))? as usize * BLOCK
+            } else {
+                u16::from_le_bytes([info[0x32], info[0x33]]) as usize
+            };
+            if size == 0 {
+                continue;
+            }
+            let block = data.get(offset..offset + size).ok_or(FormatError::InvalidSize(data.len()))?;
+            *track = read_track(block, extended)?;
+            offset += size;
+        }
+        Ok(Self { extended, disk })
+
+    }
+
+    /// Serialize disk image. Disks that can't be represented by standard image,
+    /// with sectors of different size, weak sectors or unformatted tracks, are
+    /// written as extended image.
+    pub fn write(&self) -> Result<Vec<u8>, FormatError> {
+
+        let standard_size = (!self.extended).then(|| standard_track_size(&self.disk)).flatten();
+        let sides = self.disk.sides.max(1) as usize;
+        let tracks = self.disk.tracks.iter().enumerate()
+            .map(|(index, track)| write_track(track, (index / sides) as u8, (index % sides) as u8, standard_size.is_none()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut info = vec![0; BLOCK];
+        info[..34].copy_from_slice(if standard_size.is_some() { STANDARD_HEADER } else { EXTENDED_HEADER });
+        info[0x22..0x30].copy_from_slice(CREATOR);
+        info[0x30] = self.disk.cylinders();
+        info[0x31] = self.disk.sides;
+        match standard_size {
+            Some(size) => info[0x32..0x34].copy_from_slice(&size.to_le_bytes()),
+            None => {
+                if tracks.len() > BLOCK - 0x34 {
+                    return Err(FormatError::Invalid("too many tracks for DSK image"));
+                }
+                for (index, track) in tracks.iter().enumerate() {
+                    info[0x34 + index] = (track.len() / BLOCK) as u8;
+                }
+            },
+        }
+        Ok([info, tracks.concat()].concat())
+
+    }
+
+}
+
+/// Size of every track in standard image, if the disk can be represented by one
+fn standard_track_size(disk: &Disk) -> Option<u16> {
+    let sizes = disk.tracks.iter().map(|track| {
+        let size = 128 << track.first()?.size_code.min(MAX_SIZE_CODE);
+        track.iter()
+            .all(|sector| sector.copies.is_empty() && sector.data.len() == size)
+            .then_some(BLOCK + track.len() * size)
+    });
+    let mut sizes = sizes.collect::<Option<Vec<_>>>()?.into_iter();
+    let size = sizes.next()?;
+    sizes.all(|other| other == size).then_some(u16::try_from(size).ok()?)
+}
+
+/// Sectors of the track info block followed by sector data
+fn read_track(block: &[u8], extended: bool) -> Result<Vec<DiskSector>, FormatError> {
+
+    if !block.starts_with(TRACK_HEADER) || block.len() < BLOCK {
+        return Err(FormatError::Invalid("DSK track info is missing"));
+    }
+    let count = (block[0x15] as usize).min(MAX_SECTORS);
+    let mut offset = BLOCK;
+    let mut sectors = vec![];
+    for info in block[SECTOR_INFO..SECTOR_INFO + count * 8].chunks_exact(8) {
+        let (size_code, st1, st2) = (info[3], info[4], info[5]);
+        // Standard images store all sectors with the size of the track
+        let length = if extended {
+            u16::from_le_bytes([info[6], info[7]]) as usize
+        } else {
+            128 << block[0x14].min(MAX_SIZE_CODE)
+        };
+        let data = block.get(offset..offset + length).ok_or(FormatError::Invalid("DSK sector data is cut short"))?;
+        offset += length;
+
+        let mut sector = DiskSector::new(info[0], info[1], info[2], vec![]);
+        sector.size_code = size_code;
+        sector.deleted = st2 & CONTROL_MARK != 0;
+        sector.st1 = st1;
+        sector.st2 = st2 & !CONTROL_MARK;
+        // Weak sectors are stored as several copies of the whole sector
+        let size = 128 << size_code.min(MAX_SIZE_CODE);
+        if extended && length > size && length.is_multiple_of(size) {
+            let mut copies = data.chunks_exact(size).map(<[u8]>::to_vec);
+            sector.data = copies.next().unwrap_or_default();
+            sector.copies = copies.collect();
+        } else {
+            sector.data = data.to_vec();
+        }
+        sectors.push(sector);
+    }
+    Ok(sectors)
+
+}
+
+/// Track info block followed by sector data. Unformatted track takes no space in extended image.
+fn write_track(sectors: &[DiskSector], cylinder: u8, side: u8, extended: bool) -> Result<Vec<u8>, FormatError> {
+
+    if sectors.len() > MAX_SECTORS {
+        return Err(FormatError::Invalid("too many sectors on track for DSK image"));
+    }
+    if extended && sectors.is_empty() {
+        return Ok(vec![]);
+    }
+    let size_code = sectors.first().map_or(2, |sector| sector.size_code);
+    let mut block = vec![0; BLOCK];
+    block[..TRACK_HEADER.len()].copy_from_slice(TRACK_HEADER);
+    block[0x10] = cylinder;
+    block[0x11] = side;
+    block[0x14] = size_code;
+    block[0x15] = sectors.len() as u8;
+    block[0x16] = GAP_3;
+    block[0x17] = FILLER;
+
+    let mut data = vec![];
+    for (index, sector) in sectors.iter().enumerate() {
+        let stored = [std::slice::from_ref(&sector.data), sector.copies.as_slice()].concat().concat();
+        let info = &mut block[SECTOR_INFO + index * 8..SECTOR_INFO + index * 8 + 8];
+        info[..6].copy_from_slice(&[
+            sector.cylinder, sector.head, sector.sector, sector.size_code,
+            sector.st1, sector.st2 | if sector.deleted { CONTROL_MARK } else { 0 },
+        ]);
+        if extended {
+            let length = u16::try_from(stored.len()).map_err(|_| FormatError::Invalid("sector too long for DSK image"))?;
+            info[6..].copy_from_slice(&length.to_le_bytes());
+        }
+        data.extend(stored);
+    }
+
+    block.extend(data);
+    if extended {
+        block.resize(block.len().next_multiple_of(BLOCK), 0);
+        if block.len() / BLOCK > u8::MAX as usize {
+            return Err(FormatError::Invalid("track too long for DSK image"));
+        }
+    }
+    Ok(block)
+
+}
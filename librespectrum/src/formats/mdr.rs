@@ -0,0 +1,69 @@
+use super::FormatError;
+
+/// Sector size: 15-byte header block followed by 528-byte data block
+pub const SECTOR_SIZE: usize = 543;
+
+/// Sizes of header and data blocks
+pub const HEADER_SIZE: usize = 15;
+pub const DATA_SIZE: usize = SECTOR_SIZE - HEADER_SIZE;
+
+/// Number of sectors of a cartridge image
+pub const SECTORS: usize = 254;
+
+/// Microdrive cartridge image: sectors in the order they pass the head,
+/// followed by write protection flag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mdr {
+    pub data: Vec<u8>,
+    pub write_protected: bool,
+}
+
+impl Default for Mdr {
+    fn default() -> Self {
+        Self::new(SECTORS)
+    }
+}
+
+impl Mdr {
+
+    /// Unformatted cartridge with given number of sectors
+    pub fn new(sectors: usize) -> Self {
+        Self { data: vec![0; sectors * SECTOR_SIZE], write_protected: false }
+    }
+
+    /// Parse cartridge image. Write protection flag is optional.
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+        let sectors = data.len() / SECTOR_SIZE;
+        if sectors == 0 || data.len() - sectors * SECTOR_SIZE > 1 {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        Ok(Self {
+            data: data[..sectors * SECTOR_SIZE].to_vec(),
+            write_protected: data.get(sectors * SECTOR_SIZE).is_some_and(|&flag| flag != 0),
+        })
+    }
+
+    /// Serialize cartridge image
+    pub fn write(&self) -> Vec<u8> {
+        [self.data.as_slice(), &[self.write_protected as u8]].concat()
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.data.len() / SECTOR_SIZE
+    }
+
+    /// Sector with given index in the image, which isn't the sector number
+    pub fn sector(&self, index: usize) -> &[u8] {
+        &self.data[index * SECTOR_SIZE..(index + 1) * SECTOR_SIZE]
+    }
+
+    pub fn sector_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut self.data[index * SECTOR_SIZE..(index + 1) * SECTOR_SIZE]
+    }
+
+}
+
+/// Interface 1 checksum of header and data blocks: sum of bytes modulo 255
+pub fn checksum(data: &[u8]) -> u8 {
+    (data.iter().map(|&byte| byte as u32).sum::<u32>() % 255) as u8
+}
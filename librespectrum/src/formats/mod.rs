@@ -0,0 +1,96 @@
+use std::{fmt, io};
+
+pub mod bitmap;
+pub mod csw;
+pub mod dsk;
+pub mod mdr;
+pub mod pzx;
+pub mod scl;
+pub mod sna;
+pub mod szx;
+pub mod tap;
+pub mod trd;
+pub mod tzx;
+pub mod wav;
+pub mod z80;
+
+/// Error while reading or writing file format
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    /// File size doesn't match any known variant of the format
+    InvalidSize(usize),
+    /// File contents can't be represented or interpreted
+    Invalid(&'static str),
+}
+
+impl fmt::Display for FormatError {
+
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(err) => write!(formatter, "I/O error: {}", err),
+            FormatError::InvalidSize(size) => write!(formatter, "Invalid file size: {} bytes", size),
+            FormatError::Invalid(reason) => write!(formatter, "Invalid file: {}", reason),
+        }
+    }
+
+}
+
+impl std::error::Error for FormatError {}
+
+/// Little-endian reader over file contents
+pub(crate) struct Reader<'a> {
+    pub data: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> Reader<'a> {
+
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    /// Nothing left to read
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], FormatError> {
+        let bytes = self.data.get(self.offset..self.offset + count).ok_or(FormatError::InvalidSize(self.data.len()))?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, FormatError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn word(&mut self) -> Result<u16, FormatError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn triple(&mut self) -> Result<u32, FormatError> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    }
+
+    pub fn dword(&mut self) -> Result<u32, FormatError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Remaining contents
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.offset.min(self.data.len())..];
+        self.offset = self.data.len();
+        rest
+    }
+
+}
+
+impl From<io::Error> for FormatError {
+    fn from(err: io::Error) -> Self {
+        FormatError::Io(err)
+    }
+}
@@ -0,0 +1,371 @@
+use crate::devs::{TSTATES_PER_MS, TapeBlock, TapePulse, TapeSegment};
+
+use super::{FormatError, Reader, tap::TapBlock};
+
+/// Format version written
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 0;
+
+/// Block tags
+const PZXT: [u8; 4] = *b"PZXT";
+const PULS: [u8; 4] = *b"PULS";
+const DATA: [u8; 4] = *b"DATA";
+const PAUS: [u8; 4] = *b"PAUS";
+const BRWS: [u8; 4] = *b"BRWS";
+const STOP: [u8; 4] = *b"STOP";
+
+/// Initial level bit of data and pause blocks
+const LEVEL_BIT: u32 = 0x8000_0000;
+
+/// Longest pulse repeat count stored in a word
+const MAX_REPEAT: usize = 0x7fff;
+
+/// Bit sequences of standard ROM timings
+const STANDARD_ZERO: [u16; 2] = [855, 855];
+const STANDARD_ONE: [u16; 2] = [1710, 1710];
+
+/// PZX block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PzxBlock {
+    /// Version and info texts: title followed by key and value pairs
+    Header { major: u8, minor: u8, info: Vec<String> },
+    /// Pulse lengths in t-states. The signal is low at the start of the block and flips after each pulse.
+    Pulses(Vec<u32>),
+    /// Bits encoded with pulse sequences, most significant bit first, followed by tail pulse
+    Data { initial_level: bool, bits: u32, tail: u16, zero: Vec<u16>, one: Vec<u16>, data: Vec<u8> },
+    /// Constant level for given t-states
+    Pause { level: bool, duration: u32 },
+    /// Browse point description
+    Browse(String),
+    /// Stop the tape, either always or only in 48K mode
+    Stop { only_48k: bool },
+    /// Block kept as it is in the file
+    Unknown { tag: [u8; 4], data: Vec<u8> },
+}
+
+/// PZX tape image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pzx {
+    pub blocks: Vec<PzxBlock>,
+}
+
+impl Default for Pzx {
+    fn default() -> Self {
+        Self { blocks: vec![PzxBlock::Header { major: MAJOR_VERSION, minor: MINOR_VERSION, info: vec![] }] }
+    }
+}
+
+/// Texts are stored byte per character
+fn decode_text(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn encode_text(text: &str) -> Vec<u8> {
+    text.chars().map(|ch| ch as u8).collect()
+}
+
+/// Pulses with given initial level, the level flips before each following pulse
+fn alternating(level: bool, durations: impl IntoIterator<Item = u32>) -> Vec<TapePulse> {
+    durations.into_iter().enumerate()
+        .map(|(index, duration)| if index == 0 { TapePulse::Level(level, duration) } else { TapePulse::Edge(duration) })
+        .collect()
+}
+
+/// Signal as a sequence of constant level runs, merging runs of the same level
+#[derive(Default)]
+struct Runs {
+    level: bool,
+    runs: Vec<(bool, u32)>,
+}
+
+impl Runs {
+
+    fn push(&mut self, pulse: TapePulse) {
+        let duration = match pulse {
+            TapePulse::Edge(duration) => {
+                self.level = !self.level;
+                duration
+            },
+            TapePulse::Hold(duration) => duration,
+            TapePulse::Level(level, duration) => {
+                self.level = level;
+                duration
+            },
+            TapePulse::Stop => return,
+        };
+        // Zero length run leaves no trace
+        if let Some(&(_, 0)) = self.runs.last() {
+            self.runs.pop();
+        }
+        match self.runs.last_mut() {
+            Some((level, total)) if *level == self.level => *total += duration,
+            _ => self.runs.push((self.level, duration)),
+        }
+    }
+
+    /// Pulses block starting low, empty when there is no signal
+    fn flush(&mut self) -> Option<PzxBlock> {
+        if let Some(&(_, 0)) = self.runs.last() {
+            self.runs.pop();
+        }
+        let runs = std::mem::take(&mut self.runs);
+        let first = runs.first()?;
+        let lead = first.0.then_some(0);
+        Some(PzxBlock::Pulses(lead.into_iter().chain(runs.iter().map(|&(_, duration)| duration)).collect()))
+    }
+
+}
+
+impl PzxBlock {
+
+    /// Parse block contents with given tag
+    fn read(tag: [u8; 4], data: &[u8]) -> Result<Self, FormatError> {
+
+        let mut rd = Reader::new(data);
+
+        Ok(match tag {
+            PZXT => {
+                let major = rd.byte()?;
+                let minor = rd.byte()?;
+                let rest = rd.rest();
+                let rest = rest.strip_suffix(&[0]).unwrap_or(rest);
+                let info = if rest.is_empty() { vec![] } else { rest.split(|&byte| byte == 0).map(decode_text).collect() };
+                PzxBlock::Header { major, minor, info }
+            },
+            PULS => {
+                let mut pulses = vec![];
+                while !rd.is_empty() {
+                    let mut count = 1;
+                    let mut duration = rd.word()? as u32;
+                    if duration > 0x8000 {
+                        count = duration & 0x7fff;
+                        duration = rd.word()? as u32;
+                    }
+                    if duration >= 0x8000 {
+                        duration = ((duration & 0x7fff) << 16) | rd.word()? as u32;
+                    }
+                    pulses.extend((0..count).map(|_| duration));
+                }
+                PzxBlock::Pulses(pulses)
+            },
+            DATA => {
+                let count = rd.dword()?;
+                let tail = rd.word()?;
+                let zero_pulses = rd.byte()? as usize;
+                let one_pulses = rd.byte()? as usize;
+                let zero = (0..zero_pulses).map(|_| rd.word()).collect::<Result<_, _>>()?;
+                let one = (0..one_pulses).map(|_| rd.word()).collect::<Result<_, _>>()?;
+                let bits = count & !LEVEL_BIT;
+                let data = rd.bytes((bits as usize).div_ceil(8))?.to_vec();
+                PzxBlock::Data { initial_level: count & LEVEL_BIT != 0, bits, tail, zero, one, data }
+            },
+            PAUS => {
+                let duration = rd.dword()?;
+                PzxBlock::Pause { level: duration & LEVEL_BIT != 0, duration: duration & !LEVEL_BIT }
+            },
+            BRWS => PzxBlock::Browse(decode_text(rd.rest())),
+            STOP => PzxBlock::Stop { only_48k: rd.word()? == 1 },
+            _ => PzxBlock::Unknown { tag, data: data.to_vec() },
+        })
+
+    }
+
+    /// Block tag and contents
+    fn write(&self) -> ([u8; 4], Vec<u8>) {
+
+        let mut out = vec![];
+        let word = |out: &mut Vec<u8>, value: u16| out.extend_from_slice(&value.to_le_bytes());
+
+        let tag = match self {
+            PzxBlock::Header { major, minor, info } => {
+                out.extend_from_slice(&[*major, *minor]);
+                for text in info {
+                    out.extend(encode_text(text));
+                    out.push(0);
+                }
+                PZXT
+            },
+            PzxBlock::Pulses(pulses) => {
+                let mut index = 0;
+                while index < pulses.len() {
+                    let duration = pulses[index];
+                    let count = pulses[index..].iter().take(MAX_REPEAT).take_while(|&&pulse| pulse == duration).count();
+                    // Long pulse would be taken for repeat count without one
+                    if count > 1 || duration >= 0x8000 {
+                        word(&mut out, 0x8000 | count as u16);
+                    }
+                    if duration >= 0x8000 {
+                        word(&mut out, 0x8000 | (duration >> 16) as u16);
+                    }
+                    word(&mut out, duration as u16);
+                    index += count;
+                }
+                PULS
+            },
+            PzxBlock::Data { initial_level, bits, tail, zero, one, data } => {
+                out.extend_from_slice(&(bits | if *initial_level { LEVEL_BIT } else { 0 }).to_le_bytes());
+                word(&mut out, *tail);
+                out.extend_from_slice(&[zero.len() as u8, one.len() as u8]);
+                zero.iter().chain(one).for_each(|&pulse| word(&mut out, pulse));
+                out.extend_from_slice(data);
+                DATA
+            },
+            PzxBlock::Pause { level, duration } => {
+                out.extend_from_slice(&(duration | if *level { LEVEL_BIT } else { 0 }).to_le_bytes());
+                PAUS
+            },
+            PzxBlock::Browse(text) => {
+                out.extend(encode_text(text));
+                BRWS
+            },
+            PzxBlock::Stop { only_48k } => {
+                word(&mut out, *only_48k as u16);
+                STOP
+            },
+            PzxBlock::Unknown { tag, data } => {
+                out.extend_from_slice(data);
+                *tag
+            },
+        };
+
+        (tag, out)
+
+    }
+
+    /// Data loadable by ROM LD-BYTES routine if the block uses standard bit timings
+    fn standard_data(&self) -> Option<TapBlock> {
+        match self {
+            PzxBlock::Data { bits, zero, one, data, .. }
+                if bits.is_multiple_of(8) && *zero == STANDARD_ZERO && *one == STANDARD_ONE => Some(TapBlock { data: data.clone() }),
+            _ => None,
+        }
+    }
+
+    /// One-line description for tape browser
+    pub fn describe(&self) -> String {
+        match self {
+            PzxBlock::Header { info, .. } => match info.first() {
+                Some(title) => format!("Title: {}", title),
+                None => String::from("Header"),
+            },
+            PzxBlock::Pulses(pulses) => format!("Pulses: {}", pulses.len()),
+            PzxBlock::Data { bits, .. } => match self.standard_data() {
+                Some(data) => data.describe(),
+                None => format!("Data: {} bits", bits),
+            },
+            PzxBlock::Pause { duration, .. } => format!("Pause: {} ms", duration / TSTATES_PER_MS),
+            PzxBlock::Browse(text) => text.clone(),
+            PzxBlock::Stop { only_48k: true } => String::from("Stop the tape in 48K mode"),
+            PzxBlock::Stop { only_48k: false } => String::from("Stop the tape"),
+            PzxBlock::Unknown { tag, data } => format!("Unknown block {}: {} bytes", decode_text(tag), data.len()),
+        }
+    }
+
+    /// Signal of the block
+    pub fn segments(&self, is_48k: bool) -> Vec<TapeSegment> {
+        match self {
+            PzxBlock::Pulses(pulses) if !pulses.is_empty() =>
+                vec![TapeSegment::Raw(alternating(false, pulses.iter().copied()))],
+            PzxBlock::Data { initial_level, bits, tail, zero, one, data } => {
+                let durations = (0..*bits as usize)
+                    .flat_map(|bit| if data.get(bit / 8).is_some_and(|byte| byte & (0x80 >> (bit % 8)) != 0) { one } else { zero })
+                    .map(|&pulse| pulse as u32);
+                let mut pulses = alternating(*initial_level, durations);
+                if *tail > 0 {
+                    pulses.push(TapePulse::Edge(*tail as u32));
+                }
+                vec![TapeSegment::Raw(pulses)]
+            },
+            PzxBlock::Pause { level, duration } => vec![TapeSegment::Raw(vec![TapePulse::Level(*level, *duration)])],
+            PzxBlock::Stop { only_48k } if is_48k || !only_48k => vec![TapeSegment::Stop],
+            _ => vec![],
+        }
+    }
+
+}
+
+impl Pzx {
+
+    /// Parse tape image
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+        if data.get(..4) != Some(&PZXT) {
+            return Err(FormatError::Invalid("PZX header is missing"));
+        }
+        let mut rd = Reader::new(data);
+        let mut blocks = vec![];
+        while !rd.is_empty() {
+            let tag: [u8; 4] = rd.bytes(4)?.try_into().unwrap();
+            let size = rd.dword()? as usize;
+            blocks.push(PzxBlock::read(tag, rd.bytes(size)?)?);
+        }
+        Ok(Self { blocks })
+    }
+
+    /// Serialize tape image
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for block in &self.blocks {
+            let (tag, contents) = block.write();
+            data.extend_from_slice(&tag);
+            data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            data.extend(contents);
+        }
+        data
+    }
+
+    /// Blocks for the tape deck. Pulses block followed by data block, such as pilot tone
+    /// with sync pulses and data, make a single block, which can be fast loaded when data
+    /// uses standard timings. Stop blocks marked for 48K mode stop the tape only when `is_48k` is set.
+    pub fn tape_blocks(&self, is_48k: bool) -> Vec<TapeBlock> {
+        let mut result: Vec<TapeBlock> = vec![];
+        for (index, block) in self.blocks.iter().enumerate() {
+            let segments = block.segments(is_48k);
+            let merge = matches!(block, PzxBlock::Data { .. }) && matches!(self.blocks.get(index.wrapping_sub(1)), Some(PzxBlock::Pulses(_)));
+            match result.last_mut() {
+                Some(last) if merge => {
+                    last.description = block.describe();
+                    last.data = block.standard_data();
+                    last.segments.extend(segments);
+                },
+                _ => result.push(TapeBlock { description: block.describe(), data: block.standard_data(), segments }),
+            }
+        }
+        result
+    }
+
+    /// Save the signal of the tape blocks. Each block starts with a browse point
+    /// holding its description, data bits are stored as data blocks and the rest as plain pulses.
+    pub fn from_tape(blocks: &[TapeBlock]) -> Self {
+        let mut pzx = Pzx::default();
+        let mut runs = Runs::default();
+        for block in blocks {
+            pzx.blocks.push(PzxBlock::Browse(block.description.clone()));
+            for segment in &block.segments {
+                // Data bits are kept as data block, so standard blocks stay fast loadable
+                if let TapeSegment::Data { zero, one, data, last_bits } = segment
+                    && let (Ok(zero), Ok(one)) = (u16::try_from(*zero), u16::try_from(*one)) {
+                    pzx.blocks.extend(runs.flush());
+                    pzx.blocks.push(PzxBlock::Data {
+                        initial_level: !runs.level,
+                        bits: (data.len() * 8).saturating_sub(8 - *last_bits as usize) as u32,
+                        tail: 0,
+                        zero: vec![zero; 2],
+                        one: vec![one; 2],
+                        data: data.clone(),
+                    });
+                    continue;
+                }
+                for pulse in (0..).map_while(|index| segment.pulse(index)) {
+                    if pulse == TapePulse::Stop {
+                        pzx.blocks.extend(runs.flush());
+                        pzx.blocks.push(PzxBlock::Stop { only_48k: false });
+                    }
+                    runs.push(pulse);
+                }
+            }
+            pzx.blocks.extend(runs.flush());
+        }
+        pzx
+    }
+
+}
@@ -0,0 +1,168 @@
+use super::{FormatError, Reader, trd::{SECTOR_SIZE, SECTORS, Trd}};
+
+/// File signature
+const SIGNATURE: &[u8; 8] = b"SINCLAIR";
+
+/// Catalog entry size in SCL file (without location on disk)
+const HEADER_SIZE: usize = 14;
+
+/// Catalog occupies sectors 1-8 of track 0, followed by disk info sector
+const MAX_FILES: usize = 128;
+const DISK_INFO: usize = 8 * SECTOR_SIZE;
+
+/// First name byte of catalog entries past the last file and of deleted files
+const END_OF_CATALOG: u8 = 0x00;
+const DELETED: u8 = 0x01;
+
+/// TR-DOS disk type of 80 cylinder double-sided disk
+const DISK_TYPE_80_DS: u8 = 0x16;
+
+/// TR-DOS identification byte in disk info sector
+const TRDOS_ID: u8 = 0x10;
+
+/// TR-DOS file stored in SCL archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SclFile {
+    /// Name padded with spaces
+    pub name: [u8; 8],
+    /// File type: B for BASIC program, C for code, D for array, # for sequential file
+    pub extension: u8,
+    /// Start address of code, or program length for BASIC
+    pub start: u16,
+    pub length: u16,
+    /// Whole sectors of file contents
+    pub data: Vec<u8>,
+}
+
+/// SCL archive of TR-DOS files
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Scl {
+    pub files: Vec<SclFile>,
+}
+
+impl Scl {
+
+    /// Parse archive verifying its checksum
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        if data.get(..SIGNATURE.len()) != Some(SIGNATURE) {
+            return Err(FormatError::Invalid("SCL signature is missing"));
+        }
+        let (contents, checksum) = data.split_at(data.len().saturating_sub(4).max(SIGNATURE.len()));
+        if checksum.len() != 4 || u32::from_le_bytes(checksum.try_into().unwrap()) != checksum32(contents) {
+            return Err(FormatError::Invalid("SCL checksum mismatch"));
+        }
+
+        let mut rd = Reader::new(&contents[SIGNATURE.len()..]);
+        let count = rd.byte()? as usize;
+        let mut headers = vec![];
+        for _ in 0..count {
+            headers.push(rd.bytes(HEADER_SIZE)?);
+        }
+        let mut files = vec![];
+        for header in headers {
+            let sectors = header[13] as usize;
+            files.push(SclFile {
+                name: header[..8].try_into().unwrap(),
+                extension: header[8],
+                start: u16::from_le_bytes([header[9], header[10]]),
+                length: u16::from_le_bytes([header[11], header[12]]),
+                data: rd.bytes(sectors * SECTOR_SIZE)?.to_vec(),
+            });
+        }
+        Ok(Self { files })
+
+    }
+
+    /// Serialize archive
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = SIGNATURE.to_vec();
+        data.push(self.files.len() as u8);
+        for file in &self.files {
+            data.extend(header(file));
+        }
+        for file in &self.files {
+            data.extend(&file.data);
+        }
+        data.extend(checksum32(&data).to_le_bytes());
+        data
+    }
+
+    /// Freshly formatted 80 cylinder double-sided disk with the files written one after another
+    pub fn trd(&self) -> Result<Trd, FormatError> {
+
+        if self.files.len() > MAX_FILES {
+            return Err(FormatError::Invalid("too many files for TR-DOS disk"));
+        }
+
+        let mut trd = Trd::new(80, 2);
+        let total = trd.data.len() / SECTOR_SIZE;
+        // Files start at track 1, as track 0 holds the catalog
+        let mut next = SECTORS;
+        for (index, file) in self.files.iter().enumerate() {
+            let sectors = file.data.len().div_ceil(SECTOR_SIZE);
+            if next + sectors > total {
+                return Err(FormatError::Invalid("files don't fit on TR-DOS disk"));
+            }
+            let entry = index * 16;
+            trd.data[entry..entry + HEADER_SIZE].copy_from_slice(&header(file));
+            trd.data[entry + 14] = (next % SECTORS) as u8;
+            trd.data[entry + 15] = (next / SECTORS) as u8;
+            let offset = next * SECTOR_SIZE;
+            trd.data[offset..offset + file.data.len()].copy_from_slice(&file.data);
+            next += sectors;
+        }
+
+        let info = &mut trd.data[DISK_INFO..DISK_INFO + SECTOR_SIZE];
+        info[0xe1] = (next % SECTORS) as u8;
+        info[0xe2] = (next / SECTORS) as u8;
+        info[0xe3] = DISK_TYPE_80_DS;
+        info[0xe4] = self.files.len() as u8;
+        info[0xe5..0xe7].copy_from_slice(&((total - next) as u16).to_le_bytes());
+        info[0xe7] = TRDOS_ID;
+        info[0xea..0xf3].fill(b' ');
+        info[0xf5..0xfd].fill(b' ');
+        Ok(trd)
+
+    }
+
+    /// Archive of the files in TR-DOS disk catalog, deleted files are left out
+    pub fn from_trd(trd: &Trd) -> Result<Self, FormatError> {
+        let mut files = vec![];
+        for entry in trd.data[..MAX_FILES * 16].chunks(16) {
+            match entry[0] {
+                END_OF_CATALOG => break,
+                DELETED => continue,
+                _ => (),
+            }
+            let offset = (entry[15] as usize * SECTORS + entry[14] as usize) * SECTOR_SIZE;
+            let data = trd.data.get(offset..offset + entry[13] as usize * SECTOR_SIZE)
+                .ok_or(FormatError::Invalid("TR-DOS file is out of disk"))?;
+            files.push(SclFile {
+                name: entry[..8].try_into().unwrap(),
+                extension: entry[8],
+                start: u16::from_le_bytes([entry[9], entry[10]]),
+                length: u16::from_le_bytes([entry[11], entry[12]]),
+                data: data.to_vec(),
+            });
+        }
+        Ok(Self { files })
+    }
+
+}
+
+/// Catalog entry without location on disk
+fn header(file: &SclFile) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..8].copy_from_slice(&file.name);
+    header[8] = file.extension;
+    header[9..11].copy_from_slice(&file.start.to_le_bytes());
+    header[11..13].copy_from_slice(&file.length.to_le_bytes());
+    header[13] = file.data.len().div_ceil(SECTOR_SIZE) as u8;
+    header
+}
+
+/// Sum of all bytes
+fn checksum32(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32))
+}
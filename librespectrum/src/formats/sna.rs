@@ -0,0 +1,196 @@
+use crate::{
+    cpu::tokens::IntMode,
+    devs::{Cpu, CpuRegisters, mem::Memory},
+};
+
+use super::FormatError;
+
+/// Header size in bytes
+const HEADER_SIZE: usize = 27;
+
+/// RAM bank size in bytes
+pub const BANK_SIZE: usize = 0x4000;
+
+/// 48K snapshot size
+const SIZE_48K: usize = HEADER_SIZE + 3 * BANK_SIZE;
+
+/// 128K snapshot size with 5 or 6 banks after the extended header
+const SIZE_128K: [usize; 2] = [SIZE_48K + 4 + 5 * BANK_SIZE, SIZE_48K + 4 + 6 * BANK_SIZE];
+
+/// 128K paging state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paging128 {
+    /// Last value written to port 7FFDh
+    pub port_7ffd: u8,
+    /// TR-DOS ROM is paged in
+    pub trdos: bool,
+    /// All 8 RAM banks
+    pub banks: Vec<Vec<u8>>,
+}
+
+impl Paging128 {
+
+    /// Bank paged in at C000h
+    pub fn paged_bank(&self) -> usize {
+        (self.port_7ffd & 0x07) as usize
+    }
+
+}
+
+/// SNA snapshot. 48K variant keeps PC on the stack and is resumed with RETN,
+/// 128K variant stores PC explicitly along with paging state and all RAM banks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sna {
+    pub regs: CpuRegisters,
+    pub border: u8,
+    /// RAM as seen at 4000h-FFFFh
+    pub ram: Vec<u8>,
+    /// Paging state of 128K snapshot
+    pub paging: Option<Paging128>,
+}
+
+impl Sna {
+
+    /// Parse 48K or 128K snapshot
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        let is_128k = SIZE_128K.contains(&data.len());
+        if data.len() != SIZE_48K && !is_128k {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let iff2 = data[19] & 0x04 != 0;
+
+        let mut regs = CpuRegisters {
+            ir: u16::from_be_bytes([data[0], data[20]]),
+            alt_hl: word(1),
+            alt_de: word(3),
+            alt_bc: word(5),
+            alt_af: word(7),
+            hl: word(9),
+            de: word(11),
+            bc: word(13),
+            iy: word(15),
+            ix: word(17),
+            iff1: iff2,
+            iff2,
+            af: word(21),
+            sp: word(23),
+            im: match data[25] & 0x03 {
+                0 => IntMode::IM0,
+                1 => IntMode::IM1,
+                _ => IntMode::IM2,
+            },
+            ..Default::default()
+        };
+        let border = data[26] & 0x07;
+        let ram = data[HEADER_SIZE..SIZE_48K].to_vec();
+
+        if !is_128k {
+            // Execute RETN: pop PC from the stack
+            let sp = regs.sp;
+            if sp < 0x4000 || sp == 0xffff {
+                return Err(FormatError::Invalid("stack pointer doesn't point to RAM"));
+            }
+            let offset = (sp - 0x4000) as usize;
+            regs.pc = u16::from_le_bytes([ram[offset], ram[offset + 1]]);
+            regs.sp = sp.wrapping_add(2);
+            return Ok(Self { regs, border, ram, paging: None });
+        }
+
+        regs.pc = word(SIZE_48K);
+        let port_7ffd = data[SIZE_48K + 2];
+        let trdos = data[SIZE_48K + 3] != 0;
+        let paged = (port_7ffd & 0x07) as usize;
+
+        let mut banks = vec![vec![]; 8];
+        banks[5] = ram[..BANK_SIZE].to_vec();
+        banks[2] = ram[BANK_SIZE..2 * BANK_SIZE].to_vec();
+        banks[paged] = ram[2 * BANK_SIZE..].to_vec();
+
+        // Remaining banks follow in ascending order. When paged bank is 2 or 5,
+        // it's stored twice and 6 banks follow instead of 5.
+        let mut chunks = data[SIZE_48K + 4..].chunks(BANK_SIZE);
+        for (bank, contents) in banks.iter_mut().enumerate() {
+            if bank == 2 || bank == 5 || bank == paged {
+                continue;
+            }
+            *contents = chunks.next()
+                .ok_or(FormatError::InvalidSize(data.len()))?
+                .to_vec();
+        }
+
+        Ok(Self { regs, border, ram, paging: Some(Paging128 { port_7ffd, trdos, banks }) })
+
+    }
+
+    /// Serialize snapshot. 48K snapshot pushes PC onto the stack.
+    pub fn write(&self) -> Result<Vec<u8>, FormatError> {
+
+        let regs = &self.regs;
+        let mut ram = self.ram.clone();
+        let mut sp = regs.sp;
+
+        if self.paging.is_none() {
+            sp = sp.wrapping_sub(2);
+            if sp < 0x4000 || sp == 0xffff {
+                return Err(FormatError::Invalid("stack pointer doesn't point to RAM"));
+            }
+            let offset = (sp - 0x4000) as usize;
+            ram[offset..offset + 2].copy_from_slice(&regs.pc.to_le_bytes());
+        }
+
+        let mut data = Vec::with_capacity(SIZE_128K[1]);
+        data.push((regs.ir >> 8) as u8);
+        for word in [regs.alt_hl, regs.alt_de, regs.alt_bc, regs.alt_af, regs.hl, regs.de, regs.bc, regs.iy, regs.ix] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.push(if regs.iff2 { 0x04 } else { 0x00 });
+        data.push(regs.ir as u8);
+        data.extend_from_slice(&regs.af.to_le_bytes());
+        data.extend_from_slice(&sp.to_le_bytes());
+        data.push(match regs.im {
+            IntMode::IM0 | IntMode::IM01 => 0,
+            IntMode::IM1 => 1,
+            IntMode::IM2 => 2,
+        });
+        data.push(self.border);
+        data.extend_from_slice(&ram);
+
+        if let Some(paging) = &self.paging {
+            let paged = paging.paged_bank();
+            data.extend_from_slice(&regs.pc.to_le_bytes());
+            data.push(paging.port_7ffd);
+            data.push(paging.trdos as u8);
+            for (bank, contents) in paging.banks.iter().enumerate() {
+                if bank != 2 && bank != 5 && bank != paged {
+                    data.extend_from_slice(contents);
+                }
+            }
+        }
+
+        Ok(data)
+
+    }
+
+    /// Capture 48K snapshot of CPU and memory
+    pub fn capture(cpu: &Cpu, memory: &dyn Memory, border: u8) -> Self {
+        Self {
+            regs: cpu.save_state(),
+            border,
+            ram: (0x4000..=0xffff).map(|addr| memory.read(addr)).collect(),
+            paging: None,
+        }
+    }
+
+    /// Load registers and RAM. Only banks visible at 4000h-FFFFh
+    /// are loaded from 128K snapshot.
+    pub fn apply(&self, cpu: &Cpu, memory: &dyn Memory) {
+        cpu.load_state(&self.regs);
+        for (addr, &byte) in (0x4000..=0xffff).zip(&self.ram) {
+            memory.write(addr, byte);
+        }
+    }
+
+}
@@ -0,0 +1,384 @@
+use crate::{
+    cpu::tokens::IntMode,
+    devs::{Cpu, CpuRegisters, mem::Memory},
+};
+
+use super::{FormatError, sna::BANK_SIZE, z80::{AyState, Hardware}};
+
+/// File signature
+const MAGIC: &[u8; 4] = b"ZXST";
+
+/// Header size in bytes
+const HEADER_SIZE: usize = 8;
+
+/// Z80 registers block
+pub const Z80R: [u8; 4] = *b"Z80R";
+
+/// Spectrum registers block (border and paging ports)
+pub const SPCR: [u8; 4] = *b"SPCR";
+
+/// RAM page block
+pub const RAMP: [u8; 4] = *b"RAMP";
+
+/// AY chip block
+pub const AY: [u8; 4] = *b"AY\0\0";
+
+/// Keyboard block
+pub const KEYB: [u8; 4] = *b"KEYB";
+
+/// Z80R block flags
+const Z80R_LAST_EI: u8 = 0x01;
+const Z80R_HALTED: u8 = 0x02;
+
+/// RAMP block is zlib compressed
+const RAMP_COMPRESSED: u16 = 0x0001;
+
+/// Bank numbers of RAM at 4000h, 8000h and C000h on 48K machine
+const BANKS_48K: [u8; 3] = [5, 2, 0];
+
+/// Raw block kept as it is in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SzxBlock {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// Contents of Z80R block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SzxZ80Regs {
+    /// Registers, including EI and HALT state
+    pub regs: CpuRegisters,
+    /// T-states since the start of the frame
+    pub tstates: u32,
+    /// T-states interrupt request is held for
+    pub hold_int_cycles: u8,
+    /// Remaining flags which are not reflected by registers
+    pub flags: u8,
+    pub memptr: u16,
+}
+
+/// Contents of SPCR block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SzxSpectrumRegs {
+    pub border: u8,
+    pub port_7ffd: u8,
+    /// Port 1FFDh on +2A/+3 or EFF7h on Pentagon 1024
+    pub port_1ffd: u8,
+    /// Last value written to port FEh
+    pub port_fe: u8,
+    pub reserved: [u8; 4],
+}
+
+/// Contents of KEYB block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SzxKeyboard {
+    /// Bit 0 is set for issue 2 keyboard
+    pub flags: u32,
+    /// Joystick emulated with keyboard keys
+    pub joystick: u8,
+}
+
+/// SZX (zx-state) snapshot. Blocks are kept in raw form and decoded on access,
+/// so blocks which aren't understood are written back unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Szx {
+    pub major: u8,
+    pub minor: u8,
+    pub machine_id: u8,
+    /// Bit 0 is set when late timings are used
+    pub flags: u8,
+    pub blocks: Vec<SzxBlock>,
+}
+
+impl Szx {
+
+    /// Create empty snapshot of given machine
+    pub fn new(machine_id: u8) -> Self {
+        Self { major: 1, minor: 4, machine_id, flags: 0, blocks: vec![] }
+    }
+
+    /// Parse snapshot splitting it into blocks
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        if data.len() < HEADER_SIZE {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        if &data[0..4] != MAGIC {
+            return Err(FormatError::Invalid("missing ZXST signature"));
+        }
+
+        let mut blocks = vec![];
+        let mut offset = HEADER_SIZE;
+        while offset < data.len() {
+            let header = data.get(offset..offset + 8).ok_or(FormatError::InvalidSize(data.len()))?;
+            let id = [header[0], header[1], header[2], header[3]];
+            let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            offset += 8;
+            let contents = data.get(offset..offset + size).ok_or(FormatError::InvalidSize(data.len()))?;
+            blocks.push(SzxBlock { id, data: contents.to_vec() });
+            offset += size;
+        }
+
+        Ok(Self { major: data[4], minor: data[5], machine_id: data[6], flags: data[7], blocks })
+
+    }
+
+    /// Serialize snapshot with blocks in their current order
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[self.major, self.minor, self.machine_id, self.flags]);
+        for block in &self.blocks {
+            data.extend_from_slice(&block.id);
+            data.extend_from_slice(&(block.data.len() as u32).to_le_bytes());
+            data.extend_from_slice(&block.data);
+        }
+        data
+    }
+
+    /// Hardware matching the machine identifier, if it's known
+    pub fn hardware(&self) -> Option<Hardware> {
+        use Hardware::*;
+        Some(match self.machine_id {
+            0 => Spectrum16k,
+            1 => Spectrum48k,
+            2 => Spectrum128k,
+            3 => SpectrumPlus2,
+            4 => SpectrumPlus2A,
+            5 => SpectrumPlus3,
+            7 => Pentagon128,
+            8 => Tc2048,
+            9 => Tc2068,
+            10 => Scorpion256,
+            12 => Ts2068,
+            _ => return None,
+        })
+    }
+
+    /// Machine has 128K or more memory paged through port 7FFDh
+    pub fn is_128k(&self) -> bool {
+        !matches!(self.machine_id, 0 | 1 | 8 | 9 | 12 | 15)
+    }
+
+    /// First block with given identifier
+    pub fn block(&self, id: [u8; 4]) -> Option<&SzxBlock> {
+        self.blocks.iter().find(|block| block.id == id)
+    }
+
+    /// Replace first block with given identifier or append a new one
+    pub fn set_block(&mut self, id: [u8; 4], data: Vec<u8>) {
+        match self.blocks.iter_mut().find(|block| block.id == id) {
+            Some(block) => block.data = data,
+            None => self.blocks.push(SzxBlock { id, data }),
+        }
+    }
+
+    /// Decode Z80R block
+    pub fn z80_regs(&self) -> Result<Option<SzxZ80Regs>, FormatError> {
+
+        let Some(block) = self.block(Z80R) else { return Ok(None) };
+        let data = &block.data;
+        if data.len() < 35 {
+            return Err(FormatError::Invalid("Z80R block is too short"));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let flags = data[34];
+
+        Ok(Some(SzxZ80Regs {
+            regs: CpuRegisters {
+                af: word(0),
+                bc: word(2),
+                de: word(4),
+                hl: word(6),
+                alt_af: word(8),
+                alt_bc: word(10),
+                alt_de: word(12),
+                alt_hl: word(14),
+                ix: word(16),
+                iy: word(18),
+                sp: word(20),
+                pc: word(22),
+                ir: u16::from_be_bytes([data[24], data[25]]),
+                iff1: data[26] != 0,
+                iff2: data[27] != 0,
+                im: match data[28] & 0x03 {
+                    0 => IntMode::IM0,
+                    1 => IntMode::IM1,
+                    _ => IntMode::IM2,
+                },
+                after_ei: flags & Z80R_LAST_EI != 0,
+                halted: flags & Z80R_HALTED != 0,
+                ..Default::default()
+            },
+            tstates: u32::from_le_bytes([data[29], data[30], data[31], data[32]]),
+            hold_int_cycles: data[33],
+            flags: flags & !(Z80R_LAST_EI | Z80R_HALTED),
+            memptr: if data.len() >= 37 { word(35) } else { 0 },
+        }))
+
+    }
+
+    /// Encode Z80R block
+    pub fn set_z80_regs(&mut self, z80: &SzxZ80Regs) {
+        let regs = &z80.regs;
+        let mut data = Vec::with_capacity(37);
+        for word in [
+            regs.af, regs.bc, regs.de, regs.hl, regs.alt_af, regs.alt_bc, regs.alt_de, regs.alt_hl,
+            regs.ix, regs.iy, regs.sp, regs.pc,
+        ] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(&regs.ir.to_be_bytes());
+        data.push(regs.iff1 as u8);
+        data.push(regs.iff2 as u8);
+        data.push(match regs.im {
+            IntMode::IM0 | IntMode::IM01 => 0,
+            IntMode::IM1 => 1,
+            IntMode::IM2 => 2,
+        });
+        data.extend_from_slice(&z80.tstates.to_le_bytes());
+        data.push(z80.hold_int_cycles);
+        let mut flags = z80.flags & !(Z80R_LAST_EI | Z80R_HALTED);
+        if regs.after_ei { flags |= Z80R_LAST_EI; }
+        if regs.halted { flags |= Z80R_HALTED; }
+        data.push(flags);
+        data.extend_from_slice(&z80.memptr.to_le_bytes());
+        self.set_block(Z80R, data);
+    }
+
+    /// Decode SPCR block
+    pub fn spectrum_regs(&self) -> Result<Option<SzxSpectrumRegs>, FormatError> {
+        let Some(block) = self.block(SPCR) else { return Ok(None) };
+        let data = &block.data;
+        if data.len() < 8 {
+            return Err(FormatError::Invalid("SPCR block is too short"));
+        }
+        Ok(Some(SzxSpectrumRegs {
+            border: data[0],
+            port_7ffd: data[1],
+            port_1ffd: data[2],
+            port_fe: data[3],
+            reserved: [data[4], data[5], data[6], data[7]],
+        }))
+    }
+
+    /// Encode SPCR block
+    pub fn set_spectrum_regs(&mut self, regs: &SzxSpectrumRegs) {
+        let mut data = vec![regs.border, regs.port_7ffd, regs.port_1ffd, regs.port_fe];
+        data.extend_from_slice(&regs.reserved);
+        self.set_block(SPCR, data);
+    }
+
+    /// Decode AY block
+    pub fn ay(&self) -> Result<Option<AyState>, FormatError> {
+        let Some(block) = self.block(AY) else { return Ok(None) };
+        let data = &block.data;
+        if data.len() < 18 {
+            return Err(FormatError::Invalid("AY block is too short"));
+        }
+        let mut registers = [0; 16];
+        registers.copy_from_slice(&data[2..18]);
+        Ok(Some(AyState { selected: data[1], registers }))
+    }
+
+    /// Encode AY block keeping its flags
+    pub fn set_ay(&mut self, ay: &AyState) {
+        let flags = self.block(AY).and_then(|block| block.data.first().copied()).unwrap_or(0);
+        let mut data = vec![flags, ay.selected];
+        data.extend_from_slice(&ay.registers);
+        self.set_block(AY, data);
+    }
+
+    /// Decode KEYB block
+    pub fn keyboard(&self) -> Result<Option<SzxKeyboard>, FormatError> {
+        let Some(block) = self.block(KEYB) else { return Ok(None) };
+        let data = &block.data;
+        if data.len() < 5 {
+            return Err(FormatError::Invalid("KEYB block is too short"));
+        }
+        Ok(Some(SzxKeyboard {
+            flags: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            joystick: data[4],
+        }))
+    }
+
+    /// Encode KEYB block
+    pub fn set_keyboard(&mut self, keyboard: &SzxKeyboard) {
+        let mut data = keyboard.flags.to_le_bytes().to_vec();
+        data.push(keyboard.joystick);
+        self.set_block(KEYB, data);
+    }
+
+    /// Decode RAM bank, decompressing it if needed
+    pub fn ram_page(&self, bank: u8) -> Result<Option<Vec<u8>>, FormatError> {
+        let Some(block) = self.blocks.iter().find(|block| block.id == RAMP && block.data.get(2) == Some(&bank)) else {
+            return Ok(None);
+        };
+        let flags = u16::from_le_bytes([block.data[0], block.data[1]]);
+        let contents = if flags & RAMP_COMPRESSED != 0 {
+            miniz_oxide::inflate::decompress_to_vec_zlib(&block.data[3..])
+                .map_err(|_| FormatError::Invalid("RAMP block can't be decompressed"))?
+        } else {
+            block.data[3..].to_vec()
+        };
+        if contents.len() != BANK_SIZE {
+            return Err(FormatError::Invalid("RAMP block isn't 16K long"));
+        }
+        Ok(Some(contents))
+    }
+
+    /// Encode RAM bank as compressed block
+    pub fn set_ram_page(&mut self, bank: u8, contents: &[u8]) {
+        let mut data = RAMP_COMPRESSED.to_le_bytes().to_vec();
+        data.push(bank);
+        data.extend(miniz_oxide::deflate::compress_to_vec_zlib(contents, 6));
+        match self.blocks.iter_mut().find(|block| block.id == RAMP && block.data.get(2) == Some(&bank)) {
+            Some(block) => block.data = data,
+            None => self.blocks.push(SzxBlock { id: RAMP, data }),
+        }
+    }
+
+    /// Bank numbers of RAM at 4000h, 8000h and C000h
+    fn visible_banks(&self) -> Result<[u8; 3], FormatError> {
+        Ok(if self.is_128k() {
+            let port_7ffd = self.spectrum_regs()?.map(|regs| regs.port_7ffd).unwrap_or(0);
+            [5, 2, port_7ffd & 0x07]
+        } else {
+            BANKS_48K
+        })
+    }
+
+    /// RAM as seen at 4000h-FFFFh. Missing pages are filled with zeros.
+    pub fn ram(&self) -> Result<Vec<u8>, FormatError> {
+        let mut ram = Vec::with_capacity(3 * BANK_SIZE);
+        for bank in self.visible_banks()? {
+            ram.extend(self.ram_page(bank)?.unwrap_or_else(|| vec![0; BANK_SIZE]));
+        }
+        Ok(ram)
+    }
+
+    /// Capture 48K snapshot of CPU and memory
+    pub fn capture(cpu: &Cpu, memory: &dyn Memory, border: u8, tstates: u32) -> Self {
+        let mut szx = Self::new(1);
+        szx.set_z80_regs(&SzxZ80Regs { regs: cpu.save_state(), tstates, ..Default::default() });
+        szx.set_spectrum_regs(&SzxSpectrumRegs { border, port_fe: border, ..Default::default() });
+        for (bank, base) in BANKS_48K.into_iter().zip([0x4000u16, 0x8000, 0xc000]) {
+            let contents: Vec<u8> = (0..BANK_SIZE as u16).map(|offset| memory.read(base + offset)).collect();
+            szx.set_ram_page(bank, &contents);
+        }
+        szx
+    }
+
+    /// Load registers and RAM. Only banks visible at 4000h-FFFFh
+    /// are loaded from 128K snapshot.
+    pub fn apply(&self, cpu: &Cpu, memory: &dyn Memory) -> Result<(), FormatError> {
+        let z80 = self.z80_regs()?.ok_or(FormatError::Invalid("Z80R block is missing"))?;
+        let ram = self.ram()?;
+        cpu.load_state(&z80.regs);
+        for (addr, byte) in (0x4000..=0xffff).zip(ram) {
+            memory.write(addr, byte);
+        }
+        Ok(())
+    }
+
+}
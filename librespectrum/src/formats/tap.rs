@@ -0,0 +1,164 @@
+use std::fmt;
+
+use crate::devs::TapeBlock;
+
+use super::FormatError;
+
+/// Header block length including flag and checksum bytes
+const HEADER_BLOCK_SIZE: usize = 19;
+
+/// Flag byte of header blocks
+pub const HEADER_FLAG: u8 = 0x00;
+
+/// Flag byte of data blocks
+pub const DATA_FLAG: u8 = 0xff;
+
+/// File type stored in the header block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapFileType {
+    Program,
+    NumberArray,
+    CharacterArray,
+    Bytes,
+}
+
+/// Standard ROM header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapHeader {
+    pub file_type: TapFileType,
+    pub name: String,
+    /// Length of the following data block
+    pub length: u16,
+    /// Autostart line for programs, start address for bytes
+    pub param1: u16,
+    /// Program length without variables for programs, 32768 for bytes
+    pub param2: u16,
+}
+
+impl fmt::Display for TapHeader {
+
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file_type {
+            TapFileType::Program if self.param1 < 0x8000 =>
+                write!(formatter, "Program: \"{}\" {} bytes, LINE {}", self.name, self.length, self.param1),
+            TapFileType::Program =>
+                write!(formatter, "Program: \"{}\" {} bytes", self.name, self.length),
+            TapFileType::NumberArray =>
+                write!(formatter, "Number array: \"{}\" {} bytes", self.name, self.length),
+            TapFileType::CharacterArray =>
+                write!(formatter, "Character array: \"{}\" {} bytes", self.name, self.length),
+            TapFileType::Bytes =>
+                write!(formatter, "Bytes: \"{}\" {} bytes, CODE {},{}", self.name, self.length, self.param1, self.length),
+        }
+    }
+
+}
+
+/// Tape block with flag byte, payload and checksum byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapBlock {
+    pub data: Vec<u8>,
+}
+
+impl TapBlock {
+
+    /// Create block from flag and payload, computing the checksum
+    pub fn new(flag: u8, payload: &[u8]) -> Self {
+        let mut data = vec![flag];
+        data.extend_from_slice(payload);
+        data.push(data.iter().fold(0, |parity, byte| parity ^ byte));
+        Self { data }
+    }
+
+    /// Flag byte
+    pub fn flag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Data between flag and checksum bytes
+    pub fn payload(&self) -> &[u8] {
+        if self.data.len() < 2 { &[] } else { &self.data[1..self.data.len() - 1] }
+    }
+
+    /// XOR of all bytes including checksum is zero
+    pub fn checksum_valid(&self) -> bool {
+        !self.data.is_empty() && self.data.iter().fold(0, |parity, byte| parity ^ byte) == 0
+    }
+
+    /// Decode standard ROM header
+    pub fn header(&self) -> Option<TapHeader> {
+        if self.data.len() != HEADER_BLOCK_SIZE || self.data[0] != HEADER_FLAG {
+            return None;
+        }
+        let data = &self.data;
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        Some(TapHeader {
+            file_type: match data[1] {
+                0 => TapFileType::Program,
+                1 => TapFileType::NumberArray,
+                2 => TapFileType::CharacterArray,
+                3 => TapFileType::Bytes,
+                _ => return None,
+            },
+            name: data[2..12].iter()
+                .map(|&byte| if (0x20..0x7f).contains(&byte) { byte as char } else { '?' })
+                .collect::<String>()
+                .trim_end()
+                .to_string(),
+            length: word(12),
+            param1: word(14),
+            param2: word(16),
+        })
+    }
+
+    /// One-line block description for tape browser
+    pub fn describe(&self) -> String {
+        match (self.header(), self.flag()) {
+            (Some(header), _) => header.to_string(),
+            (None, Some(flag)) => format!("Data: {} bytes, flag {:02X}h", self.payload().len(), flag),
+            (None, None) => String::from("Empty block"),
+        }
+    }
+
+}
+
+/// TAP tape image: sequence of blocks each prefixed with 16-bit length
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Tap {
+    pub blocks: Vec<TapBlock>,
+}
+
+impl Tap {
+
+    /// Parse tape image
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+        let mut blocks = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let length = data.get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+                .ok_or(FormatError::InvalidSize(data.len()))?;
+            offset += 2;
+            let block = data.get(offset..offset + length).ok_or(FormatError::InvalidSize(data.len()))?;
+            blocks.push(TapBlock { data: block.to_vec() });
+            offset += length;
+        }
+        Ok(Self { blocks })
+    }
+
+    /// Blocks played at standard speed
+    pub fn tape_blocks(&self) -> Vec<TapeBlock> {
+        self.blocks.iter().cloned().map(TapeBlock::from).collect()
+    }
+
+    /// Serialize tape image
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for block in &self.blocks {
+            data.extend_from_slice(&(block.data.len() as u16).to_le_bytes());
+            data.extend_from_slice(&block.data);
+        }
+        data
+    }
+
+}
@@ -0,0 +1,92 @@
+use crate::devs::{Disk, DiskSector};
+
+use super::FormatError;
+
+/// Sectors per track of TR-DOS disks
+pub const SECTORS: usize = 16;
+
+/// TR-DOS sector size
+pub const SECTOR_SIZE: usize = 256;
+
+/// Track size in bytes
+const TRACK_SIZE: usize = SECTORS * SECTOR_SIZE;
+
+/// Offset of the disk type byte in the disk info sector (track 0, sector 9)
+const DISK_TYPE: usize = 0x8e3;
+
+/// Disk geometry (cylinders, sides) by disk type byte
+const DISK_TYPES: [(u8, (u8, u8)); 4] = [(0x16, (80, 2)), (0x17, (40, 2)), (0x18, (80, 1)), (0x19, (40, 1))];
+
+/// Highest cylinder number of an image
+const MAX_CYLINDERS: usize = 84;
+
+/// TR-DOS disk image: 256-byte sectors 1-16 of each track, tracks of both sides interleaved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trd {
+    pub cylinders: u8,
+    pub sides: u8,
+    pub data: Vec<u8>,
+}
+
+impl Trd {
+
+    /// Empty image with given geometry
+    pub fn new(cylinders: u8, sides: u8) -> Self {
+        Self { cylinders, sides, data: vec![0; cylinders as usize * sides as usize * TRACK_SIZE] }
+    }
+
+    /// Parse disk image. Geometry is taken from the disk info sector, images cut
+    /// short are padded, unformatted images are assumed to be double-sided.
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        if data.is_empty() || !data.len().is_multiple_of(SECTOR_SIZE) || data.len() > MAX_CYLINDERS * 2 * TRACK_SIZE {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+
+        let (mut cylinders, sides) = data.get(DISK_TYPE)
+            .and_then(|disk_type| DISK_TYPES.iter().find(|(other, _)| other == disk_type))
+            .map(|&(_, geometry)| geometry)
+            .unwrap_or((0, 2));
+        let tracks = data.len().div_ceil(TRACK_SIZE).div_ceil(sides as usize);
+        cylinders = cylinders.max(tracks.min(MAX_CYLINDERS) as u8);
+
+        let mut trd = Self::new(cylinders, sides);
+        trd.data[..data.len()].copy_from_slice(data);
+        Ok(trd)
+
+    }
+
+    /// Serialize disk image
+    pub fn write(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Disk with the image sectors
+    pub fn disk(&self) -> Disk {
+        let mut disk = Disk::new(self.cylinders, self.sides);
+        for (track, sectors) in disk.tracks.iter_mut().enumerate() {
+            let (cylinder, side) = (track / self.sides as usize, track % self.sides as usize);
+            *sectors = (0..SECTORS).map(|sector| {
+                let offset = (track * SECTORS + sector) * SECTOR_SIZE;
+                DiskSector::new(cylinder as u8, side as u8, sector as u8 + 1, self.data[offset..offset + SECTOR_SIZE].to_vec())
+            }).collect();
+        }
+        disk
+    }
+
+    /// Image of the disk contents, sectors missing on the disk are left empty
+    pub fn from_disk(disk: &Disk) -> Self {
+        let mut trd = Self::new(disk.cylinders(), disk.sides);
+        for (track, sectors) in disk.tracks.iter().enumerate() {
+            for number in 1..=SECTORS {
+                if let Some(sector) = sectors.iter().find(|sector| sector.sector as usize == number) {
+                    let offset = (track * SECTORS + number - 1) * SECTOR_SIZE;
+                    let length = sector.data.len().min(SECTOR_SIZE);
+                    trd.data[offset..offset + length].copy_from_slice(&sector.data[..length]);
+                }
+            }
+        }
+        trd
+    }
+
+}
@@ -0,0 +1,798 @@
+use crate::devs::{
+    ONE_PULSE, PILOT_PULSE, SYNC1_PULSE, SYNC2_PULSE, TSTATES_PER_MS, TapeBlock, TapePulse, TapeSegment, ZERO_PULSE,
+};
+
+use super::{FormatError, Reader, csw::{decode_rle, samples_to_tstates}, tap::TapBlock};
+
+pub use super::csw::CswCompression;
+
+/// File signature
+const MAGIC: &[u8; 8] = b"ZXTape!\x1a";
+
+/// Format version written
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 20;
+
+/// Limit on blocks visited while following jumps and loops, protecting
+/// from tapes jumping back forever
+const MAX_STEPS: usize = 100_000;
+
+/// Deviation of pulse lengths still recognized as standard timings, in percent
+const TOLERANCE: u32 = 25;
+
+/// Pilot pulses needed to recognize standard speed block
+const MIN_PILOT_PULSES: usize = 256;
+
+/// Pulses this long split recorded signal into blocks (20ms)
+const GAP_TSTATES: u32 = 70_000;
+
+/// Pulses allowed after the last data bit of decoded block
+const TRAILING_PULSES: usize = 2;
+
+/// Sample length of direct recording blocks made from recorded signal (44.1kHz)
+const DIRECT_RECORDING_TSTATES: u16 = 79;
+
+/// Symbol of generalized data block: polarity of the first pulse and pulse lengths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TzxSymbol {
+    /// 0 - flip the level, 1 - keep it, 2 - force low, 3 - force high
+    pub polarity: u8,
+    /// Pulse lengths, zero length ends the symbol early
+    pub pulses: Vec<u16>,
+}
+
+/// Hardware the tape runs on, uses or doesn't run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TzxHardware {
+    /// Hardware type, such as computer, sound device or joystick
+    pub kind: u8,
+    /// Hardware id within the type
+    pub id: u8,
+    /// 0 - runs on it, 1 - uses it, 2 - runs but doesn't use it, 3 - doesn't run on it
+    pub info: u8,
+}
+
+/// TZX block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TzxBlock {
+    /// 10h: block with standard ROM timings
+    Standard { pause: u16, data: TapBlock },
+    /// 11h: block with custom pilot, sync and bit timings
+    Turbo {
+        pilot: u16, sync1: u16, sync2: u16, zero: u16, one: u16,
+        pilot_pulses: u16, last_bits: u8, pause: u16, data: Vec<u8>,
+    },
+    /// 12h: pulses of equal length
+    PureTone { pulse: u16, count: u16 },
+    /// 13h: pulses of given lengths
+    PulseSequence(Vec<u16>),
+    /// 14h: bits without pilot and sync pulses
+    PureData { zero: u16, one: u16, last_bits: u8, pause: u16, data: Vec<u8> },
+    /// 15h: signal sampled at given rate, one bit per sample
+    DirectRecording { tstates_per_sample: u16, pause: u16, last_bits: u8, data: Vec<u8> },
+    /// 18h: pulse lengths in samples at given rate
+    CswRecording { pause: u16, sample_rate: u32, compression: CswCompression, pulses: u32, data: Vec<u8> },
+    /// 19h: pilot and data encoded with custom symbol alphabets
+    GeneralizedData {
+        pause: u16,
+        pilot_symbols: Vec<TzxSymbol>,
+        /// Symbol index and repeat count
+        pilot: Vec<(u8, u16)>,
+        data_symbols: Vec<TzxSymbol>,
+        /// Number of symbols in data stream
+        data_count: u32,
+        data: Vec<u8>,
+    },
+    /// 20h: pause in milliseconds, zero stops the tape
+    Pause(u16),
+    /// 21h
+    GroupStart(String),
+    /// 22h
+    GroupEnd,
+    /// 23h: jump relative to this block
+    Jump(i16),
+    /// 24h: repeat blocks up to loop end given number of times
+    LoopStart(u16),
+    /// 25h
+    LoopEnd,
+    /// 26h: play blocks at offsets relative to this block, each up to return
+    CallSequence(Vec<i16>),
+    /// 27h
+    Return,
+    /// 2Ah
+    StopIf48k,
+    /// 2Bh
+    SignalLevel(bool),
+    /// 30h
+    Text(String),
+    /// 31h: message shown for given seconds
+    Message { seconds: u8, text: String },
+    /// 32h: text id and text
+    ArchiveInfo(Vec<(u8, String)>),
+    /// 33h
+    HardwareType(Vec<TzxHardware>),
+    /// Block kept as it is in the file, contents follow the id
+    Unknown { id: u8, data: Vec<u8> },
+}
+
+/// TZX tape image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tzx {
+    pub major: u8,
+    pub minor: u8,
+    pub blocks: Vec<TzxBlock>,
+}
+
+impl Default for Tzx {
+    fn default() -> Self {
+        Self { major: MAJOR_VERSION, minor: MINOR_VERSION, blocks: vec![] }
+    }
+}
+
+impl Reader<'_> {
+
+    /// Text with length stored in a byte
+    fn text(&mut self) -> Result<String, FormatError> {
+        let length = self.byte()? as usize;
+        Ok(decode_text(self.bytes(length)?))
+    }
+
+    fn symbols(&mut self, count: usize, pulses: usize) -> Result<Vec<TzxSymbol>, FormatError> {
+        (0..count).map(|_| Ok(TzxSymbol {
+            polarity: self.byte()?,
+            pulses: (0..pulses).map(|_| self.word()).collect::<Result<_, _>>()?,
+        })).collect()
+    }
+
+}
+
+/// Texts are stored byte per character
+fn decode_text(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn encode_text(text: &str) -> Vec<u8> {
+    text.chars().map(|ch| ch as u8).collect()
+}
+
+/// Alphabet size stored in a byte where zero means 256
+fn alphabet_size(size: u8) -> usize {
+    if size == 0 { 256 } else { size as usize }
+}
+
+/// Bits per symbol in generalized data stream
+fn symbol_bits(alphabet: usize) -> usize {
+    alphabet.next_power_of_two().trailing_zeros() as usize
+}
+
+impl TzxBlock {
+
+    /// Parse block with given id, returns the block and its size without id
+    fn read(id: u8, data: &[u8]) -> Result<(Self, usize), FormatError> {
+
+        let mut reader = Reader::new(data);
+        let rd = &mut reader;
+
+        let block = match id {
+            0x10 => {
+                let pause = rd.word()?;
+                let length = rd.word()? as usize;
+                TzxBlock::Standard { pause, data: TapBlock { data: rd.bytes(length)?.to_vec() } }
+            },
+            0x11 => TzxBlock::Turbo {
+                pilot: rd.word()?, sync1: rd.word()?, sync2: rd.word()?, zero: rd.word()?, one: rd.word()?,
+                pilot_pulses: rd.word()?, last_bits: rd.byte()?, pause: rd.word()?,
+                data: { let length = rd.triple()? as usize; rd.bytes(length)?.to_vec() },
+            },
+            0x12 => TzxBlock::PureTone { pulse: rd.word()?, count: rd.word()? },
+            0x13 => {
+                let count = rd.byte()?;
+                TzxBlock::PulseSequence((0..count).map(|_| rd.word()).collect::<Result<_, _>>()?)
+            },
+            0x14 => TzxBlock::PureData {
+                zero: rd.word()?, one: rd.word()?, last_bits: rd.byte()?, pause: rd.word()?,
+                data: { let length = rd.triple()? as usize; rd.bytes(length)?.to_vec() },
+            },
+            0x15 => TzxBlock::DirectRecording {
+                tstates_per_sample: rd.word()?, pause: rd.word()?, last_bits: rd.byte()?,
+                data: { let length = rd.triple()? as usize; rd.bytes(length)?.to_vec() },
+            },
+            0x18 => {
+                let length = rd.dword()? as usize;
+                let mut rd = Reader::new(rd.bytes(length)?);
+                let pause = rd.word()?;
+                let sample_rate = rd.triple()?;
+                let compression = match rd.byte()? {
+                    1 => CswCompression::Rle,
+                    2 => CswCompression::ZRle,
+                    _ => return Err(FormatError::Invalid("unknown CSW compression")),
+                };
+                let pulses = rd.dword()?;
+                let data = rd.rest().to_vec();
+                TzxBlock::CswRecording { pause, sample_rate, compression, pulses, data }
+            },
+            0x19 => {
+                let length = rd.dword()? as usize;
+                let mut rd = Reader::new(rd.bytes(length)?);
+                let pause = rd.word()?;
+                let pilot_count = rd.dword()? as usize;
+                let pilot_pulses = rd.byte()? as usize;
+                let pilot_alphabet = alphabet_size(rd.byte()?);
+                let data_count = rd.dword()?;
+                let data_pulses = rd.byte()? as usize;
+                let data_alphabet = alphabet_size(rd.byte()?);
+                let (pilot_symbols, pilot) = if pilot_count > 0 {
+                    let symbols = rd.symbols(pilot_alphabet, pilot_pulses)?;
+                    let pilot = (0..pilot_count).map(|_| Ok((rd.byte()?, rd.word()?))).collect::<Result<_, FormatError>>()?;
+                    (symbols, pilot)
+                } else {
+                    (vec![], vec![])
+                };
+                let (data_symbols, data) = if data_count > 0 {
+                    let symbols = rd.symbols(data_alphabet, data_pulses)?;
+                    let length = (symbol_bits(data_alphabet) * data_count as usize).div_ceil(8);
+                    (symbols, rd.bytes(length)?.to_vec())
+                } else {
+                    (vec![], vec![])
+                };
+                TzxBlock::GeneralizedData { pause, pilot_symbols, pilot, data_symbols, data_count, data }
+            },
+            0x20 => TzxBlock::Pause(rd.word()?),
+            0x21 => TzxBlock::GroupStart(rd.text()?),
+            0x22 => TzxBlock::GroupEnd,
+            0x23 => TzxBlock::Jump(rd.word()? as i16),
+            0x24 => TzxBlock::LoopStart(rd.word()?),
+            0x25 => TzxBlock::LoopEnd,
+            0x26 => {
+                let count = rd.word()?;
+                TzxBlock::CallSequence((0..count).map(|_| Ok(rd.word()? as i16)).collect::<Result<_, FormatError>>()?)
+            },
+            0x27 => TzxBlock::Return,
+            0x2a => {
+                rd.dword()?;
+                TzxBlock::StopIf48k
+            },
+            0x2b => {
+                let length = rd.dword()? as usize;
+                TzxBlock::SignalLevel(rd.bytes(length)?.first().is_some_and(|&level| level != 0))
+            },
+            0x30 => TzxBlock::Text(rd.text()?),
+            0x31 => TzxBlock::Message { seconds: rd.byte()?, text: rd.text()? },
+            0x32 => {
+                let length = rd.word()? as usize;
+                let mut rd = Reader::new(rd.bytes(length)?);
+                let count = rd.byte()?;
+                TzxBlock::ArchiveInfo((0..count).map(|_| Ok((rd.byte()?, rd.text()?))).collect::<Result<_, FormatError>>()?)
+            },
+            0x33 => {
+                let count = rd.byte()?;
+                TzxBlock::HardwareType((0..count).map(|_| Ok(TzxHardware {
+                    kind: rd.byte()?, id: rd.byte()?, info: rd.byte()?,
+                })).collect::<Result<_, FormatError>>()?)
+            },
+            _ => {
+                // Blocks of fixed or unknown size, since version 1.10 any block
+                // that isn't listed in the specification starts with 32-bit length
+                let size = match id {
+                    0x28 => rd.word()? as usize + 2,
+                    0x34 => 8,
+                    0x35 => { rd.bytes(16)?; rd.dword()? as usize + 20 },
+                    0x40 => { rd.byte()?; rd.triple()? as usize + 4 },
+                    0x5a => 9,
+                    _ => rd.dword()? as usize + 4,
+                };
+                rd.offset = 0;
+                TzxBlock::Unknown { id, data: rd.bytes(size)?.to_vec() }
+            },
+        };
+
+        Ok((block, reader.offset))
+
+    }
+
+    /// Block id and contents
+    fn write(&self) -> (u8, Vec<u8>) {
+
+        let mut out = vec![];
+        let word = |out: &mut Vec<u8>, value: u16| out.extend_from_slice(&value.to_le_bytes());
+        let triple = |out: &mut Vec<u8>, value: usize| out.extend_from_slice(&(value as u32).to_le_bytes()[..3]);
+        let dword = |out: &mut Vec<u8>, value: usize| out.extend_from_slice(&(value as u32).to_le_bytes());
+        let text = |out: &mut Vec<u8>, value: &str| {
+            let bytes = encode_text(value);
+            out.push(bytes.len() as u8);
+            out.extend(bytes);
+        };
+        let symbols = |out: &mut Vec<u8>, symbols: &[TzxSymbol]| {
+            for symbol in symbols {
+                out.push(symbol.polarity);
+                symbol.pulses.iter().for_each(|&pulse| out.extend_from_slice(&pulse.to_le_bytes()));
+            }
+        };
+
+        let id = match self {
+            TzxBlock::Standard { pause, data } => {
+                word(&mut out, *pause);
+                word(&mut out, data.data.len() as u16);
+                out.extend_from_slice(&data.data);
+                0x10
+            },
+            TzxBlock::Turbo { pilot, sync1, sync2, zero, one, pilot_pulses, last_bits, pause, data } => {
+                [*pilot, *sync1, *sync2, *zero, *one, *pilot_pulses].iter().for_each(|&value| word(&mut out, value));
+                out.push(*last_bits);
+                word(&mut out, *pause);
+                triple(&mut out, data.len());
+                out.extend_from_slice(data);
+                0x11
+            },
+            TzxBlock::PureTone { pulse, count } => {
+                word(&mut out, *pulse);
+                word(&mut out, *count);
+                0x12
+            },
+            TzxBlock::PulseSequence(pulses) => {
+                out.push(pulses.len() as u8);
+                pulses.iter().for_each(|&pulse| word(&mut out, pulse));
+                0x13
+            },
+            TzxBlock::PureData { zero, one, last_bits, pause, data } => {
+                word(&mut out, *zero);
+                word(&mut out, *one);
+                out.push(*last_bits);
+                word(&mut out, *pause);
+                triple(&mut out, data.len());
+                out.extend_from_slice(data);
+                0x14
+            },
+            TzxBlock::DirectRecording { tstates_per_sample, pause, last_bits, data } => {
+                word(&mut out, *tstates_per_sample);
+                word(&mut out, *pause);
+                out.push(*last_bits);
+                triple(&mut out, data.len());
+                out.extend_from_slice(data);
+                0x15
+            },
+            TzxBlock::CswRecording { pause, sample_rate, compression, pulses, data } => {
+                dword(&mut out, data.len() + 10);
+                word(&mut out, *pause);
+                triple(&mut out, *sample_rate as usize);
+                out.push(match compression { CswCompression::Rle => 1, CswCompression::ZRle => 2 });
+                dword(&mut out, *pulses as usize);
+                out.extend_from_slice(data);
+                0x18
+            },
+            TzxBlock::GeneralizedData { pause, pilot_symbols, pilot, data_symbols, data_count, data } => {
+                let mut body = vec![];
+                word(&mut body, *pause);
+                dword(&mut body, pilot.len());
+                body.push(pilot_symbols.first().map_or(0, |symbol| symbol.pulses.len() as u8));
+                body.push(pilot_symbols.len() as u8);
+                dword(&mut body, *data_count as usize);
+                body.push(data_symbols.first().map_or(0, |symbol| symbol.pulses.len() as u8));
+                body.push(data_symbols.len() as u8);
+                if !pilot.is_empty() {
+                    symbols(&mut body, pilot_symbols);
+                    for &(symbol, count) in pilot {
+                        body.push(symbol);
+                        word(&mut body, count);
+                    }
+                }
+                if *data_count > 0 {
+                    symbols(&mut body, data_symbols);
+                    body.extend_from_slice(data);
+                }
+                dword(&mut out, body.len());
+                out.extend(body);
+                0x19
+            },
+            TzxBlock::Pause(pause) => {
+                word(&mut out, *pause);
+                0x20
+            },
+            TzxBlock::GroupStart(name) => {
+                text(&mut out, name);
+                0x21
+            },
+            TzxBlock::GroupEnd => 0x22,
+            TzxBlock::Jump(offset) => {
+                word(&mut out, *offset as u16);
+                0x23
+            },
+            TzxBlock::LoopStart(count) => {
+                word(&mut out, *count);
+                0x24
+            },
+            TzxBlock::LoopEnd => 0x25,
+            TzxBlock::CallSequence(offsets) => {
+                word(&mut out, offsets.len() as u16);
+                offsets.iter().for_each(|&offset| word(&mut out, offset as u16));
+                0x26
+            },
+            TzxBlock::Return => 0x27,
+            TzxBlock::StopIf48k => {
+                dword(&mut out, 0);
+                0x2a
+            },
+            TzxBlock::SignalLevel(level) => {
+                dword(&mut out, 1);
+                out.push(*level as u8);
+                0x2b
+            },
+            TzxBlock::Text(value) => {
+                text(&mut out, value);
+                0x30
+            },
+            TzxBlock::Message { seconds, text: value } => {
+                out.push(*seconds);
+                text(&mut out, value);
+                0x31
+            },
+            TzxBlock::ArchiveInfo(entries) => {
+                let mut body = vec![entries.len() as u8];
+                for (id, value) in entries {
+                    body.push(*id);
+                    text(&mut body, value);
+                }
+                word(&mut out, body.len() as u16);
+                out.extend(body);
+                0x32
+            },
+            TzxBlock::HardwareType(entries) => {
+                out.push(entries.len() as u8);
+                entries.iter().for_each(|entry| out.extend_from_slice(&[entry.kind, entry.id, entry.info]));
+                0x33
+            },
+            TzxBlock::Unknown { id, data } => {
+                out.extend_from_slice(data);
+                *id
+            },
+        };
+
+        (id, out)
+
+    }
+
+    /// One-line description for tape browser
+    pub fn describe(&self) -> String {
+        match self {
+            TzxBlock::Standard { data, .. } => data.describe(),
+            TzxBlock::Turbo { data, .. } => format!("Turbo data: {} bytes", data.len()),
+            TzxBlock::PureTone { pulse, count } => format!("Pure tone: {} pulses of {} T", count, pulse),
+            TzxBlock::PulseSequence(pulses) => format!("Pulse sequence: {} pulses", pulses.len()),
+            TzxBlock::PureData { data, .. } => format!("Pure data: {} bytes", data.len()),
+            TzxBlock::DirectRecording { data, .. } => format!("Direct recording: {} bytes", data.len()),
+            TzxBlock::CswRecording { pulses, .. } => format!("CSW recording: {} pulses", pulses),
+            TzxBlock::GeneralizedData { data_count, .. } => format!("Generalized data: {} symbols", data_count),
+            TzxBlock::Pause(0) => String::from("Stop the tape"),
+            TzxBlock::Pause(pause) => format!("Pause: {} ms", pause),
+            TzxBlock::GroupStart(name) => format!("Group: {}", name),
+            TzxBlock::GroupEnd => String::from("Group end"),
+            TzxBlock::Jump(offset) => format!("Jump: {:+}", offset),
+            TzxBlock::LoopStart(count) => format!("Loop: {} times", count),
+            TzxBlock::LoopEnd => String::from("Loop end"),
+            TzxBlock::CallSequence(offsets) => format!("Call sequence: {} calls", offsets.len()),
+            TzxBlock::Return => String::from("Return"),
+            TzxBlock::StopIf48k => String::from("Stop the tape in 48K mode"),
+            TzxBlock::SignalLevel(level) => format!("Signal level: {}", if *level { "high" } else { "low" }),
+            TzxBlock::Text(text) => format!("Text: {}", text),
+            TzxBlock::Message { text, .. } => format!("Message: {}", text),
+            TzxBlock::ArchiveInfo(entries) => entries.iter()
+                .map(|(id, text)| format!("{}: {}", archive_info_name(*id), text))
+                .collect::<Vec<_>>()
+                .join(", "),
+            TzxBlock::HardwareType(entries) => format!("Hardware type: {} entries", entries.len()),
+            TzxBlock::Unknown { id, data } => format!("Unknown block {:02X}h: {} bytes", id, data.len()),
+        }
+    }
+
+    /// Signal of the block, blocks controlling the playback have no signal
+    pub fn segments(&self) -> Vec<TapeSegment> {
+
+        let pause = |pause: u16| TapeSegment::Pause(pause as u32 * TSTATES_PER_MS);
+
+        match self {
+            TzxBlock::Standard { pause, data } => TapeSegment::standard(data, *pause as u32),
+            TzxBlock::Turbo { pilot, sync1, sync2, zero, one, pilot_pulses, last_bits, pause: ms, data } => vec![
+                TapeSegment::Tone { pulse: *pilot as u32, count: *pilot_pulses as u32 },
+                TapeSegment::Pulses(vec![*sync1 as u32, *sync2 as u32]),
+                TapeSegment::Data { zero: *zero as u32, one: *one as u32, data: data.clone(), last_bits: *last_bits },
+                pause(*ms),
+            ],
+            TzxBlock::PureTone { pulse, count } =>
+                vec![TapeSegment::Tone { pulse: *pulse as u32, count: *count as u32 }],
+            TzxBlock::PulseSequence(pulses) =>
+                vec![TapeSegment::Pulses(pulses.iter().map(|&pulse| pulse as u32).collect())],
+            TzxBlock::PureData { zero, one, last_bits, pause: ms, data } => vec![
+                TapeSegment::Data { zero: *zero as u32, one: *one as u32, data: data.clone(), last_bits: *last_bits },
+                pause(*ms),
+            ],
+            TzxBlock::DirectRecording { tstates_per_sample, pause: ms, last_bits, data } => {
+                let samples = (data.len() * 8).saturating_sub(8 - *last_bits as usize);
+                let mut pulses: Vec<TapePulse> = vec![];
+                for sample in 0..samples {
+                    let level = data[sample / 8] & (0x80 >> (sample % 8)) != 0;
+                    match pulses.last_mut() {
+                        Some(TapePulse::Level(last, tstates)) if *last == level => *tstates += *tstates_per_sample as u32,
+                        _ => pulses.push(TapePulse::Level(level, *tstates_per_sample as u32)),
+                    }
+                }
+                vec![TapeSegment::Raw(pulses), pause(*ms)]
+            },
+            TzxBlock::CswRecording { pause: ms, sample_rate, compression, data, .. } => {
+                // Corrupted recording plays as silence
+                let pulses = decode_rle(data, *compression).unwrap_or_default();
+                vec![TapeSegment::Pulses(samples_to_tstates(&pulses, *sample_rate)), pause(*ms)]
+            },
+            TzxBlock::GeneralizedData { pause: ms, pilot_symbols, pilot, data_symbols, data_count, data } => {
+                let mut pulses = vec![];
+                for &(symbol, count) in pilot {
+                    if let Some(symbol) = pilot_symbols.get(symbol as usize) {
+                        (0..count).for_each(|_| push_symbol(&mut pulses, symbol));
+                    }
+                }
+                let bits = symbol_bits(data_symbols.len());
+                for index in 0..*data_count as usize {
+                    let symbol = (0..bits).fold(0, |symbol, bit| {
+                        let bit = index * bits + bit;
+                        (symbol << 1) | ((data.get(bit / 8).copied().unwrap_or(0) >> (7 - bit % 8)) & 1) as usize
+                    });
+                    if let Some(symbol) = data_symbols.get(symbol) {
+                        push_symbol(&mut pulses, symbol);
+                    }
+                }
+                vec![TapeSegment::Raw(pulses), pause(*ms)]
+            },
+            TzxBlock::Pause(0) => vec![TapeSegment::Stop],
+            TzxBlock::Pause(ms) => vec![pause(*ms)],
+            TzxBlock::SignalLevel(level) => vec![TapeSegment::Raw(vec![TapePulse::Level(*level, 0)])],
+            _ => vec![],
+        }
+
+    }
+
+    /// Block for the tape deck. Only standard speed blocks can be fast loaded.
+    pub fn tape_block(&self) -> TapeBlock {
+        TapeBlock {
+            description: self.describe(),
+            data: match self {
+                TzxBlock::Standard { data, .. } => Some(data.clone()),
+                _ => None,
+            },
+            segments: self.segments(),
+        }
+    }
+
+}
+
+/// Append pulses of generalized data symbol
+fn push_symbol(pulses: &mut Vec<TapePulse>, symbol: &TzxSymbol) {
+    for (index, &pulse) in symbol.pulses.iter().take_while(|&&pulse| pulse > 0).enumerate() {
+        let pulse = pulse as u32;
+        pulses.push(match (index, symbol.polarity & 0x03) {
+            (0, 1) => TapePulse::Hold(pulse),
+            (0, 2) => TapePulse::Level(false, pulse),
+            (0, 3) => TapePulse::Level(true, pulse),
+            _ => TapePulse::Edge(pulse),
+        });
+    }
+}
+
+/// Archive info text id name
+fn archive_info_name(id: u8) -> &'static str {
+    match id {
+        0x00 => "Title",
+        0x01 => "Publisher",
+        0x02 => "Author",
+        0x03 => "Year",
+        0x04 => "Language",
+        0x05 => "Type",
+        0x06 => "Price",
+        0x07 => "Loader",
+        0x08 => "Origin",
+        _ => "Comment",
+    }
+}
+
+/// Pulse length is close to the target
+fn near(pulse: u32, target: u32) -> bool {
+    pulse.abs_diff(target) * 100 <= target * TOLERANCE
+}
+
+/// Decode standard speed block from pilot tone, sync pulses and data bits
+fn decode_standard(pulses: &[u32]) -> Option<TapBlock> {
+    let pilot = pulses.iter().take_while(|&&pulse| near(pulse, PILOT_PULSE)).count();
+    let [sync1, sync2, bits @ ..] = pulses.get(pilot..)? else { return None };
+    if pilot < MIN_PILOT_PULSES || !near(*sync1, SYNC1_PULSE) || !near(*sync2, SYNC2_PULSE) {
+        return None;
+    }
+    let edges = bits.len();
+    let bits: Vec<bool> = bits.chunks_exact(2).map_while(|pair| match pair {
+        [first, second] if near(*first, ZERO_PULSE) && near(*second, ZERO_PULSE) => Some(false),
+        [first, second] if near(*first, ONE_PULSE) && near(*second, ONE_PULSE) => Some(true),
+        _ => None,
+    }).collect();
+    // Saving routine leaves up to two trailing edges when it restores the border
+    if bits.len() * 2 + TRAILING_PULSES < edges {
+        return None;
+    }
+    if bits.is_empty() || !bits.len().is_multiple_of(8) {
+        return None;
+    }
+    let data = bits.chunks(8).map(|byte| byte.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8)).collect();
+    Some(TapBlock { data })
+}
+
+/// Direct recording of the signal starting with given level, followed by pause in milliseconds
+fn direct_recording(level: bool, pulses: &[u32], tstates_per_sample: u16, pause: u16) -> TzxBlock {
+    let total: u64 = pulses.iter().map(|&pulse| pulse as u64).sum();
+    let samples = (total / tstates_per_sample as u64) as usize;
+    let mut data = vec![0u8; samples.div_ceil(8)];
+    let mut pulse = 0;
+    let mut pulse_end = pulses.first().copied().unwrap_or(0) as u64;
+    for sample in 0..samples {
+        // Level in the middle of the sample
+        let time = sample as u64 * tstates_per_sample as u64 + tstates_per_sample as u64 / 2;
+        while time >= pulse_end && pulse + 1 < pulses.len() {
+            pulse += 1;
+            pulse_end += pulses[pulse] as u64;
+        }
+        if level ^ (pulse % 2 == 1) {
+            data[sample / 8] |= 0x80 >> (sample % 8);
+        }
+    }
+    let last_bits = match samples % 8 { 0 => 8, bits => bits as u8 };
+    TzxBlock::DirectRecording { tstates_per_sample, pause, last_bits, data }
+}
+
+impl Tzx {
+
+    /// Convert recorded signal, given by initial level and pulse lengths in t-states,
+    /// into blocks split by silence. Blocks with standard ROM timings are decoded,
+    /// others are stored as direct recordings.
+    pub fn from_pulses(initial_level: bool, pulses: &[u32]) -> Self {
+
+        let mut tzx = Tzx::default();
+
+        let mut push_block = |level: bool, pulses: &[u32], gap: u32| {
+            let pause = (gap / TSTATES_PER_MS).min(u16::MAX as u32) as u16;
+            tzx.blocks.push(match decode_standard(pulses) {
+                Some(data) => TzxBlock::Standard { pause, data },
+                None => direct_recording(level, pulses, DIRECT_RECORDING_TSTATES, pause),
+            });
+        };
+
+        // Index and level of the first pulse of the current block
+        let mut start: Option<(usize, bool)> = None;
+        let mut level = initial_level;
+        for (index, &pulse) in pulses.iter().enumerate() {
+            if pulse >= GAP_TSTATES {
+                if let Some((first, first_level)) = start.take() {
+                    push_block(first_level, &pulses[first..index], pulse);
+                }
+            } else if start.is_none() {
+                start = Some((index, level));
+            }
+            level = !level;
+        }
+        if let Some((first, first_level)) = start {
+            push_block(first_level, &pulses[first..], 0);
+        }
+
+        tzx
+
+    }
+
+    /// Parse tape image
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+        if data.len() < 10 || &data[..8] != MAGIC {
+            return Err(FormatError::Invalid("TZX signature is missing"));
+        }
+        let mut blocks = vec![];
+        let mut offset = 10;
+        while offset < data.len() {
+            let (block, size) = TzxBlock::read(data[offset], &data[offset + 1..])?;
+            blocks.push(block);
+            offset += size + 1;
+        }
+        Ok(Self { major: data[8], minor: data[9], blocks })
+    }
+
+    /// Serialize tape image
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[self.major, self.minor]);
+        for block in &self.blocks {
+            let (id, contents) = block.write();
+            data.push(id);
+            data.extend(contents);
+        }
+        data
+    }
+
+    /// Blocks in the order of playback, following jumps, loops and call sequences.
+    /// Stop-the-tape-if-48K blocks stop the tape only when `is_48k` is set.
+    pub fn tape_blocks(&self, is_48k: bool) -> Vec<TapeBlock> {
+
+        let mut result = vec![];
+        let mut index = 0;
+        // Index of the first block in the loop and remaining repetitions
+        let mut loops: Vec<(usize, u16)> = vec![];
+        // Index of call sequence block and the current call
+        let mut call: Option<(usize, usize)> = None;
+
+        let offset = |index: usize, offset: i16| index.checked_add_signed(offset as isize);
+
+        for _ in 0..MAX_STEPS {
+            let Some(block) = self.blocks.get(index) else { break };
+            let next = match block {
+                TzxBlock::Jump(rel) => offset(index, *rel),
+                TzxBlock::LoopStart(count) => {
+                    loops.push((index + 1, *count));
+                    Some(index + 1)
+                },
+                TzxBlock::LoopEnd => match loops.last_mut() {
+                    Some((start, count)) if *count > 1 => {
+                        *count -= 1;
+                        Some(*start)
+                    },
+                    _ => {
+                        loops.pop();
+                        Some(index + 1)
+                    },
+                },
+                TzxBlock::CallSequence(offsets) => match offsets.first() {
+                    Some(&rel) => {
+                        call = Some((index, 0));
+                        offset(index, rel)
+                    },
+                    None => Some(index + 1),
+                },
+                TzxBlock::Return => match call {
+                    Some((caller, current)) => {
+                        let TzxBlock::CallSequence(offsets) = &self.blocks[caller] else { unreachable!() };
+                        match offsets.get(current + 1) {
+                            Some(&rel) => {
+                                call = Some((caller, current + 1));
+                                offset(caller, rel)
+                            },
+                            None => {
+                                call = None;
+                                Some(caller + 1)
+                            },
+                        }
+                    },
+                    None => Some(index + 1),
+                },
+                TzxBlock::StopIf48k => {
+                    let mut block = block.tape_block();
+                    if is_48k {
+                        block.segments = vec![TapeSegment::Stop];
+                    }
+                    result.push(block);
+                    Some(index + 1)
+                },
+                _ => {
+                    result.push(block.tape_block());
+                    Some(index + 1)
+                },
+            };
+            let Some(next) = next else { break };
+            index = next;
+        }
+
+        result
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn symbol_bits_cover_alphabet() {
+        assert_eq!([1, 2, 3, 4, 5, 256].map(symbol_bits), [0, 1, 2, 2, 3, 8]);
+    }
+
+}
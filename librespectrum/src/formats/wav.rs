@@ -0,0 +1,193 @@
+use crate::devs::{TapeBlock, TapePulse, TapeSegment};
+
+use super::{FormatError, Reader, csw::samples_to_tstates};
+
+/// CPU clock the t-states refer to
+const CLOCK_HZ: u64 = 3_500_000;
+
+/// PCM format tag
+const FORMAT_PCM: u16 = 1;
+
+/// Sample values of high and low signal level
+const HIGH: i16 = 0x4000;
+const LOW: i16 = -0x4000;
+
+/// How the signal level is recovered from audio samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeDetector {
+    /// High when the sample is above the threshold
+    Threshold(i16),
+    /// High once the sample rises above `high`, low once it falls below `low`,
+    /// so noise smaller than the hysteresis doesn't produce edges
+    Schmitt { low: i16, high: i16 },
+}
+
+impl Default for EdgeDetector {
+    fn default() -> Self {
+        EdgeDetector::Schmitt { low: -0x400, high: 0x400 }
+    }
+}
+
+impl EdgeDetector {
+
+    /// Level after the sample given the previous level
+    fn level(&self, level: bool, sample: i16) -> bool {
+        match *self {
+            EdgeDetector::Threshold(threshold) => sample > threshold,
+            EdgeDetector::Schmitt { low, high } => (level || sample > high) && sample >= low,
+        }
+    }
+
+}
+
+/// PCM WAV audio
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// 8 or 16
+    pub bits_per_sample: u16,
+    /// Interleaved samples scaled to 16-bit range
+    pub samples: Vec<i16>,
+}
+
+impl Wav {
+
+    /// Parse PCM audio file with 8 or 16-bit samples
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        let mut rd = Reader::new(data);
+        let riff = rd.bytes(4)?;
+        rd.dword()?;
+        if riff != b"RIFF" || rd.bytes(4)? != b"WAVE" {
+            return Err(FormatError::Invalid("WAV signature is missing"));
+        }
+
+        let mut format = None;
+        while !rd.is_empty() {
+            let id = rd.bytes(4)?;
+            // Captures cut short often have data chunk size larger than the file
+            let size = (rd.dword()? as usize).min(rd.data.len() - rd.offset);
+            let mut chunk = Reader::new(rd.bytes(size)?);
+            // Chunks are padded to even size
+            if !size.is_multiple_of(2) && !rd.is_empty() {
+                rd.byte()?;
+            }
+            match id {
+                b"fmt " => {
+                    if chunk.word()? != FORMAT_PCM {
+                        return Err(FormatError::Invalid("only PCM audio is supported"));
+                    }
+                    let channels = chunk.word()?;
+                    let sample_rate = chunk.dword()?;
+                    chunk.bytes(6)?;
+                    let bits_per_sample = chunk.word()?;
+                    if channels == 0 || sample_rate == 0 || !matches!(bits_per_sample, 8 | 16) {
+                        return Err(FormatError::Invalid("unsupported WAV sample format"));
+                    }
+                    format = Some((channels, sample_rate, bits_per_sample));
+                },
+                b"data" => {
+                    let (channels, sample_rate, bits_per_sample) = format.ok_or(FormatError::Invalid("WAV format chunk is missing"))?;
+                    let samples = match bits_per_sample {
+                        8 => chunk.data.iter().map(|&sample| ((sample as i16) - 128) << 8).collect(),
+                        _ => chunk.data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect(),
+                    };
+                    return Ok(Self { sample_rate, channels, bits_per_sample, samples });
+                },
+                _ => (),
+            }
+        }
+
+        Err(FormatError::Invalid("WAV data chunk is missing"))
+
+    }
+
+    /// Initial level and pulse lengths in t-states detected in the audio.
+    /// Channels are mixed together.
+    pub fn pulses(&self, detector: EdgeDetector) -> (bool, Vec<u32>) {
+        let channels = self.channels.max(1) as usize;
+        let mut mono = self.samples.chunks_exact(channels)
+            .map(|frame| (frame.iter().map(|&sample| sample as i32).sum::<i32>() / channels as i32) as i16);
+        let Some(first) = mono.next() else { return (false, vec![]) };
+
+        // Pulse lengths in samples
+        let initial_level = detector.level(first > 0, first);
+        let mut level = initial_level;
+        let mut pulses = vec![1];
+        for sample in mono {
+            let next = detector.level(level, sample);
+            if next == level {
+                *pulses.last_mut().unwrap() += 1;
+            } else {
+                pulses.push(1);
+                level = next;
+            }
+        }
+
+        (initial_level, samples_to_tstates(&pulses, self.sample_rate))
+    }
+
+    /// Whole recording as a single block
+    pub fn tape_blocks(&self, detector: EdgeDetector) -> Vec<TapeBlock> {
+        let (initial_level, pulses) = self.pulses(detector);
+        let description = format!("WAV recording: {} pulses", pulses.len());
+        let pulses = pulses.into_iter().enumerate()
+            .map(|(index, pulse)| if index == 0 { TapePulse::Level(initial_level, pulse) } else { TapePulse::Edge(pulse) })
+            .collect();
+        vec![TapeBlock { description, data: None, segments: vec![TapeSegment::Raw(pulses)] }]
+    }
+
+    /// 8-bit mono square wave of the signal given by initial level and pulse lengths in t-states
+    pub fn from_pulses(sample_rate: u32, initial_level: bool, pulses: &[u32]) -> Self {
+        let mut samples = vec![];
+        let mut tstates = 0u64;
+        let mut level = initial_level;
+        for &pulse in pulses {
+            tstates += pulse as u64;
+            // Pulse ends at the sample boundary closest to its end time, so no rounding error accumulates
+            let end = ((tstates * sample_rate as u64 + CLOCK_HZ / 2) / CLOCK_HZ) as usize;
+            samples.resize(end.max(samples.len()), if level { HIGH } else { LOW });
+            level = !level;
+        }
+        Self { sample_rate, channels: 1, bits_per_sample: 8, samples }
+    }
+
+    /// Serialize audio file
+    pub fn write(&self) -> Result<Vec<u8>, FormatError> {
+
+        let bytes_per_sample = match self.bits_per_sample {
+            8 => 1,
+            16 => 2,
+            _ => return Err(FormatError::Invalid("only 8 and 16-bit samples are supported")),
+        };
+        let data_size = self.samples.len() * bytes_per_sample;
+        let block_align = self.channels * bytes_per_sample as u16;
+
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(36 + data_size as u32).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        data.extend_from_slice(&self.channels.to_le_bytes());
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+        data.extend_from_slice(&block_align.to_le_bytes());
+        data.extend_from_slice(&self.bits_per_sample.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(data_size as u32).to_le_bytes());
+
+        for &sample in &self.samples {
+            if bytes_per_sample == 1 {
+                // 8-bit samples are unsigned
+                data.push(((sample >> 8) + 128) as u8);
+            } else {
+                data.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+
+        Ok(data)
+
+    }
+
+}
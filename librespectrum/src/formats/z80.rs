@@ -0,0 +1,446 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    cpu::tokens::IntMode,
+    devs::{Cpu, CpuRegisters, mem::Memory},
+};
+
+use super::{FormatError, sna::BANK_SIZE};
+
+/// Version 1 header size in bytes
+const V1_HEADER_SIZE: usize = 30;
+
+/// Additional header length of version 2
+const V2_EXTRA_SIZE: usize = 23;
+
+/// Additional header lengths of version 3, the longer one includes port 1FFDh
+const V3_EXTRA_SIZE: [usize; 2] = [54, 55];
+
+/// Page numbers of 48K RAM at 4000h, 8000h and C000h
+const PAGES_48K: [u8; 3] = [8, 4, 5];
+
+/// Hardware the snapshot was taken on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hardware {
+    Spectrum16k,
+    Spectrum48k,
+    Spectrum48kIf1,
+    SamRam,
+    Spectrum48kMgt,
+    Spectrum128k,
+    Spectrum128kIf1,
+    Spectrum128kMgt,
+    SpectrumPlus2,
+    SpectrumPlus2A,
+    SpectrumPlus3,
+    Pentagon128,
+    Scorpion256,
+    DidaktikKompakt,
+    Tc2048,
+    Tc2068,
+    Ts2068,
+}
+
+impl Hardware {
+
+    /// Decode hardware mode byte, modified by bit 7 of the flags byte
+    fn decode(version: u8, mode: u8, modified: bool) -> Result<Self, FormatError> {
+        use Hardware::*;
+        let hardware = match (version, mode) {
+            (_, 0) => Spectrum48k,
+            (_, 1) => Spectrum48kIf1,
+            (_, 2) => SamRam,
+            (2, 3) | (3, 4) => Spectrum128k,
+            (2, 4) | (3, 5) => Spectrum128kIf1,
+            (3, 3) => Spectrum48kMgt,
+            (3, 6) => Spectrum128kMgt,
+            (_, 7) | (_, 8) => SpectrumPlus3,
+            (_, 9) => Pentagon128,
+            (_, 10) => Scorpion256,
+            (_, 11) => DidaktikKompakt,
+            (_, 12) => SpectrumPlus2,
+            (_, 13) => SpectrumPlus2A,
+            (_, 14) => Tc2048,
+            (_, 15) => Tc2068,
+            (_, 128) => Ts2068,
+            _ => return Err(FormatError::Invalid("unknown hardware mode")),
+        };
+        Ok(match (hardware, modified) {
+            (Spectrum48k, true) => Spectrum16k,
+            (Spectrum128k, true) => SpectrumPlus2,
+            (SpectrumPlus3, true) => SpectrumPlus2A,
+            _ => hardware,
+        })
+    }
+
+    /// Version 3 hardware mode byte and modify flag
+    fn encode(&self) -> (u8, bool) {
+        use Hardware::*;
+        match self {
+            Spectrum16k => (0, true),
+            Spectrum48k => (0, false),
+            Spectrum48kIf1 => (1, false),
+            SamRam => (2, false),
+            Spectrum48kMgt => (3, false),
+            Spectrum128k => (4, false),
+            Spectrum128kIf1 => (5, false),
+            Spectrum128kMgt => (6, false),
+            SpectrumPlus3 => (7, false),
+            Pentagon128 => (9, false),
+            Scorpion256 => (10, false),
+            DidaktikKompakt => (11, false),
+            SpectrumPlus2 => (12, false),
+            SpectrumPlus2A => (13, false),
+            Tc2048 => (14, false),
+            Tc2068 => (15, false),
+            Ts2068 => (128, false),
+        }
+    }
+
+    /// Machine has 128K memory paged through port 7FFDh
+    pub fn is_128k(&self) -> bool {
+        use Hardware::*;
+        matches!(self,
+            Spectrum128k | Spectrum128kIf1 | Spectrum128kMgt | SpectrumPlus2 |
+            SpectrumPlus2A | SpectrumPlus3 | Pentagon128 | Scorpion256
+        )
+    }
+
+    /// Frame length in t-states
+    pub fn frame_tstates(&self) -> u32 {
+        use Hardware::*;
+        match self {
+            Pentagon128 => 71680,
+            _ if self.is_128k() => 70908,
+            _ => 69888,
+        }
+    }
+
+}
+
+/// AY-3-8912 sound chip state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AyState {
+    /// Last value written to port FFFDh
+    pub selected: u8,
+    pub registers: [u8; 16],
+}
+
+/// Z80 snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Z80Snapshot {
+    /// Format version the snapshot was read from (1, 2 or 3)
+    pub version: u8,
+    pub hardware: Hardware,
+    pub regs: CpuRegisters,
+    pub border: u8,
+    /// Last value written to port 7FFDh
+    pub port_7ffd: u8,
+    /// Last value written to port 1FFDh
+    pub port_1ffd: Option<u8>,
+    pub ay: Option<AyState>,
+    /// T-states since the start of the frame
+    pub tstates: Option<u32>,
+    /// 16K memory pages by page number: 4, 5 and 8 for 48K, bank number plus 3 for 128K
+    pub pages: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Z80Snapshot {
+
+    /// Parse version 1, 2 or 3 snapshot
+    pub fn read(data: &[u8]) -> Result<Self, FormatError> {
+
+        if data.len() < V1_HEADER_SIZE {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let flags = if data[12] == 0xff { 0x01 } else { data[12] };
+
+        let mut regs = CpuRegisters {
+            af: u16::from_be_bytes([data[0], data[1]]),
+            bc: word(2),
+            hl: word(4),
+            pc: word(6),
+            sp: word(8),
+            ir: u16::from_be_bytes([data[10], (data[11] & 0x7f) | ((flags & 0x01) << 7)]),
+            de: word(13),
+            alt_bc: word(15),
+            alt_de: word(17),
+            alt_hl: word(19),
+            alt_af: u16::from_be_bytes([data[21], data[22]]),
+            iy: word(23),
+            ix: word(25),
+            iff1: data[27] != 0,
+            iff2: data[28] != 0,
+            im: match data[29] & 0x03 {
+                0 => IntMode::IM0,
+                1 => IntMode::IM1,
+                _ => IntMode::IM2,
+            },
+            ..Default::default()
+        };
+        let border = (flags >> 1) & 0x07;
+
+        if regs.pc != 0 {
+            // Version 1 is always 48K with optionally compressed memory dump
+            let dump = &data[V1_HEADER_SIZE..];
+            let ram = if flags & 0x20 != 0 {
+                decompress(dump, 3 * BANK_SIZE)?
+            } else if dump.len() >= 3 * BANK_SIZE {
+                dump[..3 * BANK_SIZE].to_vec()
+            } else {
+                return Err(FormatError::InvalidSize(data.len()));
+            };
+            let pages = PAGES_48K.iter().zip(ram.chunks(BANK_SIZE))
+                .map(|(&page, contents)| (page, contents.to_vec()))
+                .collect();
+            return Ok(Self {
+                version: 1,
+                hardware: Hardware::Spectrum48k,
+                regs,
+                border,
+                port_7ffd: 0,
+                port_1ffd: None,
+                ay: None,
+                tstates: None,
+                pages,
+            });
+        }
+
+        if data.len() < V1_HEADER_SIZE + 2 {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+        let extra_size = word(30) as usize;
+        let version = match extra_size {
+            V2_EXTRA_SIZE => 2,
+            _ if V3_EXTRA_SIZE.contains(&extra_size) => 3,
+            _ => return Err(FormatError::Invalid("unknown header length")),
+        };
+        let header_size = V1_HEADER_SIZE + 2 + extra_size;
+        if data.len() < header_size {
+            return Err(FormatError::InvalidSize(data.len()));
+        }
+
+        regs.pc = word(32);
+        let hardware = Hardware::decode(version, data[34], data[37] & 0x80 != 0)?;
+        let ay = (data[37] & 0x04 != 0 || hardware.is_128k()).then(|| {
+            let mut registers = [0; 16];
+            registers.copy_from_slice(&data[39..55]);
+            AyState { selected: data[38], registers }
+        });
+        let tstates = (version == 3).then(|| {
+            let quarter = hardware.frame_tstates() / 4;
+            let hi = (data[57] as u32 + 1) % 4;
+            (hi + 1) * quarter - (word(55) as u32 % quarter) - 1
+        });
+        let port_1ffd = (extra_size == V3_EXTRA_SIZE[1]).then(|| data[86]);
+
+        // Memory blocks: compressed length (FFFFh when stored uncompressed), page number, data
+        let mut pages = BTreeMap::new();
+        let mut offset = header_size;
+        while offset < data.len() {
+            if offset + 3 > data.len() {
+                return Err(FormatError::InvalidSize(data.len()));
+            }
+            let length = word(offset);
+            let page = data[offset + 2];
+            offset += 3;
+            let (size, compressed) = match length {
+                0xffff => (BANK_SIZE, false),
+                _ => (length as usize, true),
+            };
+            let block = data.get(offset..offset + size).ok_or(FormatError::InvalidSize(data.len()))?;
+            let contents = if compressed { decompress(block, BANK_SIZE)? } else { block.to_vec() };
+            pages.insert(page, contents);
+            offset += size;
+        }
+
+        Ok(Self {
+            version,
+            hardware,
+            regs,
+            border,
+            port_7ffd: if hardware.is_128k() { data[35] } else { 0 },
+            port_1ffd,
+            ay,
+            tstates,
+            pages,
+        })
+
+    }
+
+    /// Serialize snapshot as version 3
+    pub fn write(&self) -> Result<Vec<u8>, FormatError> {
+
+        let regs = &self.regs;
+        let [i, r] = regs.ir.to_be_bytes();
+        let [a, f] = regs.af.to_be_bytes();
+        let [alt_a, alt_f] = regs.alt_af.to_be_bytes();
+
+        let mut data = Vec::with_capacity(V1_HEADER_SIZE + 2 + V3_EXTRA_SIZE[1] + 3 * BANK_SIZE);
+        data.extend_from_slice(&[a, f]);
+        data.extend_from_slice(&regs.bc.to_le_bytes());
+        data.extend_from_slice(&regs.hl.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes()); // PC is in additional header
+        data.extend_from_slice(&regs.sp.to_le_bytes());
+        data.extend_from_slice(&[i, r & 0x7f, (r >> 7) | ((self.border & 0x07) << 1)]);
+        for word in [regs.de, regs.alt_bc, regs.alt_de, regs.alt_hl] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(&[alt_a, alt_f]);
+        data.extend_from_slice(&regs.iy.to_le_bytes());
+        data.extend_from_slice(&regs.ix.to_le_bytes());
+        data.push(regs.iff1 as u8);
+        data.push(regs.iff2 as u8);
+        data.push(match regs.im {
+            IntMode::IM0 | IntMode::IM01 => 0,
+            IntMode::IM1 => 1,
+            IntMode::IM2 => 2,
+        });
+
+        let extra_size = V3_EXTRA_SIZE[self.port_1ffd.is_some() as usize];
+        let (mode, modified) = self.hardware.encode();
+        let ay = self.ay.unwrap_or_default();
+        let mut extra = vec![0; extra_size];
+        extra[0..2].copy_from_slice(&regs.pc.to_le_bytes());
+        extra[2] = mode;
+        extra[3] = self.port_7ffd;
+        extra[5] = ((self.ay.is_some() as u8) << 2) | ((modified as u8) << 7);
+        extra[6] = ay.selected;
+        extra[7..23].copy_from_slice(&ay.registers);
+        let quarter = self.hardware.frame_tstates() / 4;
+        let tstates = self.tstates.unwrap_or(0) % self.hardware.frame_tstates();
+        extra[23..25].copy_from_slice(&((quarter - tstates % quarter - 1) as u16).to_le_bytes());
+        extra[25] = ((tstates / quarter + 3) % 4) as u8;
+        if let Some(port) = self.port_1ffd {
+            extra[54] = port;
+        }
+        data.extend_from_slice(&(extra_size as u16).to_le_bytes());
+        data.extend_from_slice(&extra);
+
+        for (&page, contents) in &self.pages {
+            if contents.len() != BANK_SIZE {
+                return Err(FormatError::Invalid("memory page isn't 16K long"));
+            }
+            let compressed = compress(contents);
+            if compressed.len() < BANK_SIZE {
+                data.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+                data.push(page);
+                data.extend_from_slice(&compressed);
+            } else {
+                data.extend_from_slice(&0xffffu16.to_le_bytes());
+                data.push(page);
+                data.extend_from_slice(contents);
+            }
+        }
+
+        Ok(data)
+
+    }
+
+    /// Page number of RAM at 4000h, 8000h and C000h
+    fn visible_pages(&self) -> [u8; 3] {
+        if self.hardware.is_128k() {
+            [8, 5, 3 + (self.port_7ffd & 0x07)]
+        } else {
+            PAGES_48K
+        }
+    }
+
+    /// RAM as seen at 4000h-FFFFh. Missing pages are filled with zeros.
+    pub fn ram(&self) -> Vec<u8> {
+        let empty = vec![0; BANK_SIZE];
+        self.visible_pages().iter()
+            .flat_map(|page| self.pages.get(page).unwrap_or(&empty).clone())
+            .collect()
+    }
+
+    /// Capture 48K snapshot of CPU and memory
+    pub fn capture(cpu: &Cpu, memory: &dyn Memory, border: u8) -> Self {
+        let pages = PAGES_48K.iter().zip([0x4000u16, 0x8000, 0xc000])
+            .map(|(&page, base)| (page, (0..BANK_SIZE as u16).map(|offset| memory.read(base + offset)).collect()))
+            .collect();
+        Self {
+            version: 3,
+            hardware: Hardware::Spectrum48k,
+            regs: cpu.save_state(),
+            border,
+            port_7ffd: 0,
+            port_1ffd: None,
+            ay: None,
+            tstates: None,
+            pages,
+        }
+    }
+
+    /// Load registers and RAM. Only pages visible at 4000h-FFFFh
+    /// are loaded from 128K snapshot.
+    pub fn apply(&self, cpu: &Cpu, memory: &dyn Memory) {
+        cpu.load_state(&self.regs);
+        for (addr, byte) in (0x4000..=0xffff).zip(self.ram()) {
+            memory.write(addr, byte);
+        }
+    }
+
+}
+
+/// Expand ED ED nn bb sequences (byte bb repeated nn times) until given size is reached
+fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>, FormatError> {
+    let mut output = Vec::with_capacity(size);
+    let mut index = 0;
+    while output.len() < size {
+        match data.get(index..index + 4) {
+            Some(&[0xed, 0xed, count, byte]) => {
+                output.extend(std::iter::repeat_n(byte, count as usize));
+                index += 4;
+            },
+            _ => {
+                output.push(*data.get(index).ok_or(FormatError::Invalid("compressed data is truncated"))?);
+                index += 1;
+            },
+        }
+    }
+    if output.len() != size {
+        return Err(FormatError::Invalid("compressed block size mismatch"));
+    }
+    Ok(output)
+}
+
+/// Replace runs of 5 or more equal bytes and runs of 2 or more EDh bytes with ED ED nn bb.
+/// Byte following single EDh is never included in a run.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut index = 0;
+    while index < data.len() {
+        let byte = data[index];
+        let run = data[index..].iter().take(255).take_while(|&&next| next == byte).count();
+        if run >= 5 || (byte == 0xed && run >= 2) {
+            output.extend_from_slice(&[0xed, 0xed, run as u8, byte]);
+            index += run;
+        } else {
+            output.push(byte);
+            index += 1;
+            if byte == 0xed && index < data.len() {
+                output.push(data[index]);
+                index += 1;
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_round_trips_and_follows_ed_rules() {
+        assert_eq!(compress(&[0xed, 0, 0, 0, 0, 0, 0]), vec![0xed, 0, 0xed, 0xed, 5, 0]);
+        assert_eq!(compress(&[0xed, 0xed, 1]), vec![0xed, 0xed, 2, 0xed, 1]);
+        assert_eq!(compress(&[7; 300]), vec![0xed, 0xed, 255, 7, 0xed, 0xed, 45, 7]);
+        let data: Vec<u8> = (0..BANK_SIZE).map(|i| if i % 100 < 50 { 0xed } else { i as u8 }).collect();
+        assert_eq!(decompress(&compress(&data), BANK_SIZE).unwrap(), data);
+    }
+
+}
@@ -8,3 +8,5 @@ extern crate bitflags;
 pub mod core;
 pub mod cpu;
 pub mod devs;
+pub mod formats;
+pub mod machine;
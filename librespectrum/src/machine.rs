@@ -0,0 +1,289 @@
+use std::{cell::{Cell, RefCell}, fmt, ops::{Coroutine, CoroutineState}, pin::Pin, rc::Rc};
+
+use crate::{
+    core::{Clock, CpuBus, Identifier, NoReturnTask, Scheduler},
+    cpu::CpuModel,
+    devs::{
+        BreakCondition, BreakpointManager, Cpu, CpuRegisters, CpuTrap, Device, DeviceManager, FastCpu, LdBytesTrap, Snapshot, TapeDeck,
+        mem::{Memory, Static48k}
+    },
+    formats::FormatError,
+};
+
+/// CPU emulation accuracy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Accuracy {
+    /// Bus-accurate CPU core which models every half t-cycle of bus activity
+    #[default]
+    BusCycles,
+    /// Fast instruction-stepped CPU core which accesses memory directly.
+    /// It doesn't run IO cycles: IN reads FFh and OUT is ignored, so devices
+    /// decoding IO ports can't be used with it.
+    Instructions,
+}
+
+/// CPU emulation accuracy can't be switched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccuracyError {
+    /// Devices with given IDs decode IO ports, which fast core doesn't drive
+    PortDevices(Vec<Identifier>),
+}
+
+impl fmt::Display for AccuracyError {
+
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccuracyError::PortDevices(ids) => write!(formatter, "Devices decoding IO ports are attached: {:?}", ids),
+        }
+    }
+
+}
+
+impl std::error::Error for AccuracyError {}
+
+/// Complete machine state captured on instruction boundary
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineSnapshot {
+    /// Clock in half t-cycles
+    pub clock: u64,
+    pub model: CpuModel,
+    pub cpu: CpuRegisters,
+    /// Whole 64K address space including ROM
+    pub memory: Vec<u8>,
+    /// State of the devices added to the machine snapshot
+    pub devices: Vec<DeviceSnapshot>,
+}
+
+/// Device state serialized by `Snapshot::save_snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceSnapshot {
+    pub name: String,
+    pub state: Vec<u8>,
+}
+
+/// Machine with Z80 CPU and 48K memory which can run either
+/// bus-accurate or fast CPU core. Cores share CPU registers
+/// and are switched on instruction boundary.
+pub struct Machine {
+    scheduler: RefCell<Option<Scheduler<'static>>>,
+    /// Bus-accurate CPU is stopped right before the next opcode fetch
+    at_boundary: Cell<bool>,
+    accuracy: Cell<Accuracy>,
+    fast_cpu: FastCpu,
+    ld_bytes_trap: Rc<dyn CpuTrap>,
+    fast_load: Cell<bool>,
+    /// Devices with state captured in snapshot, by name
+    snapshot_devices: RefCell<Vec<(&'static str, Rc<dyn Snapshot>)>>,
+    clock: Rc<Clock>,
+    bus: Rc<CpuBus>,
+    breakpoint_manager: Rc<BreakpointManager>,
+    device_manager: Rc<DeviceManager>,
+    cpu: Rc<Cpu>,
+    memory: Rc<Static48k>,
+    tape: Rc<TapeDeck>,
+}
+
+impl Machine {
+
+    /// Create new machine with given CPU emulation accuracy
+    pub fn new(accuracy: Accuracy) -> Self {
+        let bus: Rc<CpuBus> = Default::default();
+        let clock: Rc<Clock> = Default::default();
+        let breakpoint_manager = Rc::new(BreakpointManager::default());
+        let device_manager = Rc::new(DeviceManager::new(&bus, &clock, &breakpoint_manager));
+        let cpu = device_manager.create_cpu();
+        let memory = device_manager.create_48k_memory();
+        let dyn_memory: Rc<dyn Memory> = Rc::clone(&memory) as Rc<dyn Memory>;
+        let fast_cpu = FastCpu::new(&cpu, &dyn_memory, &clock, &breakpoint_manager);
+        let tape = Rc::new(TapeDeck::default());
+        let ld_bytes_trap: Rc<dyn CpuTrap> = Rc::new(LdBytesTrap::new(&tape, &dyn_memory));
+        Self {
+            scheduler: RefCell::new(None),
+            at_boundary: Cell::new(true),
+            accuracy: Cell::new(accuracy),
+            fast_cpu,
+            ld_bytes_trap,
+            fast_load: Cell::new(false),
+            snapshot_devices: RefCell::new(vec![("Tape Deck", Rc::clone(&tape) as Rc<dyn Snapshot>)]),
+            clock,
+            bus,
+            breakpoint_manager,
+            device_manager,
+            cpu,
+            memory,
+            tape,
+        }
+    }
+
+    pub fn clock(&self) -> &Rc<Clock> {
+        &self.clock
+    }
+
+    pub fn bus(&self) -> &Rc<CpuBus> {
+        &self.bus
+    }
+
+    pub fn breakpoint_manager(&self) -> &Rc<BreakpointManager> {
+        &self.breakpoint_manager
+    }
+
+    pub fn device_manager(&self) -> &Rc<DeviceManager> {
+        &self.device_manager
+    }
+
+    pub fn cpu(&self) -> &Rc<Cpu> {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &Rc<Static48k> {
+        &self.memory
+    }
+
+    pub fn tape(&self) -> &Rc<TapeDeck> {
+        &self.tape
+    }
+
+    /// Get CPU emulation accuracy
+    pub fn accuracy(&self) -> Accuracy {
+        self.accuracy.get()
+    }
+
+    /// Switch CPU emulation accuracy. Bus-accurate core finishes
+    /// current instruction before the switch. Fast core is refused
+    /// once devices decoding IO ports were created.
+    pub fn set_accuracy(&self, accuracy: Accuracy) -> Result<(), AccuracyError> {
+        let port_devices = self.device_manager.port_devices();
+        if accuracy == Accuracy::Instructions && !port_devices.is_empty() {
+            return Err(AccuracyError::PortDevices(port_devices));
+        }
+        if accuracy != self.accuracy.get() {
+            if !self.at_boundary.get() {
+                self.finish_instruction();
+            }
+            self.scheduler.replace(None);
+            self.accuracy.set(accuracy);
+        }
+        Ok(())
+    }
+
+    /// Check if tape blocks are loaded by ROM LD-BYTES trap
+    pub fn fast_load(&self) -> bool {
+        self.fast_load.get()
+    }
+
+    /// Enable or disable ROM LD-BYTES trap loading tape blocks instantly
+    pub fn set_fast_load(&self, enabled: bool) {
+        if enabled != self.fast_load.replace(enabled) {
+            if enabled {
+                self.cpu.add_trap(&self.ld_bytes_trap);
+            } else {
+                self.cpu.remove_trap(&self.ld_bytes_trap);
+            }
+        }
+    }
+
+    /// Capture state of given device in machine snapshot under given name
+    pub fn add_snapshot_device(&self, name: &'static str, device: &Rc<dyn Snapshot>) {
+        self.snapshot_devices.borrow_mut().push((name, Rc::clone(device)));
+    }
+
+    /// Run for given t-cycles or until breakpoint is hit.
+    /// Returns breakpoint ID if it was hit.
+    pub fn run(&self, tcycles: u64) -> Option<Identifier> {
+        match self.accuracy.get() {
+            Accuracy::BusCycles => {
+                self.at_boundary.set(false);
+                self.with_scheduler(|scheduler| scheduler.run(tcycles << 1))
+            },
+            Accuracy::Instructions => self.fast_cpu.run(tcycles << 1),
+        }
+    }
+
+    /// Execute single instruction
+    pub fn step(&self) {
+        match self.accuracy.get() {
+            Accuracy::BusCycles => self.finish_instruction(),
+            Accuracy::Instructions => { self.fast_cpu.step(); },
+        }
+    }
+
+    /// Capture machine state. Bus-accurate core finishes current instruction first.
+    pub fn snapshot(&self) -> MachineSnapshot {
+        if self.accuracy.get() == Accuracy::BusCycles && !self.at_boundary.get() {
+            self.finish_instruction();
+        }
+        MachineSnapshot {
+            clock: self.clock.get(),
+            model: self.cpu.model(),
+            cpu: self.cpu.save_state(),
+            memory: self.memory.dump(),
+            devices: self.snapshot_devices.borrow().iter()
+                .map(|(name, device)| DeviceSnapshot { name: name.to_string(), state: device.save_snapshot() })
+                .collect(),
+        }
+    }
+
+    /// Restore machine state. Bus tasks are dropped and recreated
+    /// on the next run, so they start on the restored instruction boundary.
+    /// Every device added to the snapshot must have its state in it.
+    pub fn restore(&self, snapshot: &MachineSnapshot) -> Result<(), FormatError> {
+        let devices = self.snapshot_devices.borrow();
+        let states = devices.iter()
+            .map(|(name, _)| snapshot.devices.iter().find(|device| device.name == *name).map(|device| &device.state))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(FormatError::Invalid("device state is missing"))?;
+        for ((_, device), state) in devices.iter().zip(states) {
+            device.load_snapshot(state)?;
+        }
+        self.scheduler.replace(None);
+        self.at_boundary.set(true);
+        self.clock.set(snapshot.clock);
+        self.cpu.set_model(snapshot.model);
+        self.cpu.load_state(&snapshot.cpu);
+        self.memory.load(0, &snapshot.memory);
+        Ok(())
+    }
+
+    /// Run bus-accurate core until the next instruction boundary
+    fn finish_instruction(&self) {
+        self.with_scheduler(|scheduler| {
+            let id = self.breakpoint_manager.once(BreakCondition::BeforeOpcodeRead(None));
+            while scheduler.run(u64::MAX >> 1) != Some(id) {}
+        });
+        self.at_boundary.set(true);
+    }
+
+    /// Run closure with bus scheduler, creating the scheduler if it doesn't exist yet.
+    /// CPU task is started on instruction boundary, so new scheduler is advanced until
+    /// the CPU is about to fetch the first opcode.
+    fn with_scheduler<T>(&self, f: impl FnOnce(&mut Scheduler<'static>) -> T) -> T {
+        let mut scheduler = self.scheduler.borrow_mut();
+        let scheduler = scheduler.get_or_insert_with(|| {
+            let mut scheduler = Scheduler::new(&self.clock, self.tasks());
+            let id = self.breakpoint_manager.once(BreakCondition::BeforeOpcodeRead(None));
+            while scheduler.run(u64::MAX >> 1) != Some(id) {}
+            scheduler
+        });
+        f(scheduler)
+    }
+
+    /// Create tasks of the devices running on the bus
+    fn tasks(&self) -> Vec<Box<dyn NoReturnTask>> {
+        vec![owned_task(Rc::clone(&self.cpu)), owned_task(Rc::clone(&self.memory))]
+    }
+
+}
+
+/// Task running device it owns, so the task doesn't borrow the machine.
+/// Coroutine is immovable as device task borrows the device kept in it.
+fn owned_task<D: Device + 'static>(device: Rc<D>) -> Box<dyn NoReturnTask> {
+    Box::new(Box::pin(#[coroutine] static move || {
+        let mut task = device.run();
+        loop {
+            let CoroutineState::Yielded(result) = Pin::new(&mut task).resume(());
+            yield result;
+        }
+    }))
+}
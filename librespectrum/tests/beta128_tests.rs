@@ -0,0 +1,252 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem, interface_rom};
+
+use std::rc::Rc;
+
+use librespectrum::{
+    core::Clock,
+    cpu::tokens::Reg,
+    devs::{Device, DiskSector, Wd1793, mem::Memory},
+    formats::trd::Trd,
+};
+
+/// Routine entry in TR-DOS ROM
+const ENTRY: usize = 0x3d00;
+
+/// Disk with each sector filled with its logical track and sector numbers
+fn test_disk() -> Trd {
+    let mut trd = Trd::new(80, 2);
+    for (index, sector) in trd.data.chunks_mut(256).enumerate() {
+        sector.iter_mut().enumerate().for_each(|(offset, byte)| *byte = (index + offset) as u8);
+    }
+    trd
+}
+
+/// Program calling the routine at ROM entry
+const CALL_ENTRY: [u8; 4] = [
+    0xcd, 0x00, 0x3d,   // CALL 3D00h
+    0x76,               // HALT
+];
+
+/// Test ROM with the routine at 3D00h
+fn trdos_rom(routine: &[u8]) -> Vec<u8> {
+    let mut rom = interface_rom(0x4000, &[]);
+    rom[ENTRY..ENTRY + routine.len()].copy_from_slice(routine);
+    rom
+}
+
+/// Select drive A, start type II command on given sector and wait for INTRQ without transferring data.
+/// Status is returned in A.
+fn wait_routine(sector: u8) -> Vec<u8> {
+    vec![
+        0x3e, 0x3c, 0xd3, 0xff, // LD A,3Ch; OUT (FFh),A
+        0x3e, sector,           // LD A,sector
+        0xd3, 0x5f,             // OUT (5Fh),A
+        0x3e, 0x80, 0xd3, 0x1f, // LD A,80h; OUT (1Fh),A
+        0xdb, 0xff,             // IN A,(FFh)
+        0x17,                   // RLA
+        0x30, 0xfb,             // JR NC,-5
+        0xdb, 0x1f,             // IN A,(1Fh)
+        0xc9,                   // RET
+    ]
+}
+
+#[test]
+fn rom_is_paged_by_opcode_fetch() {
+    let rom = trdos_rom(&[
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x47,               // LD B,A
+        0xdb, 0x1f,         // IN A,(1Fh)
+        0x57,               // LD D,A
+        0xc9,               // RET
+    ]);
+    let (system, beta) = TestSystem::with_device(&[
+        0xcd, 0x00, 0x3d,   // CALL 3D00h
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x4f,               // LD C,A
+        0xdb, 0x1f,         // IN A,(1Fh)
+        0x76,               // HALT
+    ], |devices, memory| devices.create_beta128(memory, &rom));
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 9, 1000));
+    assert_eq!(system.cpu.rg(Reg::B).get(), 0xaa);
+    assert_eq!(system.cpu.rg(Reg::C).get(), 0x00);
+    // Type I status: not ready, head at track 0
+    assert_eq!(system.cpu.rg(Reg::D).get(), 0x84);
+    // Ports are disabled with the ROM paged out
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0xff);
+    assert!(!beta.is_active());
+    assert!(!system.memory.writable(0x3d00));
+    assert_eq!(system.memory.read(0), 0x00);
+}
+
+#[test]
+fn reads_sector_after_seek() {
+    let rom = trdos_rom(&[
+        0x3e, 0x3c, 0xd3, 0xff, // LD A,3Ch; OUT (FFh),A (drive A, side 0)
+        0x3e, 0x08, 0xd3, 0x1f, // LD A,08h; OUT (1Fh),A (restore)
+        0xdb, 0xff, 0x17,       // IN A,(FFh); RLA
+        0x30, 0xfb,             // JR NC,-5
+        0x3e, 0x03, 0xd3, 0x7f, // LD A,3; OUT (7Fh),A
+        0x3e, 0x18, 0xd3, 0x1f, // LD A,18h; OUT (1Fh),A (seek)
+        0xdb, 0xff, 0x17,       // IN A,(FFh); RLA
+        0x30, 0xfb,             // JR NC,-5
+        0x3e, 0x05, 0xd3, 0x5f, // LD A,5; OUT (5Fh),A
+        0x21, 0x00, 0xc0,       // LD HL,C000h
+        0x3e, 0x80, 0xd3, 0x1f, // LD A,80h; OUT (1Fh),A (read sector)
+        0xdb, 0xff,             // IN A,(FFh)
+        0xe6, 0xc0,             // AND C0h
+        0x28, 0xfa,             // JR Z,-6
+        0xfa, 0x34, 0x3d,       // JP M,3D34h
+        0xdb, 0x7f,             // IN A,(7Fh)
+        0x77, 0x23,             // LD (HL),A; INC HL
+        0x18, 0xf1,             // JR -15
+        0xdb, 0x1f,             // IN A,(1Fh)
+        0xc9,                   // RET
+    ]);
+    let (system, beta) = TestSystem::with_device(&CALL_ENTRY, |devices, memory| devices.create_beta128(memory, &rom));
+    let trd = test_disk();
+    beta.drive(0).insert(trd.disk());
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 2_000_000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x00);
+    assert_eq!(beta.drive(0).cylinder(), 3);
+    // Track 3 side 0 is logical track 6
+    let offset = (6 * 16 + 4) * 256;
+    assert_eq!((0..256).map(|addr| system.memory.read(0xc000 + addr)).collect::<Vec<_>>(), trd.data[offset..offset + 256]);
+    assert_eq!(system.memory.read(0xc100), 0x00);
+    // Three steps at 6 ms and head settling at least
+    assert!(system.clock.get() / 2 > 3 * 21_000);
+}
+
+#[test]
+fn writes_sector_back_to_image() {
+    let rom = trdos_rom(&[
+        0x3e, 0x3c, 0xd3, 0xff, // LD A,3Ch; OUT (FFh),A
+        0x3e, 0x01, 0xd3, 0x5f, // LD A,1; OUT (5Fh),A
+        0x21, 0x00, 0xc0,       // LD HL,C000h
+        0x3e, 0xa0, 0xd3, 0x1f, // LD A,A0h; OUT (1Fh),A (write sector)
+        0xdb, 0xff,             // IN A,(FFh)
+        0xe6, 0xc0,             // AND C0h
+        0x28, 0xfa,             // JR Z,-6
+        0xfa, 0x1e, 0x3d,       // JP M,3D1Eh
+        0x7e,                   // LD A,(HL)
+        0xd3, 0x7f,             // OUT (7Fh),A
+        0x23,                   // INC HL
+        0x18, 0xf1,             // JR -15
+        0xdb, 0x1f,             // IN A,(1Fh)
+        0xc9,                   // RET
+    ]);
+    let (system, beta) = TestSystem::with_device(&CALL_ENTRY, |devices, memory| devices.create_beta128(memory, &rom));
+    beta.drive(0).insert(Trd::new(80, 2).disk());
+    let payload: Vec<u8> = (0..=255).rev().collect();
+    system.memory.load(0xc000, &payload);
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 2_000_000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x00);
+    assert!(beta.drive(0).is_modified());
+    let image = Trd::from_disk(&beta.drive(0).disk().unwrap()).write();
+    assert_eq!(image[..256], payload);
+    assert!(image[256..].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn reports_lost_data_and_missing_sectors() {
+    let rom = trdos_rom(&wait_routine(1));
+    let (system, beta) = TestSystem::with_device(&CALL_ENTRY, |devices, memory| devices.create_beta128(memory, &rom));
+    beta.drive(0).insert(test_disk().disk());
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 2_000_000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x04);
+
+    let rom = trdos_rom(&wait_routine(17));
+    let (system, beta) = TestSystem::with_device(&CALL_ENTRY, |devices, memory| devices.create_beta128(memory, &rom));
+    beta.drive(0).insert(test_disk().disk());
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 5_000_000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x10);
+    // Five revolutions are searched
+    assert!(system.clock.get() / 2 >= 5 * 700_000);
+
+    let rom = trdos_rom(&wait_routine(1));
+    let (system, beta) = TestSystem::with_device(&CALL_ENTRY, |devices, memory| devices.create_beta128(memory, &rom));
+    let mut scheduler = system.scheduler_with(vec![beta.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 1000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x80);
+}
+
+#[test]
+fn steps_head_at_selected_rate() {
+    let clock = Rc::new(Clock::default());
+    let at = |tstates: u64| { clock.set(tstates << 1); };
+    let fdc = Wd1793::new(&clock, 1);
+    fdc.drive(0).insert(Trd::new(80, 2).disk());
+
+    // Seek to track 5 at 30 ms rate, the first step is made right away
+    fdc.write(3, 5);
+    fdc.write(0, 0x13);
+    fdc.update();
+    assert_eq!(fdc.read(1), 1);
+    at(4 * 105_000 - 1);
+    fdc.update();
+    assert_eq!((fdc.read(1), fdc.read(0) & 0x01), (4, 0x01));
+    at(5 * 105_000);
+    fdc.update();
+    assert!(fdc.intrq());
+    assert_eq!((fdc.read(1), fdc.drive(0).cylinder()), (5, 5));
+    assert_eq!(fdc.read(0) & 0x01, 0x00);
+    assert!(!fdc.intrq());
+
+    // Index pulse is shown at the start of each revolution
+    assert_eq!(fdc.read(0) & 0x02, 0x00);
+    at(7 * 700_000 + 100);
+    assert_eq!(fdc.read(0) & 0x02, 0x02);
+
+    // Step out updating track register, then restore
+    fdc.write(0, 0x70);
+    at(8 * 700_000);
+    fdc.update();
+    assert_eq!((fdc.read(1), fdc.drive(0).cylinder()), (4, 4));
+    fdc.write(0, 0x00);
+    at(9 * 700_000);
+    fdc.update();
+    assert_eq!((fdc.read(1), fdc.drive(0).cylinder(), fdc.read(0) & 0x04), (0, 0, 0x04));
+}
+
+#[test]
+fn reads_address_and_formats_track() {
+    let clock = Rc::new(Clock::default());
+    let fdc = Wd1793::new(&clock, 1);
+    fdc.drive(0).insert(Trd::new(80, 2).disk());
+
+    // Read address returns the first ID passing the head
+    fdc.write(0, 0xc0);
+    let mut id = vec![];
+    while { fdc.update(); fdc.is_busy() } {
+        if fdc.drq() {
+            id.push(fdc.read(3));
+        }
+        clock.set(clock.get() + 2 * 56);
+    }
+    assert_eq!(id[..4], [0, 0, 1, 1]);
+    assert_eq!(fdc.read(2), 0);
+
+    // Format the track with a single 512-byte sector
+    let sector = DiskSector::new(0, 0, 9, vec![0xe5; 512]);
+    let mut stream = [vec![0x4e; 60], vec![0x00; 12], vec![0xf5; 3], vec![0xfe, 0, 0, 9, 2, 0xf7], vec![0x4e; 22], vec![0x00; 12],
+        vec![0xf5; 3], vec![0xfb], vec![0xe5; 512], vec![0xf7]].concat().into_iter();
+    fdc.write(0, 0xf0);
+    while { fdc.update(); fdc.is_busy() } {
+        if fdc.drq() {
+            fdc.write(3, stream.next().unwrap_or(0x4e));
+        }
+        clock.set(clock.get() + 2 * 56);
+    }
+    assert_eq!(fdc.read(0), 0x00);
+    assert_eq!(fdc.drive(0).track(0), vec![sector]);
+    assert!(fdc.drive(0).is_modified());
+}
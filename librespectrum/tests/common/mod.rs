@@ -0,0 +1,201 @@
+#![allow(dead_code)]
+
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use librespectrum::{
+    core::{Clock, CpuBus, Ctrl, Identifiable, Identifier, NoReturnTask, Scheduler},
+    cpu::{CpuModel, tokens::RegPair},
+    devs::{BreakCondition, BreakpointManager, Cpu, Device, DeviceManager, MemoryImage, mem::{Memory, Static48k}},
+    machine::{Accuracy, Machine},
+    yield_wait
+};
+
+/// Program base address
+pub const BASE: u16 = 0x8000;
+
+/// Test device which asserts INT and NMI lines during given windows (in t-cycles)
+/// and supplies response bytes during interrupt acknowledge cycles (one byte per cycle,
+/// nothing is supplied when response is empty)
+pub struct IntGenerator {
+    bus: Rc<CpuBus>,
+    clock: Rc<Clock>,
+    pub int_window: Cell<(u64, u64)>,
+    pub nmi_window: Cell<(u64, u64)>,
+    pub response: RefCell<Vec<u8>>,
+    pub acknowledges: Cell<usize>,
+}
+
+impl Identifiable for IntGenerator {
+    fn id(&self) -> Identifier { 100 }
+}
+
+impl Device for IntGenerator {
+
+    fn run<'a>(&'a self) -> Box<dyn NoReturnTask + 'a> {
+
+        Box::new(#[coroutine] move || {
+
+            let mut acknowledging = false;
+
+            loop {
+
+                let tcycles = self.clock.get() >> 1;
+                let within = |(start, end): (u64, u64)| start <= tcycles && tcycles < end;
+
+                if within(self.int_window.get()) {
+                    self.bus.int.drive(self, true);
+                } else {
+                    self.bus.int.release(self);
+                }
+
+                if within(self.nmi_window.get()) {
+                    self.bus.nmi.drive(self, true);
+                } else {
+                    self.bus.nmi.release(self);
+                }
+
+                let ctrl = self.bus.ctrl.probe().unwrap_or(Ctrl::NONE);
+                let response = self.response.borrow();
+                if ctrl.contains(Ctrl::IORQ) && self.bus.m1.probe().unwrap_or(false) && !response.is_empty() {
+                    self.bus.data.drive(self, response[self.acknowledges.get() % response.len()]);
+                    acknowledging = true;
+                } else {
+                    self.bus.data.release(self);
+                    if acknowledging {
+                        self.acknowledges.update(|count| count + 1);
+                        acknowledging = false;
+                    }
+                }
+                drop(response);
+
+                yield_wait!(self.clock.rising(1));
+
+            }
+
+        })
+
+    }
+
+}
+
+/// CPU, 48K memory and interrupt generator wired together
+pub struct TestSystem {
+    pub clock: Rc<Clock>,
+    pub bus: Rc<CpuBus>,
+    pub cpu: Rc<Cpu>,
+    pub memory: Rc<Static48k>,
+    pub generator: Rc<IntGenerator>,
+    pub breakpoint_manager: Rc<BreakpointManager>,
+    pub device_manager: Rc<DeviceManager>,
+}
+
+impl TestSystem {
+
+    /// Create system with the program loaded at the base address
+    /// and HALT instructions at IM1 and NMI handler addresses
+    pub fn new(program: &[u8]) -> Self {
+        let bus: Rc<CpuBus> = Default::default();
+        let clock: Rc<Clock> = Default::default();
+        let breakpoint_manager = Rc::new(BreakpointManager::default());
+        let device_manager = Rc::new(DeviceManager::new(&bus, &clock, &breakpoint_manager));
+        let cpu = device_manager.create_cpu();
+        let memory = device_manager.create_48k_memory();
+        memory.load(BASE, &program.to_vec());
+        memory.load(0x0038, &vec![0x76]); // HALT
+        memory.load(0x0066, &vec![0x76]); // HALT
+        cpu.rp(RegPair::PC).set(BASE);
+        cpu.rp(RegPair::SP).set(0xfffe);
+        let generator = Rc::new(IntGenerator {
+            bus: Rc::clone(&bus),
+            clock: Rc::clone(&clock),
+            int_window: Cell::new((u64::MAX, u64::MAX)),
+            nmi_window: Cell::new((u64::MAX, u64::MAX)),
+            response: RefCell::new(vec![0xff]),
+            acknowledges: Cell::new(0),
+        });
+        Self { clock, bus, cpu, memory, generator, breakpoint_manager, device_manager }
+    }
+
+    /// Create system with the program loaded and a peripheral created by its device manager,
+    /// which also adds the peripheral's memory overlay to the system memory
+    pub fn with_device<T>(program: &[u8], create: impl FnOnce(&DeviceManager, &Rc<Static48k>) -> Rc<T>) -> (Self, Rc<T>) {
+        let system = Self::new(program);
+        let device = create(&system.device_manager, &system.memory);
+        (system, device)
+    }
+
+    /// Create scheduler running all system devices
+    pub fn scheduler(&self) -> Scheduler<'_> {
+        self.scheduler_with(vec![])
+    }
+
+    /// Create scheduler running all system devices and given extra tasks
+    pub fn scheduler_with<'a>(&'a self, tasks: Vec<Box<dyn NoReturnTask + 'a>>) -> Scheduler<'a> {
+        let mut all_tasks = vec![self.cpu.run(), self.memory.run(), self.generator.run()];
+        all_tasks.extend(tasks);
+        Scheduler::new(&self.clock, all_tasks)
+    }
+
+    /// Run until CPU is about to fetch opcode at given address. Returns false on timeout.
+    pub fn run_until(&self, scheduler: &mut Scheduler, addr: u16, tcycles: u64) -> bool {
+        let id = self.breakpoint_manager.once(BreakCondition::BeforeOpcodeRead(Some(addr)));
+        let hit = scheduler.run(tcycles << 1) == Some(id);
+        self.breakpoint_manager.remove(id);
+        hit
+    }
+
+    /// Return address on top of the stack
+    pub fn pushed_pc(&self) -> u16 {
+        let sp = self.cpu.rp(RegPair::SP).get();
+        u16::from_le_bytes([self.memory.read(sp), self.memory.read(sp.wrapping_add(1))])
+    }
+
+}
+
+/// Interface ROM of given size with the code at address 0, where AAh is placed to tell it from 48K ROM
+pub fn interface_rom(size: usize, code: &[u8]) -> Vec<u8> {
+    let mut rom = code.to_vec();
+    rom.resize(size, 0);
+    rom[0] = 0xaa;
+    rom
+}
+
+/// Image with each block filled with its number and byte offset
+pub fn test_image(blocks: u64) -> Rc<MemoryImage> {
+    let data = (0..blocks as usize * 512).map(|index| (index / 512 + index) as u8).collect::<Vec<_>>();
+    Rc::new(MemoryImage::from(data))
+}
+
+/// Create machine with given CPU model and the program loaded at the base address
+pub fn program_machine(accuracy: Accuracy, model: CpuModel, program: &[u8]) -> Machine {
+    let machine = Machine::new(accuracy);
+    machine.cpu().set_model(model);
+    machine.memory().load(BASE, &program.to_vec());
+    machine.cpu().rp(RegPair::PC).set(BASE);
+    machine.cpu().rp(RegPair::SP).set(0xfffe);
+    machine
+}
+
+/// Run the program until HALT on both CPU cores, check that cores agree
+/// on resulting state and timing and return the machine run by bus-accurate core
+pub fn run_on_both_cores(model: CpuModel, program: &[u8], setup: impl Fn(&Machine)) -> Machine {
+
+    let bus = program_machine(Accuracy::BusCycles, model, program);
+    let fast = program_machine(Accuracy::Instructions, model, program);
+
+    for machine in [&bus, &fast] {
+        setup(machine);
+        for _ in 0..1000 {
+            if machine.cpu().halted.get() { break; }
+            machine.step();
+        }
+        assert!(machine.cpu().halted.get(), "Program didn't reach HALT");
+    }
+
+    assert_eq!(format!("{:?}", ***bus.cpu()), format!("{:?}", ***fast.cpu()), "Cores diverged");
+    // Bus-accurate core starts opcode fetch on the rising edge after instruction completes
+    assert_eq!((bus.clock().get() | 1) + 1, fast.clock().get() + 2, "Cores took different time");
+
+    bus
+
+}
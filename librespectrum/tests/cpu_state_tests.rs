@@ -0,0 +1,76 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use librespectrum::{
+    cpu::tokens::{IntMode, Reg, RegPair},
+    devs::CpuRegisters,
+};
+
+#[test]
+fn saved_state_is_restored_on_another_cpu() {
+    let system = TestSystem::new(&[
+        0x01, 0x34, 0x12,   // LD BC,1234h
+        0xdd, 0x21, 0x78, 0x56, // LD IX,5678h
+        0xd9,               // EXX
+        0xed, 0x5e,         // IM 2
+        0xfb,               // EI
+        0x00,               // NOP
+    ]);
+    let mut scheduler = system.scheduler();
+    assert!(system.run_until(&mut scheduler, BASE + 11, 100));
+    let regs = system.cpu.save_state();
+    assert_eq!(regs.alt_bc, 0x1234);
+    assert_eq!(regs.ix, 0x5678);
+    assert_eq!(regs.im, IntMode::IM2);
+    assert!(regs.iff1 && regs.iff2 && regs.after_ei);
+    assert_eq!(regs.pc, BASE + 11);
+
+    let other = TestSystem::new(&[]);
+    other.cpu.load_state(&regs);
+    assert_eq!(other.cpu.save_state(), regs);
+    assert_eq!(other.cpu.rp(RegPair::BC).get(), 0);
+    assert_eq!(other.cpu.rp(RegPair::IX).get(), 0x5678);
+}
+
+#[test]
+fn running_cpu_continues_from_loaded_pc() {
+    let system = TestSystem::new(&[
+        0x3e, 0x01,         // LD A,1
+        0x3e, 0x02,         // LD A,2
+        0x3e, 0x03,         // LD A,3
+        0x76,               // HALT
+    ]);
+    let mut scheduler = system.scheduler();
+    assert!(system.run_until(&mut scheduler, BASE + 2, 100));
+    let regs = CpuRegisters { pc: BASE + 4, ..system.cpu.save_state() };
+    system.cpu.load_state(&regs);
+    assert!(system.run_until(&mut scheduler, BASE + 6, 100));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 3);
+}
+
+#[test]
+fn loading_halted_state_drives_halt_line() {
+    let system = TestSystem::new(&[
+        0xf3,               // DI
+        0x00,               // NOP
+    ]);
+    let mut scheduler = system.scheduler();
+    assert!(system.run_until(&mut scheduler, BASE + 1, 100));
+    system.cpu.load_state(&CpuRegisters { halted: true, ..system.cpu.save_state() });
+    scheduler.run(100 << 1);
+    assert_eq!(system.bus.halt.probe(), Some(true));
+    assert_eq!(system.cpu.pc.value().get(), BASE + 1);
+}
+
+#[test]
+fn diff_lists_changed_registers() {
+    let before = CpuRegisters { pc: 0x8000, sp: 0xfffe, ..Default::default() };
+    let after = CpuRegisters { pc: 0x8003, sp: 0xfffe, hl: 0x4000, halted: true, ..before };
+    let diffs: Vec<String> = before.diff(&after).iter().map(|diff| diff.to_string()).collect();
+    assert_eq!(diffs, vec!["HL: 0000h -> 4000h", "PC: 8000h -> 8003h", "HALT: false -> true"]);
+    assert!(after.diff(&after).is_empty());
+}
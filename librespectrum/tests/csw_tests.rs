@@ -0,0 +1,54 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    devs::{TapePulse, TapeSegment},
+    formats::{FormatError, csw::Csw},
+};
+
+/// CSW 1.01 header with given sample rate and initial polarity
+fn csw_v1(sample_rate: u16, high: bool) -> Vec<u8> {
+    let mut data = b"Compressed Square Wave\x1a\x01\x01".to_vec();
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&[0x01, high as u8, 0, 0, 0]);
+    data
+}
+
+/// CSW 2.0 header with given sample rate, pulse count, compression and extension
+fn csw_v2(sample_rate: u32, pulses: u32, compression: u8, extension: &[u8]) -> Vec<u8> {
+    let mut data = b"Compressed Square Wave\x1a\x02\x00".to_vec();
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&pulses.to_le_bytes());
+    data.extend_from_slice(&[compression, 0x00, extension.len() as u8]);
+    data.extend_from_slice(b"respectrum\0\0\0\0\0\0");
+    data.extend_from_slice(extension);
+    data
+}
+
+#[test]
+fn reads_version_1() {
+    let data = [csw_v1(35000, true), vec![0x03, 0x00, 0xa0, 0x86, 0x01, 0x00, 0x0a]].concat();
+    let csw = Csw::read(&data).unwrap();
+    assert_eq!(csw, Csw { sample_rate: 35000, initial_level: true, pulses: vec![3, 100_000, 10] });
+    assert_eq!(csw.tstates(), vec![300, 10_000_000, 1000]);
+    assert_eq!(csw.tape_blocks()[0].segments, vec![TapeSegment::Raw(vec![
+        TapePulse::Level(true, 300), TapePulse::Edge(10_000_000), TapePulse::Edge(1000),
+    ])]);
+}
+
+#[test]
+fn reads_version_2_with_zrle() {
+    let rle = [0x02, 0x04, 0x06];
+    let data = [csw_v2(44100, 3, 2, &[0xee, 0xee]), miniz_oxide::deflate::compress_to_vec_zlib(&rle, 6)].concat();
+    let csw = Csw::read(&data).unwrap();
+    assert_eq!(csw, Csw { sample_rate: 44100, initial_level: false, pulses: vec![2, 4, 6] });
+    let rle = [csw_v2(44100, 3, 1, &[]), rle.to_vec()].concat();
+    assert_eq!(Csw::read(&rle).unwrap(), csw);
+}
+
+#[test]
+fn rejects_invalid_files() {
+    assert!(matches!(Csw::read(b"Compressed Square Wave"), Err(FormatError::Invalid(_))));
+    assert!(matches!(Csw::read(&csw_v2(44100, 1, 3, &[])), Err(FormatError::Invalid(_))));
+    assert!(matches!(Csw::read(&[csw_v1(44100, false), vec![0x00, 0x01]].concat()), Err(FormatError::InvalidSize(_))));
+    assert!(matches!(Csw::read(&[csw_v2(44100, 1, 2, &[]), vec![0x78, 0x9c, 0x00]].concat()), Err(FormatError::Invalid(_))));
+}
@@ -0,0 +1,127 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use std::rc::Rc;
+
+use librespectrum::{
+    core::{Ctrl, Identifiable},
+    cpu::tokens::Reg,
+    devs::{BreakCondition, BusState, Ctc, DaisyChain, Device, InterruptSource}
+};
+
+/// Create system running IM2 program which sets CTC vector to 10h and starts
+/// channel 1 as a timer (prescaler 16) with time constant 0Ah. Interrupt handler is at A000h.
+fn ctc_system() -> TestSystem {
+    let system = TestSystem::new(&[
+        0x3e, 0x90, // LD A,90h
+        0xed, 0x47, // LD I,A
+        0xed, 0x5e, // IM 2
+        0x3e, 0x10, // LD A,10h
+        0xd3, 0x80, // OUT (80h),A
+        0x3e, 0x87, // LD A,87h
+        0xd3, 0x81, // OUT (81h),A
+        0x3e, 0x0a, // LD A,0Ah
+        0xd3, 0x81, // OUT (81h),A
+        0xfb,       // EI
+        0x18, 0xfe, // JR $
+    ]);
+    system.generator.response.replace(vec![]);
+    system.memory.load(0x9012, &vec![0x00, 0xa0]);
+    system.memory.load(0xa000, &vec![
+        0xfb,       // EI
+        0xed, 0x4d, // RETI
+    ]);
+    system
+}
+
+fn mkctc(system: &TestSystem) -> Rc<Ctc> {
+    system.device_manager.create_ctc(0x80)
+}
+
+#[test]
+fn ctc_timer_interrupts_periodically_through_daisy_chain() {
+
+    let system = ctc_system();
+    let ctc = mkctc(&system);
+    let chain = DaisyChain::new(301, &system.bus, vec![ctc.clone()]);
+    let mut scheduler = system.scheduler_with(vec![ctc.run(), chain.run()]);
+
+    assert!(system.run_until(&mut scheduler, 0xa000, 400));
+    assert!(ctc.int_in_service());
+    assert_eq!(system.pushed_pc(), BASE + 19);
+    let first = system.clock.get() >> 1;
+
+    assert!(system.run_until(&mut scheduler, 0xa000, 400));
+    let period = (system.clock.get() >> 1) - first;
+    // Interrupt latency depends on the instruction being interrupted
+    assert!((160 - 8..160 + 8).contains(&period), "period is {}", period);
+
+}
+
+#[test]
+fn ctc_counter_is_readable_via_io_port() {
+
+    let system = TestSystem::new(&[
+        0x3e, 0x07, // LD A,07h
+        0xd3, 0x82, // OUT (82h),A
+        0x3e, 0x64, // LD A,64h
+        0xd3, 0x82, // OUT (82h),A
+        0x06, 0x08, // LD B,08h
+        0x10, 0xfe, // DJNZ $
+        0xdb, 0x82, // IN A,(82h)
+        0x76,       // HALT
+    ]);
+    let ctc = mkctc(&system);
+    let mut scheduler = system.scheduler_with(vec![ctc.run()]);
+
+    assert!(system.run_until(&mut scheduler, BASE + 14, 200));
+    // About 110 t-cycles (7 prescaler periods) pass between time constant load and read
+    let a = system.cpu.rg(Reg::A).get();
+    assert!((0x5c..0x60).contains(&a), "counter is {:02x}", a);
+
+}
+
+#[test]
+fn io_read_from_unmapped_port_returns_floating_bus_value() {
+
+    let system = TestSystem::new(&[
+        0xdb, 0xfe, // IN A,(FEh)
+        0x76,       // HALT
+    ]);
+    let ctc = mkctc(&system);
+    let mut scheduler = system.scheduler_with(vec![ctc.run()]);
+
+    assert!(system.run_until(&mut scheduler, BASE + 2, 100));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0xff);
+
+}
+
+#[test]
+fn ctc_supplies_vector_in_interrupt_acknowledge_cycle() {
+
+    let system = ctc_system();
+    let ctc = mkctc(&system);
+    let chain = system.device_manager.create_daisy_chain(vec![ctc.clone()]);
+    let logger = system.device_manager.create_bus_logger();
+    let mut scheduler = system.scheduler_with(vec![ctc.run(), chain.run(), logger.run()]);
+
+    // Vector table entry is read right after the acknowledge cycle and return address push
+    let id = system.breakpoint_manager.once(BreakCondition::AfterMemoryRead(0x9012));
+    assert_eq!(scheduler.run(800), Some(id));
+
+    let readings = logger.readings.borrow();
+    let acknowledge: Vec<BusState> = readings.iter_to_tail()
+        .filter(|state| state.m1.is_some_and(|(_, m1)| m1))
+        .filter(|state| state.ctrl.is_some_and(|(_, ctrl)| ctrl.contains(Ctrl::IORQ)))
+        .collect();
+    assert!(!acknowledge.is_empty());
+    // Acknowledge cycle is neither IO read nor write
+    assert!(acknowledge.iter().all(|state| !state.ctrl.unwrap().1.intersects(Ctrl::RD | Ctrl::WR)));
+    // Channel 1 vector is supplied on behalf of the CTC
+    assert!(acknowledge.iter().any(|state| state.data == Some((ctc.id(), 0x12))));
+
+}
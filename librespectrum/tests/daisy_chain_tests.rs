@@ -0,0 +1,142 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use std::{cell::Cell, rc::Rc};
+
+use librespectrum::{core::{Identifiable, Identifier}, devs::{DaisyChain, Device, InterruptSource}};
+
+/// Test peripheral with a single interrupt channel
+struct TestSource {
+    id: Identifier,
+    vector: u8,
+    pending: Cell<bool>,
+    in_service: Cell<bool>,
+    returns: Cell<usize>,
+}
+
+impl TestSource {
+    fn new(id: Identifier, vector: u8) -> Rc<Self> {
+        Rc::new(Self { id, vector, pending: Cell::new(false), in_service: Cell::new(false), returns: Cell::new(0) })
+    }
+}
+
+impl Identifiable for TestSource {
+    fn id(&self) -> Identifier { self.id }
+}
+
+impl InterruptSource for TestSource {
+
+    fn int_pending(&self) -> bool {
+        self.pending.get()
+    }
+
+    fn int_in_service(&self) -> bool {
+        self.in_service.get()
+    }
+
+    fn int_acknowledge(&self) -> u8 {
+        self.pending.set(false);
+        self.in_service.set(true);
+        self.vector
+    }
+
+    fn int_return(&self) {
+        self.in_service.set(false);
+        self.returns.update(|count| count + 1);
+    }
+
+}
+
+/// Main loop address
+const LOOP: u16 = BASE + 7;
+
+/// Create system running in IM2 with two handlers: for vector 10h at A000h and for vector 20h at B000h
+fn im2_system() -> TestSystem {
+    let system = TestSystem::new(&[
+        0x3e, 0x90, // LD A,90h
+        0xed, 0x47, // LD I,A
+        0xed, 0x5e, // IM 2
+        0xfb,       // EI
+        0x18, 0xfe, // JR $
+    ]);
+    system.generator.response.replace(vec![]);
+    system.memory.load(0x9010, &vec![0x00, 0xa0]);
+    system.memory.load(0x9020, &vec![0x00, 0xb0]);
+    system.memory.load(0xa000, &vec![
+        0xfb,       // EI
+        0x00,       // NOP
+        0xed, 0x4d, // RETI
+    ]);
+    system.memory.load(0xb000, &vec![
+        0xfb,       // EI
+        0x00,       // NOP
+        0x00,       // NOP
+        0x00,       // NOP
+        0xed, 0x4d, // RETI
+    ]);
+    system
+}
+
+#[test]
+fn daisy_chain_supplies_vector_and_decodes_reti() {
+    let system = im2_system();
+    let (high, low) = (TestSource::new(201, 0x10), TestSource::new(202, 0x20));
+    let chain = DaisyChain::new(200, &system.bus, vec![high.clone(), low.clone()]);
+    let mut scheduler = system.scheduler_with(vec![chain.run()]);
+    low.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xb000, 200));
+    assert!(low.in_service.get() && !low.pending.get());
+    assert_eq!(system.pushed_pc(), LOOP);
+    assert!(system.run_until(&mut scheduler, LOOP, 200));
+    assert!(!low.in_service.get());
+    assert_eq!((high.returns.get(), low.returns.get()), (0, 1));
+}
+
+#[test]
+fn higher_priority_device_is_acknowledged_first() {
+    let system = im2_system();
+    let (high, low) = (TestSource::new(201, 0x10), TestSource::new(202, 0x20));
+    let chain = DaisyChain::new(200, &system.bus, vec![high.clone(), low.clone()]);
+    let mut scheduler = system.scheduler_with(vec![chain.run()]);
+    high.pending.set(true);
+    low.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xa000, 200));
+    assert!(high.in_service.get() && low.pending.get());
+    assert!(system.run_until(&mut scheduler, 0xb000, 200));
+    assert_eq!((high.returns.get(), low.returns.get()), (1, 0));
+    assert_eq!(system.pushed_pc(), LOOP);
+}
+
+#[test]
+fn lower_priority_device_waits_until_higher_one_returns() {
+    let system = im2_system();
+    let (high, low) = (TestSource::new(201, 0x10), TestSource::new(202, 0x20));
+    let chain = DaisyChain::new(200, &system.bus, vec![high.clone(), low.clone()]);
+    let mut scheduler = system.scheduler_with(vec![chain.run()]);
+    high.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xa000, 200));
+    low.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xb000, 200));
+    assert_eq!(high.returns.get(), 1);
+    assert_eq!(system.pushed_pc(), LOOP);
+}
+
+#[test]
+fn higher_priority_device_nests_into_lower_priority_service() {
+    let system = im2_system();
+    let (high, low) = (TestSource::new(201, 0x10), TestSource::new(202, 0x20));
+    let chain = DaisyChain::new(200, &system.bus, vec![high.clone(), low.clone()]);
+    let mut scheduler = system.scheduler_with(vec![chain.run()]);
+    low.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xb000, 200));
+    high.pending.set(true);
+    assert!(system.run_until(&mut scheduler, 0xa000, 200));
+    assert!(low.in_service.get());
+    assert!((0xb001..0xb006).contains(&system.pushed_pc()));
+    assert!(system.run_until(&mut scheduler, LOOP, 200));
+    assert_eq!((high.returns.get(), low.returns.get()), (1, 1));
+}
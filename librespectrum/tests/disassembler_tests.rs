@@ -6,13 +6,40 @@ use std::{
     fs::File, io::{self, BufRead}, ops::{Coroutine, CoroutineState}, pin::Pin
 };
 
-use librespectrum::cpu::decoder::instruction_decoder;
+use librespectrum::cpu::{CpuModel, decoder::instruction_decoder};
 
 #[test]
 fn disassembler_recognizes_all_z80_opcodes() {
+    assert_listing("tests/misc/opcodes.lst", CpuModel::Z80);
+}
+
+#[test]
+fn disassembler_recognizes_z80n_extended_opcodes() {
+    assert_listing("tests/misc/z80n_opcodes.lst", CpuModel::Z80N);
+}
+
+#[test]
+fn disassembler_recognizes_z180_extended_opcodes() {
+    assert_listing("tests/misc/z180_opcodes.lst", CpuModel::Z180);
+}
+
+#[test]
+fn z80n_extended_opcodes_are_nops_on_plain_z80() {
+    for opcode in [0x23, 0x24, 0x30, 0x90, 0x93, 0xa4, 0xb4] {
+        let mut decoder = instruction_decoder(CpuModel::Z80);
+        Pin::new(&mut decoder).resume(0xed);
+        match Pin::new(&mut decoder).resume(opcode) {
+            CoroutineState::Complete(instruction) => assert_eq!(instruction.format_mnemonic(), "NOP"),
+            _ => panic!("Expecting ED {:02X} to be decoded as NOP", opcode)
+        }
+    }
+}
+
+/// Check that decoder produces expected mnemonics for reference listing
+fn assert_listing(path: &str, model: CpuModel) {
 
     // File with reference listing
-    let file = File::open("tests/misc/opcodes.lst").unwrap();
+    let file = File::open(path).unwrap();
     let mut lines = io::BufReader::new(file).lines().enumerate();
 
     // Iterate over listing lines
@@ -35,7 +62,7 @@ fn disassembler_recognizes_all_z80_opcodes() {
             .enumerate().peekable();
         let expected_mnemonic = parts.next().unwrap().trim();
 
-        let mut decoder = instruction_decoder();
+        let mut decoder = instruction_decoder(model);
 
         // Feed bytes to disassembler and observe results
         while let Some((byte_num, byte)) = bytes_iter.next() {
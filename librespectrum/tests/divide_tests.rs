@@ -0,0 +1,128 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem, test_image};
+
+use std::rc::Rc;
+
+use librespectrum::{
+    cpu::tokens::Reg,
+    devs::{AtaDrive, BlockImage, Device, MemoryImage},
+};
+
+/// Task file registers
+const DATA: u8 = 0;
+const ERROR: u8 = 1;
+const COUNT: u8 = 2;
+const SECTOR: u8 = 3;
+const CYLINDER_LOW: u8 = 4;
+const CYLINDER_HIGH: u8 = 5;
+const DEVICE_HEAD: u8 = 6;
+const COMMAND: u8 = 7;
+
+fn drive_with(image: &Rc<MemoryImage>) -> AtaDrive {
+    let drive = AtaDrive::default();
+    drive.insert(&(Rc::clone(image) as Rc<dyn BlockImage>));
+    drive
+}
+
+fn read_bytes(drive: &AtaDrive, count: usize) -> Vec<u8> {
+    (0..count).map(|_| drive.read(DATA)).collect()
+}
+
+#[test]
+fn drive_identifies_itself() {
+    let drive = drive_with(&Rc::new(MemoryImage::new(16 * 63 * 20)));
+    assert_eq!(drive.read(COMMAND), 0x50);
+    drive.write(COMMAND, 0xec);
+    assert_eq!(drive.read(COMMAND), 0x58);
+    let data = read_bytes(&drive, 512);
+    assert_eq!(drive.read(COMMAND), 0x50);
+    let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+    assert_eq!((word(1), word(3), word(6)), (20, 16, 63));
+    assert_ne!(word(49) & 0x0200, 0);
+    assert_eq!((word(61) as u32) << 16 | word(60) as u32, 16 * 63 * 20);
+    assert_eq!(word(27).to_be_bytes(), *b"RE");
+}
+
+#[test]
+fn drive_reads_sectors_by_lba() {
+    let image = test_image(8);
+    let drive = drive_with(&image);
+    drive.write(COUNT, 2);
+    drive.write(SECTOR, 3);
+    drive.write(CYLINDER_LOW, 0);
+    drive.write(CYLINDER_HIGH, 0);
+    drive.write(DEVICE_HEAD, 0xe0);
+    drive.write(COMMAND, 0x20);
+    assert_eq!(read_bytes(&drive, 1024), image.data()[3 * 512..5 * 512]);
+    assert_eq!(drive.read(COMMAND), 0x50);
+    assert_eq!(drive.read(COUNT), 0);
+    assert_eq!(drive.read(SECTOR), 4);
+}
+
+#[test]
+fn drive_writes_sectors_by_chs() {
+    let image = Rc::new(MemoryImage::new(16 * 63 * 2));
+    let drive = drive_with(&image);
+    // Cylinder 1, head 2, sector 5
+    drive.write(COUNT, 2);
+    drive.write(SECTOR, 5);
+    drive.write(CYLINDER_LOW, 1);
+    drive.write(CYLINDER_HIGH, 0);
+    drive.write(DEVICE_HEAD, 0xa2);
+    drive.write(COMMAND, 0x30);
+    assert_eq!(drive.read(COMMAND), 0x58);
+    (0..1024).for_each(|index| drive.write(DATA, (index / 512 + 1) as u8));
+    assert_eq!(drive.read(COMMAND), 0x50);
+    let block = ((16 + 2) * 63 + 4) * 512;
+    assert_eq!(image.data()[block..block + 512], [1; 512]);
+    assert_eq!(image.data()[block + 512..block + 1024], [2; 512]);
+    assert_eq!(drive.read(SECTOR), 6);
+}
+
+#[test]
+fn drive_aborts_invalid_commands() {
+    let drive = drive_with(&test_image(8));
+    drive.write(COMMAND, 0xff);
+    assert_eq!(drive.read(COMMAND), 0x51);
+    assert_eq!(drive.read(ERROR), 0x04);
+    // Sector out of the image
+    drive.write(COUNT, 1);
+    drive.write(SECTOR, 8);
+    drive.write(DEVICE_HEAD, 0xe0);
+    drive.write(COMMAND, 0x20);
+    assert_eq!(drive.read(COMMAND), 0x51);
+    assert_eq!(drive.read(ERROR), 0x10);
+    // Slave device is absent
+    drive.write(DEVICE_HEAD, 0xf0);
+    assert_eq!(drive.read(COMMAND), 0x00);
+}
+
+#[test]
+fn divide_reads_sector_through_ports() {
+    let system = TestSystem::new(&[
+        0x3e, 0x01,         // LD A,1
+        0xd3, 0xab,         // OUT (ABh),A
+        0xd3, 0xaf,         // OUT (AFh),A
+        0x3e, 0xe0,         // LD A,E0h
+        0xd3, 0xbb,         // OUT (BBh),A
+        0x3e, 0x20,         // LD A,20h
+        0xd3, 0xbf,         // OUT (BFh),A
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x01, 0xa3, 0x00,   // LD BC,00A3h
+        0xed, 0xb2,         // INIR
+        0xed, 0xb2,         // INIR
+        0xdb, 0xbf,         // IN A,(BFh)
+        0x76,               // HALT
+    ]);
+    let image = test_image(4);
+    let divide = system.device_manager.create_divide(&system.memory, &[]);
+    divide.drive().insert(&(Rc::clone(&image) as Rc<dyn BlockImage>));
+    let mut scheduler = system.scheduler_with(vec![divide.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 26, 20000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x50);
+    assert_eq!(system.memory.dump()[0x9000..0x9200], image.data()[512..1024]);
+}
@@ -0,0 +1,218 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem, test_image};
+
+use std::rc::Rc;
+
+use librespectrum::{
+    cpu::tokens::Reg,
+    devs::{BlockImage, Device, MemoryImage, SdCard, mem::Memory},
+};
+
+fn selected_card(image: &Rc<MemoryImage>) -> SdCard {
+    let card = SdCard::default();
+    card.insert(&(Rc::clone(image) as Rc<dyn BlockImage>));
+    card.select(true);
+    card
+}
+
+/// Send command and return R1 response
+fn command(card: &SdCard, index: u8, arg: u32) -> u8 {
+    card.transfer(0x40 | index);
+    arg.to_be_bytes().into_iter().for_each(|byte| { card.transfer(byte); });
+    card.transfer(0x01);
+    (0..8).map(|_| card.transfer(0xff)).find(|&byte| byte != 0xff).expect("no response")
+}
+
+/// Wait for data token and read block of given size
+fn read_data(card: &SdCard, size: usize) -> Vec<u8> {
+    assert_eq!((0..8).map(|_| card.transfer(0xff)).find(|&byte| byte != 0xff), Some(0xfe));
+    let data = (0..size).map(|_| card.transfer(0xff)).collect();
+    // CRC
+    card.transfer(0xff);
+    card.transfer(0xff);
+    data
+}
+
+/// Send data token and block, return data response
+fn write_data(card: &SdCard, token: u8, data: &[u8]) -> u8 {
+    card.transfer(0xff);
+    card.transfer(token);
+    data.iter().for_each(|&byte| { card.transfer(byte); });
+    card.transfer(0xff);
+    card.transfer(0xff);
+    let response = card.transfer(0xff) & 0x1f;
+    // Wait while busy
+    while card.transfer(0xff) != 0xff {}
+    response
+}
+
+#[test]
+fn memory_is_automapped_after_entry_point_fetch() {
+    let mut rom = vec![0; 0x2000];
+    rom[0x0000] = 0xaa;
+    // Opcode at the entry point is fetched from Spectrum ROM
+    rom[0x0008] = 0x76;
+    rom[0x0009..0x0010].copy_from_slice(&[
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x47,               // LD B,A
+        0xc3, 0xf8, 0x1f,   // JP 1FF8h
+    ]);
+    rom[0x1ff8] = 0xc9;     // RET, memory is mapped out after it's fetched
+    let (system, divmmc) = TestSystem::with_device(&[
+        0xcf,               // RST 8
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x4f,               // LD C,A
+        0x76,               // HALT
+    ], |devices, memory| devices.create_divmmc(memory, &rom));
+    let mut scheduler = system.scheduler_with(vec![divmmc.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 5, 1000));
+    assert_eq!(system.cpu.rg(Reg::B).get(), 0xaa);
+    assert_eq!(system.cpu.rg(Reg::C).get(), 0x00);
+    assert!(!divmmc.memory().is_automapped());
+}
+
+#[test]
+fn memory_is_mapped_instantly_by_fetch_from_3dxx() {
+    let (system, divmmc) = TestSystem::with_device(&[
+        0x3e, 0x80,         // LD A,80h
+        0xd3, 0xe3,         // OUT (E3h),A
+        0x3e, 0xc9,         // LD A,C9h
+        0x32, 0x00, 0x3d,   // LD (3D00h),A
+        0xaf,               // XOR A
+        0xd3, 0xe3,         // OUT (E3h),A
+        0xcd, 0x00, 0x3d,   // CALL 3D00h
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x76,               // HALT
+    ], |devices, memory| devices.create_divmmc(memory, &[0x55]));
+    let mut scheduler = system.scheduler_with(vec![divmmc.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 18, 1000));
+    assert!(divmmc.memory().is_automapped());
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x55);
+    // Spectrum RAM wasn't written
+    assert_eq!(divmmc.memory().ram()[0x1d00], 0xc9);
+}
+
+#[test]
+fn conmem_maps_eeprom_and_writable_bank() {
+    let (system, divmmc) = TestSystem::with_device(&[], |devices, memory| devices.create_divmmc(memory, &[0x55]));
+    let memory = divmmc.memory();
+    memory.write_control(0x85);
+    assert!(!system.memory.writable(0x1fff));
+    assert!(system.memory.writable(0x2000));
+    system.memory.write(0x2000, 0x12);
+    assert_eq!(system.memory.read(0x0000), 0x55);
+    assert_eq!(system.memory.read(0x2000), 0x12);
+    assert_eq!(memory.ram()[5 * 0x2000], 0x12);
+    memory.write_control(0x00);
+    assert_eq!(system.memory.read(0x0000), 0x00);
+}
+
+#[test]
+fn mapram_maps_write_protected_bank_3() {
+    let (system, divmmc) = TestSystem::with_device(&[], |devices, memory| devices.create_divmmc(memory, &[0x55]));
+    let memory = divmmc.memory();
+    memory.write_control(0x83);
+    system.memory.write(0x2000, 0x33);
+    memory.write_control(0x40);
+    assert!(!memory.is_paged());
+    memory.fetch(0x0038);
+    assert!(!memory.is_paged());
+    memory.fetch_end();
+    assert!(memory.is_paged());
+    assert_eq!(system.memory.read(0x0000), 0x33);
+    assert!(!system.memory.writable(0x0000));
+    // MAPRAM can't be reset, bank 3 is write protected at 2000h as well
+    memory.write_control(0x03);
+    assert_eq!(system.memory.read(0x0000), 0x33);
+    assert!(!system.memory.writable(0x2000));
+    memory.write_control(0x02);
+    assert!(system.memory.writable(0x2000));
+}
+
+#[test]
+fn card_responds_through_spi_ports() {
+    let (system, divmmc) = TestSystem::with_device(&[
+        0x3e, 0xfe,         // LD A,FEh
+        0xd3, 0xe7,         // OUT (E7h),A
+        0x21, 0x14, 0x80,   // LD HL,8014h
+        0x01, 0xeb, 0x06,   // LD BC,06EBh
+        0xed, 0xb3,         // OTIR
+        0xed, 0x78,         // IN A,(C)
+        0xfe, 0xff,         // CP FFh
+        0x28, 0xfa,         // JR Z,-6
+        0x76,               // HALT
+        0x00,
+        0x40, 0x00, 0x00, 0x00, 0x00, 0x95,
+    ], |devices, memory| devices.create_divmmc(memory, &[]));
+    divmmc.card().insert(&(test_image(4) as Rc<dyn BlockImage>));
+    let mut scheduler = system.scheduler_with(vec![divmmc.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 18, 2000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x01);
+}
+
+#[test]
+fn card_is_initialized_as_high_capacity_card() {
+    let image = test_image(4);
+    let card = selected_card(&image);
+    assert_eq!(command(&card, 0, 0), 0x01);
+    assert_eq!(command(&card, 8, 0x1aa), 0x01);
+    assert_eq!([0; 4].map(|_| card.transfer(0xff)), [0x00, 0x00, 0x01, 0xaa]);
+    assert_eq!(command(&card, 55, 0), 0x01);
+    assert_eq!(command(&card, 41, 0x4000_0000), 0x00);
+    assert_eq!(command(&card, 58, 0), 0x00);
+    assert_eq!(card.transfer(0xff) & 0xc0, 0xc0);
+    // Unknown command
+    assert_eq!(command(&card, 5, 0), 0x04);
+}
+
+#[test]
+fn card_reports_capacity_in_csd() {
+    let image = Rc::new(MemoryImage::new(4096));
+    let card = selected_card(&image);
+    assert_eq!(command(&card, 9, 0), 0x01);
+    let csd = read_data(&card, 16);
+    assert_eq!(csd[0] >> 6, 1);
+    let size = (csd[7] as u32 & 0x3f) << 16 | (csd[8] as u32) << 8 | csd[9] as u32;
+    assert_eq!((size + 1) * 1024, 4096);
+}
+
+#[test]
+fn card_reads_blocks() {
+    let image = test_image(4);
+    let card = selected_card(&image);
+    assert_eq!(command(&card, 17, 2), 0x01);
+    assert_eq!(read_data(&card, 512), image.data()[1024..1536]);
+
+    assert_eq!(command(&card, 18, 1), 0x01);
+    assert_eq!(read_data(&card, 512), image.data()[512..1024]);
+    assert_eq!(read_data(&card, 512), image.data()[1024..1536]);
+    assert_eq!(command(&card, 12, 0), 0x01);
+
+    assert_eq!(command(&card, 17, 4), 0x21);
+}
+
+#[test]
+fn card_writes_blocks() {
+    let image = test_image(4);
+    let card = selected_card(&image);
+    assert_eq!(command(&card, 24, 3), 0x01);
+    assert_eq!(write_data(&card, 0xfe, &[0x11; 512]), 0x05);
+    assert_eq!(image.data()[1536..], [0x11; 512]);
+
+    assert_eq!(command(&card, 25, 0), 0x01);
+    assert_eq!(write_data(&card, 0xfc, &[0x22; 512]), 0x05);
+    assert_eq!(write_data(&card, 0xfc, &[0x33; 512]), 0x05);
+    card.transfer(0xfd);
+    while card.transfer(0xff) != 0xff {}
+    assert_eq!(image.data()[..512], [0x22; 512]);
+    assert_eq!(image.data()[512..1024], [0x33; 512]);
+    assert_eq!(image.data()[1024], 2);
+
+    // Card accepts commands after the stop token
+    assert_eq!(command(&card, 17, 3), 0x01);
+    assert_eq!(read_data(&card, 512), [0x11; 512]);
+}
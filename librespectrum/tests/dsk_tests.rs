@@ -0,0 +1,73 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    devs::{Disk, DiskSector},
+    formats::{FormatError, dsk::Dsk},
+};
+
+/// Double-sided disk of two cylinders with nine 512-byte sectors per track
+fn test_disk() -> Disk {
+    let mut disk = Disk::new(2, 2);
+    for (index, track) in disk.tracks.iter_mut().enumerate() {
+        *track = (1..=9).map(|sector| DiskSector::new((index / 2) as u8, (index % 2) as u8, sector, vec![index as u8 * 16 + sector; 512])).collect();
+    }
+    disk
+}
+
+#[test]
+fn reads_and_writes_standard_image() {
+    let dsk = Dsk { extended: false, disk: test_disk() };
+    let data = dsk.write().unwrap();
+    assert_eq!(&data[..34], b"MV - CPCEMU Disk-File\r\nDisk-Info\r\n");
+    assert_eq!(data[0x30..0x34], [2, 2, 0x00, 0x13]);
+    assert_eq!(data.len(), 256 + 4 * (256 + 9 * 512));
+    // Track info block of cylinder 1 side 0
+    let track = &data[256 + 2 * 0x1300..];
+    assert_eq!(&track[..12], b"Track-Info\r\n");
+    assert_eq!(track[0x10..0x18], [1, 0, 0, 0, 2, 9, 0x4e, 0xe5]);
+    assert_eq!(track[0x18..0x20], [1, 0, 1, 2, 0, 0, 0, 0]);
+    assert_eq!(track[0x100..0x300], [0x21; 512]);
+    assert_eq!(Dsk::read(&data).unwrap(), dsk);
+}
+
+#[test]
+fn reads_and_writes_extended_image() {
+    let mut disk = test_disk();
+    // Unformatted track, odd sector sizes, deleted sector and weak sector
+    disk.tracks[3].clear();
+    disk.tracks[2] = vec![DiskSector::new(1, 0, 0xc1, vec![1; 256]), DiskSector::new(1, 0, 0xc2, vec![2; 6144])];
+    disk.tracks[2][1].size_code = 6;
+    disk.tracks[0][1].deleted = true;
+    let weak = &mut disk.tracks[0][0];
+    weak.st1 = 0x20;
+    weak.st2 = 0x20;
+    weak.copies = vec![vec![0xaa; 512], vec![0x55; 512]];
+
+    let dsk = Dsk { extended: true, disk };
+    let data = dsk.write().unwrap();
+    assert_eq!(&data[..34], b"EXTENDED CPC DSK File\r\nDisk-Info\r\n");
+    assert_eq!(data[0x30..0x38], [2, 2, 0, 0, 0x17, 0x13, 0x1a, 0x00]);
+    assert_eq!(data.len(), 256 + (0x17 + 0x13 + 0x1a) * 256);
+    // Weak sector keeps its status and all copies, deleted sector has control mark
+    assert_eq!(data[0x100 + 0x18..0x100 + 0x28], [0, 0, 1, 2, 0x20, 0x20, 0x00, 0x06, 0, 0, 2, 2, 0, 0x40, 0x00, 0x02]);
+    assert_eq!(data[0x200 + 512..0x200 + 1024], [0xaa; 512]);
+    assert_eq!(Dsk::read(&data).unwrap(), dsk);
+
+    // Disk that changed since read from standard image is written as extended
+    let standard = Dsk { extended: false, disk: dsk.disk.clone() };
+    assert_eq!(Dsk::read(&standard.write().unwrap()).unwrap(), dsk);
+}
+
+#[test]
+fn rejects_invalid_images() {
+    assert!(matches!(Dsk::read(b"NOT A DISK IMAGE"), Err(FormatError::Invalid(_))));
+    assert!(matches!(Dsk::read(b"EXTENDED CPC DSK File\r\n"), Err(FormatError::InvalidSize(_))));
+    let data = Dsk { extended: true, disk: test_disk() }.write().unwrap();
+    assert!(matches!(Dsk::read(&data[..data.len() - 256]), Err(FormatError::InvalidSize(_))));
+    let mut corrupted = data.clone();
+    corrupted[0x100] = b'X';
+    assert!(matches!(Dsk::read(&corrupted), Err(FormatError::Invalid(_))));
+    let mut disk = test_disk();
+    disk.tracks[0] = (0..30).map(|sector| DiskSector::new(0, 0, sector, vec![0; 128])).collect();
+    assert!(matches!(Dsk { extended: true, disk }.write(), Err(FormatError::Invalid(_))));
+}
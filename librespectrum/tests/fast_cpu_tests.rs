@@ -0,0 +1,203 @@
+extern crate librespectrum;
+
+use std::fs;
+
+use librespectrum::{
+    cpu::{CpuModel, tokens::{Reg, RegPair}},
+    core::Identifiable,
+    devs::{BreakCondition, PrinterModel},
+    machine::{Accuracy, AccuracyError, Machine},
+};
+
+/// Number of ZEXDOC instructions to compare cores on
+const INSTRUCTIONS: usize = 200_000;
+
+/// Endless loop ZEXDOC ends in after the last test
+const ZEXDOC_STOP: u16 = 0x803d;
+
+/// Create machine running ZEXDOC. Spectrum ROM print routine is replaced with RET.
+fn zexdoc_machine(accuracy: Accuracy) -> Machine {
+    let machine = Machine::new(accuracy);
+    machine.memory().load(0x8000, &fs::read("tests/exerciser/zexdoc_patched.bin").unwrap());
+    machine.memory().load(0x0010, &vec![0xc9]); // RET
+    machine.cpu().rp(RegPair::PC).set(0x8000);
+    machine
+}
+
+/// Half t-cycle when bus-accurate CPU starts the next instruction opcode fetch.
+/// Instruction may end either on falling or rising clock edge, while opcode fetch
+/// always starts on rising edge.
+fn next_fetch(machine: &Machine) -> u64 {
+    (machine.clock().get() | 1) + 1
+}
+
+#[test]
+fn fast_core_matches_bus_accurate_core_on_zexdoc() {
+
+    let bus = zexdoc_machine(Accuracy::BusCycles);
+    let fast = zexdoc_machine(Accuracy::Instructions);
+
+    let mut bus_htcycles = next_fetch(&bus);
+    let mut fast_htcycles = fast.clock().get();
+
+    for step in 1..=INSTRUCTIONS {
+
+        let pc = fast.cpu().pc.value().get();
+        bus.step();
+        fast.step();
+
+        assert_eq!(
+            format!("{:?}", ***bus.cpu()), format!("{:?}", ***fast.cpu()),
+            "Cores diverged after {} instructions at {:04x}h", step, pc
+        );
+        assert_eq!(
+            next_fetch(&bus) - bus_htcycles, fast.clock().get() - fast_htcycles,
+            "Instruction at {:04x}h took different time", pc
+        );
+
+        bus_htcycles = next_fetch(&bus);
+        fast_htcycles = fast.clock().get();
+
+    }
+
+}
+
+/// Instructions of every opcode table, followed by displacement and data bytes.
+/// HALT and chains of prefixes are left out.
+fn all_instructions() -> Vec<Vec<u8>> {
+    let mut instructions = vec![];
+    for opcode in (0..=0xff).filter(|&opcode| opcode != 0x76) {
+        instructions.push(vec![opcode, 0x05, 0x00]);
+        instructions.push(vec![0xcb, opcode]);
+        instructions.push(vec![0xed, opcode, 0x05, 0x00]);
+        for prefix in [0xdd, 0xfd] {
+            if !matches!(opcode, 0xcb | 0xdd | 0xed | 0xfd) {
+                instructions.push(vec![prefix, opcode, 0x05, 0x00]);
+            }
+            instructions.push(vec![prefix, 0xcb, 0x05, opcode]);
+        }
+    }
+    instructions
+}
+
+#[test]
+fn fast_core_matches_bus_accurate_core_timing_of_every_instruction() {
+
+    for model in [CpuModel::Z80, CpuModel::Z80N, CpuModel::Z180] {
+        for code in all_instructions() {
+            // Each condition is met with one of the flags values. BC = 0001h stops LDIR and CPIR,
+            // B = 1 stops INIR, OTIR and DJNZ.
+            for (flags, bc) in [(0x00, 0x0000), (0xff, 0x0001), (0x00, 0x0101)] {
+                let [bus, fast] = [Accuracy::BusCycles, Accuracy::Instructions].map(|accuracy| {
+                    let machine = Machine::new(accuracy);
+                    machine.cpu().set_model(model);
+                    machine.memory().load(0x8000, &code);
+                    machine.cpu().rp(RegPair::PC).set(0x8000);
+                    machine.cpu().rp(RegPair::SP).set(0xc000);
+                    machine.cpu().rp(RegPair::AF).set(0x4200 | flags);
+                    machine.cpu().rp(RegPair::BC).set(bc);
+                    for rpair in [RegPair::DE, RegPair::HL, RegPair::IX, RegPair::IY] {
+                        machine.cpu().rp(rpair).set(0x9000);
+                    }
+                    machine
+                });
+                let start = next_fetch(&bus);
+                bus.step();
+                fast.step();
+                assert_eq!(
+                    next_fetch(&bus) - start, fast.clock().get(),
+                    "{:?} instruction {:02x?} took different time with F={:02x}h, BC={:04x}h", model, code, flags, bc
+                );
+            }
+        }
+    }
+
+}
+
+/// Run ZEXDOC to the end, collecting characters it prints through RST 10h
+fn run_zexdoc(accuracy: Accuracy) -> (Machine, String) {
+    let machine = zexdoc_machine(accuracy);
+    let print = machine.breakpoint_manager().add(BreakCondition::BeforeOpcodeRead(Some(0x0010)), false);
+    let stop = machine.breakpoint_manager().add(BreakCondition::BeforeOpcodeRead(Some(ZEXDOC_STOP)), false);
+    let mut output = String::new();
+    loop {
+        match machine.run(u64::MAX >> 2) {
+            Some(id) if id == print => output.push(machine.cpu().rg(Reg::A).get() as char),
+            Some(id) if id == stop => break,
+            _ => (),
+        }
+    }
+    (machine, output)
+}
+
+#[test]
+#[cfg_attr(not(feature = "zexdoc-full"), ignore = "runs all ZEXDOC groups on both cores, takes over an hour; enable zexdoc-full feature in release build")]
+fn fast_core_matches_bus_accurate_core_on_complete_zexdoc() {
+
+    let (bus, bus_output) = run_zexdoc(Accuracy::BusCycles);
+    let (fast, fast_output) = run_zexdoc(Accuracy::Instructions);
+
+    // Both cores report the same CRCs for every group
+    assert!(fast_output.ends_with("Tests complete"), "{}", fast_output);
+    assert_eq!(bus_output, fast_output);
+    assert!(!fast_output.contains("CRC:"), "{}", fast_output);
+
+    assert_eq!(format!("{:?}", ***bus.cpu()), format!("{:?}", ***fast.cpu()));
+    assert_eq!(bus.memory().dump(), fast.memory().dump());
+    assert_eq!(next_fetch(&bus), fast.clock().get() + 2);
+
+}
+
+#[test]
+fn machine_switches_cores_on_instruction_boundary() {
+
+    let reference = zexdoc_machine(Accuracy::Instructions);
+    let machine = zexdoc_machine(Accuracy::BusCycles);
+
+    for _ in 0..3000 {
+        reference.step();
+    }
+
+    for _ in 0..1000 {
+        machine.step();
+    }
+    machine.set_accuracy(Accuracy::Instructions).unwrap();
+    for _ in 0..1000 {
+        machine.step();
+    }
+    machine.set_accuracy(Accuracy::BusCycles).unwrap();
+    for _ in 0..1000 {
+        machine.step();
+    }
+
+    assert_eq!(format!("{:?}", ***machine.cpu()), format!("{:?}", ***reference.cpu()));
+
+}
+
+#[test]
+fn machine_finishes_current_instruction_when_switching_to_fast_core() {
+
+    let reference = zexdoc_machine(Accuracy::Instructions);
+    let machine = zexdoc_machine(Accuracy::BusCycles);
+
+    machine.run(10_001);
+    machine.set_accuracy(Accuracy::Instructions).unwrap();
+
+    // Bus-accurate core stopped in the middle of instruction and then finished it.
+    // Fast core clock is 1 t-cycle behind since bus-accurate core starts fetch on rising edge.
+    while reference.clock().get() + 2 < next_fetch(&machine) {
+        reference.step();
+    }
+
+    assert_eq!(format!("{:?}", ***machine.cpu()), format!("{:?}", ***reference.cpu()));
+
+}
+
+#[test]
+fn fast_core_is_refused_with_port_devices() {
+    let machine = Machine::new(Accuracy::BusCycles);
+    let printer = machine.device_manager().create_zx_printer(PrinterModel::ZxPrinter);
+    assert_eq!(machine.set_accuracy(Accuracy::Instructions), Err(AccuracyError::PortDevices(vec![printer.id()])));
+    assert_eq!(machine.accuracy(), Accuracy::BusCycles);
+    assert_eq!(machine.set_accuracy(Accuracy::BusCycles), Ok(()));
+}
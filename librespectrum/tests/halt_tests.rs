@@ -0,0 +1,56 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use librespectrum::cpu::tokens::Reg;
+
+#[test]
+fn halted_cpu_keeps_refreshing_memory_without_advancing_pc() {
+    let system = TestSystem::new(&[
+        0xf3,       // DI
+        0x76,       // HALT
+        0x00,       // NOP
+    ]);
+    let mut scheduler = system.scheduler();
+    scheduler.run(100 << 1);
+    assert!(system.cpu.halted.get());
+    assert_eq!(system.bus.halt.probe(), Some(true));
+    let r = system.cpu.rg(Reg::R).get();
+    scheduler.run(400 << 1);
+    assert_eq!(system.cpu.rg(Reg::R).get().wrapping_sub(r) & 0x7f, 100);
+    assert_eq!(system.cpu.pc.value().get(), BASE + 2);
+}
+
+#[test]
+fn interrupt_exits_halt_and_pushes_address_after_halt() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0x76,       // HALT
+        0x00,       // NOP
+    ]);
+    system.generator.int_window.set((200, 232));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 300));
+    assert!(!system.cpu.halted.get());
+    assert_eq!(system.bus.halt.probe(), Some(false));
+    assert_eq!(system.pushed_pc(), BASE + 4);
+}
+
+#[test]
+fn halt_with_interrupts_disabled_is_left_on_nmi_only() {
+    let system = TestSystem::new(&[
+        0xf3,       // DI
+        0x76,       // HALT
+        0x00,       // NOP
+    ]);
+    let mut scheduler = system.scheduler();
+    system.generator.int_window.set((0, u64::MAX));
+    system.generator.nmi_window.set((500, 510));
+    assert!(!system.run_until(&mut scheduler, 0x0038, 400));
+    assert!(system.cpu.halted.get());
+    assert!(system.run_until(&mut scheduler, 0x0066, 400));
+    assert_eq!(system.pushed_pc(), BASE + 2);
+}
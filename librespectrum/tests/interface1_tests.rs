@@ -0,0 +1,230 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem, interface_rom};
+
+use std::{fs, rc::Rc, thread, time::Duration};
+
+use librespectrum::{
+    cpu::tokens::Reg,
+    devs::{Device, HostSerial, Microdrive, SerialLoopback, SerialPort},
+    formats::mdr::{DATA_SIZE, HEADER_SIZE, Mdr, checksum},
+};
+
+/// Formatted cartridge with given number of sectors, numbered downwards as the
+/// Interface 1 does, with data blocks filled with sector index
+fn test_cartridge(sectors: usize) -> Mdr {
+    let mut mdr = Mdr::new(sectors);
+    for index in 0..sectors {
+        let sector = mdr.sector_mut(index);
+        let mut header = vec![0x01, (sectors - index) as u8, 0x00, 0x00];
+        header.extend(b"CARTRIDGE ");
+        header.push(checksum(&header));
+        sector[..HEADER_SIZE].copy_from_slice(&header);
+        let mut record = vec![0x00; HEADER_SIZE];
+        record[14] = checksum(&record[..14]);
+        record.extend([index as u8; 512]);
+        record.push(checksum(&record[HEADER_SIZE..]));
+        sector[HEADER_SIZE..].copy_from_slice(&record);
+    }
+    mdr
+}
+
+/// Poll status until sync is detected
+fn wait_sync(drive: &Microdrive) {
+    assert!((0..100).any(|_| drive.status().sync));
+}
+
+fn read_block(drive: &Microdrive, size: usize) -> Vec<u8> {
+    wait_sync(drive);
+    (0..size).map(|_| drive.read()).collect()
+}
+
+#[test]
+fn shadow_rom_is_paged_by_rst_8() {
+    let mut rom = vec![0; 0x0008];
+    rom.extend([
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x47,               // LD B,A
+        0xc3, 0x00, 0x07,   // JP 0700h
+    ]);
+    rom.resize(0x0700, 0);
+    rom.push(0xc9);         // RET
+    let (system, if1) = TestSystem::with_device(&[
+        0xcf,               // RST 8
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x4f,               // LD C,A
+        0x76,               // HALT
+    ], |devices, memory| devices.create_interface1(memory, &interface_rom(0x2000, &rom)));
+    let mut scheduler = system.scheduler_with(vec![if1.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 5, 1000));
+    assert_eq!(system.cpu.rg(Reg::B).get(), 0xaa);
+    assert_eq!(system.cpu.rg(Reg::C).get(), 0x00);
+    assert!(!if1.is_paged());
+}
+
+#[test]
+fn shadow_rom_is_paged_by_fetch_from_1708() {
+    let mut rom = vec![0; 0x1708];
+    rom.push(0xc9);         // RET
+    let (system, if1) = TestSystem::with_device(&[
+        0xcd, 0x08, 0x17,   // CALL 1708h
+        0x76,               // HALT
+    ], |devices, memory| devices.create_interface1(memory, &interface_rom(0x2000, &rom)));
+    let mut scheduler = system.scheduler_with(vec![if1.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 3, 1000));
+    assert!(if1.is_paged());
+}
+
+#[test]
+fn microdrive_is_selected_and_read_through_ports() {
+    let program = [
+        0x06, 0x07,         // LD B,7
+        0x3e, 0x1f,         // LD A,1Fh
+        0xd3, 0xef,         // OUT (EFh),A
+        0x3e, 0x1d,         // LD A,1Dh
+        0xd3, 0xef,         // OUT (EFh),A
+        0x10, 0xf6,         // DJNZ -10
+        0x3e, 0x1e,         // LD A,1Eh
+        0xd3, 0xef,         // OUT (EFh),A
+        0x3e, 0x1c,         // LD A,1Ch
+        0xd3, 0xef,         // OUT (EFh),A
+        0xdb, 0xef,         // IN A,(EFh)
+        0xe6, 0x02,         // AND 02h
+        0x20, 0xfa,         // JR NZ,-6
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x01, 0xe7, 0x0f,   // LD BC,0FE7h
+        0xed, 0xb2,         // INIR
+        0x76,               // HALT
+    ];
+    let (system, if1) = TestSystem::with_device(&program, |devices, memory| devices.create_interface1(memory, &interface_rom(0x2000, &[])));
+    let cartridge = test_cartridge(4);
+    if1.drive(0).insert(cartridge.clone());
+    if1.drive(1).insert(test_cartridge(4));
+    let mut scheduler = system.scheduler_with(vec![if1.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 34, 5000));
+    assert!(if1.drive(0).motor());
+    assert!((1..8).all(|index| !if1.drive(index).motor()));
+    assert_eq!(system.memory.dump()[0x9000..0x900f], cartridge.data[..HEADER_SIZE]);
+}
+
+#[test]
+fn tape_moves_on_while_status_is_polled() {
+    let drive = Microdrive::default();
+    let cartridge = test_cartridge(3);
+    drive.insert(cartridge.clone());
+    drive.set_motor(true);
+    assert!(!drive.status().write_protected);
+    // Header and data blocks of the first sector pass unread
+    for _ in 0..2 {
+        wait_sync(&drive);
+        while drive.status().sync {}
+    }
+    assert_eq!(read_block(&drive, HEADER_SIZE), cartridge.sector(1)[..HEADER_SIZE]);
+    // Transfer stopped in the middle of the data block
+    assert_eq!(read_block(&drive, 10), cartridge.sector(1)[HEADER_SIZE..HEADER_SIZE + 10]);
+    assert_eq!(read_block(&drive, HEADER_SIZE), cartridge.sector(2)[..HEADER_SIZE]);
+    assert_eq!(read_block(&drive, DATA_SIZE), cartridge.sector(2)[HEADER_SIZE..]);
+    // Tape is a loop
+    assert_eq!(read_block(&drive, HEADER_SIZE), cartridge.sector(0)[..HEADER_SIZE]);
+    drive.set_motor(false);
+    assert_eq!(drive.read(), 0xff);
+}
+
+#[test]
+fn microdrive_writes_data_block_after_preamble() {
+    let drive = Microdrive::default();
+    drive.insert(test_cartridge(3));
+    drive.set_motor(true);
+    read_block(&drive, HEADER_SIZE);
+    drive.start_write();
+    [0x00; 10].into_iter().chain([0xff; 2]).chain([0x5a; DATA_SIZE]).for_each(|byte| drive.write(byte));
+    let cartridge = drive.eject().unwrap();
+    assert_eq!(cartridge.sector(0)[HEADER_SIZE..], [0x5a; DATA_SIZE]);
+    assert_eq!(cartridge.sector(1), test_cartridge(3).sector(1));
+
+    let mut protected = test_cartridge(3);
+    protected.write_protected = true;
+    drive.insert(protected.clone());
+    assert!(drive.status().write_protected);
+    read_block(&drive, HEADER_SIZE);
+    drive.start_write();
+    (0..12 + DATA_SIZE).for_each(|_| drive.write(0x5a));
+    assert_eq!(drive.cartridge().unwrap(), protected);
+}
+
+#[test]
+fn rs232_sends_and_receives_through_loopback() {
+    let (system, if1) = TestSystem::with_device(&[
+        0x1e, 0x5a,         // LD E,5Ah
+        0x0e, 0xf7,         // LD C,F7h
+        0x3e, 0x01,         // LD A,1
+        0xed, 0x79,         // OUT (C),A            start bit
+        0xcd, 0x3f, 0x80,   // CALL DELAY
+        0x16, 0x08,         // LD D,8
+        0xcb, 0x1b,         // RR E                 send bit
+        0x9f,               // SBC A,A
+        0x3c,               // INC A
+        0xed, 0x79,         // OUT (C),A
+        0xcd, 0x3f, 0x80,   // CALL DELAY
+        0x15,               // DEC D
+        0x20, 0xf4,         // JR NZ,-12
+        0xaf,               // XOR A
+        0xed, 0x79,         // OUT (C),A            stop bit
+        0xcd, 0x3f, 0x80,   // CALL DELAY
+        0x3e, 0x0c,         // LD A,0Ch
+        0xd3, 0xef,         // OUT (EFh),A          CTS on
+        0xdb, 0xf7,         // IN A,(F7h)           wait for start bit
+        0x17,               // RLA
+        0x30, 0xfb,         // JR NC,-5
+        0x3e, 0x1c,         // LD A,1Ch
+        0xd3, 0xef,         // OUT (EFh),A          CTS off
+        0xcd, 0x44, 0x80,   // CALL HALF_DELAY
+        0x16, 0x08,         // LD D,8
+        0xcd, 0x3f, 0x80,   // CALL DELAY           receive bit
+        0xdb, 0xf7,         // IN A,(F7h)
+        0x17,               // RLA
+        0x3f,               // CCF
+        0xcb, 0x1b,         // RR E
+        0x15,               // DEC D
+        0x20, 0xf4,         // JR NZ,-12
+        0x7b,               // LD A,E
+        0x76,               // HALT
+        0x06, 0x47,         // DELAY: LD B,71
+        0x10, 0xfe,         // DJNZ $
+        0xc9,               // RET
+        0x06, 0x24,         // HALF_DELAY: LD B,36
+        0x10, 0xfe,         // DJNZ $
+        0xc9,               // RET
+    ], |devices, memory| devices.create_interface1(memory, &interface_rom(0x2000, &[])));
+    let loopback = Rc::new(SerialLoopback::default());
+    if1.connect(&(Rc::clone(&loopback) as Rc<dyn SerialPort>));
+    // Bit takes 1000 t-states
+    if1.set_baud(3500);
+    let mut scheduler = system.scheduler_with(vec![if1.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 0x3e, 30000));
+    assert_eq!(system.cpu.rg(Reg::A).get(), 0x5a);
+    assert_eq!(loopback.receive(), None);
+}
+
+#[test]
+fn host_serial_exchanges_bytes_with_files() {
+    let dir = std::env::temp_dir().join(format!("respectrum_serial_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("input"), b"AB").unwrap();
+    let serial = HostSerial::open(dir.join("input"), dir.join("output")).unwrap();
+    serial.send(b'x');
+    let mut received = vec![];
+    for _ in 0..100 {
+        received.extend(serial.receive());
+        if received.len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(received, b"AB");
+    assert_eq!(fs::read(dir.join("output")).unwrap(), b"x");
+    fs::remove_dir_all(&dir).unwrap();
+}
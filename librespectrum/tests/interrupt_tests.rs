@@ -0,0 +1,212 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use librespectrum::cpu::{Flags, tokens::{Reg, RegPair}};
+
+#[test]
+fn interrupt_is_accepted_only_after_instruction_following_ei() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0x00,       // NOP
+        0x00,       // NOP
+    ]);
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 100));
+    assert_eq!(system.pushed_pc(), BASE + 4);
+    assert!(!system.cpu.iff1.get());
+}
+
+#[test]
+fn chain_of_ei_blocks_interrupt_until_chain_ends() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0xfb,       // EI
+        0xfb,       // EI
+        0x00,       // NOP
+        0x00,       // NOP
+    ]);
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 100));
+    assert_eq!(system.pushed_pc(), BASE + 6);
+}
+
+#[test]
+fn chain_of_index_prefixes_is_not_interrupted() {
+    let mut program = vec![
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0x00,       // NOP
+    ];
+    program.extend([0xdd; 20]); // DD prefix chain (80 t-cycles)
+    program.extend([0x21, 0x34, 0x12]); // LD IX,1234h
+    program.push(0x00); // NOP
+    let system = TestSystem::new(&program);
+    system.generator.int_window.set((40, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 200));
+    assert_eq!(system.pushed_pc(), BASE + 4 + 20 + 3);
+    assert_eq!(system.cpu.rp(RegPair::IX).get(), 0x1234);
+}
+
+#[test]
+fn int_is_level_triggered_and_missed_while_disabled() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xf3,       // DI
+        0x06, 0x14, // LD B,14h
+        0x10, 0xfe, // DJNZ $
+        0xfb,       // EI
+        0x00,       // NOP
+        0x18, 0xfe, // JR $
+    ]);
+    // Spectrum-like 32 t-cycles long INT pulse while interrupts are disabled
+    system.generator.int_window.set((50, 82));
+    assert!(!system.run_until(&mut system.scheduler(), 0x0038, 1000));
+    assert!(system.cpu.iff1.get());
+}
+
+#[test]
+fn interrupt_right_after_ld_a_i_resets_parity_flag() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0xed, 0x57, // LD A,I
+        0x00,       // NOP
+    ]);
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 100));
+    assert_eq!(system.pushed_pc(), BASE + 5);
+    assert!(!system.cpu.get_flags().contains(Flags::P));
+}
+
+#[test]
+fn nmi_right_after_ld_a_i_keeps_parity_flag() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0xed, 0x57, // LD A,I
+        0x00,       // NOP
+    ]);
+    // NMI pulse during LD A,I, which leaves IFF2 set when accepted
+    system.generator.nmi_window.set((14, 18));
+    assert!(system.run_until(&mut system.scheduler(), 0x0066, 100));
+    assert_eq!(system.pushed_pc(), BASE + 5);
+    assert!(system.cpu.get_flags().contains(Flags::P));
+    assert!(system.cpu.iff2.get());
+}
+
+#[test]
+fn ld_a_r_copies_iff2_to_parity_flag_when_not_interrupted() {
+    let system = TestSystem::new(&[
+        0xed, 0x56, // IM 1
+        0xfb,       // EI
+        0xed, 0x5f, // LD A,R
+        0x00,       // NOP
+    ]);
+    assert!(system.run_until(&mut system.scheduler(), BASE + 5, 100));
+    assert!(system.cpu.get_flags().contains(Flags::P));
+}
+
+#[test]
+fn im0_executes_single_byte_instruction_supplied_by_device() {
+    let system = TestSystem::new(&[
+        0xed, 0x46, // IM 0
+        0xfb,       // EI
+        0x00,       // NOP
+        0x00,       // NOP
+    ]);
+    system.generator.response.replace(vec![0xff]); // RST 38h
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x0038, 100));
+    assert_eq!(system.pushed_pc(), BASE + 4);
+    assert_eq!(system.generator.acknowledges.get(), 1);
+}
+
+#[test]
+fn im0_reads_multi_byte_instruction_with_further_acknowledge_cycles() {
+    let system = TestSystem::new(&[
+        0xed, 0x46, // IM 0
+        0xfb,       // EI
+        0x00,       // NOP
+        0x00,       // NOP
+    ]);
+    system.memory.load(0x9000, &vec![0x76]); // HALT
+    system.generator.response.replace(vec![0xcd, 0x00, 0x90]); // CALL 9000h
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0x9000, 100));
+    assert_eq!(system.pushed_pc(), BASE + 4);
+    assert_eq!(system.generator.acknowledges.get(), 3);
+}
+
+#[test]
+fn im0_supports_prefixed_instructions() {
+    let system = TestSystem::new(&[
+        0xed, 0x46, // IM 0
+        0xfb,       // EI
+        0x00,       // NOP
+        0x18, 0xfe, // JR $
+    ]);
+    system.generator.response.replace(vec![0xdd, 0x21, 0x34, 0x12]); // LD IX,1234h
+    system.generator.int_window.set((0, 40));
+    system.scheduler().run(100 << 1);
+    assert_eq!(system.cpu.rp(RegPair::IX).get(), 0x1234);
+    assert_eq!(system.cpu.pc.value().get(), BASE + 4);
+    assert_eq!(system.generator.acknowledges.get(), 4);
+}
+
+/// Interrupt timing program in the style of test suites which count M1 cycles with R.
+/// Sets up IM2 with handler at A000h, clears R and runs given code after EI.
+/// Handler stores R read on entry in register A and halts.
+fn timing_program(code: &[u8]) -> TestSystem {
+    let mut program = vec![
+        0x3e, 0x90, // LD A,90h
+        0xed, 0x47, // LD I,A
+        0xed, 0x5e, // IM 2
+        0xaf,       // XOR A
+        0xed, 0x4f, // LD R,A
+        0xfb,       // EI
+    ];
+    program.extend(code);
+    let system = TestSystem::new(&program);
+    system.generator.response.replace(vec![0x10]);
+    system.memory.load(0x9010, &vec![0x00, 0xa0]);
+    system.memory.load(0xa000, &vec![
+        0xed, 0x5f, // LD A,R
+        0x76,       // HALT
+    ]);
+    system
+}
+
+#[test]
+fn timing_program_counts_m1_cycles_of_ei_and_prefix_chain() {
+    let system = timing_program(&[
+        0xdd, 0xdd, 0xdd,   // DD prefix chain
+        0xdd, 0x21, 0x34, 0x12, // LD IX,1234h
+        0x00,               // NOP
+    ]);
+    system.generator.int_window.set((0, u64::MAX));
+    assert!(system.run_until(&mut system.scheduler(), 0xa002, 200));
+    assert_eq!(system.pushed_pc(), BASE + 17);
+    // EI, 4 prefixes, LD IX opcode, acknowledge cycle and LD A,R
+    assert_eq!(system.cpu.rg(Reg::A).get(), 1 + 4 + 1 + 1 + 2);
+}
+
+#[test]
+fn timing_program_counts_halt_m1_cycles_until_interrupt() {
+    // INT arrives 4 t-cycles (one HALT M1 cycle) later with each run
+    let counts: Vec<u8> = (0..8).map(|step| {
+        let system = timing_program(&[
+            0x76,   // HALT
+        ]);
+        system.generator.int_window.set((100 + 4 * step, u64::MAX));
+        assert!(system.run_until(&mut system.scheduler(), 0xa002, 300));
+        assert_eq!(system.pushed_pc(), BASE + 11);
+        system.cpu.rg(Reg::A).get()
+    }).collect();
+    assert!(counts.windows(2).all(|pair| pair[1] == pair[0] + 1), "counts are {:?}", counts);
+}
@@ -0,0 +1,30 @@
+extern crate librespectrum;
+
+use librespectrum::formats::{FormatError, mdr::{Mdr, SECTOR_SIZE, SECTORS, checksum}};
+
+#[test]
+fn reads_and_writes_cartridge() {
+    let mut mdr = Mdr::default();
+    assert_eq!(mdr.sectors(), SECTORS);
+    mdr.sector_mut(1)[0] = 0x01;
+    mdr.write_protected = true;
+    let data = mdr.write();
+    assert_eq!(data.len(), SECTORS * SECTOR_SIZE + 1);
+    assert_eq!(data[SECTOR_SIZE], 0x01);
+    assert_eq!(Mdr::read(&data).unwrap(), mdr);
+}
+
+#[test]
+fn write_protection_flag_is_optional() {
+    let mdr = Mdr::read(&vec![0; 10 * SECTOR_SIZE]).unwrap();
+    assert_eq!((mdr.sectors(), mdr.write_protected), (10, false));
+    assert!(matches!(Mdr::read(&[0; 100]), Err(FormatError::InvalidSize(100))));
+    assert!(matches!(Mdr::read(&vec![0; SECTOR_SIZE + 2]), Err(FormatError::InvalidSize(545))));
+}
+
+#[test]
+fn computes_interface_1_checksum() {
+    assert_eq!(checksum(&[0x01, 0x02, 0x03]), 0x06);
+    assert_eq!(checksum(&[0xff, 0x01]), 0x01);
+    assert_eq!(checksum(&[0xfe, 0x01]), 0x00);
+}
@@ -0,0 +1,109 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem, interface_rom};
+
+use librespectrum::{
+    cpu::tokens::{Reg, RegPair},
+    devs::{Device, MultifaceModel},
+};
+
+#[test]
+fn red_button_pages_memory_in_on_nmi() {
+    let mut rom = vec![0; 0x0066];
+    rom.extend([
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x47,               // LD B,A
+        0x3e, 0x55,         // LD A,55h
+        0x32, 0x00, 0x20,   // LD (2000h),A
+        0xc3, 0x10, 0x80,   // JP 8010h
+    ]);
+    let mut program = vec![0x18, 0xfe];   // JR $
+    program.resize(0x10, 0);
+    program.extend([
+        0xdb, 0x1f,         // IN A,(1Fh)
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x4f,               // LD C,A
+        0x76,               // HALT
+    ]);
+    let (system, multiface) = TestSystem::with_device(&program, |devices, memory| devices.create_multiface(memory, MultifaceModel::One, &interface_rom(0x2000, &rom)));
+    let mut scheduler = system.scheduler_with(vec![multiface.run()]);
+    assert!(!system.run_until(&mut scheduler, BASE + 0x16, 200));
+    multiface.press();
+    assert!(system.run_until(&mut scheduler, BASE + 0x16, 1000));
+    assert_eq!(system.cpu.rg(Reg::B).get(), 0xaa);
+    assert_eq!(system.cpu.rg(Reg::C).get(), 0x00);
+    assert_eq!(multiface.ram()[0], 0x55);
+    assert!(!multiface.is_paged());
+    // NMI was accepted once
+    assert_eq!(system.cpu.rp(RegPair::SP).get(), 0xfffc);
+    assert_eq!(system.pushed_pc(), BASE);
+}
+
+#[test]
+fn plain_fetch_of_nmi_handler_is_ignored() {
+    let (system, multiface) = TestSystem::with_device(&[
+        0xcd, 0x66, 0x00,   // CALL 0066h
+    ], |devices, memory| devices.create_multiface(memory, MultifaceModel::One, &interface_rom(0x2000, &[])));
+    let mut scheduler = system.scheduler_with(vec![multiface.run()]);
+    scheduler.run(400);
+    assert!(!multiface.is_paged());
+    assert!(system.cpu.halted.get());
+}
+
+#[test]
+fn multiface_128_returns_screen_bit() {
+    let (system, multiface) = TestSystem::with_device(&[
+        0x01, 0xfd, 0x7f,   // LD BC,7FFDh
+        0xaf,               // XOR A
+        0xed, 0x79,         // OUT (C),A
+        0xdb, 0xbf,         // IN A,(BFh)
+        0x57,               // LD D,A
+        0x3a, 0x00, 0x00,   // LD A,(0000h)
+        0x67,               // LD H,A
+        0x3e, 0x08,         // LD A,08h
+        0xed, 0x79,         // OUT (C),A
+        0xdb, 0xbf,         // IN A,(BFh)
+        0x5f,               // LD E,A
+        0xdb, 0x3f,         // IN A,(3Fh)
+        0x76,               // HALT
+    ], |devices, memory| devices.create_multiface(memory, MultifaceModel::M128, &interface_rom(0x2000, &[])));
+    let mut scheduler = system.scheduler_with(vec![multiface.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 22, 1000));
+    assert_eq!(system.cpu.rg(Reg::D).get(), 0x7f);
+    assert_eq!(system.cpu.rg(Reg::E).get(), 0xff);
+    assert_eq!(system.cpu.rg(Reg::H).get(), 0xaa);
+    assert!(!multiface.is_paged());
+}
+
+#[test]
+fn multiface_3_returns_paging_registers() {
+    let (system, multiface) = TestSystem::with_device(&[
+        0x01, 0xfd, 0x1f,   // LD BC,1FFDh
+        0x3e, 0x04,         // LD A,04h
+        0xed, 0x79,         // OUT (C),A
+        0x06, 0x7f,         // LD B,7Fh
+        0x3e, 0x10,         // LD A,10h
+        0xed, 0x79,         // OUT (C),A
+        0x3e, 0x7f,         // LD A,7Fh
+        0xdb, 0x3f,         // IN A,(3Fh)
+        0x57,               // LD D,A
+        0x3e, 0x7f,         // LD A,7Fh
+        0xdb, 0x3f,         // IN A,(3Fh)
+        0x5f,               // LD E,A
+        0x3e, 0x1f,         // LD A,1Fh
+        0xdb, 0x3f,         // IN A,(3Fh)
+        0x67,               // LD H,A
+        0xdb, 0xbf,         // IN A,(BFh)
+        0x76,               // HALT
+    ], |devices, memory| devices.create_multiface(memory, MultifaceModel::Three, &interface_rom(0x2000, &[])));
+    let mut scheduler = system.scheduler_with(vec![multiface.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 30, 1000));
+    // Registers aren't readable by the access paging memory in
+    assert_eq!(system.cpu.rg(Reg::D).get(), 0xff);
+    assert_eq!(system.cpu.rg(Reg::E).get(), 0x10);
+    assert_eq!(system.cpu.rg(Reg::H).get(), 0x04);
+    assert!(!multiface.is_paged());
+}
@@ -0,0 +1,316 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use std::rc::Rc;
+
+use librespectrum::{
+    core::Clock,
+    devs::{Device, Disk, DiskSector, Upd765, mem::Memory},
+    formats::dsk::Dsk,
+};
+
+/// Routines called by test programs
+const SEND: u16 = BASE + 0x100;
+const RESULT: u16 = BASE + 0x120;
+const READ: u16 = BASE + 0x140;
+const WRITE: u16 = BASE + 0x160;
+
+/// Buffers for sector data and result bytes
+const DATA: u16 = 0xc000;
+const RESULTS: u16 = 0xd000;
+
+/// +3 format: 40 cylinders of nine 512-byte sectors, filled with cylinder and sector numbers
+fn test_disk() -> Disk {
+    let mut disk = Disk::new(40, 1);
+    for (cylinder, track) in disk.tracks.iter_mut().enumerate() {
+        *track = (1..=9).map(|sector| {
+            let data = (0..512).map(|offset| (cylinder * 16 + sector as usize + offset) as u8).collect();
+            DiskSector::new(cylinder as u8, 0, sector, data)
+        }).collect();
+    }
+    disk
+}
+
+/// LD A,byte; CALL SEND for each byte
+fn send(bytes: &[u8]) -> Vec<u8> {
+    let [lo, hi] = SEND.to_le_bytes();
+    bytes.iter().flat_map(|&byte| [0x3e, byte, 0xcd, lo, hi]).collect()
+}
+
+/// LD HL,addr; CALL routine
+fn call(addr: u16, routine: u16) -> Vec<u8> {
+    let ([lo, hi], [rlo, rhi]) = (addr.to_le_bytes(), routine.to_le_bytes());
+    vec![0x21, lo, hi, 0xcd, rlo, rhi]
+}
+
+/// Program switching the motor on and seeking given cylinder, followed by given code and HALT.
+/// Address of the HALT is returned with the program.
+fn program(cylinder: u8, code: &[u8]) -> (Vec<u8>, u16) {
+    let sense = [send(&[0x08]), call(RESULTS, RESULT), vec![
+        0x3a, 0x00, 0xd0,           // LD A,(D000h)
+        0xe6, 0x20,                 // AND 20h
+    ]].concat();
+    let mut program = [
+        vec![0x3e, 0x08, 0x01, 0xfd, 0x1f, 0xed, 0x79], // LD A,08h; LD BC,1FFDh; OUT (C),A
+        send(&[0x0f, 0x00, cylinder]),
+        sense.clone(),
+        vec![0x28, (-(sense.len() as i8) - 2) as u8], // JR Z,sense
+        code.to_vec(),
+    ].concat();
+    let halt = BASE + program.len() as u16;
+    program.push(0x76);             // HALT
+    program.resize(0x100, 0);
+    program.extend([
+        // SEND: wait for RQM and write A to data register
+        0xf5,                       // PUSH AF
+        0x01, 0xfd, 0x2f,           // LD BC,2FFDh
+        0xed, 0x78,                 // IN A,(C)
+        0x87,                       // ADD A,A
+        0x30, 0xfb,                 // JR NC,-5
+        0xf1,                       // POP AF
+        0x06, 0x3f,                 // LD B,3Fh
+        0xed, 0x79,                 // OUT (C),A
+        0xc9,                       // RET
+    ]);
+    program.resize(0x120, 0);
+    program.extend([
+        // RESULT: read result bytes to (HL) until controller expects a command
+        0x01, 0xfd, 0x2f,           // LD BC,2FFDh
+        0xed, 0x78,                 // IN A,(C)
+        0x87,                       // ADD A,A
+        0x30, 0xfb,                 // JR NC,-5
+        0xf0,                       // RET P
+        0x06, 0x3f,                 // LD B,3Fh
+        0xed, 0x78,                 // IN A,(C)
+        0x77,                       // LD (HL),A
+        0x23,                       // INC HL
+        0x06, 0x2f,                 // LD B,2Fh
+        0x18, 0xf0,                 // JR -16
+    ]);
+    program.resize(0x140, 0);
+    program.extend([
+        // READ: read data bytes to (HL) until execution phase ends
+        0x01, 0xfd, 0x2f,           // LD BC,2FFDh
+        0xed, 0x78,                 // IN A,(C)
+        0x87,                       // ADD A,A
+        0x30, 0xfb,                 // JR NC,-5
+        0xe6, 0x40,                 // AND 40h
+        0xc8,                       // RET Z
+        0x06, 0x3f,                 // LD B,3Fh
+        0xed, 0x78,                 // IN A,(C)
+        0x77,                       // LD (HL),A
+        0x23,                       // INC HL
+        0x06, 0x2f,                 // LD B,2Fh
+        0x18, 0xee,                 // JR -18
+    ]);
+    program.resize(0x160, 0);
+    program.extend([
+        // WRITE: write data bytes from (HL) until execution phase ends
+        0x01, 0xfd, 0x2f,           // LD BC,2FFDh
+        0xed, 0x78,                 // IN A,(C)
+        0x87,                       // ADD A,A
+        0x30, 0xfb,                 // JR NC,-5
+        0xe6, 0x40,                 // AND 40h
+        0xc8,                       // RET Z
+        0x06, 0x3f,                 // LD B,3Fh
+        0x7e,                       // LD A,(HL)
+        0xed, 0x79,                 // OUT (C),A
+        0x23,                       // INC HL
+        0x06, 0x2f,                 // LD B,2Fh
+        0x18, 0xee,                 // JR -18
+    ]);
+    (program, halt)
+}
+
+fn memory_bytes(system: &TestSystem, addr: u16, count: u16) -> Vec<u8> {
+    (0..count).map(|offset| system.memory.read(addr + offset)).collect()
+}
+
+/// Controller with the motor on and given disk in drive A
+fn controller(clock: &Rc<Clock>, disk: Disk) -> Upd765 {
+    let fdc = Upd765::new(clock, 2);
+    fdc.drive(0).insert(disk);
+    fdc.set_motor(true);
+    fdc
+}
+
+/// Run command polling the controller every 56 t-states, transferring data
+/// bytes in execution phase. Returns data read and result bytes.
+fn execute(fdc: &Upd765, clock: &Clock, command: &[u8], input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    command.iter().for_each(|&byte| fdc.write_data(byte));
+    let (mut data, mut result) = (vec![], vec![]);
+    let mut input = input.iter();
+    loop {
+        fdc.update();
+        match fdc.status() & 0xe0 {
+            0xe0 => data.push(fdc.read_data()),
+            0xa0 => fdc.write_data(input.next().copied().unwrap_or(0)),
+            0xc0 => result.push(fdc.read_data()),
+            0x80 if !fdc.is_busy() => return (data, result),
+            _ => (),
+        }
+        clock.set(clock.get() + 2 * 56);
+    }
+}
+
+#[test]
+fn reads_sector_after_seek() {
+    let (program, halt) = program(2, &[
+        send(&[0x46, 0x00, 0x02, 0x00, 0x03, 0x02, 0x04, 0x2a, 0xff]),
+        call(DATA, READ),
+        call(RESULTS, RESULT),
+    ].concat());
+    let system = TestSystem::new(&program);
+    let disk = system.device_manager.create_plus3_disk();
+    disk.drive(0).insert(test_disk());
+    let mut scheduler = system.scheduler_with(vec![disk.run()]);
+    assert!(system.run_until(&mut scheduler, halt, 2_000_000));
+    assert_eq!(disk.drive(0).cylinder(), 2);
+    // Sectors 3 and 4 are read, terminal count isn't connected so the command ends with end of cylinder
+    let track = &test_disk().tracks[2];
+    assert_eq!(memory_bytes(&system, DATA, 1024), [track[2].data.clone(), track[3].data.clone()].concat());
+    assert_eq!(system.memory.read(DATA + 1024), 0);
+    assert_eq!(memory_bytes(&system, RESULTS, 7), [0x40, 0x80, 0x00, 0x03, 0x00, 0x01, 0x02]);
+}
+
+#[test]
+fn writes_sector_persisted_to_image() {
+    let (program, halt) = program(1, &[
+        send(&[0x45, 0x00, 0x01, 0x00, 0x09, 0x02, 0x09, 0x2a, 0xff]),
+        call(DATA, WRITE),
+        call(RESULTS, RESULT),
+    ].concat());
+    let system = TestSystem::new(&program);
+    let disk = system.device_manager.create_plus3_disk();
+    disk.drive(0).insert(Dsk::read(&Dsk { extended: false, disk: test_disk() }.write().unwrap()).unwrap().disk);
+    let payload: Vec<u8> = (0..512).map(|index| (index * 7) as u8).collect();
+    system.memory.load(DATA, &payload);
+    let mut scheduler = system.scheduler_with(vec![disk.run()]);
+    assert!(system.run_until(&mut scheduler, halt, 2_000_000));
+    assert_eq!(memory_bytes(&system, RESULTS, 7), [0x40, 0x80, 0x00, 0x02, 0x00, 0x01, 0x02]);
+    assert!(disk.drive(0).is_modified());
+
+    let image = Dsk { extended: false, disk: disk.drive(0).disk().unwrap() }.write().unwrap();
+    let written = Dsk::read(&image).unwrap();
+    assert!(!written.extended);
+    assert_eq!(written.disk.track(1, 0).unwrap()[8].data, payload);
+    assert_eq!(written.disk.track(1, 0).unwrap()[7].data, test_disk().tracks[1][7].data);
+}
+
+#[test]
+fn weak_sector_returns_copies_in_turn() {
+    let clock = Rc::new(Clock::default());
+    let mut disk = test_disk();
+    let sector = &mut disk.tracks[0][0];
+    sector.st1 = 0x20;
+    sector.st2 = 0x20;
+    sector.copies = vec![vec![0x11; 512], vec![0x22; 512]];
+    let fdc = controller(&clock, disk);
+    let read = [0x46, 0x00, 0x00, 0x00, 0x01, 0x02, 0x09, 0x2a, 0xff];
+    let copies: Vec<_> = (0..4).map(|_| execute(&fdc, &clock, &read, &[])).collect();
+    assert_eq!(copies[0].0, test_disk().tracks[0][0].data);
+    assert_eq!(copies[1].0, vec![0x11; 512]);
+    assert_eq!(copies[2].0, vec![0x22; 512]);
+    assert_eq!(copies[3].0, copies[0].0);
+    // Data CRC error recorded in the image ends the command after the sector
+    assert_eq!(copies[0].1, [0x40, 0x20, 0x20, 0x00, 0x00, 0x01, 0x02]);
+}
+
+#[test]
+fn reports_missing_sector_overrun_and_deleted_data() {
+    let clock = Rc::new(Clock::default());
+    let mut disk = test_disk();
+    disk.tracks[0][4].deleted = true;
+    let fdc = controller(&clock, disk);
+
+    let (data, result) = execute(&fdc, &clock, &[0x46, 0x00, 0x00, 0x00, 0x0a, 0x02, 0x0a, 0x2a, 0xff], &[]);
+    assert!(data.is_empty());
+    assert_eq!(result, [0x40, 0x04, 0x00, 0x00, 0x00, 0x0a, 0x02]);
+    // Two revolutions are searched
+    assert!(clock.get() / 2 >= 2 * 700_000);
+
+    // Deleted sector is read ending the command, or skipped
+    let (data, result) = execute(&fdc, &clock, &[0x46, 0x00, 0x00, 0x00, 0x05, 0x02, 0x06, 0x2a, 0xff], &[]);
+    assert_eq!(data.len(), 512);
+    assert_eq!(result, [0x00, 0x00, 0x40, 0x00, 0x00, 0x05, 0x02]);
+    let (data, result) = execute(&fdc, &clock, &[0x66, 0x00, 0x00, 0x00, 0x05, 0x02, 0x06, 0x2a, 0xff], &[]);
+    assert_eq!(data, test_disk().tracks[0][5].data);
+    assert_eq!(result, [0x40, 0x80, 0x40, 0x01, 0x00, 0x01, 0x02]);
+    let (data, _) = execute(&fdc, &clock, &[0x4c, 0x00, 0x00, 0x00, 0x05, 0x02, 0x05, 0x2a, 0xff], &[]);
+    assert_eq!(data, test_disk().tracks[0][4].data);
+
+    // Data bytes not taken by CPU are overrun
+    [0x46, 0x00, 0x00, 0x00, 0x01, 0x02, 0x01, 0x2a, 0xff].iter().for_each(|&byte| fdc.write_data(byte));
+    while fdc.status() & 0xe0 != 0xc0 {
+        clock.set(clock.get() + 2 * 1000);
+        fdc.update();
+    }
+    let result: Vec<u8> = (0..7).map(|_| fdc.read_data()).collect();
+    assert_eq!(result[..2], [0x40, 0x10]);
+    assert!(!fdc.is_busy());
+}
+
+#[test]
+fn senses_drive_status_and_seek_end() {
+    let clock = Rc::new(Clock::default());
+    let fdc = controller(&clock, test_disk());
+    fdc.drive(0).insert(test_disk());
+    assert_eq!(execute(&fdc, &clock, &[0x04, 0x00], &[]).1, [0x30]);
+    assert_eq!(execute(&fdc, &clock, &[0x04, 0x01], &[]).1, [0x11]);
+    // No interrupt is pending
+    assert_eq!(execute(&fdc, &clock, &[0x08], &[]).1, [0x80]);
+
+    // Seek to cylinder 5 at 6 ms step rate
+    execute(&fdc, &clock, &[0x03, 0xdf, 0x03], &[]);
+    let start = clock.get() / 2;
+    execute(&fdc, &clock, &[0x0f, 0x00, 0x05], &[]);
+    assert_eq!(fdc.status() & 0x01, 0x01);
+    while fdc.status() & 0x01 != 0 {
+        clock.set(clock.get() + 2 * 100);
+        fdc.update();
+    }
+    assert!((5 * 21_000..5 * 21_000 + 200).contains(&(clock.get() / 2 - start)));
+    assert_eq!(execute(&fdc, &clock, &[0x08], &[]).1, [0x20, 0x05]);
+    assert_eq!(fdc.drive(0).cylinder(), 5);
+
+    // Recalibrate, then motor off makes the drive not ready
+    execute(&fdc, &clock, &[0x07, 0x00], &[]);
+    clock.set(clock.get() + 2 * 200_000);
+    fdc.update();
+    assert_eq!(execute(&fdc, &clock, &[0x08], &[]).1, [0x20, 0x00]);
+    fdc.set_motor(false);
+    assert_eq!(execute(&fdc, &clock, &[0x04, 0x00], &[]).1, [0x10]);
+    assert_eq!(execute(&fdc, &clock, &[0x4a, 0x00], &[]).1[..2], [0x48, 0x00]);
+}
+
+#[test]
+fn formats_track_and_reads_ids() {
+    let clock = Rc::new(Clock::default());
+    let fdc = controller(&clock, test_disk());
+    // Four 1024-byte sectors in reverse order
+    let ids = [3, 2, 1, 0].map(|sector| [0x00, 0x00, sector + 0x41, 0x03]).concat();
+    let (_, result) = execute(&fdc, &clock, &[0x4d, 0x00, 0x03, 0x04, 0x2a, 0xe5], &ids);
+    assert_eq!(result[..3], [0x00, 0x00, 0x00]);
+    let track = fdc.drive(0).track(0);
+    assert_eq!(track.iter().map(|sector| sector.sector).collect::<Vec<_>>(), [0x44, 0x43, 0x42, 0x41]);
+    assert!(track.iter().all(|sector| sector.size_code == 3 && sector.data == vec![0xe5; 1024]));
+    assert!(fdc.drive(0).is_modified());
+
+    let (_, result) = execute(&fdc, &clock, &[0x4a, 0x00], &[]);
+    assert_eq!(result, [0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x03]);
+    let (data, result) = execute(&fdc, &clock, &[0x42, 0x00, 0x00, 0x00, 0x44, 0x03, 0x02, 0x2a, 0xff], &[]);
+    assert_eq!(data, vec![0xe5; 2048]);
+    // The second sector ID doesn't match the sector number expected
+    assert_eq!(result[..3], [0x40, 0x84, 0x00]);
+
+    // Write protected disk can't be formatted
+    let mut disk = test_disk();
+    disk.write_protected = true;
+    fdc.drive(0).insert(disk);
+    let (_, result) = execute(&fdc, &clock, &[0x4d, 0x00, 0x03, 0x04, 0x2a, 0xe5], &ids);
+    assert_eq!(result[..2], [0x40, 0x02]);
+}
@@ -0,0 +1,139 @@
+extern crate librespectrum;
+
+use std::fs;
+
+use librespectrum::{
+    devs::{TapeBlock, TapePulse, TapeSegment},
+    formats::{
+        FormatError,
+        pzx::{Pzx, PzxBlock},
+        tap::{DATA_FLAG, HEADER_FLAG, Tap, TapBlock},
+        tzx::{Tzx, TzxBlock, TzxSymbol},
+    },
+};
+
+/// PZX block with given tag and contents
+fn pzx_block(tag: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    [tag.as_slice(), &(contents.len() as u32).to_le_bytes(), contents].concat()
+}
+
+/// Signal as runs of constant level starting low, ignoring zero length runs
+fn signal(blocks: &[TapeBlock]) -> Vec<(bool, u32)> {
+    let mut level = false;
+    let mut runs: Vec<(bool, u32)> = vec![];
+    for pulse in blocks.iter().flat_map(TapeBlock::pulses) {
+        let duration = match pulse {
+            TapePulse::Edge(duration) => { level = !level; duration },
+            TapePulse::Hold(duration) => duration,
+            TapePulse::Level(new, duration) => { level = new; duration },
+            TapePulse::Stop => continue,
+        };
+        match runs.last_mut() {
+            Some((last, total)) if *last == level => *total += duration,
+            _ if duration > 0 => runs.push((level, duration)),
+            _ => (),
+        }
+    }
+    runs
+}
+
+#[test]
+fn reads_and_writes_all_block_types() {
+    let data = [
+        pzx_block(b"PZXT", b"\x01\x00Game\0Author\0Me\0"),
+        pzx_block(b"PULS", &[0x00, 0x00, 0x02, 0x80, 0x78, 0x08, 0x9b, 0x02, 0x01, 0x80, 0x01, 0x80, 0xa0, 0x86]),
+        pzx_block(b"DATA", &[0x0c, 0x00, 0x00, 0x80, 0xb1, 0x03, 0x02, 0x02, 0x57, 0x03, 0x57, 0x03, 0xae, 0x06, 0xae, 0x06, 0xa5, 0xf0]),
+        pzx_block(b"PAUS", &[0xb0, 0x35, 0x00, 0x00]),
+        pzx_block(b"BRWS", b"Level 1"),
+        pzx_block(b"STOP", &[0x01, 0x00]),
+        pzx_block(b"XTRA", &[0x01, 0x02]),
+    ].concat();
+    let pzx = Pzx::read(&data).unwrap();
+    assert_eq!(pzx.blocks, vec![
+        PzxBlock::Header { major: 1, minor: 0, info: vec![String::from("Game"), String::from("Author"), String::from("Me")] },
+        PzxBlock::Pulses(vec![0, 2168, 2168, 667, 100_000]),
+        PzxBlock::Data { initial_level: true, bits: 12, tail: 945, zero: vec![855, 855], one: vec![1710, 1710], data: vec![0xa5, 0xf0] },
+        PzxBlock::Pause { level: false, duration: 13744 },
+        PzxBlock::Browse(String::from("Level 1")),
+        PzxBlock::Stop { only_48k: true },
+        PzxBlock::Unknown { tag: *b"XTRA", data: vec![0x01, 0x02] },
+    ]);
+    assert_eq!(pzx.write(), data);
+    assert_eq!(pzx.blocks[0].describe(), "Title: Game");
+}
+
+#[test]
+fn rejects_invalid_files() {
+    assert!(matches!(Pzx::read(&pzx_block(b"PULS", &[])), Err(FormatError::Invalid(_))));
+    let truncated = [pzx_block(b"PZXT", &[1, 0]), b"PULS\x04\x00\x00\x00\x01".to_vec()].concat();
+    assert!(matches!(Pzx::read(&truncated), Err(FormatError::InvalidSize(_))));
+}
+
+#[test]
+fn converts_blocks_into_pulses() {
+    let pulses = PzxBlock::Pulses(vec![0, 100, 200]);
+    assert_eq!(pulses.segments(true), vec![TapeSegment::Raw(vec![
+        TapePulse::Level(false, 0), TapePulse::Edge(100), TapePulse::Edge(200),
+    ])]);
+    let data = PzxBlock::Data { initial_level: false, bits: 2, tail: 50, zero: vec![10], one: vec![20, 30], data: vec![0x40] };
+    assert_eq!(data.segments(true), vec![TapeSegment::Raw(vec![
+        TapePulse::Level(false, 10), TapePulse::Edge(20), TapePulse::Edge(30), TapePulse::Edge(50),
+    ])]);
+    assert_eq!(PzxBlock::Pause { level: true, duration: 7 }.segments(true), vec![TapeSegment::Raw(vec![TapePulse::Level(true, 7)])]);
+    assert_eq!(PzxBlock::Stop { only_48k: true }.segments(true), vec![TapeSegment::Stop]);
+    assert!(PzxBlock::Stop { only_48k: true }.segments(false).is_empty());
+}
+
+#[test]
+fn merges_pilot_with_standard_data_for_fast_load() {
+    let block = TapBlock::new(HEADER_FLAG, &[3; 17]);
+    let pzx = Pzx {
+        blocks: vec![
+            PzxBlock::Pulses(vec![2168, 2168, 667, 735]),
+            PzxBlock::Data {
+                initial_level: false, bits: block.data.len() as u32 * 8, tail: 945,
+                zero: vec![855, 855], one: vec![1710, 1710], data: block.data.clone(),
+            },
+            PzxBlock::Pause { level: false, duration: 3500 },
+        ],
+    };
+    let blocks = pzx.tape_blocks(true);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].data, Some(block.clone()));
+    assert_eq!(blocks[0].description, block.describe());
+    assert_eq!(blocks[0].segments.len(), 2);
+    assert_eq!(blocks[1].description, "Pause: 1 ms");
+}
+
+#[test]
+fn saves_tape_signal_losslessly() {
+    let tap = Tap::read(&fs::read("tests/exerciser/zexall.tap").unwrap()).unwrap();
+    let tzx = Tzx {
+        blocks: vec![
+            TzxBlock::Standard { pause: 500, data: TapBlock::new(DATA_FLAG, &[0x55, 0xaa]) },
+            TzxBlock::SignalLevel(true),
+            TzxBlock::PureTone { pulse: 40_000, count: 3 },
+            TzxBlock::DirectRecording { tstates_per_sample: 79, pause: 0, last_bits: 5, data: vec![0xf0, 0x0f, 0xcc] },
+            TzxBlock::GeneralizedData {
+                pause: 20,
+                pilot_symbols: vec![TzxSymbol { polarity: 1, pulses: vec![300, 0] }],
+                pilot: vec![(0, 2)],
+                data_symbols: vec![TzxSymbol { polarity: 3, pulses: vec![400, 500] }, TzxSymbol { polarity: 2, pulses: vec![600, 0] }],
+                data_count: 4,
+                data: vec![0b0110_0000],
+            },
+            TzxBlock::Pause(0),
+            TzxBlock::PulseSequence(vec![50_000, 0, 0, 40_000]),
+        ],
+        ..Default::default()
+    };
+    for blocks in [tap.tape_blocks(), tzx.tape_blocks(true)] {
+        let pzx = Pzx::read(&Pzx::from_tape(&blocks).write()).unwrap();
+        let restored = pzx.tape_blocks(true);
+        assert_eq!(signal(&restored), signal(&blocks));
+        let stops = |blocks: &[TapeBlock]| blocks.iter().flat_map(TapeBlock::pulses).filter(|&pulse| pulse == TapePulse::Stop).count();
+        assert_eq!(stops(&restored), stops(&blocks));
+        let descriptions: Vec<String> = blocks.iter().map(|block| block.description.clone()).collect();
+        assert!(descriptions.iter().all(|description| restored.iter().any(|block| block.description == *description)));
+    }
+}
@@ -0,0 +1,110 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    cpu::tokens::{IntMode, RegPair},
+    devs::mem::Memory,
+    formats::{FormatError, sna::{BANK_SIZE, Sna}},
+    machine::{Accuracy, Machine},
+};
+
+/// 48K snapshot with PC=8000h pushed onto the stack at FFFCh
+fn sna_48k() -> Vec<u8> {
+    let mut data = vec![
+        0x3f,                   // I
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // HL', DE', BC', AF'
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06,             // HL, DE, BC
+        0x07, 0x08, 0x09, 0x0a, // IY, IX
+        0x04,                   // IFF2
+        0x42,                   // R
+        0xaa, 0xbb,             // AF
+        0xfc, 0xff,             // SP
+        0x01,                   // IM 1
+        0x02,                   // border
+    ];
+    data.resize(27 + 3 * BANK_SIZE, 0);
+    data[27 + 0xbffc] = 0x00;
+    data[27 + 0xbffd] = 0x80;
+    data
+}
+
+/// 128K snapshot with given bank paged in, each bank filled with its number
+fn sna_128k(paged: u8) -> Vec<u8> {
+    let mut data = sna_48k();
+    data.truncate(27);
+    for bank in [5, 2, paged] {
+        data.extend(vec![bank; BANK_SIZE]);
+    }
+    data.extend_from_slice(&[0x34, 0x12, 0x10 | paged, 0x01]);
+    for bank in 0..8 {
+        if bank != 2 && bank != 5 && bank != paged {
+            data.extend(vec![bank; BANK_SIZE]);
+        }
+    }
+    data
+}
+
+#[test]
+fn reads_48k_snapshot_and_pops_pc() {
+    let sna = Sna::read(&sna_48k()).unwrap();
+    let regs = sna.regs;
+    assert_eq!(regs.pc, 0x8000);
+    assert_eq!(regs.sp, 0xfffe);
+    assert_eq!(regs.ir, 0x3f42);
+    assert_eq!((regs.hl, regs.de, regs.bc), (0x0201, 0x0403, 0x0605));
+    assert_eq!((regs.alt_hl, regs.alt_af), (0x2211, 0x8877));
+    assert_eq!((regs.iy, regs.ix, regs.af), (0x0807, 0x0a09, 0xbbaa));
+    assert!(regs.iff1 && regs.iff2);
+    assert_eq!(regs.im, IntMode::IM1);
+    assert_eq!(sna.border, 2);
+    assert!(sna.paging.is_none());
+}
+
+#[test]
+fn writes_48k_snapshot_back_unchanged() {
+    let data = sna_48k();
+    assert_eq!(Sna::read(&data).unwrap().write().unwrap(), data);
+}
+
+#[test]
+fn reads_and_writes_128k_snapshot() {
+    for paged in [0, 2, 5, 7] {
+        let data = sna_128k(paged);
+        let sna = Sna::read(&data).unwrap();
+        assert_eq!(sna.regs.pc, 0x1234);
+        assert_eq!(sna.regs.sp, 0xfffc);
+        let paging = sna.paging.as_ref().unwrap();
+        assert_eq!(paging.paged_bank(), paged as usize);
+        assert!(paging.trdos);
+        for (bank, contents) in paging.banks.iter().enumerate() {
+            assert!(contents.len() == BANK_SIZE && contents.iter().all(|&byte| byte == bank as u8), "bank {}", bank);
+        }
+        assert_eq!(sna.write().unwrap(), data);
+    }
+}
+
+#[test]
+fn rejects_invalid_snapshots() {
+    assert!(matches!(Sna::read(&[0; 100]), Err(FormatError::InvalidSize(100))));
+    let mut data = sna_48k();
+    data[23..25].copy_from_slice(&[0x00, 0x10]);
+    assert!(matches!(Sna::read(&data), Err(FormatError::Invalid(_))));
+}
+
+#[test]
+fn applies_and_captures_machine_state() {
+    let machine = Machine::new(Accuracy::BusCycles);
+    machine.memory().load(0x8000, &vec![0x76]); // HALT
+    Sna::read(&sna_48k()).unwrap().apply(machine.cpu(), &**machine.memory());
+    assert_eq!(machine.cpu().rp(RegPair::PC).get(), 0x8000);
+    assert_eq!(machine.memory().read(0x8000), 0x00);
+
+    machine.memory().write(0x8000, 0x76);
+    machine.step();
+    assert!(machine.cpu().halted.get());
+    assert_eq!(machine.cpu().rp(RegPair::PC).get(), 0x8001);
+
+    let sna = Sna::capture(machine.cpu(), &**machine.memory(), 2);
+    let restored = Sna::read(&sna.write().unwrap()).unwrap();
+    assert_eq!((restored.regs.pc, restored.regs.sp), (0x8001, 0xfffe));
+    assert_eq!(restored.ram[0x4000], 0x76);
+}
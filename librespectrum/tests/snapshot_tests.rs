@@ -0,0 +1,126 @@
+extern crate librespectrum;
+
+use std::rc::Rc;
+
+use librespectrum::{
+    cpu::{CpuModel, tokens::RegPair},
+    devs::{Snapshot, TapeBlock, TapeDeck, TapePulse, TapeSegment},
+    formats::tap::TapBlock,
+    machine::{Accuracy, Machine},
+};
+
+/// Create machine running endless loop which increments bytes starting from 9000h
+fn looping_machine(accuracy: Accuracy) -> Machine {
+    let machine = Machine::new(accuracy);
+    machine.memory().load(0x8000, &vec![
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x34,               // INC (HL)
+        0x23,               // INC HL
+        0x18, 0xfc,         // JR -4
+    ]);
+    machine.cpu().rp(RegPair::PC).set(0x8000);
+    machine
+}
+
+#[test]
+fn restored_machine_replays_the_same_execution() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    machine.run(1000);
+    let snapshot = machine.snapshot();
+    machine.run(5000);
+    let expected = machine.snapshot();
+    assert_ne!(snapshot, expected);
+
+    machine.restore(&snapshot).unwrap();
+    assert_eq!(machine.snapshot(), snapshot);
+    machine.run(5000);
+    assert_eq!(machine.snapshot(), expected);
+}
+
+#[test]
+fn snapshot_is_taken_on_instruction_boundary() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    machine.run(1001);
+    let snapshot = machine.snapshot();
+    let pc = snapshot.cpu.pc;
+    assert!([0x8003, 0x8004, 0x8005].contains(&pc), "PC {:04X}h", pc);
+    assert_eq!(snapshot.memory[0x8000], 0x21);
+}
+
+#[test]
+fn snapshot_can_be_restored_on_another_machine_and_core() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    machine.cpu().set_model(CpuModel::Z80N);
+    machine.run(2000);
+    let snapshot = machine.snapshot();
+
+    let other = Machine::new(Accuracy::Instructions);
+    other.restore(&snapshot).unwrap();
+    assert_eq!(other.snapshot(), snapshot);
+    assert_eq!(other.cpu().model(), CpuModel::Z80N);
+
+    for _ in 0..100 {
+        machine.step();
+        other.step();
+    }
+    let (bus, fast) = (machine.snapshot(), other.snapshot());
+    assert_eq!(bus.cpu, fast.cpu);
+    assert_eq!(bus.memory, fast.memory);
+}
+
+#[test]
+fn snapshot_captures_tape_deck() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    let blocks = vec![
+        TapeBlock::from(TapBlock { data: vec![0x00, 0x01, 0x02, 0x03] }),
+        TapeBlock::info(String::from("Info")),
+        TapeBlock {
+            description: String::from("Turbo"),
+            data: None,
+            segments: vec![
+                TapeSegment::Tone { pulse: 1000, count: 10 },
+                TapeSegment::Pulses(vec![300, 400]),
+                TapeSegment::Data { zero: 500, one: 1000, data: vec![0xa5, 0xf0], last_bits: 4 },
+                TapeSegment::Raw(vec![TapePulse::Edge(100), TapePulse::Hold(200), TapePulse::Level(true, 300), TapePulse::Stop]),
+                TapeSegment::Pause(3500),
+                TapeSegment::Stop,
+            ],
+        },
+    ];
+    machine.tape().insert(blocks.clone());
+    machine.tape().seek(1);
+    let snapshot = machine.snapshot();
+
+    machine.tape().eject();
+    machine.restore(&snapshot).unwrap();
+    assert_eq!(machine.tape().blocks(), blocks);
+    assert_eq!(machine.tape().position(), 1);
+}
+
+#[test]
+fn restore_requires_state_of_every_snapshot_device() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    let snapshot = machine.snapshot();
+    let deck: Rc<dyn Snapshot> = Rc::new(TapeDeck::default());
+    machine.add_snapshot_device("Second Tape Deck", &deck);
+    assert!(machine.restore(&snapshot).is_err());
+    assert!(machine.restore(&machine.snapshot()).is_ok());
+}
+
+#[test]
+fn snapshot_captures_peripheral_registers() {
+    let machine = looping_machine(Accuracy::BusCycles);
+    let ctc = machine.device_manager().create_ctc(0x10);
+    machine.add_snapshot_device("CTC", &(Rc::clone(&ctc) as Rc<dyn Snapshot>));
+    ctc.write(0, 0x40);     // vector
+    ctc.write(1, 0x45);     // counter mode, time constant follows
+    ctc.write(1, 0x20);
+    ctc.trigger(1);
+    let snapshot = machine.snapshot();
+
+    ctc.write(1, 0x03);     // reset
+    ctc.write(1, 0x10);
+    machine.restore(&snapshot).unwrap();
+    assert_eq!(ctc.read(1), 0x1f);
+    assert_eq!(ctc.save_snapshot(), snapshot.devices.iter().find(|device| device.name == "CTC").unwrap().state);
+}
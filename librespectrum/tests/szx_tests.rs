@@ -0,0 +1,128 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    cpu::tokens::{IntMode, RegPair},
+    devs::{CpuRegisters, mem::Memory},
+    formats::{
+        FormatError, sna::BANK_SIZE, szx::{KEYB, RAMP, SPCR, Szx, SzxBlock, SzxKeyboard, SzxZ80Regs, Z80R},
+        z80::{AyState, Hardware},
+    },
+    machine::{Accuracy, Machine},
+};
+
+/// Build snapshot file from header fields and raw blocks
+fn szx_file(machine_id: u8, blocks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut data = b"ZXST".to_vec();
+    data.extend_from_slice(&[1, 4, machine_id, 0]);
+    for (id, contents) in blocks {
+        data.extend_from_slice(*id);
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(contents);
+    }
+    data
+}
+
+/// Z80R block contents
+fn z80r() -> Vec<u8> {
+    let mut data: Vec<u8> = (1..=24).collect(); // AF..PC
+    data.extend_from_slice(&[0x3f, 0x80, 1, 0, 2]); // I, R, IFF1, IFF2, IM
+    data.extend_from_slice(&12345u32.to_le_bytes());
+    data.extend_from_slice(&[32, 0x06, 0x34, 0x12]); // hold cycles, flags (halted + FSET), MEMPTR
+    data
+}
+
+#[test]
+fn reads_registers_and_machine_state() {
+    let data = szx_file(2, &[
+        (&Z80R, z80r()),
+        (&SPCR, vec![3, 0x17, 0, 0x13, 0, 0, 0, 0]),
+        (b"AY\0\0", [vec![0, 7], (0..16).collect()].concat()),
+        (&KEYB, vec![1, 0, 0, 0, 2]),
+    ]);
+    let szx = Szx::read(&data).unwrap();
+    assert_eq!((szx.major, szx.minor), (1, 4));
+    assert_eq!(szx.hardware(), Some(Hardware::Spectrum128k));
+    assert!(szx.is_128k());
+
+    let z80 = szx.z80_regs().unwrap().unwrap();
+    assert_eq!((z80.regs.af, z80.regs.pc), (0x0201, 0x1817));
+    assert_eq!(z80.regs.ir, 0x3f80);
+    assert!(z80.regs.iff1 && !z80.regs.iff2 && z80.regs.halted && !z80.regs.after_ei);
+    assert_eq!(z80.regs.im, IntMode::IM2);
+    assert_eq!((z80.tstates, z80.hold_int_cycles, z80.flags, z80.memptr), (12345, 32, 0x04, 0x1234));
+
+    let spectrum = szx.spectrum_regs().unwrap().unwrap();
+    assert_eq!((spectrum.border, spectrum.port_7ffd, spectrum.port_fe), (3, 0x17, 0x13));
+    assert_eq!(szx.ay().unwrap(), Some(AyState { selected: 7, registers: core::array::from_fn(|i| i as u8) }));
+    assert_eq!(szx.keyboard().unwrap(), Some(SzxKeyboard { flags: 1, joystick: 2 }));
+}
+
+#[test]
+fn unknown_blocks_round_trip_losslessly() {
+    let data = szx_file(1, &[
+        (b"DIDE", vec![1, 2, 3]),
+        (&Z80R, z80r()),
+        (b"MFCE", vec![]),
+        (b"XXXX", vec![0xff; 100]),
+    ]);
+    let mut szx = Szx::read(&data).unwrap();
+    assert_eq!(szx.write(), data);
+    assert_eq!(szx.blocks[0], SzxBlock { id: *b"DIDE", data: vec![1, 2, 3] });
+
+    // Re-encoding known block keeps the rest untouched
+    let z80 = szx.z80_regs().unwrap().unwrap();
+    szx.set_z80_regs(&z80);
+    assert_eq!(szx.write(), data);
+}
+
+#[test]
+fn ram_pages_are_decompressed_and_compressed() {
+    let mut uncompressed = vec![0, 0, 5];
+    uncompressed.extend(vec![0xaa; BANK_SIZE]);
+    let mut szx = Szx::read(&szx_file(1, &[(&RAMP, uncompressed)])).unwrap();
+    assert_eq!(szx.ram_page(5).unwrap(), Some(vec![0xaa; BANK_SIZE]));
+    assert_eq!(szx.ram_page(2).unwrap(), None);
+
+    let contents: Vec<u8> = (0..BANK_SIZE).map(|i| (i % 13) as u8).collect();
+    szx.set_ram_page(2, &contents);
+    szx.set_ram_page(5, &contents);
+    let szx = Szx::read(&szx.write()).unwrap();
+    assert_eq!(szx.blocks.len(), 2);
+    assert!(szx.blocks[1].data.len() < BANK_SIZE);
+    assert_eq!(szx.ram_page(2).unwrap(), Some(contents.clone()));
+    assert_eq!(szx.ram_page(5).unwrap(), Some(contents));
+}
+
+#[test]
+fn rejects_invalid_snapshots() {
+    assert!(matches!(Szx::read(b"ZXST"), Err(FormatError::InvalidSize(4))));
+    assert!(matches!(Szx::read(b"ZXSX\x01\x04\x01\x00"), Err(FormatError::Invalid(_))));
+    let mut data = szx_file(1, &[(&Z80R, z80r())]);
+    data.truncate(data.len() - 1);
+    assert!(matches!(Szx::read(&data), Err(FormatError::InvalidSize(_))));
+    let szx = Szx::read(&szx_file(1, &[(&Z80R, vec![0; 10])])).unwrap();
+    assert!(matches!(szx.z80_regs(), Err(FormatError::Invalid(_))));
+}
+
+#[test]
+fn applies_and_captures_machine_state() {
+    let machine = Machine::new(Accuracy::Instructions);
+    machine.memory().write(0x4000, 0x11);
+    machine.memory().write(0x8000, 0x22);
+    machine.memory().write(0xffff, 0x33);
+    machine.cpu().load_state(&CpuRegisters { pc: 0x8000, after_ei: true, ..Default::default() });
+
+    let szx = Szx::read(&Szx::capture(machine.cpu(), &**machine.memory(), 4, 500).write()).unwrap();
+    assert_eq!(szx.spectrum_regs().unwrap().unwrap().border, 4);
+    assert_eq!(szx.z80_regs().unwrap().unwrap(), SzxZ80Regs {
+        regs: machine.cpu().save_state(),
+        tstates: 500,
+        ..Default::default()
+    });
+
+    let other = Machine::new(Accuracy::Instructions);
+    szx.apply(other.cpu(), &**other.memory()).unwrap();
+    assert_eq!(other.cpu().rp(RegPair::PC).get(), 0x8000);
+    assert!(other.cpu().after_ei.get());
+    assert_eq!((other.memory().read(0x4000), other.memory().read(0x8000), other.memory().read(0xffff)), (0x11, 0x22, 0x33));
+}
@@ -0,0 +1,121 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use std::{fs, rc::Rc};
+
+use librespectrum::{
+    cpu::tokens::{Reg, RegPair},
+    devs::{Device, TapeDeck, TapePulse, TapeSegment, mem::Memory},
+    formats::tap::{DATA_FLAG, HEADER_FLAG, Tap, TapBlock},
+};
+
+/// Tape deck with given blocks inserted
+fn deck_with(blocks: Vec<TapBlock>) -> Rc<TapeDeck> {
+    let deck = Rc::new(TapeDeck::default());
+    deck.insert(Tap { blocks }.tape_blocks());
+    deck
+}
+
+/// Signal segments of the standard speed block
+fn standard(flag: u8, payload: &[u8]) -> Vec<TapeSegment> {
+    TapeSegment::standard(&TapBlock::new(flag, payload), 1000)
+}
+
+#[test]
+fn standard_block_follows_rom_timings() {
+    let header = standard(HEADER_FLAG, &[0; 17]);
+    assert_eq!(header[0], TapeSegment::Tone { pulse: 2168, count: 8063 });
+    let data = standard(DATA_FLAG, &[0x80]);
+    assert_eq!(data[0], TapeSegment::Tone { pulse: 2168, count: 3223 });
+    assert_eq!(data[1], TapeSegment::Pulses(vec![667, 735]));
+    // Flag FFh, payload 80h, checksum 7Fh
+    let bits = &data[2];
+    assert_eq!(bits.pulse(0), Some(TapePulse::Edge(1710)));
+    assert_eq!(bits.pulse(17), Some(TapePulse::Edge(1710)));
+    assert_eq!(bits.pulse(18), Some(TapePulse::Edge(855)));
+    assert_eq!(bits.pulse(32), Some(TapePulse::Edge(855)));
+    assert_eq!(bits.pulse(34), Some(TapePulse::Edge(1710)));
+    assert_eq!(bits.pulse(48), None);
+    assert_eq!(data[3].pulse(0), Some(TapePulse::Level(false, 3_500_000)));
+}
+
+#[test]
+fn partial_last_byte_plays_only_used_bits() {
+    let segment = TapeSegment::Data { zero: 855, one: 1710, data: vec![0xff, 0xc0], last_bits: 2 };
+    assert_eq!(segment.pulse(19), Some(TapePulse::Edge(1710)));
+    assert_eq!(segment.pulse(20), None);
+}
+
+#[test]
+fn player_flips_ear_on_pulse_edges() {
+    let system = TestSystem::new(&[0x76]);
+    let player = system.device_manager.create_tape_player(&deck_with(vec![TapBlock::new(DATA_FLAG, &[0])]));
+    let mut scheduler = system.scheduler_with(vec![player.run()]);
+    assert!(!player.ear());
+    player.play();
+    let start = system.clock.get();
+    let mut run_to = |tcycles: u64| scheduler.run(start + (tcycles << 1) - system.clock.get());
+    run_to(1);
+    assert!(player.ear());
+    run_to(2167);
+    assert!(player.ear(), "clock {}", system.clock.get() - start);
+    run_to(2169);
+    assert!(!player.ear(), "clock {}", system.clock.get() - start);
+    assert!(player.is_playing());
+}
+
+#[test]
+fn player_stops_and_rewinds() {
+    let system = TestSystem::new(&[0x76]);
+    let deck = deck_with(vec![TapBlock::new(DATA_FLAG, &[0]), TapBlock::new(DATA_FLAG, &[1])]);
+    let player = system.device_manager.create_tape_player(&deck);
+    let mut scheduler = system.scheduler_with(vec![player.run()]);
+    player.play();
+    scheduler.run(1000);
+    assert_eq!(deck.position(), 1);
+    player.stop();
+    let level = player.ear();
+    scheduler.run(20000);
+    assert_eq!(player.ear(), level);
+    player.rewind();
+    assert!(!player.is_playing());
+    assert_eq!(deck.position(), 0);
+}
+
+#[test]
+fn player_stops_when_ear_is_not_read() {
+    let system = TestSystem::new(&[0x76]);
+    let player = system.device_manager.create_tape_player(&deck_with(vec![TapBlock::new(DATA_FLAG, &[0; 1000])]));
+    let mut scheduler = system.scheduler_with(vec![player.run()]);
+    player.play();
+    scheduler.run(3_400_000 << 1);
+    assert!(player.is_playing());
+    scheduler.run(200_000 << 1);
+    assert!(!player.is_playing());
+}
+
+#[test]
+fn rom_loader_reads_played_signal() {
+    let payload = [0x12, 0x34, 0x56, 0x78];
+    let system = TestSystem::new(&[
+        0xdd, 0x21, 0x00, 0xa0, // LD IX,A000h
+        0x11, 0x04, 0x00,       // LD DE,4
+        0x3e, DATA_FLAG,        // LD A,FFh
+        0x37,                   // SCF
+        0xcd, 0x56, 0x05,       // CALL 0556h
+        0x76,                   // HALT
+    ]);
+    system.memory.load(0, &fs::read("../roms/48.rom").unwrap());
+    let player = system.device_manager.create_tape_player(&deck_with(vec![TapBlock::new(DATA_FLAG, &payload)]));
+    let mut scheduler = system.scheduler_with(vec![player.run()]);
+    player.play();
+    assert!(system.run_until(&mut scheduler, BASE + 13, 10_000_000));
+    assert!(system.cpu.rg(Reg::F).get() & 0x01 != 0, "Loading failed");
+    assert_eq!((0..4).map(|i| system.memory.read(0xa000 + i)).collect::<Vec<_>>(), payload);
+    assert_eq!(system.cpu.rp(RegPair::IX).get(), 0xa004);
+    assert!(player.is_playing());
+}
@@ -0,0 +1,101 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use std::fs;
+
+use librespectrum::{
+    devs::{Device, TapeBlock, TapeSegment},
+    formats::{tap::{DATA_FLAG, HEADER_FLAG, TapBlock}, tzx::{Tzx, TzxBlock}, wav::Wav},
+};
+
+/// Pulse lengths of the block signal without pause
+fn standard_pulses(block: &TapBlock) -> Vec<u32> {
+    let mut segments = TapeSegment::standard(block, 0);
+    segments.pop();
+    TapeBlock { description: String::new(), data: None, segments }.pulses().iter().map(|pulse| match pulse {
+        librespectrum::devs::TapePulse::Edge(pulse) => *pulse,
+        _ => unreachable!(),
+    }).collect()
+}
+
+#[test]
+fn recorder_timestamps_mic_edges() {
+    let system = TestSystem::new(&[
+        0x3e, 0x08,         // LD A,08h
+        0xd3, 0xfe,         // OUT (FEh),A
+        0x3e, 0x00,         // LD A,00h
+        0xd3, 0xfe,         // OUT (FEh),A
+        0xd3, 0xfe,         // OUT (FEh),A
+        0x3e, 0x0f,         // LD A,0Fh
+        0xd3, 0xfd,         // OUT (FDh),A
+        0x76,               // HALT
+    ]);
+    let recorder = system.device_manager.create_tape_recorder();
+    let mut scheduler = system.scheduler_with(vec![recorder.run()]);
+    recorder.record();
+    assert!(system.run_until(&mut scheduler, BASE + 14, 1000));
+    recorder.stop();
+    let (level, pulses) = recorder.pulses();
+    assert!(!level);
+    // Each OUT takes 11 t-states and the port is written on its last machine cycle
+    assert_eq!(pulses.len(), 3);
+    assert_eq!(pulses[1], 7 + 11);
+    assert_eq!(pulses.iter().sum::<u32>() as u64, system.clock.get() / 2);
+}
+
+#[test]
+fn rom_save_is_decoded_into_tap_block() {
+    let payload = [0x12, 0x34, 0x56, 0x78, 0x9a];
+    let system = TestSystem::new(&[
+        0xdd, 0x21, 0x00, 0xa0, // LD IX,A000h
+        0x11, 0x05, 0x00,       // LD DE,5
+        0x3e, DATA_FLAG,        // LD A,FFh
+        0xcd, 0xc2, 0x04,       // CALL 04C2h (SA-BYTES)
+        0x76,                   // HALT
+    ]);
+    system.memory.load(0, &fs::read("../roms/48.rom").unwrap());
+    system.memory.load(0xa000, &payload.to_vec());
+    let recorder = system.device_manager.create_tape_recorder();
+    let mut scheduler = system.scheduler_with(vec![recorder.run()]);
+    recorder.record();
+    assert!(system.run_until(&mut scheduler, BASE + 12, 10_000_000));
+    recorder.stop();
+    assert_eq!(recorder.to_tap().blocks, vec![TapBlock::new(DATA_FLAG, &payload)]);
+}
+
+#[test]
+fn non_standard_signal_falls_back_to_direct_recording() {
+    let header = TapBlock::new(HEADER_FLAG, &[0; 17]);
+    let mut pulses = vec![1_000_000];
+    pulses.extend(standard_pulses(&header));
+    pulses.push(3_500_000);
+    pulses.extend([500; 301]);
+    pulses.push(790);
+    let tzx = Tzx::from_pulses(true, &pulses);
+    assert_eq!(tzx.blocks.len(), 2);
+    assert_eq!(tzx.blocks[0], TzxBlock::Standard { pause: 1000, data: header });
+    let TzxBlock::DirectRecording { tstates_per_sample: 79, pause: 0, last_bits, data } = &tzx.blocks[1] else {
+        panic!("Direct recording expected");
+    };
+    // 301 pulses of 500 t-states, the first one is low, and final high pulse of 790 t-states
+    assert_eq!((data.len() - 1) * 8 + *last_bits as usize, (301 * 500 + 790) / 79);
+    assert_eq!(data[0], 0b0000_0011);
+    assert_eq!(data[data.len() - 1].count_ones(), *last_bits as u32);
+}
+
+#[test]
+fn writes_wav_audio() {
+    let wav = Wav::from_pulses(35000, true, &[300, 200, 500]);
+    assert_eq!(wav.samples.len(), 10);
+    assert_eq!(wav.samples.iter().filter(|&&sample| sample > 0).count(), 8);
+    let data = wav.write().unwrap();
+    assert_eq!(&data[..4], b"RIFF");
+    assert_eq!(&data[8..16], b"WAVEfmt ");
+    assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 35000);
+    assert_eq!(data.len(), 44 + 10);
+    assert_eq!(&data[44..], &[0xc0, 0xc0, 0xc0, 0x40, 0x40, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0]);
+}
@@ -0,0 +1,141 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, run_on_both_cores};
+
+use std::{fs, rc::Rc};
+
+use librespectrum::{
+    cpu::{CpuModel, Flags, tokens::{Reg, RegPair}},
+    devs::{LD_BYTES, mem::{Memory, MemoryOverlay}},
+    formats::{FormatError, tap::{DATA_FLAG, HEADER_FLAG, Tap, TapBlock, TapFileType}},
+    machine::Machine,
+};
+
+/// Code calling LD-BYTES with given flag, destination, length and load/verify mode
+fn call_ld_bytes(flag: u8, dest: u16, length: u16, load: bool) -> Vec<u8> {
+    let [dest_lo, dest_hi] = dest.to_le_bytes();
+    let [len_lo, len_hi] = length.to_le_bytes();
+    vec![
+        0xdd, 0x21, dest_lo, dest_hi,   // LD IX,dest
+        0x11, len_lo, len_hi,           // LD DE,length
+        0x3e, flag,                     // LD A,flag
+        if load { 0x37 } else { 0xb7 }, // SCF / OR A
+        0xcd, 0x56, 0x05,               // CALL 0556h
+    ]
+}
+
+/// Program calling LD-BYTES once and halting
+fn ld_bytes(flag: u8, dest: u16, length: u16, load: bool) -> Vec<u8> {
+    [call_ld_bytes(flag, dest, length, load), vec![0x76]].concat()
+}
+
+/// Run program with 48K ROM, tape inserted and fast load enabled
+fn run_with_tape(program: &[u8], blocks: Vec<TapBlock>, setup: impl Fn(&Machine)) -> Machine {
+    run_on_both_cores(CpuModel::Z80, program, |machine| {
+        machine.memory().load(0, &fs::read("../roms/48.rom").unwrap());
+        machine.tape().insert(Tap { blocks: blocks.clone() }.tape_blocks());
+        machine.set_fast_load(true);
+        setup(machine);
+    })
+}
+
+#[test]
+fn parses_exerciser_tape() {
+    let tap = Tap::read(&fs::read("tests/exerciser/zexall.tap").unwrap()).unwrap();
+    let listing: Vec<String> = tap.blocks.iter().map(TapBlock::describe).collect();
+    assert_eq!(listing, vec![
+        "Program: \"zexall\" 42 bytes, LINE 0",
+        "Data: 42 bytes, flag FFh",
+        "Bytes: \"zexall\" 8624 bytes, CODE 32768,8624",
+        "Data: 8624 bytes, flag FFh",
+    ]);
+    let header = tap.blocks[2].header().unwrap();
+    assert_eq!((header.file_type, header.param1), (TapFileType::Bytes, 0x8000));
+    assert!(tap.blocks.iter().all(TapBlock::checksum_valid));
+    assert_eq!(tap.write(), fs::read("tests/exerciser/zexall.tap").unwrap());
+}
+
+#[test]
+fn rejects_truncated_tape() {
+    assert!(matches!(Tap::read(&[0x13, 0x00, 0x00]), Err(FormatError::InvalidSize(3))));
+    assert!(matches!(Tap::read(&[0x13]), Err(FormatError::InvalidSize(1))));
+}
+
+#[test]
+fn trap_loads_block_into_memory() {
+    let machine = run_with_tape(&ld_bytes(DATA_FLAG, 0x9000, 5, true), vec![TapBlock::new(DATA_FLAG, &[1, 2, 3, 4, 5])], |_| {});
+    assert_eq!((0..5).map(|i| machine.memory().read(0x9000 + i)).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+    assert!(machine.cpu().get_flags().contains(Flags::C));
+    assert_eq!(machine.cpu().rp(RegPair::IX).get(), 0x9005);
+    assert_eq!(machine.cpu().rp(RegPair::DE).get(), 0);
+    assert_eq!(machine.cpu().rp(RegPair::SP).get(), 0xfffe);
+    assert_eq!(machine.cpu().pc.value().get(), BASE + 14);
+    assert_eq!(machine.tape().position(), 1);
+    // Trap leaves through SA/LD-RET which enables interrupts
+    assert!(machine.cpu().iff1.get() && machine.cpu().iff2.get());
+}
+
+#[test]
+fn trap_reports_flag_length_and_checksum_errors() {
+    let mut corrupted = TapBlock::new(DATA_FLAG, &[1, 2, 3]);
+    corrupted.data[2] ^= 0xff;
+    for block in [TapBlock::new(HEADER_FLAG, &[1, 2, 3]), TapBlock::new(DATA_FLAG, &[1, 2]), corrupted] {
+        let machine = run_with_tape(&ld_bytes(DATA_FLAG, 0x9000, 3, true), vec![block.clone()], |_| {});
+        assert!(!machine.cpu().get_flags().contains(Flags::C), "{:?}", block);
+        assert_eq!(machine.cpu().pc.value().get(), BASE + 14);
+    }
+}
+
+#[test]
+fn trap_verifies_memory_without_writing() {
+    let block = TapBlock::new(DATA_FLAG, &[1, 2, 3]);
+    let machine = run_with_tape(&ld_bytes(DATA_FLAG, 0x9000, 3, false), vec![block.clone()], |machine| {
+        machine.memory().load(0x9000, &vec![1, 2, 3]);
+    });
+    assert!(machine.cpu().get_flags().contains(Flags::C));
+
+    let machine = run_with_tape(&ld_bytes(DATA_FLAG, 0x9000, 3, false), vec![block], |_| {});
+    assert!(!machine.cpu().get_flags().contains(Flags::C));
+    assert_eq!(machine.memory().read(0x9000), 0);
+}
+
+/// Interface ROM paged in at LD-BYTES, which returns right away
+struct ShadowRom;
+
+impl MemoryOverlay for ShadowRom {
+    fn covers(&self, addr: u16) -> bool { addr == LD_BYTES }
+    fn writable(&self, _addr: u16) -> bool { false }
+    fn write(&self, _addr: u16, _byte: u8) {}
+    fn read(&self, _addr: u16) -> u8 { 0xc9 } // RET
+}
+
+#[test]
+fn trap_doesnt_fire_under_paged_overlay() {
+    let machine = run_with_tape(&ld_bytes(DATA_FLAG, 0x9000, 3, true), vec![TapBlock::new(DATA_FLAG, &[1, 2, 3])], |machine| {
+        machine.memory().add_overlay(&(Rc::new(ShadowRom) as Rc<dyn MemoryOverlay>));
+    });
+    assert_eq!(machine.memory().read(0x9000), 0);
+    assert_eq!(machine.cpu().rp(RegPair::DE).get(), 3);
+    assert_eq!(machine.tape().position(), 0);
+}
+
+#[test]
+fn loads_exerciser_with_rom_routine_trapped() {
+    let tap = Tap::read(&fs::read("tests/exerciser/zexall.tap").unwrap()).unwrap();
+    let program = [
+        call_ld_bytes(HEADER_FLAG, 0x6000, 17, true),
+        call_ld_bytes(DATA_FLAG, 0xa000, 8624, true),
+        vec![0x76], // HALT
+    ].concat();
+    let machine = run_with_tape(&program, tap.blocks, |machine| {
+        machine.tape().seek(2);
+    });
+    assert_eq!(machine.memory().read(0x6001), b'z');
+    let expected = fs::read("tests/exerciser/zexall.bin").unwrap();
+    assert!((0..expected.len()).all(|i| machine.memory().read(0xa000 + i as u16) == expected[i]));
+    assert_eq!(machine.cpu().rg(Reg::H).get(), 0);
+    assert_eq!(machine.tape().position(), 4);
+}
@@ -0,0 +1,83 @@
+extern crate librespectrum;
+
+use librespectrum::formats::{FormatError, scl::{Scl, SclFile}, trd::Trd};
+
+/// SCL archive with a BASIC program of one sector and code of two sectors
+fn test_scl() -> Scl {
+    Scl { files: vec![
+        SclFile { name: *b"boot    ", extension: b'B', start: 100, length: 100, data: vec![0x11; 256] },
+        SclFile { name: *b"game    ", extension: b'C', start: 0x8000, length: 500, data: vec![0x22; 512] },
+    ]}
+}
+
+#[test]
+fn reads_geometry_from_disk_info() {
+    let mut data = vec![0; 40 * 4096];
+    data[0x8e3] = 0x19;
+    let trd = Trd::read(&data).unwrap();
+    assert_eq!((trd.cylinders, trd.sides), (40, 1));
+    // Images cut short are padded to the full disk
+    let trd = Trd::read(&data[..0x900]).unwrap();
+    assert_eq!((trd.cylinders, trd.sides, trd.data.len()), (40, 1, 40 * 4096));
+    let trd = Trd::read(&vec![0; 3 * 8192]).unwrap();
+    assert_eq!((trd.cylinders, trd.sides), (3, 2));
+    assert!(matches!(Trd::read(&[0; 100]), Err(FormatError::InvalidSize(100))));
+    assert!(matches!(Trd::read(&[]), Err(FormatError::InvalidSize(0))));
+}
+
+#[test]
+fn converts_image_to_disk_and_back() {
+    let mut trd = Trd::new(80, 2);
+    trd.data.iter_mut().enumerate().for_each(|(index, byte)| *byte = (index / 256) as u8);
+    let disk = trd.disk();
+    assert_eq!((disk.cylinders(), disk.sides, disk.tracks.len()), (80, 2, 160));
+    // Cylinder 1 side 1 is the fourth track in the image
+    let sector = &disk.track(1, 1).unwrap()[2];
+    assert_eq!((sector.cylinder, sector.head, sector.sector, sector.size_code), (1, 1, 3, 1));
+    assert_eq!(sector.data, vec![(3 * 16 + 2) as u8; 256]);
+    assert_eq!(Trd::from_disk(&disk), trd);
+}
+
+#[test]
+fn reads_and_writes_scl() {
+    let scl = test_scl();
+    let data = scl.write();
+    assert_eq!(&data[..9], b"SINCLAIR\x02");
+    assert_eq!(data.len(), 9 + 2 * 14 + 3 * 256 + 4);
+    assert_eq!(Scl::read(&data).unwrap(), scl);
+
+    let mut corrupted = data.clone();
+    corrupted[40] ^= 1;
+    assert!(matches!(Scl::read(&corrupted), Err(FormatError::Invalid(_))));
+    assert!(matches!(Scl::read(b"SINCLAIX\x00\x00\x00\x00\x00"), Err(FormatError::Invalid(_))));
+    assert!(matches!(Scl::read(&data[..40]), Err(FormatError::Invalid(_))));
+}
+
+#[test]
+fn places_scl_files_on_formatted_disk() {
+    let trd = test_scl().trd().unwrap();
+    assert_eq!((trd.cylinders, trd.sides), (80, 2));
+    // Catalog entries with first sector and track
+    assert_eq!(trd.data[..16], [b'b', b'o', b'o', b't', b' ', b' ', b' ', b' ', b'B', 100, 0, 100, 0, 1, 0, 1]);
+    assert_eq!(trd.data[16..32], [b'g', b'a', b'm', b'e', b' ', b' ', b' ', b' ', b'C', 0x00, 0x80, 0xf4, 0x01, 2, 1, 1]);
+    assert_eq!(trd.data[4096..4096 + 256], [0x11; 256]);
+    assert_eq!(trd.data[4096 + 256..4096 + 768], [0x22; 512]);
+    // Disk info: first free sector and track, disk type, file count, free sectors and TR-DOS ID
+    assert_eq!(trd.data[0x8e1..0x8e8], [3, 1, 0x16, 2, 0xed, 0x09, 0x10]);
+    assert_eq!(Trd::read(&trd.write()).unwrap(), trd);
+}
+
+#[test]
+fn extracts_scl_files_from_disk() {
+    let scl = test_scl();
+    let mut trd = scl.trd().unwrap();
+    assert_eq!(Scl::from_trd(&trd).unwrap(), scl);
+
+    // Deleted file is left out
+    trd.data[0] = 0x01;
+    assert_eq!(Scl::from_trd(&trd).unwrap().files, scl.files[1..]);
+
+    // File beyond the last track
+    trd.data[16 + 15] = 200;
+    assert!(matches!(Scl::from_trd(&trd), Err(FormatError::Invalid(_))));
+}
@@ -0,0 +1,195 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    devs::{TapePulse, TapeSegment},
+    formats::{FormatError, tap::{DATA_FLAG, TapBlock}, tzx::{CswCompression, Tzx, TzxBlock, TzxHardware, TzxSymbol}},
+};
+
+/// TZX file with given blocks (id followed by contents)
+fn tzx_file(blocks: &[&[u8]]) -> Vec<u8> {
+    let mut data = b"ZXTape!\x1a\x01\x14".to_vec();
+    blocks.iter().for_each(|block| data.extend_from_slice(block));
+    data
+}
+
+/// Descriptions of the blocks in playback order
+fn playback(tzx: &Tzx, is_48k: bool) -> Vec<String> {
+    tzx.tape_blocks(is_48k).into_iter().map(|block| block.description).collect()
+}
+
+#[test]
+fn reads_and_writes_all_block_types() {
+    let data = tzx_file(&[
+        &[0x10, 0xe8, 0x03, 0x03, 0x00, 0xff, 0xaa, 0x55],
+        &[0x11, 0x78, 0x08, 0x9b, 0x02, 0xdf, 0x02, 0x57, 0x03, 0xae, 0x06, 0x97, 0x0c, 0x06, 0x00, 0x00, 0x02, 0x00, 0x00, 0x12, 0x34],
+        &[0x12, 0x78, 0x08, 0x10, 0x00],
+        &[0x13, 0x02, 0x9b, 0x02, 0xdf, 0x02],
+        &[0x14, 0x57, 0x03, 0xae, 0x06, 0x08, 0x64, 0x00, 0x01, 0x00, 0x00, 0xf0],
+        &[0x15, 0x4f, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0xf0],
+        &[0x18, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0xac, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x05, 0x07],
+        &[0x19, 0x1f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01, 0x08, 0x00, 0x00, 0x00, 0x02, 0x02,
+            0x00, 0x78, 0x08, 0x00, 0x0a, 0x00, 0x00, 0x57, 0x03, 0x57, 0x03, 0x00, 0xae, 0x06, 0xae, 0x06, 0xa5],
+        &[0x20, 0x00, 0x00],
+        &[0x21, 0x04, b'G', b'a', b'm', b'e'],
+        &[0x22],
+        &[0x23, 0xfe, 0xff],
+        &[0x24, 0x03, 0x00],
+        &[0x25],
+        &[0x26, 0x02, 0x00, 0x01, 0x00, 0xff, 0xff],
+        &[0x27],
+        &[0x2a, 0x00, 0x00, 0x00, 0x00],
+        &[0x2b, 0x01, 0x00, 0x00, 0x00, 0x01],
+        &[0x30, 0x02, b'H', b'i'],
+        &[0x31, 0x05, 0x02, b'O', b'k'],
+        &[0x32, 0x08, 0x00, 0x02, 0x00, 0x02, b'J', b'P', 0x03, 0x01, b'1'],
+        &[0x33, 0x01, 0x00, 0x01, 0x00],
+        &[0x5a, b'X', b'T', b'a', b'p', b'e', b'!', 0x1a, 0x01, 0x14],
+    ]);
+    let tzx = Tzx::read(&data).unwrap();
+    assert_eq!((tzx.major, tzx.minor, tzx.blocks.len()), (1, 20, 23));
+    assert_eq!(tzx.blocks[0], TzxBlock::Standard { pause: 1000, data: TapBlock { data: vec![0xff, 0xaa, 0x55] } });
+    assert_eq!(tzx.blocks[1], TzxBlock::Turbo {
+        pilot: 2168, sync1: 667, sync2: 735, zero: 855, one: 1710, pilot_pulses: 3223,
+        last_bits: 6, pause: 0, data: vec![0x12, 0x34],
+    });
+    assert_eq!(tzx.blocks[2], TzxBlock::PureTone { pulse: 2168, count: 16 });
+    assert_eq!(tzx.blocks[3], TzxBlock::PulseSequence(vec![667, 735]));
+    assert_eq!(tzx.blocks[4], TzxBlock::PureData { zero: 855, one: 1710, last_bits: 8, pause: 100, data: vec![0xf0] });
+    assert_eq!(tzx.blocks[5], TzxBlock::DirectRecording { tstates_per_sample: 79, pause: 0, last_bits: 4, data: vec![0xf0] });
+    assert_eq!(tzx.blocks[6], TzxBlock::CswRecording {
+        pause: 0, sample_rate: 44100, compression: CswCompression::Rle, pulses: 2, data: vec![0x05, 0x07],
+    });
+    assert_eq!(tzx.blocks[7], TzxBlock::GeneralizedData {
+        pause: 0,
+        pilot_symbols: vec![TzxSymbol { polarity: 0, pulses: vec![2168] }],
+        pilot: vec![(0, 10)],
+        data_symbols: vec![TzxSymbol { polarity: 0, pulses: vec![855, 855] }, TzxSymbol { polarity: 0, pulses: vec![1710, 1710] }],
+        data_count: 8,
+        data: vec![0xa5],
+    });
+    assert_eq!(tzx.blocks[8..18], [
+        TzxBlock::Pause(0), TzxBlock::GroupStart(String::from("Game")), TzxBlock::GroupEnd, TzxBlock::Jump(-2),
+        TzxBlock::LoopStart(3), TzxBlock::LoopEnd, TzxBlock::CallSequence(vec![1, -1]), TzxBlock::Return,
+        TzxBlock::StopIf48k, TzxBlock::SignalLevel(true),
+    ]);
+    assert_eq!(tzx.blocks[18..21], [
+        TzxBlock::Text(String::from("Hi")),
+        TzxBlock::Message { seconds: 5, text: String::from("Ok") },
+        TzxBlock::ArchiveInfo(vec![(0x00, String::from("JP")), (0x03, String::from("1"))]),
+    ]);
+    assert_eq!(tzx.blocks[21], TzxBlock::HardwareType(vec![TzxHardware { kind: 0, id: 1, info: 0 }]));
+    assert_eq!(tzx.blocks[22], TzxBlock::Unknown { id: 0x5a, data: b"XTape!\x1a\x01\x14".to_vec() });
+    assert_eq!(tzx.blocks[20].describe(), "Title: JP, Year: 1");
+    assert_eq!(tzx.write(), data);
+}
+
+#[test]
+fn skips_unknown_blocks_by_length() {
+    let tzx = Tzx::read(&tzx_file(&[&[0x4b, 0x02, 0x00, 0x00, 0x00, 0x01, 0x02], &[0x22]])).unwrap();
+    assert_eq!(tzx.blocks, vec![TzxBlock::Unknown { id: 0x4b, data: vec![0x02, 0x00, 0x00, 0x00, 0x01, 0x02] }, TzxBlock::GroupEnd]);
+}
+
+#[test]
+fn rejects_invalid_files() {
+    assert!(matches!(Tzx::read(b"ZXTape?\x1a\x01\x14"), Err(FormatError::Invalid(_))));
+    assert!(matches!(Tzx::read(&tzx_file(&[&[0x10, 0xe8, 0x03, 0x05, 0x00, 0xff]])), Err(FormatError::InvalidSize(_))));
+}
+
+#[test]
+fn converts_data_blocks_into_pulses() {
+    let turbo = TzxBlock::Turbo {
+        pilot: 2000, sync1: 600, sync2: 700, zero: 800, one: 1600, pilot_pulses: 100, last_bits: 3, pause: 10, data: vec![0xe0],
+    };
+    assert_eq!(turbo.segments(), vec![
+        TapeSegment::Tone { pulse: 2000, count: 100 },
+        TapeSegment::Pulses(vec![600, 700]),
+        TapeSegment::Data { zero: 800, one: 1600, data: vec![0xe0], last_bits: 3 },
+        TapeSegment::Pause(35000),
+    ]);
+    let standard = TzxBlock::Standard { pause: 0, data: TapBlock::new(DATA_FLAG, &[1]) }.tape_block();
+    assert!(standard.data.is_some());
+    assert_eq!(standard.segments.len(), 4);
+    assert!(turbo.tape_block().data.is_none());
+}
+
+#[test]
+fn converts_direct_recording_into_levels() {
+    let block = TzxBlock::DirectRecording { tstates_per_sample: 100, pause: 0, last_bits: 6, data: vec![0xff, 0x0c] };
+    assert_eq!(block.segments()[0], TapeSegment::Raw(vec![
+        TapePulse::Level(true, 800),
+        TapePulse::Level(false, 400),
+        TapePulse::Level(true, 200),
+    ]));
+}
+
+#[test]
+fn converts_csw_recording_into_pulses() {
+    let rle = [0x02, 0x00, 0x10, 0x27, 0x00, 0x00, 0x01];
+    let block = TzxBlock::CswRecording { pause: 0, sample_rate: 350_000, compression: CswCompression::Rle, pulses: 3, data: rle.to_vec() };
+    assert_eq!(block.segments()[0], TapeSegment::Pulses(vec![20, 100_000, 10]));
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&rle, 6);
+    let block = TzxBlock::CswRecording { pause: 0, sample_rate: 350_000, compression: CswCompression::ZRle, pulses: 3, data: compressed };
+    assert_eq!(block.segments()[0], TapeSegment::Pulses(vec![20, 100_000, 10]));
+}
+
+#[test]
+fn converts_generalized_data_symbols() {
+    let block = TzxBlock::GeneralizedData {
+        pause: 0,
+        pilot_symbols: vec![TzxSymbol { polarity: 3, pulses: vec![500, 0] }],
+        pilot: vec![(0, 2)],
+        data_symbols: vec![
+            TzxSymbol { polarity: 1, pulses: vec![100, 0] },
+            TzxSymbol { polarity: 0, pulses: vec![200, 300] },
+            TzxSymbol { polarity: 2, pulses: vec![400, 0] },
+        ],
+        data_count: 3,
+        data: vec![0b10_01_00_00],
+    };
+    assert_eq!(block.segments()[0], TapeSegment::Raw(vec![
+        TapePulse::Level(true, 500), TapePulse::Level(true, 500),
+        TapePulse::Level(false, 400),
+        TapePulse::Edge(200), TapePulse::Edge(300),
+        TapePulse::Hold(100),
+    ]));
+}
+
+#[test]
+fn follows_loops_jumps_and_calls() {
+    let text = |text: &str| TzxBlock::Text(String::from(text));
+    let tzx = Tzx {
+        blocks: vec![
+            TzxBlock::LoopStart(2),
+            text("a"),
+            TzxBlock::LoopEnd,
+            TzxBlock::CallSequence(vec![3, 5]),
+            TzxBlock::Jump(5),
+            text("b"),
+            TzxBlock::Return,
+            TzxBlock::Jump(-3),
+            text("c"),
+            TzxBlock::Return,
+            text("d"),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(playback(&tzx, true), vec!["Text: a", "Text: a", "Text: c", "Text: d"]);
+    let tzx = Tzx { blocks: vec![TzxBlock::CallSequence(vec![2, 3]), TzxBlock::Jump(3), text("x"), TzxBlock::Return], ..Default::default() };
+    assert_eq!(playback(&tzx, true), vec!["Text: x"]);
+}
+
+#[test]
+fn stops_endless_jumps() {
+    let tzx = Tzx { blocks: vec![TzxBlock::Text(String::from("t")), TzxBlock::Jump(-1)], ..Default::default() };
+    assert_eq!(tzx.tape_blocks(true).len(), 50_000);
+}
+
+#[test]
+fn stops_tape_on_zero_pause_and_in_48k_mode() {
+    let tzx = Tzx { blocks: vec![TzxBlock::StopIf48k, TzxBlock::Pause(0)], ..Default::default() };
+    let blocks = tzx.tape_blocks(true);
+    assert_eq!(blocks[0].segments, vec![TapeSegment::Stop]);
+    assert_eq!(blocks[1].segments, vec![TapeSegment::Stop]);
+    assert!(tzx.tape_blocks(false)[0].segments.is_empty());
+    assert_eq!(TapeSegment::Stop.pulse(0), Some(TapePulse::Stop));
+}
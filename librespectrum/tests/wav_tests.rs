@@ -0,0 +1,86 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    devs::{TapeBlock, TapePulse, TapeSegment},
+    formats::{FormatError, pzx::{Pzx, PzxBlock}, tap::{DATA_FLAG, TapBlock}, tzx::{Tzx, TzxBlock}, wav::{EdgeDetector, Wav}},
+};
+
+/// WAV file with given format tag, channels, sample rate, bits per sample and sample bytes
+fn wav_file(format: u16, channels: u16, sample_rate: u32, bits: u16, samples: &[u8]) -> Vec<u8> {
+    let mut data = b"RIFF".to_vec();
+    data.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+    data.extend_from_slice(b"WAVEfmt \x10\x00\x00\x00");
+    data.extend_from_slice(&format.to_le_bytes());
+    data.extend_from_slice(&channels.to_le_bytes());
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&(sample_rate * (channels * bits / 8) as u32).to_le_bytes());
+    data.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+    data.extend_from_slice(&bits.to_le_bytes());
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+    data.extend_from_slice(samples);
+    data
+}
+
+#[test]
+fn reads_pcm_formats() {
+    let wav = Wav::read(&wav_file(1, 1, 35000, 8, &[0x80, 0xff, 0x00])).unwrap();
+    assert_eq!((wav.sample_rate, wav.channels, wav.bits_per_sample), (35000, 1, 8));
+    assert_eq!(wav.samples, vec![0, 0x7f00, -0x8000]);
+    let wav = Wav::read(&wav_file(1, 2, 44100, 16, &[0x00, 0x10, 0x00, 0xf0, 0x34, 0x12])).unwrap();
+    assert_eq!(wav.samples, vec![0x1000, -0x1000, 0x1234]);
+    // Written audio reads back
+    let wav = Wav::from_pulses(35000, true, &[300, 200]);
+    assert_eq!(Wav::read(&wav.write().unwrap()).unwrap().samples.len(), wav.samples.len());
+}
+
+#[test]
+fn rejects_invalid_files() {
+    assert!(matches!(Wav::read(b"RIFX\0\0\0\0WAVE"), Err(FormatError::Invalid(_))));
+    assert!(matches!(Wav::read(&wav_file(3, 1, 44100, 32, &[0; 4])), Err(FormatError::Invalid(_))));
+    assert!(matches!(Wav::read(&wav_file(1, 1, 44100, 24, &[0; 3])), Err(FormatError::Invalid(_))));
+    assert!(matches!(Wav::read(b"RIFF\x04\0\0\0WAVE"), Err(FormatError::Invalid(_))));
+}
+
+#[test]
+fn detects_edges_in_t_states() {
+    // 35 kHz sample is 100 t-states, stereo channels are mixed
+    let samples: Vec<u8> = [0x60, 0x60, 0xa0, 0xa0, 0xa0, 0x60, 0xa0, 0xa0].iter()
+        .flat_map(|&sample| [sample, sample]).collect();
+    let wav = Wav::read(&wav_file(1, 2, 35000, 8, &samples)).unwrap();
+    assert_eq!(wav.pulses(EdgeDetector::Threshold(0)), (false, vec![200, 300, 100, 200]));
+    assert_eq!(wav.tape_blocks(EdgeDetector::Threshold(0))[0].segments, vec![TapeSegment::Raw(vec![
+        TapePulse::Level(false, 200), TapePulse::Edge(300), TapePulse::Edge(100), TapePulse::Edge(200),
+    ])]);
+}
+
+#[test]
+fn schmitt_trigger_ignores_noise() {
+    // Square wave with small spikes around zero crossings
+    let wav = Wav { sample_rate: 35000, channels: 1, bits_per_sample: 16, samples: vec![
+        -0x2000, -0x2000, 0x100, -0x100, 0x2000, 0x2000, -0x100, 0x100, -0x2000, -0x2000,
+    ]};
+    assert_eq!(wav.pulses(EdgeDetector::Threshold(0)).1.len(), 7);
+    assert_eq!(wav.pulses(EdgeDetector::Schmitt { low: -0x400, high: 0x400 }), (false, vec![400, 400, 200]));
+}
+
+#[test]
+fn converts_capture_into_clean_tape() {
+    let block = TapBlock::new(DATA_FLAG, &[0x12, 0x34, 0x56]);
+    let pulses: Vec<u32> = TapeBlock::standard(block.clone(), 0).pulses().iter().filter_map(|pulse| match pulse {
+        TapePulse::Edge(pulse) => Some(*pulse),
+        _ => None,
+    }).collect();
+    // 44.1 kHz capture read back from 16-bit file with some noise
+    let mut wav = Wav::from_pulses(44100, false, &[[350_000].as_slice(), &pulses, &[350_000]].concat());
+    wav.bits_per_sample = 16;
+    let wav = Wav::read(&wav.write().unwrap()).unwrap();
+    let wav = Wav { samples: wav.samples.iter().enumerate().map(|(idx, &sample)| sample + (idx % 3) as i16 * 0x100).collect(), ..wav };
+
+    let (level, pulses) = wav.pulses(EdgeDetector::default());
+    let tzx = Tzx::from_pulses(level, &pulses);
+    assert_eq!(tzx.blocks, vec![TzxBlock::Standard { pause: 100, data: block.clone() }]);
+    let pzx = Pzx::from_tape(&tzx.tape_blocks(false));
+    assert!(pzx.blocks.contains(&PzxBlock::Browse(tzx.blocks[0].describe())));
+    assert_eq!(pzx.tape_blocks(false).iter().find_map(|block| block.data.clone()), Some(block));
+}
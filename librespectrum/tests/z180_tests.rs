@@ -0,0 +1,125 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, run_on_both_cores};
+
+use librespectrum::{
+    cpu::{CpuModel, Flags, tokens::{Reg, RegPair}},
+    devs::mem::Memory,
+    machine::Machine,
+};
+
+/// Run Z180 program until HALT
+fn run(program: &[u8], setup: impl Fn(&Machine)) -> Machine {
+    run_on_both_cores(CpuModel::Z180, program, setup)
+}
+
+#[test]
+fn mlt_multiplies_register_pair_halves() {
+    let machine = run(&[
+        0x01, 0x0a, 0x0c,   // LD BC,0C0Ah
+        0xed, 0x4c,         // MLT BC
+        0x21, 0xff, 0xff,   // LD HL,FFFFh
+        0xed, 0x6c,         // MLT HL
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rp(RegPair::BC).get(), 0x0078);
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0xfe01);
+}
+
+#[test]
+fn tst_sets_flags_without_changing_accumulator() {
+    let machine = run(&[
+        0x3e, 0x0f,         // LD A,0Fh
+        0x06, 0xf0,         // LD B,F0h
+        0xed, 0x04,         // TST B
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x0f);
+    assert!(machine.cpu().get_flags().contains(Flags::Z));
+
+    let machine = run(&[
+        0x3e, 0x8f,         // LD A,8Fh
+        0xed, 0x64, 0x80,   // TST 80h
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x8f);
+    assert!(machine.cpu().get_flags().contains(Flags::S));
+    assert!(!machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn tstio_tests_port_addressed_by_c() {
+    // Floating bus reads FFh
+    let machine = run(&[
+        0x0e, 0x10,         // LD C,10h
+        0xed, 0x74, 0x00,   // TSTIO 0h
+        0x76,               // HALT
+    ], |_| {});
+    assert!(machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn in0_reads_port_and_sets_flags() {
+    let machine = run(&[
+        0xed, 0x00, 0x10,   // IN0 B,(10h)
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::B).get(), 0xff);
+    assert!(machine.cpu().get_flags().contains(Flags::S));
+    assert!(!machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn in0_f_sets_flags_and_discards_value() {
+    let machine = run(&[
+        0x3e, 0x12,         // LD A,12h
+        0xed, 0x30, 0x10,   // IN0 F,(10h)
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x12);
+    assert!(machine.cpu().get_flags().contains(Flags::S));
+    assert!(!machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn otimr_outputs_block_to_incrementing_ports() {
+    let machine = run(&[
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x01, 0x10, 0x03,   // LD BC,0310h
+        0xed, 0x93,         // OTIMR
+        0x76,               // HALT
+    ], |machine| {
+        machine.memory().load(0x9000, &vec![1, 2, 3]);
+    });
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x9003);
+    assert_eq!(machine.cpu().rp(RegPair::BC).get(), 0x0013);
+    assert!(machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn undefined_opcode_traps_to_zero() {
+    let machine = run(&[
+        0x00,               // NOP
+        0xed, 0x4e,         // undefined
+    ], |machine| {
+        machine.memory().load(0x0000, &vec![0x76]); // HALT
+    });
+    assert_eq!(machine.cpu().pc.value().get(), 0x0001);
+    assert_eq!(machine.cpu().rp(RegPair::SP).get(), 0xfffc);
+    assert_eq!(machine.memory().read(0xfffc), (BASE + 1) as u8);
+    assert_eq!(machine.memory().read(0xfffd), ((BASE + 1) >> 8) as u8);
+}
+
+#[test]
+fn undocumented_z80_opcode_traps() {
+    let machine = run(&[
+        0xcb, 0x30,         // SLL B
+    ], |machine| {
+        machine.memory().load(0x0000, &vec![0x76]); // HALT
+    });
+    assert_eq!(machine.cpu().pc.value().get(), 0x0001);
+    assert_eq!(machine.memory().read(0xfffc), BASE as u8);
+}
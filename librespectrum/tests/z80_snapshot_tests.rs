@@ -0,0 +1,152 @@
+extern crate librespectrum;
+
+use librespectrum::{
+    cpu::tokens::{IntMode, RegPair},
+    devs::mem::Memory,
+    formats::{FormatError, sna::BANK_SIZE, z80::{AyState, Hardware, Z80Snapshot}},
+    machine::{Accuracy, Machine},
+};
+
+/// Version 1 header with PC=8000h, border 3 and given flags bit 5 (compression)
+fn v1_header(compressed: bool) -> Vec<u8> {
+    vec![
+        0x12, 0x34,             // A, F
+        0x56, 0x78,             // BC
+        0x9a, 0xbc,             // HL
+        0x00, 0x80,             // PC
+        0xfe, 0xff,             // SP
+        0x3f, 0x05,             // I, R
+        0x01 | (3 << 1) | ((compressed as u8) << 5),
+        0x11, 0x22,             // DE
+        0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // BC', DE', HL'
+        0x99, 0xaa,             // A', F'
+        0x01, 0x02, 0x03, 0x04, // IY, IX
+        0x01, 0x01,             // IFF1, IFF2
+        0x02,                   // IM 2
+    ]
+}
+
+#[test]
+fn reads_version_1_compressed_snapshot() {
+    let mut data = v1_header(true);
+    // 4000h-5FFFh filled with EDh, the rest with zeros followed by end marker
+    for _ in 0..(0x2000 / 255) {
+        data.extend_from_slice(&[0xed, 0xed, 255, 0xed]);
+    }
+    data.extend_from_slice(&[0xed, 0xed, (0x2000 % 255) as u8, 0xed]);
+    for _ in 0..(0xa000 / 250) {
+        data.extend_from_slice(&[0xed, 0xed, 250, 0x00]);
+    }
+    data.extend_from_slice(&[0xed, 0xed, (0xa000 % 250) as u8, 0x00]);
+    data.extend_from_slice(&[0x00, 0xed, 0xed, 0x00]);
+
+    let snapshot = Z80Snapshot::read(&data).unwrap();
+    assert_eq!(snapshot.version, 1);
+    assert_eq!(snapshot.hardware, Hardware::Spectrum48k);
+    let regs = snapshot.regs;
+    assert_eq!((regs.af, regs.bc, regs.hl, regs.pc, regs.sp), (0x1234, 0x7856, 0xbc9a, 0x8000, 0xfffe));
+    assert_eq!(regs.ir, 0x3f85);
+    assert_eq!((regs.alt_af, regs.iy, regs.ix), (0x99aa, 0x0201, 0x0403));
+    assert_eq!(regs.im, IntMode::IM2);
+    assert_eq!(snapshot.border, 3);
+    let ram = snapshot.ram();
+    assert_eq!(ram.len(), 3 * BANK_SIZE);
+    assert!(ram[..0x2000].iter().all(|&byte| byte == 0xed));
+    assert!(ram[0x2000..].iter().all(|&byte| byte == 0x00));
+}
+
+#[test]
+fn reads_version_1_uncompressed_snapshot() {
+    let mut data = v1_header(false);
+    data.extend((0..3 * BANK_SIZE).map(|i| (i >> 8) as u8));
+    let snapshot = Z80Snapshot::read(&data).unwrap();
+    assert_eq!(snapshot.pages[&8][0x100], 1);
+    assert_eq!(snapshot.pages[&4][0], 0x40);
+    assert_eq!(snapshot.pages[&5][0x3fff], 0xbf);
+}
+
+#[test]
+fn reads_version_2_128k_snapshot() {
+    let mut data = v1_header(false);
+    data[6..8].copy_from_slice(&[0, 0]);
+    data.extend_from_slice(&23u16.to_le_bytes());
+    let mut extra = vec![0; 23];
+    extra[0..2].copy_from_slice(&0x1234u16.to_le_bytes());
+    extra[2] = 3; // 128K
+    extra[3] = 0x13; // bank 3 paged in
+    extra[6] = 0x07;
+    extra[7..23].copy_from_slice(&(0..16).collect::<Vec<u8>>());
+    data.extend(extra);
+    for bank in 0..8u8 {
+        data.extend_from_slice(&[0xff, 0xff, bank + 3]);
+        data.extend(vec![bank; BANK_SIZE]);
+    }
+
+    let snapshot = Z80Snapshot::read(&data).unwrap();
+    assert_eq!(snapshot.version, 2);
+    assert_eq!(snapshot.hardware, Hardware::Spectrum128k);
+    assert!(snapshot.hardware.is_128k());
+    assert_eq!(snapshot.regs.pc, 0x1234);
+    assert_eq!(snapshot.port_7ffd, 0x13);
+    assert_eq!(snapshot.ay.unwrap().selected, 7);
+    assert_eq!(snapshot.ay.unwrap().registers[15], 15);
+    assert_eq!(snapshot.tstates, None);
+    let ram = snapshot.ram();
+    assert_eq!((ram[0], ram[BANK_SIZE], ram[2 * BANK_SIZE]), (5, 2, 3));
+}
+
+#[test]
+fn version_3_snapshot_round_trips() {
+    let mut snapshot = Z80Snapshot::read(&{
+        let mut data = v1_header(false);
+        data.extend((0..3 * BANK_SIZE).map(|i| (i % 7) as u8));
+        data
+    }).unwrap();
+    snapshot.hardware = Hardware::SpectrumPlus2A;
+    snapshot.port_7ffd = 0x05;
+    snapshot.port_1ffd = Some(0x04);
+    snapshot.ay = Some(AyState { selected: 14, registers: [0xaa; 16] });
+    snapshot.tstates = Some(12345);
+    snapshot.pages.insert(3, vec![0xed; BANK_SIZE]);
+
+    let restored = Z80Snapshot::read(&snapshot.write().unwrap()).unwrap();
+    assert_eq!(restored.version, 3);
+    assert_eq!(restored, Z80Snapshot { version: 3, ..snapshot });
+}
+
+#[test]
+fn tstate_counter_round_trips_for_every_quarter() {
+    let mut data = v1_header(false);
+    data.extend(vec![0; 3 * BANK_SIZE]);
+    let mut snapshot = Z80Snapshot::read(&data).unwrap();
+    for tstates in [0, 1, 17471, 17472, 40000, 69887] {
+        snapshot.tstates = Some(tstates);
+        assert_eq!(Z80Snapshot::read(&snapshot.write().unwrap()).unwrap().tstates, Some(tstates));
+    }
+}
+
+#[test]
+fn rejects_invalid_snapshots() {
+    assert!(matches!(Z80Snapshot::read(&[0; 10]), Err(FormatError::InvalidSize(10))));
+    let mut data = v1_header(true);
+    data.extend_from_slice(&[0xed, 0xed, 10, 0]);
+    assert!(matches!(Z80Snapshot::read(&data), Err(FormatError::Invalid(_))));
+    let mut data = v1_header(false);
+    data[6..8].copy_from_slice(&[0, 0]);
+    data.extend_from_slice(&[40, 0]);
+    data.extend(vec![0; 40]);
+    assert!(matches!(Z80Snapshot::read(&data), Err(FormatError::Invalid(_))));
+}
+
+#[test]
+fn applies_and_captures_machine_state() {
+    let machine = Machine::new(Accuracy::Instructions);
+    machine.memory().write(0x9000, 0x55);
+    machine.cpu().rp(RegPair::PC).set(0x8000);
+    let snapshot = Z80Snapshot::capture(machine.cpu(), &**machine.memory(), 1);
+
+    let other = Machine::new(Accuracy::Instructions);
+    Z80Snapshot::read(&snapshot.write().unwrap()).unwrap().apply(other.cpu(), &**other.memory());
+    assert_eq!(other.cpu().rp(RegPair::PC).get(), 0x8000);
+    assert_eq!(other.memory().read(0x9000), 0x55);
+}
@@ -0,0 +1,170 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{program_machine, run_on_both_cores};
+
+use librespectrum::{
+    cpu::{CpuModel, Flags, tokens::{Reg, RegPair}},
+    devs::mem::Memory,
+    machine::{Accuracy, Machine},
+};
+
+/// Run Z80N program until HALT
+fn run(program: &[u8], setup: impl Fn(&Machine)) -> Machine {
+    run_on_both_cores(CpuModel::Z80N, program, setup)
+}
+
+#[test]
+fn swapnib_mirror_and_mul() {
+    let machine = run(&[
+        0x3e, 0x12,         // LD A,12h
+        0xed, 0x23,         // SWAPNIB
+        0xed, 0x24,         // MIRROR A
+        0x11, 0x0a, 0x0c,   // LD DE,0C0Ah
+        0xed, 0x30,         // MUL D,E
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x84);
+    assert_eq!(machine.cpu().rp(RegPair::DE).get(), 0x0078);
+}
+
+#[test]
+fn test_sets_flags_without_changing_accumulator() {
+    let machine = run(&[
+        0x3e, 0x0f,         // LD A,0Fh
+        0xed, 0x27, 0xf0,   // TEST F0h
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x0f);
+    assert!(machine.cpu().get_flags().contains(Flags::Z));
+}
+
+#[test]
+fn barrel_shifts_de_by_b() {
+    for (opcode, expected) in [(0x28, 0x0010), (0x29, 0xf800), (0x2a, 0x0800), (0x2b, 0xf800), (0x2c, 0x0018)] {
+        let machine = run(&[
+            0x11, 0x01, 0x80,   // LD DE,8001h
+            0x06, 0x04,         // LD B,4
+            0xed, opcode,       // BSLA/BSRA/BSRL/BSRF/BRLC DE,B
+            0x76,               // HALT
+        ], |_| {});
+        assert_eq!(machine.cpu().rp(RegPair::DE).get(), expected, "ED {:02X}", opcode);
+    }
+}
+
+#[test]
+fn pixel_address_calculation() {
+    let machine = run(&[
+        0x11, 0x23, 0x4f,   // LD DE,4F23h (Y=79, X=35)
+        0xed, 0x94,         // PIXELAD
+        0xed, 0x95,         // SETAE
+        0xe5,               // PUSH HL
+        0xed, 0x93,         // PIXELDN
+        0x76,               // HALT
+    ], |_| {});
+    assert_eq!(machine.memory().read(0xfffc), 0x24);
+    assert_eq!(machine.memory().read(0xfffd), 0x4f);
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x10);
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x4844);
+}
+
+#[test]
+fn add_register_pair_and_push_immediate() {
+    let machine = run(&[
+        0x21, 0x00, 0x10,       // LD HL,1000h
+        0x3e, 0xff,             // LD A,FFh
+        0xed, 0x31,             // ADD HL,A
+        0xed, 0x34, 0x34, 0x12, // ADD HL,1234h
+        0xed, 0x8a, 0x56, 0x78, // PUSH 5678h
+        0x76,                   // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x2333);
+    assert_eq!(machine.memory().read(0xfffc), 0x78);
+    assert_eq!(machine.memory().read(0xfffd), 0x56);
+}
+
+#[test]
+fn ldirx_skips_bytes_equal_to_accumulator() {
+    let machine = run(&[
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x11, 0x00, 0xa0,   // LD DE,A000h
+        0x01, 0x05, 0x00,   // LD BC,5
+        0x3e, 0xe3,         // LD A,E3h
+        0xed, 0xb4,         // LDIRX
+        0x76,               // HALT
+    ], |machine| {
+        machine.memory().load(0x9000, &vec![1, 2, 3, 0xe3, 5]);
+        machine.memory().load(0xa000, &vec![0xee; 5]);
+    });
+    assert_eq!((0..5).map(|i| machine.memory().read(0xa000 + i)).collect::<Vec<_>>(), vec![1, 2, 3, 0xee, 5]);
+    assert_eq!(machine.cpu().rp(RegPair::BC).get(), 0);
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x9005);
+    assert_eq!(machine.cpu().rp(RegPair::DE).get(), 0xa005);
+}
+
+#[test]
+fn ldpirx_repeats_8_byte_pattern() {
+    let machine = run(&[
+        0x21, 0x00, 0x90,   // LD HL,9000h
+        0x11, 0x05, 0xa0,   // LD DE,A005h
+        0x01, 0x04, 0x00,   // LD BC,4
+        0x3e, 0xff,         // LD A,FFh
+        0xed, 0xb7,         // LDPIRX
+        0x76,               // HALT
+    ], |machine| {
+        machine.memory().load(0x9000, &(0..8).collect());
+    });
+    assert_eq!((5..9).map(|i| machine.memory().read(0xa000 + i)).collect::<Vec<_>>(), vec![5, 6, 7, 0]);
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x9000);
+}
+
+#[test]
+fn ldws_advances_l_and_d() {
+    let machine = run(&[
+        0x21, 0xff, 0x90,   // LD HL,90FFh
+        0x11, 0x00, 0xa0,   // LD DE,A000h
+        0xed, 0xa5,         // LDWS
+        0x76,               // HALT
+    ], |machine| {
+        machine.memory().load(0x90ff, &vec![0x55]);
+    });
+    assert_eq!(machine.memory().read(0xa000), 0x55);
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x9000);
+    assert_eq!(machine.cpu().rp(RegPair::DE).get(), 0xa100);
+}
+
+#[test]
+fn outinb_and_nextreg_take_the_same_time_on_both_cores() {
+    let machine = run(&[
+        0x21, 0x00, 0x90,       // LD HL,9000h
+        0xed, 0x90,             // OUTINB
+        0xed, 0x91, 0x07, 0x03, // NEXTREG 7h,3h
+        0xed, 0x92, 0x56,       // NEXTREG 56h,A
+        0x76,                   // HALT
+    ], |_| {});
+    assert_eq!(machine.cpu().rp(RegPair::HL).get(), 0x9001);
+}
+
+#[test]
+fn jp_c_jumps_within_16k_bank() {
+    // Floating bus reads FFh, so jump goes to the last 64-byte block of the bank
+    let machine = run(&[
+        0xed, 0x98,         // JP (C)
+    ], |machine| {
+        machine.memory().load(0xbfc0, &vec![0x76]); // HALT
+    });
+    assert_eq!(machine.cpu().pc.value().get(), 0xbfc1);
+}
+
+#[test]
+fn plain_z80_treats_extended_opcodes_as_nops() {
+    // LD A,12h; SWAPNIB; HALT
+    let machine = program_machine(Accuracy::BusCycles, CpuModel::Z80, &[0x3e, 0x12, 0xed, 0x23, 0x76]);
+    for _ in 0..3 {
+        machine.step();
+    }
+    assert!(machine.cpu().halted.get());
+    assert_eq!(machine.cpu().rg(Reg::A).get(), 0x12);
+}
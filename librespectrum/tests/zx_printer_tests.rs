@@ -0,0 +1,139 @@
+#![feature(coroutines)]
+
+extern crate librespectrum;
+
+mod common;
+use common::{BASE, TestSystem};
+
+use librespectrum::{
+    cpu::tokens::Reg,
+    devs::{Device, PAPER_WIDTH, PrinterModel},
+    formats::bitmap::Bitmap,
+};
+
+/// Line buffer printed by the program
+const BUFFER: u16 = 0x9000;
+
+/// Print 3 dot rows from the buffer the way the ROM COPY-LINE routine does,
+/// slowing down for the last 2 rows
+const COPY_LINES: [u8; 53] = [
+    0x21, 0x00, 0x90,   // LD HL,9000h
+    0x06, 0x03,         // LD B,3
+    0xc5,               // LINE: PUSH BC
+    0x78,               // LD A,B
+    0xfe, 0x03,         // CP 3
+    0x9f,               // SBC A,A
+    0xe6, 0x02,         // AND 2
+    0xd3, 0xfb,         // OUT (FBh),A          motor on
+    0x57,               // LD D,A
+    0xdb, 0xfb,         // IN A,(FBh)           wait for line start
+    0x87,               // ADD A,A
+    0x30, 0xfb,         // JR NC,-5
+    0x0e, 0x20,         // LD C,32
+    0x5e,               // BYTE: LD E,(HL)
+    0x23,               // INC HL
+    0x06, 0x08,         // LD B,8
+    0xcb, 0x12,         // DOT: RL D
+    0xcb, 0x13,         // RL E
+    0xcb, 0x1a,         // RR D
+    0xdb, 0xfb,         // IN A,(FBh)           wait for encoder
+    0x1f,               // RRA
+    0x30, 0xfb,         // JR NC,-5
+    0x7a,               // LD A,D
+    0xd3, 0xfb,         // OUT (FBh),A          stylus
+    0x10, 0xf0,         // DJNZ DOT
+    0x0d,               // DEC C
+    0x20, 0xe9,         // JR NZ,BYTE
+    0xc1,               // POP BC
+    0x10, 0xd5,         // DJNZ LINE
+    0x3e, 0x04,         // LD A,4
+    0xd3, 0xfb,         // OUT (FBh),A          motor off
+    0x76,               // HALT
+];
+
+/// Dot rows with distinct patterns, including solid runs and single dots
+fn test_rows() -> Vec<u8> {
+    (0..3 * 32).map(|index: usize| match index / 32 {
+        0 => if index.is_multiple_of(2) { 0xff } else { 0x00 },
+        1 => 0x81,
+        _ => (index * 37) as u8,
+    }).collect()
+}
+
+#[test]
+fn copy_routine_prints_dot_rows() {
+    for model in [PrinterModel::ZxPrinter, PrinterModel::Alphacom32] {
+        let (system, printer) = TestSystem::with_device(&COPY_LINES, |devices, _| devices.create_zx_printer(model));
+        system.memory.load(BUFFER, &test_rows());
+        let mut scheduler = system.scheduler_with(vec![printer.run()]);
+        assert!(system.run_until(&mut scheduler, BASE + 52, 3_000_000));
+        assert!(!printer.motor());
+        let paper = printer.paper();
+        assert_eq!((paper.width, paper.height), (PAPER_WIDTH, 3));
+        assert_eq!(paper.data, test_rows());
+        assert_eq!(printer.tear_off(), paper);
+        assert_eq!(printer.paper().height, 0);
+    }
+}
+
+#[test]
+fn encoder_doesnt_pulse_while_motor_is_off() {
+    let (system, printer) = TestSystem::with_device(&[
+        0xdb, 0xfb,         // IN A,(FBh)
+        0x5f,               // LD E,A
+        0x06, 0x00,         // LD B,0
+        0x10, 0xfe,         // DJNZ $
+        0xdb, 0xfb,         // IN A,(FBh)
+        0x4f,               // LD C,A
+        0x76,               // HALT
+    ], |devices, _| devices.create_zx_printer(PrinterModel::ZxPrinter));
+    let mut scheduler = system.scheduler_with(vec![printer.run()]);
+    assert!(system.run_until(&mut scheduler, BASE + 10, 10000));
+    // Printer is present, stylus is off the paper
+    assert_eq!(system.cpu.rg(Reg::E).get() & 0xc1, 0x00);
+    assert_eq!(system.cpu.rg(Reg::C).get() & 0xc1, 0x00);
+    assert_eq!(printer.paper().height, 0);
+}
+
+#[test]
+fn powered_stylus_burns_dots_it_passes() {
+    let (system, printer) = TestSystem::with_device(&[
+        0x3e, 0x80,         // LD A,80h
+        0xd3, 0xfb,         // OUT (FBh),A          motor on, stylus on
+        0x76,               // HALT
+    ], |devices, _| devices.create_zx_printer(PrinterModel::Alphacom32));
+    let mut scheduler = system.scheduler_with(vec![printer.run()]);
+    // Way to the left edge, two passes and 192 dots of the third one
+    scheduler.run(2 * 600 * (128 + 384 * 2 + 192));
+    let paper = printer.paper();
+    assert_eq!(paper.height, 3);
+    assert!((0..3).all(|y| (0..128).all(|x| paper.pixel(x, y))));
+    assert!((0..2).all(|y| paper.row(y) == [0xff; 32]));
+    assert!((200..PAPER_WIDTH).all(|x| !paper.pixel(x, 2)));
+}
+
+#[test]
+fn bitmap_is_saved_as_pbm_and_png() {
+    let mut bitmap = Bitmap::new(10, 2);
+    bitmap.set_pixel(0, 0, true);
+    bitmap.set_pixel(9, 1, true);
+    bitmap.set_pixel(5, 1, true);
+    bitmap.set_pixel(5, 1, false);
+    assert_eq!(bitmap.data, [0x80, 0x00, 0x00, 0x40]);
+
+    let mut pbm = b"P4\n10 2\n".to_vec();
+    pbm.extend(&bitmap.data);
+    assert_eq!(bitmap.write_pbm(), pbm);
+
+    let png = bitmap.write_png().unwrap();
+    assert_eq!(png[..8], [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+    assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+    assert_eq!(png[16..29], [0, 0, 0, 10, 0, 0, 0, 2, 1, 0, 0, 0, 0]);
+    let idat_size = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+    assert_eq!(png[37..41], *b"IDAT");
+    let rows = miniz_oxide::inflate::decompress_to_vec_zlib(&png[41..41 + idat_size]).unwrap();
+    assert_eq!(rows, [0x00, 0x7f, 0xff, 0x00, 0xff, 0xbf]);
+    assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+
+    assert!(Bitmap::new(PAPER_WIDTH, 0).write_png().is_err());
+}
@@ -4,24 +4,303 @@ extern crate librespectrum;
 
 use librespectrum::{
     core::{Clock, CpuBus, Scheduler},
-    devs::{BreakpointManager, Device, DeviceManager, mem::Memory}
+    devs::{Beta128, BlockImage, BreakpointManager, Cpu, CpuTrap, Device, DeviceManager, FileImage, FloppyDrive, Interface1, LdBytesTrap, Multiface, MultifaceModel, Plus3Disk, PrinterModel, TapeDeck, TapeRecorder, ZxPrinter, mem::Memory},
+    formats::{FormatError, csw::Csw, dsk::Dsk, mdr::Mdr, pzx::Pzx, scl::Scl, sna::Sna, szx::Szx, tap::Tap, trd::Trd, tzx::Tzx, wav::{EdgeDetector, Wav}, z80::Z80Snapshot},
 };
 
 use std::{
     rc::Rc,
     vec::Vec,
-    fs::File,
+    fs::{self, File},
+    path::Path,
     io::Read,
     ops::Deref,
     cell::RefCell,
 };
 
 mod windows;
-use windows::{SubWindow, CpuWindow, DisassmWindow, MemoryWindow, BusWindow, DisplayWindow};
+use windows::{SubWindow, CpuWindow, DisassmWindow, MemoryWindow, BusWindow, DisplayWindow, TapeWindow};
+
+/// File operation requested from the menu
+#[derive(Clone, Copy, PartialEq)]
+enum FileAction {
+    OpenSnapshot,
+    SaveSnapshot,
+    InsertTape,
+    SaveRecording,
+    InsertDisk,
+    SaveDisk,
+    SavePrintout,
+}
+
+/// File path prompt with the last error message
+struct FileDialog {
+    action: FileAction,
+    path: String,
+    error: Option<String>,
+}
+
+/// Disk image format
+#[derive(Clone, Copy)]
+enum DiskFormat {
+    Dsk { extended: bool },
+    Trd,
+    Scl,
+}
+
+/// Image file the disk in a drive was inserted from, written back when the disk is modified
+struct DiskSource {
+    path: String,
+    format: DiskFormat,
+}
+
+impl DiskSource {
+
+    /// Write modified disk in the drive back to the image
+    fn write_back(&self, drive: &FloppyDrive) -> Result<(), FormatError> {
+        if let Some(disk) = drive.disk() && drive.is_modified() {
+            let data = match self.format {
+                DiskFormat::Dsk { extended } => Dsk { extended, disk }.write()?,
+                DiskFormat::Trd => Trd::from_disk(&disk).write(),
+                DiskFormat::Scl => Scl::from_trd(&Trd::from_disk(&disk))?.write(),
+            };
+            fs::write(&self.path, data)?;
+        }
+        Ok(())
+    }
+
+}
+
+/// Lowercase file extension
+fn extension(path: &str) -> String {
+    Path::new(path).extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_lowercase()
+}
 
 struct EmulApp<'a> {
     windows: Vec<(bool, Box<dyn SubWindow + 'a>)>,
     focus: usize,
+    cpu: Rc<Cpu>,
+    memory: Rc<dyn Memory>,
+    tape: Rc<TapeDeck>,
+    recorder: Rc<TapeRecorder>,
+    beta: Option<Rc<Beta128>>,
+    beta_source: RefCell<Option<DiskSource>>,
+    interface1: Option<Rc<Interface1>>,
+    multiface: Option<Rc<Multiface>>,
+    plus3_disk: Rc<Plus3Disk>,
+    plus3_source: RefCell<Option<DiskSource>>,
+    printer: Rc<ZxPrinter>,
+    dialog: Option<FileDialog>,
+}
+
+impl EmulApp<'_> {
+
+    /// Load SNA, Z80 or SZX snapshot into CPU and memory
+    fn open_snapshot(&self, path: &str) -> Result<(), FormatError> {
+        let data = fs::read(path)?;
+        match extension(path).as_str() {
+            "z80" => {
+                let snapshot = Z80Snapshot::read(&data)?;
+                if snapshot.hardware.is_128k() {
+                    return Err(FormatError::Invalid("128K snapshots are not supported yet"));
+                }
+                snapshot.apply(&self.cpu, &*self.memory);
+            },
+            "szx" => {
+                let szx = Szx::read(&data)?;
+                if szx.is_128k() {
+                    return Err(FormatError::Invalid("128K snapshots are not supported yet"));
+                }
+                szx.apply(&self.cpu, &*self.memory)?;
+            },
+            "sna" => {
+                let snapshot = Sna::read(&data)?;
+                if snapshot.paging.is_some() {
+                    return Err(FormatError::Invalid("128K snapshots are not supported yet"));
+                }
+                snapshot.apply(&self.cpu, &*self.memory);
+            },
+            _ => return Err(FormatError::Invalid("Unknown snapshot extension")),
+        }
+        Ok(())
+    }
+
+    /// Save CPU and memory as 48K SNA, Z80 or SZX snapshot. Border color isn't emulated yet.
+    fn save_snapshot(&self, path: &str) -> Result<(), FormatError> {
+        let data = match extension(path).as_str() {
+            "z80" => Z80Snapshot::capture(&self.cpu, &*self.memory, 0).write()?,
+            "szx" => Szx::capture(&self.cpu, &*self.memory, 0, 0).write(),
+            "sna" => Sna::capture(&self.cpu, &*self.memory, 0).write()?,
+            _ => return Err(FormatError::Invalid("Unknown snapshot extension")),
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Insert TAP, TZX, PZX, CSW tape image or WAV capture into the tape deck
+    fn insert_tape(&self, path: &str) -> Result<(), FormatError> {
+        let data = fs::read(path)?;
+        self.tape.insert(match extension(path).as_str() {
+            "tzx" => Tzx::read(&data)?.tape_blocks(true),
+            "pzx" => Pzx::read(&data)?.tape_blocks(true),
+            "csw" => Csw::read(&data)?.tape_blocks(),
+            "wav" => Wav::read(&data)?.tape_blocks(EdgeDetector::default()),
+            _ => Tap::read(&data)?.tape_blocks(),
+        });
+        Ok(())
+    }
+
+    /// Save recorded tape signal as TAP, TZX or 44.1 kHz WAV
+    fn save_recording(&self, path: &str) -> Result<(), FormatError> {
+        let data = match extension(path).as_str() {
+            "tzx" => self.recorder.to_tzx().write(),
+            "wav" => self.recorder.to_wav(44100).write()?,
+            _ => self.recorder.to_tap().write(),
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Beta 128 interface, available with TR-DOS ROM only
+    fn beta(&self) -> Result<&Beta128, FormatError> {
+        self.beta.as_deref().ok_or(FormatError::Invalid("Beta 128 interface requires roms/trdos.rom"))
+    }
+
+    /// Interface 1, available with its ROM only
+    fn interface1(&self) -> Result<&Interface1, FormatError> {
+        self.interface1.as_deref().ok_or(FormatError::Invalid("Interface 1 requires roms/if1.rom"))
+    }
+
+    /// Insert DSK image into +3 drive A, TRD or SCL image into Beta 128 drive A,
+    /// or MDR cartridge into microdrive 1. Modified disk is written back before it's replaced.
+    fn insert_disk(&self, path: &str) -> Result<(), FormatError> {
+        let extension = extension(path);
+        match extension.as_str() {
+            "dsk" => {
+                self.write_back_plus3_disk()?;
+                let dsk = Dsk::read(&fs::read(path)?)?;
+                self.plus3_disk.drive(0).insert(dsk.disk);
+                self.plus3_source.replace(Some(DiskSource { path: path.to_string(), format: DiskFormat::Dsk { extended: dsk.extended } }));
+            },
+            "trd" | "scl" => {
+                self.write_back_beta_disk()?;
+                let data = fs::read(path)?;
+                let (trd, format) = if extension == "scl" {
+                    (Scl::read(&data)?.trd()?, DiskFormat::Scl)
+                } else {
+                    (Trd::read(&data)?, DiskFormat::Trd)
+                };
+                self.beta()?.drive(0).insert(trd.disk());
+                self.beta_source.replace(Some(DiskSource { path: path.to_string(), format }));
+            },
+            "mdr" => self.interface1()?.drive(0).insert(Mdr::read(&fs::read(path)?)?),
+            _ => return Err(FormatError::Invalid("Unknown disk image extension")),
+        }
+        Ok(())
+    }
+
+    /// Save disk in +3 drive A as DSK image, disk in Beta 128 drive A as TRD image, or
+    /// cartridge in microdrive 1 as MDR image. DSK image is extended only if the disk
+    /// can't be saved as standard one.
+    fn save_disk(&self, path: &str) -> Result<(), FormatError> {
+        let data = match extension(path).as_str() {
+            "mdr" => self.interface1()?.drive(0).cartridge().ok_or(FormatError::Invalid("No cartridge in microdrive 1"))?.write(),
+            "dsk" => {
+                let disk = self.plus3_disk.drive(0).disk().ok_or(FormatError::Invalid("No disk in +3 drive A"))?;
+                Dsk { extended: false, disk }.write()?
+            },
+            "trd" => {
+                let disk = self.beta()?.drive(0).disk().ok_or(FormatError::Invalid("No disk in Beta 128 drive A"))?;
+                Trd::from_disk(&disk).write()
+            },
+            _ => return Err(FormatError::Invalid("Unknown disk image extension")),
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Write modified disk in +3 drive A back to the image it was inserted from
+    fn write_back_plus3_disk(&self) -> Result<(), FormatError> {
+        match &*self.plus3_source.borrow() {
+            Some(source) => source.write_back(self.plus3_disk.drive(0)),
+            None => Ok(()),
+        }
+    }
+
+    /// Write modified disk in Beta 128 drive A back to the image it was inserted from
+    fn write_back_beta_disk(&self) -> Result<(), FormatError> {
+        match (&*self.beta_source.borrow(), &self.beta) {
+            (Some(source), Some(beta)) => source.write_back(beta.drive(0)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Save paper printed so far as PBM or PNG image
+    fn save_printout(&self, path: &str) -> Result<(), FormatError> {
+        let paper = self.printer.paper();
+        let data = match extension(path).as_str() {
+            "pbm" => paper.write_pbm(),
+            _ => paper.write_png()?,
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Show file path prompt and perform requested action once confirmed
+    fn show_dialog(&mut self, ctx: &egui::Context) {
+
+        let Some(dialog) = &mut self.dialog else { return };
+        let (title, hint) = match dialog.action {
+            FileAction::OpenSnapshot => ("Open snapshot", "File path (.sna, .z80, .szx):"),
+            FileAction::SaveSnapshot => ("Save snapshot", "File path (.sna, .z80, .szx):"),
+            FileAction::InsertTape => ("Insert tape", "File path (.tap, .tzx, .pzx, .csw, .wav):"),
+            FileAction::SaveRecording => ("Save recording", "File path (.tap, .tzx, .wav):"),
+            FileAction::InsertDisk => ("Insert disk", "File path (.trd, .scl, .dsk, .mdr):"),
+            FileAction::SaveDisk => ("Save disk", "File path (.trd, .dsk, .mdr):"),
+            FileAction::SavePrintout => ("Save printout", "File path (.png, .pbm):"),
+        };
+
+        let mut confirmed = false;
+        let mut cancelled = false;
+
+        egui::Window::new(title).collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label(hint);
+            let response = ui.text_edit_singleline(&mut dialog.path);
+            if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                confirmed = true;
+            }
+            if let Some(error) = &dialog.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+            ui.horizontal(|ui| {
+                confirmed |= ui.button("OK").clicked();
+                cancelled = ui.button("Cancel").clicked();
+            });
+        });
+
+        if confirmed {
+            let (action, path) = (dialog.action, dialog.path.clone());
+            let result = match action {
+                FileAction::OpenSnapshot => self.open_snapshot(&path),
+                FileAction::SaveSnapshot => self.save_snapshot(&path),
+                FileAction::InsertTape => self.insert_tape(&path),
+                FileAction::SaveRecording => self.save_recording(&path),
+                FileAction::InsertDisk => self.insert_disk(&path),
+                FileAction::SaveDisk => self.save_disk(&path),
+                FileAction::SavePrintout => self.save_printout(&path),
+            };
+            match result {
+                Ok(()) => self.dialog = None,
+                Err(err) => if let Some(dialog) = &mut self.dialog {
+                    dialog.error = Some(err.to_string());
+                },
+            }
+        } else if cancelled {
+            self.dialog = None;
+        }
+
+    }
+
 }
 
 impl eframe::App for EmulApp<'_> {
@@ -35,6 +314,21 @@ impl eframe::App for EmulApp<'_> {
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
+                    for (action, label) in [
+                        (FileAction::OpenSnapshot, "Open snapshot..."),
+                        (FileAction::SaveSnapshot, "Save snapshot..."),
+                        (FileAction::InsertTape, "Insert tape..."),
+                        (FileAction::SaveRecording, "Save recording..."),
+                        (FileAction::InsertDisk, "Insert disk..."),
+                        (FileAction::SaveDisk, "Save disk..."),
+                        (FileAction::SavePrintout, "Save printout..."),
+                    ] {
+                        if ui.button(label).clicked() {
+                            self.dialog = Some(FileDialog { action, path: String::new(), error: None });
+                            ui.close_menu();
+                        }
+                    }
+                    ui.separator();
                     if ui.button("Quit").clicked() {
                         frame.quit();
                     }
@@ -44,6 +338,9 @@ impl eframe::App for EmulApp<'_> {
                         ui.checkbox(open, window.name());
                     }
                 });
+                if let Some(multiface) = &self.multiface && ui.button("Multiface").clicked() {
+                    multiface.press();
+                }
                 ui.with_layout(egui::Layout::right_to_left(), |ui| {
                     egui::warn_if_debug_build(ui);
                 });
@@ -59,6 +356,16 @@ impl eframe::App for EmulApp<'_> {
             }
         }
 
+        self.show_dialog(ctx);
+
+    }
+
+    fn on_exit(&mut self, _gl: &eframe::glow::Context) {
+        for result in [self.write_back_plus3_disk(), self.write_back_beta_disk()] {
+            if let Err(err) = result {
+                eprintln!("Failed to write back disk: {}", err);
+            }
+        }
     }
 
 }
@@ -71,18 +378,48 @@ fn main() {
     let breakpoint_manager = Rc::new(BreakpointManager::default());
     let device_manager = Rc::new(DeviceManager::new(&bus, &clock, &breakpoint_manager));
     let cpu = device_manager.create_cpu();
-    let mem: Rc<dyn Memory> = {
-        let mem = device_manager.create_48k_memory();
+    let mem = device_manager.create_48k_memory();
+    {
         let mut buffer: Vec<u8> = Vec::new();
         File::open("roms/48.rom").unwrap().read_to_end(&mut buffer).unwrap();
         mem.load(0, &buffer);
-        mem
-    };
+    }
+    // Beta 128 interface is attached when TR-DOS ROM is available
+    let beta = fs::read("roms/trdos.rom").ok().map(|rom| device_manager.create_beta128(&mem, &rom));
+    // DivMMC interface is attached when esxDOS ROM is available, with card backed by sd.img if it exists
+    let divmmc = fs::read("roms/esxmmc.rom").ok().map(|rom| device_manager.create_divmmc(&mem, &rom));
+    if let Some(divmmc) = &divmmc && let Ok(image) = FileImage::open("sd.img") {
+        divmmc.card().insert(&(Rc::new(image) as Rc<dyn BlockImage>));
+    }
+    // Interface 1 is attached when its ROM is available
+    let interface1 = fs::read("roms/if1.rom").ok().map(|rom| device_manager.create_interface1(&mem, &rom));
+    // Multiface One is attached when its ROM is available
+    let multiface = fs::read("roms/mf1.rom").ok().map(|rom| device_manager.create_multiface(&mem, MultifaceModel::One, &rom));
+    let mem: Rc<dyn Memory> = mem;
+    let plus3_disk = device_manager.create_plus3_disk();
+    let printer = device_manager.create_zx_printer(PrinterModel::ZxPrinter);
     let logger = device_manager.create_bus_logger();
+    let tape = Rc::new(TapeDeck::default());
+    // Fast load is enabled initially
+    let ld_bytes_trap: Rc<dyn CpuTrap> = Rc::new(LdBytesTrap::new(&tape, &mem));
+    cpu.add_trap(&ld_bytes_trap);
+    let player = device_manager.create_tape_player(&tape);
+    let recorder = device_manager.create_tape_recorder();
 
-    let scheduler =  Rc::new(RefCell::new(
-        Scheduler::new(&clock, vec![cpu.run(), mem.run(), logger.run()])
-    ));
+    let mut tasks = vec![cpu.run(), mem.run(), logger.run(), player.run(), recorder.run(), plus3_disk.run(), printer.run()];
+    if let Some(beta) = &beta {
+        tasks.push(beta.run());
+    }
+    if let Some(divmmc) = &divmmc {
+        tasks.push(divmmc.run());
+    }
+    if let Some(interface1) = &interface1 {
+        tasks.push(interface1.run());
+    }
+    if let Some(multiface) = &multiface {
+        tasks.push(multiface.run());
+    }
+    let scheduler =  Rc::new(RefCell::new(Scheduler::new(&clock, tasks)));
 
     let app = Box::new(EmulApp {
         windows: vec![
@@ -91,8 +428,21 @@ fn main() {
             (true, Box::new(MemoryWindow::new(&mem))),
             (false, Box::new(BusWindow::new(&logger, &device_manager))),
             (false, Box::new(DisplayWindow::new(&mem))),
+            (false, Box::new(TapeWindow::new(&cpu, &tape, &player, &recorder, &ld_bytes_trap))),
         ],
         focus: 0,
+        cpu,
+        memory: mem,
+        tape,
+        recorder,
+        beta,
+        beta_source: RefCell::new(None),
+        interface1,
+        multiface,
+        plus3_disk,
+        plus3_source: RefCell::new(None),
+        printer,
+        dialog: None,
     });
 
     run_native(app);
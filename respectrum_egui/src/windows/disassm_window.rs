@@ -39,7 +39,7 @@ impl<'a> DisassmWindow<'a> {
 
     fn prev_instr(&self, addr: u16) -> u16 {
         let mut ptr = addr.wrapping_sub((LINE_BYTES * 2) as u16);
-        let mut disasm = disassembler(ptr, LINE_BYTES);
+        let mut disasm = disassembler(ptr, LINE_BYTES, self.cpu.model());
         let mut prev = addr;
         loop {
             let byte = self.memory.read(ptr);
@@ -55,7 +55,7 @@ impl<'a> DisassmWindow<'a> {
 
     fn next_instr(&self, addr: u16) -> u16 {
         let mut ptr = addr;
-        let mut disasm = disassembler(ptr, LINE_BYTES);
+        let mut disasm = disassembler(ptr, LINE_BYTES, self.cpu.model());
         loop {
             let byte = self.memory.read(ptr);
             ptr = ptr.wrapping_add(1);
@@ -154,7 +154,7 @@ impl<'a> SubWindow for DisassmWindow<'a> {
 
             Grid::new("memory").min_col_width(0.0).show(ui, |ui| {
 
-                let mut disasm = disassembler(self.addr, LINE_BYTES);
+                let mut disasm = disassembler(self.addr, LINE_BYTES, self.cpu.model());
                 let mut ptr = self.addr;
                 let pc = self.cpu.pc.value().get();
 
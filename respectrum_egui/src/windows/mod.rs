@@ -11,6 +11,8 @@ mod memory_window;
 pub use memory_window::MemoryWindow;
 mod display_window;
 pub use display_window::DisplayWindow;
+mod tape_window;
+pub use tape_window::TapeWindow;
 
 pub trait SubWindow {
 
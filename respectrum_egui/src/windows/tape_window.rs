@@ -0,0 +1,106 @@
+use egui::*;
+use std::rc::Rc;
+
+use librespectrum::devs::{Cpu, CpuTrap, TapeDeck, TapePlayer, TapeRecorder};
+
+use super::{SubWindow, draw_window, cursor_color};
+
+pub struct TapeWindow {
+    cpu: Rc<Cpu>,
+    deck: Rc<TapeDeck>,
+    player: Rc<TapePlayer>,
+    recorder: Rc<TapeRecorder>,
+    ld_bytes_trap: Rc<dyn CpuTrap>,
+    fast_load: bool,
+}
+
+impl TapeWindow {
+
+    /// Create tape browser with fast load switch reflecting whether the trap is registered
+    pub fn new(cpu: &Rc<Cpu>, deck: &Rc<TapeDeck>, player: &Rc<TapePlayer>, recorder: &Rc<TapeRecorder>, ld_bytes_trap: &Rc<dyn CpuTrap>) -> Self {
+        Self {
+            cpu: Rc::clone(cpu),
+            deck: Rc::clone(deck),
+            player: Rc::clone(player),
+            recorder: Rc::clone(recorder),
+            ld_bytes_trap: Rc::clone(ld_bytes_trap),
+            fast_load: cpu.has_trap(ld_bytes_trap),
+        }
+    }
+
+}
+
+impl SubWindow for TapeWindow {
+
+    fn name(&self) -> String { String::from("Tape") }
+
+    fn show(&mut self, ctx: &Context, focused: bool) -> Response {
+
+        draw_window(self.name(), focused, ctx, |ui| {
+
+            if ui.checkbox(&mut self.fast_load, "Fast load").changed() {
+                if self.fast_load {
+                    self.cpu.add_trap(&self.ld_bytes_trap);
+                } else {
+                    self.cpu.remove_trap(&self.ld_bytes_trap);
+                }
+            }
+
+            ui.horizontal(|ui| {
+                if self.player.is_playing() {
+                    if ui.button("Stop").clicked() {
+                        self.player.stop();
+                    }
+                } else if ui.button("Play").clicked() {
+                    self.player.play();
+                }
+                if ui.button("Rewind").clicked() {
+                    self.player.rewind();
+                }
+                let mut auto_stop = self.player.auto_stop.get();
+                if ui.checkbox(&mut auto_stop, "Auto stop").changed() {
+                    self.player.auto_stop.set(auto_stop);
+                }
+            });
+
+            // Recording is saved with File > Save recording
+            ui.horizontal(|ui| {
+                if self.recorder.is_recording() {
+                    if ui.button("Stop recording").clicked() {
+                        self.recorder.stop();
+                    }
+                } else if ui.button("Record").clicked() {
+                    self.recorder.record();
+                }
+            });
+
+            ui.separator();
+
+            let blocks = self.deck.blocks();
+            if blocks.is_empty() {
+                ui.label("No tape inserted");
+            }
+
+            // Click on a block to continue loading from it
+            Grid::new("tape_blocks").min_col_width(0.0).show(ui, |ui| {
+                for (idx, block) in blocks.iter().enumerate() {
+                    let background = if idx == self.deck.position() {cursor_color(focused)} else {Color32::default()};
+                    ui.label(format!("{:>3}", idx + 1));
+                    if ui.add(
+                        Label::new(RichText::new(&block.description).background_color(background))
+                            .sense(Sense::click())
+                    ).clicked() {
+                        self.deck.seek(idx);
+                    }
+                    if block.data.as_ref().is_some_and(|data| !data.checksum_valid()) {
+                        ui.colored_label(Color32::RED, "bad checksum");
+                    }
+                    ui.end_row();
+                }
+            });
+
+        })
+
+    }
+
+}
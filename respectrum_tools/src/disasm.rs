@@ -13,7 +13,7 @@ use std::{
     io::{self, BufReader, BufRead, Read}
 };
 
-use librespectrum::cpu::decoder::disassembler;
+use librespectrum::cpu::{CpuModel, decoder::disassembler};
 
 /// Maximum bytes to process for each disassembled line
 const LINE_BYTES: usize = 4;
@@ -31,6 +31,10 @@ struct Args {
     #[clap(short, long, value_name = "FILE")]
     input_file: Option<PathBuf>,
 
+    /// CPU model (z80, z80n or z180)
+    #[clap(short, long, value_name = "CPU", default_value = "z80")]
+    cpu: CpuModel,
+
 }
 
 fn main() {
@@ -43,7 +47,7 @@ fn main() {
     };
 
     let mut bytes = reader.bytes();
-    let mut disasm = disassembler(args.base_address, LINE_BYTES);
+    let mut disasm = disassembler(args.base_address, LINE_BYTES, args.cpu);
 
     while let Some(Ok(byte)) = bytes.next() {
 
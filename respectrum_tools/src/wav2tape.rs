@@ -0,0 +1,65 @@
+extern crate librespectrum;
+
+use clap::Parser;
+
+use std::{fs, path::PathBuf, process};
+
+use librespectrum::formats::{FormatError, pzx::Pzx, tzx::Tzx, wav::{EdgeDetector, Wav}};
+
+/// Convert WAV tape capture into TZX or PZX
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+
+    /// Signal level separating low and high samples (16-bit scale)
+    #[clap(short, long, value_name = "LEVEL", default_value_t = 0, allow_hyphen_values = true)]
+    threshold: i16,
+
+    /// Schmitt trigger hysteresis around the threshold, 0 for plain threshold
+    #[clap(short = 'y', long, value_name = "LEVEL", default_value_t = 0x400)]
+    hysteresis: i16,
+
+    /// WAV file to convert
+    #[clap(value_name = "INPUT")]
+    input_file: PathBuf,
+
+    /// Output file, format is selected by .tzx or .pzx extension
+    #[clap(value_name = "OUTPUT")]
+    output_file: PathBuf,
+
+}
+
+fn convert(args: &Args) -> Result<(), FormatError> {
+
+    let detector = match args.hysteresis {
+        0 => EdgeDetector::Threshold(args.threshold),
+        hysteresis => EdgeDetector::Schmitt {
+            low: args.threshold.saturating_sub(hysteresis),
+            high: args.threshold.saturating_add(hysteresis),
+        },
+    };
+
+    let (level, pulses) = Wav::read(&fs::read(&args.input_file)?)?.pulses(detector);
+
+    // Standard speed blocks are decoded, so they are stored clean
+    let tzx = Tzx::from_pulses(level, &pulses);
+    let data = match args.output_file.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+        Some("pzx") => Pzx::from_tape(&tzx.tape_blocks(false)).write(),
+        _ => tzx.write(),
+    };
+
+    fs::write(&args.output_file, data)?;
+    Ok(())
+
+}
+
+fn main() {
+
+    let args = Args::parse();
+
+    if let Err(err) = convert(&args) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+}